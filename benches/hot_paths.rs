@@ -0,0 +1,129 @@
+#[macro_use] extern crate criterion;
+extern crate futures;
+extern crate netbuf;
+extern crate tk_bufstream;
+extern crate tk_http;
+
+use criterion::Criterion;
+use futures::Async;
+use futures::future::{FutureResult, ok};
+use netbuf::Buf;
+use tk_bufstream::MockData;
+
+use tk_http::{Version};
+use tk_http::bench::{parse_request_headers, decode_chunked};
+use tk_http::bench::{MessageState, MessageBody, HeaderLimits};
+use tk_http::bench::{websocket_mask};
+use tk_http::server::{Dispatcher, Codec, Head, RecvMode, Error};
+use tk_http::server::{Encoder, EncoderDone};
+use tk_http::websocket::{Frame, Packet, ClientCodec};
+use tk_bufstream::Encode;
+
+
+const REQUEST: &'static [u8] = b"\
+    GET /api/v1/items?offset=10&limit=100 HTTP/1.1\r\n\
+    Host: example.com\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:58.0) Firefox/58.0\r\n\
+    Accept: text/html,application/xhtml+xml,application/xml;q=0.9\r\n\
+    Accept-Language: en-US,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate, br\r\n\
+    Cookie: session=0123456789abcdef; theme=dark\r\n\
+    Connection: keep-alive\r\n\
+    \r\n";
+
+struct Disp;
+struct Nop;
+
+impl Dispatcher<MockData> for Disp {
+    type Codec = Nop;
+    fn headers_received(&mut self, _headers: &Head)
+        -> Result<Nop, Error>
+    {
+        Ok(Nop)
+    }
+}
+
+impl Codec<MockData> for Nop {
+    type ResponseFuture = FutureResult<EncoderDone<MockData>, Error>;
+    fn recv_mode(&mut self) -> RecvMode {
+        RecvMode::buffered_upfront(0)
+    }
+    fn data_received(&mut self, data: &[u8], _end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        Ok(Async::Ready(data.len()))
+    }
+    fn start_response(&mut self, e: Encoder<MockData>)
+        -> Self::ResponseFuture
+    {
+        ok(e.done())
+    }
+}
+
+fn headers(c: &mut Criterion) {
+    c.bench_function("parse_request_headers", |b| b.iter(|| {
+        let mut buf = Buf::new();
+        buf.extend(REQUEST);
+        parse_request_headers(&mut buf, &mut Disp).unwrap().unwrap();
+    }));
+}
+
+fn serializer(c: &mut Criterion) {
+    c.bench_function("serialize_response", |b| b.iter(|| {
+        let mut buf = Buf::new();
+        let mut msg = MessageState::ResponseStart {
+            version: Version::Http11,
+            body: MessageBody::Normal,
+            close: false,
+        };
+        let limits = HeaderLimits::default();
+        msg.response_status(&mut buf, 200, "OK");
+        msg.add_header(&mut buf, "Content-Type", b"text/html", &limits)
+            .unwrap();
+        msg.add_header(&mut buf, "Cache-Control", b"no-cache", &limits)
+            .unwrap();
+        msg.format_header(&mut buf, "X-Request-Id", 1234567, &limits)
+            .unwrap();
+        msg.add_length(&mut buf, 12).unwrap();
+        msg.done_headers(&mut buf).unwrap();
+        msg.write_body(&mut buf, b"Hello World!");
+        msg.done(&mut buf);
+    }));
+}
+
+fn chunked(c: &mut Criterion) {
+    let mut data = Vec::new();
+    for _ in 0..16 {
+        data.extend_from_slice(b"400\r\n");
+        data.extend_from_slice(&[b'x'; 1024]);
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b"0\r\n\r\n");
+    c.bench_function("decode_chunked_16k", move |b| b.iter(|| {
+        let mut buf = Buf::new();
+        buf.extend(&data);
+        assert_eq!(decode_chunked(&mut buf).unwrap(), Some(16384));
+    }));
+}
+
+fn websocket(c: &mut Criterion) {
+    let mut data = Buf::new();
+    // frames sent by client are masked, so this includes unmasking
+    ClientCodec.encode(Packet::Binary(vec![b'x'; 4096]), &mut data);
+    c.bench_function("parse_websocket_frame_4k", move |b| b.iter(|| {
+        let mut buf = Buf::new();
+        buf.extend(&data[..]);
+        Frame::parse(&mut buf, 65536, true).unwrap().unwrap();
+    }));
+}
+
+fn masking(c: &mut Criterion) {
+    // offset by one byte, so both unaligned head and tail are exercised
+    let mut data = vec![b'x'; (1 << 20) + 1];
+    c.bench_function("websocket_mask_1m", move |b| b.iter(|| {
+        websocket_mask(&mut data[1..], [0x12, 0x34, 0x56, 0x78]);
+    }));
+}
+
+criterion_group!(benches, headers, serializer, chunked, websocket, masking);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate tk_http;
+
+fuzz_target!(|data: &[u8]| {
+    tk_http::fuzz::request_head(data);
+});
@@ -2,7 +2,8 @@
 //! implementation
 
 use std::fmt::Display;
-use std::io::Write;
+use std::io::{self, Write};
+use std::usize;
 #[allow(unused_imports)]
 use std::ascii::AsciiExt;
 
@@ -12,38 +13,68 @@ use tk_bufstream::Buf;
 use enums::Version;
 
 quick_error! {
+    /// Error adding a header (or a status line) to the message
     #[derive(Debug)]
     pub enum HeaderError {
+        /// Content-Length header is added twice
         DuplicateContentLength {
             description("Content-Length is added twice")
         }
+        /// Transfer-Encoding header is added twice
         DuplicateTransferEncoding {
             description("Transfer-Encoding is added twice")
         }
+        /// Header name contains invalid characters
         InvalidHeaderName {
             description("Header name contains invalid characters")
         }
-        InvalidHeaderValue {
-            description("Header value contains invalid characters")
+        /// Header value contains invalid characters or is too long
+        InvalidHeaderValue(name: String) {
+            description("Header value contains invalid characters \
+                or is too long")
+            display("Value of the header {:?} contains invalid characters \
+                or is too long", name)
         }
+        /// Transfer-Encoding is added after Content-Length
         TransferEncodingAfterContentLength {
             description("Transfer encoding added when Content-Length is \
                 already specified")
         }
+        /// Content-Length is added after Transfer-Encoding
         ContentLengthAfterTransferEncoding {
             description("Content-Length added after Transfer-Encoding")
         }
+        /// Neither Content-Length nor Transfer-Encoding is added
         CantDetermineBodySize {
             description("Neither Content-Length nor Transfer-Encoding \
                 is present in the headers")
         }
+        /// Body length header is added by the generic header method
         BodyLengthHeader {
             description("Content-Length and Transfer-Encoding must be set \
                 using the specialized methods")
         }
+        /// Body length header is added to a message without body
         RequireBodyless {
             description("This message must not contain body length fields.")
         }
+        /// Status reason phrase contains invalid characters
+        InvalidReason {
+            description("Status reason phrase contains invalid characters")
+        }
+        /// Raw request head is malformed
+        InvalidRequestHead {
+            description("Raw request head is not a single complete \
+                request line with headers")
+        }
+        /// Host header is added when one is already deferred
+        DuplicateHost {
+            description("Host header is added when one is already deferred")
+        }
+        /// Content coding is not supported for compression
+        UnsupportedCoding {
+            description("Content coding is not supported for compression")
+        }
     }
 }
 
@@ -54,18 +85,58 @@ quick_error! {
 #[derive(Debug)]
 pub enum MessageState {
     /// Nothing has been sent.
-    ResponseStart { version: Version, body: Body, close: bool },
+    ResponseStart {
+        /// Version of the response
+        version: Version,
+        /// Whether the response may contain a body
+        body: Body,
+        /// Connection is closed after the response
+        close: bool,
+    },
     /// A continuation line has been sent.
-    FinalResponseStart { version: Version, body: Body, close: bool },
+    FinalResponseStart {
+        /// Version of the response
+        version: Version,
+        /// Whether the response may contain a body
+        body: Body,
+        /// Connection is closed after the response
+        close: bool,
+    },
+    /// Status line of an informational (1xx) response is in the buffer.
+    Informational {
+        /// Version of the final response
+        version: Version,
+        /// Whether the final response may contain a body
+        body: Body,
+        /// Connection is closed after the final response
+        close: bool,
+    },
     /// Nothing has been sent.
     #[allow(dead_code)] // until we implement client requests
     RequestStart,
     /// Status line is already in the buffer.
-    Headers { body: Body, close: bool },
+    Headers {
+        /// Whether the message may contain a body
+        body: Body,
+        /// Connection is closed after the message
+        close: bool,
+    },
     /// The message contains a fixed size body.
-    FixedHeaders { is_head: bool, close: bool, content_length: u64 },
+    FixedHeaders {
+        /// Body is not sent (response to a `HEAD` request)
+        is_head: bool,
+        /// Connection is closed after the message
+        close: bool,
+        /// Value of the `Content-Length` header
+        content_length: u64,
+    },
     /// The message contains a chunked body.
-    ChunkedHeaders { is_head: bool, close: bool },
+    ChunkedHeaders {
+        /// Body is not sent (response to a `HEAD` request)
+        is_head: bool,
+        /// Connection is closed after the message
+        close: bool,
+    },
     /// The message contains no body.
     ///
     /// A request without a `Content-Length` or `Transfer-Encoding`
@@ -75,13 +146,22 @@ pub enum MessageState {
     /// and 304 (Not Modified) responses do not include a message body.
     Bodyless,
     /// The message contains a body with the given length.
-    FixedBody { is_head: bool, content_length: u64 },
+    FixedBody {
+        /// Body is not sent (response to a `HEAD` request)
+        is_head: bool,
+        /// Number of body bytes left to write
+        content_length: u64,
+    },
     /// The message contains a chunked body.
-    ChunkedBody { is_head: bool },
+    ChunkedBody {
+        /// Body is not sent (response to a `HEAD` request)
+        is_head: bool,
+    },
     /// A message in final state.
     Done,
 }
 
+/// Whether a message may contain a body
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Body {
     /// Message contains a body.
@@ -96,10 +176,110 @@ pub enum Body {
     Request,
 }
 
+/// Validation rules for header names and values added by the user
+///
+/// By default only line breaks are rejected, which is enough to keep the
+/// message well-formed. Proxies which pass headers from the other side
+/// may want stricter checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderLimits {
+    /// Header names must be tokens (RFC 7230), i.e. ASCII without spaces
+    /// and separators
+    pub strict_names: bool,
+    /// Maximum size of the (formatted) header value in bytes
+    pub max_value_size: Option<usize>,
+}
+
+/// Writer which fails instead of writing more than `left` bytes
+struct Limited<'a> {
+    buf: &'a mut Buf,
+    left: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> HeaderLimits {
+        HeaderLimits {
+            strict_names: false,
+            max_value_size: None,
+        }
+    }
+}
+
+impl<'a> Write for Limited<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() > self.left {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.left -= data.len();
+        self.buf.write_all(data)?;
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 fn invalid_header(value: &[u8]) -> bool {
     return value.iter().any(|&x| x == b'\r' || x == b'\n')
 }
 
+/// Returns true if value is a `token` as defined in RFC 7230
+pub fn is_token(value: &str) -> bool {
+    value.len() > 0 && value.bytes().all(|c| match c {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' |
+        b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false,
+    })
+}
+
+fn is_body_length(name: &str) -> bool {
+    name.eq_ignore_ascii_case("Content-Length")
+        || name.eq_ignore_ascii_case("Transfer-Encoding")
+}
+
+fn check_name(name: &str, limits: &HeaderLimits) -> Result<(), HeaderError> {
+    let valid = if limits.strict_names {
+        is_token(name)
+    } else {
+        !invalid_header(name.as_bytes())
+    };
+    if !valid {
+        return Err(HeaderError::InvalidHeaderName);
+    }
+    Ok(())
+}
+
+fn check_value(name: &str, value: &[u8], limits: &HeaderLimits)
+    -> Result<(), HeaderError>
+{
+    if invalid_header(value) ||
+        limits.max_value_size.map(|max| value.len() > max).unwrap_or(false)
+    {
+        return Err(HeaderError::InvalidHeaderValue(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks that header can be added using `add_header` without writing it
+pub fn check_header(name: &str, value: &[u8], limits: &HeaderLimits)
+    -> Result<(), HeaderError>
+{
+    if is_body_length(name) {
+        return Err(HeaderError::BodyLengthHeader)
+    }
+    check_name(name, limits)?;
+    check_value(name, value, limits)
+}
+
+/// Checks that reason phrase can be written into the status line
+pub fn check_reason(reason: &str) -> Result<(), HeaderError> {
+    if invalid_header(reason.as_bytes()) {
+        return Err(HeaderError::InvalidReason);
+    }
+    Ok(())
+}
+
 impl MessageState {
     /// Write status line.
     ///
@@ -170,6 +350,34 @@ impl MessageState {
         }
     }
 
+    /// Write pre-serialized request line and headers (including the empty
+    /// line at the end)
+    ///
+    /// The head must be validated by caller, `content_length` of `None`
+    /// means chunked body.
+    ///
+    /// # Panics
+    ///
+    /// When request line is already written.
+    pub fn raw_request_head(&mut self, buf: &mut Buf, head: &[u8],
+        content_length: Option<u64>)
+    {
+        use self::MessageState::*;
+        match *self {
+            RequestStart => {
+                buf.write_all(head).unwrap();
+                *self = match content_length {
+                    Some(n) => FixedBody { is_head: false, content_length: n },
+                    None => ChunkedBody { is_head: false },
+                };
+            }
+            ref state => {
+                panic!("Called raw_request_head() method on request \
+                    in state {:?}", state)
+            }
+        }
+    }
+
     /// Write a 100 (Continue) response.
     ///
     /// A server should respond with the 100 status code if it receives a
@@ -195,43 +403,89 @@ impl MessageState {
         }
     }
 
-    fn write_header(&mut self, buf: &mut Buf, name: &str, value: &[u8])
-        -> Result<(), HeaderError>
+    /// Write status line of an informational (1xx) response
+    ///
+    /// Headers may be added after this call, then `end_informational`
+    /// must be called. Multiple informational responses may precede the
+    /// final one.
+    ///
+    /// # Panics
+    ///
+    /// When final response is already started, or when code is not 1xx
+    /// or is 101 (Switching Protocols).
+    pub fn start_informational(&mut self, buf: &mut Buf,
+        code: u16, reason: &str)
     {
-        if invalid_header(name.as_bytes()) {
-            return Err(HeaderError::InvalidHeaderName);
+        use self::MessageState::*;
+        assert!(code >= 100 && code < 200 && code != 101,
+            "Informational status code expected, got {}", code);
+        match *self {
+            ResponseStart { version, body, close } |
+            FinalResponseStart { version, body, close } => {
+                write!(buf, "{} {} {}\r\n", version, code, reason).unwrap();
+                *self = Informational { version: version,
+                                        body: body,
+                                        close: close }
+            }
+            ref state => {
+                panic!("Called start_informational() method on response \
+                    in state {:?}", state)
+            }
         }
-        let start = buf.len();
-        buf.write_all(name.as_bytes()).unwrap();
-        buf.write_all(b": ").unwrap();
+    }
 
-        let value_start = buf.len();
-        buf.write_all(value).unwrap();
-        if invalid_header(&buf[value_start..]) {
-            buf.remove_range(start..);
-            return Err(HeaderError::InvalidHeaderValue);
+    /// Finish the header block of an informational response
+    ///
+    /// # Panics
+    ///
+    /// When `start_informational` wasn't called before.
+    pub fn end_informational(&mut self, buf: &mut Buf) {
+        use self::MessageState::*;
+        match *self {
+            Informational { version, body, close } => {
+                buf.write_all(b"\r\n").unwrap();
+                *self = FinalResponseStart { version: version,
+                                             body: body,
+                                             close: close }
+            }
+            ref state => {
+                panic!("Called end_informational() method on response \
+                    in state {:?}", state)
+            }
         }
+    }
 
+    fn write_header(&mut self, buf: &mut Buf, name: &str, value: &[u8],
+        limits: &HeaderLimits)
+        -> Result<(), HeaderError>
+    {
+        check_name(name, limits)?;
+        check_value(name, value, limits)?;
+        buf.write_all(name.as_bytes()).unwrap();
+        buf.write_all(b": ").unwrap();
+        buf.write_all(value).unwrap();
         buf.write_all(b"\r\n").unwrap();
         Ok(())
     }
 
     fn write_formatted<D: Display>(&mut self, buf: &mut Buf,
-        name: &str, value: D)
+        name: &str, value: D, limits: &HeaderLimits)
         -> Result<(), HeaderError>
     {
-        if invalid_header(name.as_bytes()) {
-            return Err(HeaderError::InvalidHeaderName);
-        }
+        check_name(name, limits)?;
         let start = buf.len();
         buf.write_all(name.as_bytes()).unwrap();
         buf.write_all(b": ").unwrap();
 
         let value_start = buf.len();
-        write!(buf, "{}", value).unwrap();
-        if invalid_header(&buf[value_start..]) {
+        // limit the writer, so huge values don't bloat the buffer
+        let written = write!(Limited {
+            buf: buf,
+            left: limits.max_value_size.unwrap_or(usize::MAX),
+        }, "{}", value);
+        if written.is_err() || invalid_header(&buf[value_start..]) {
             buf.remove_range(start..);
-            return Err(HeaderError::InvalidHeaderValue);
+            return Err(HeaderError::InvalidHeaderValue(name.to_string()));
         }
 
         buf.write_all(b"\r\n").unwrap();
@@ -254,21 +508,23 @@ impl MessageState {
     /// application handler it's okay to unwrap the result and to get
     /// a meaningful panic (that is basically an assertion).
     ///
+    /// Name and value are validated according to `limits`.
+    ///
     /// # Panics
     ///
     /// Panics when `add_header` is called in the wrong state.
-    pub fn add_header(&mut self, buf: &mut Buf, name: &str, value: &[u8])
+    pub fn add_header(&mut self, buf: &mut Buf, name: &str, value: &[u8],
+        limits: &HeaderLimits)
         -> Result<(), HeaderError>
     {
         use self::MessageState::*;
-        use self::HeaderError::*;
-        if name.eq_ignore_ascii_case("Content-Length")
-            || name.eq_ignore_ascii_case("Transfer-Encoding") {
-            return Err(BodyLengthHeader)
+        if is_body_length(name) {
+            return Err(HeaderError::BodyLengthHeader)
         }
         match *self {
-            Headers { .. } | FixedHeaders { .. } | ChunkedHeaders { .. } => {
-                self.write_header(buf, name, value)?;
+            Headers { .. } | FixedHeaders { .. } | ChunkedHeaders { .. } |
+            Informational { .. } => {
+                self.write_header(buf, name, value, limits)?;
                 Ok(())
             }
             ref state => {
@@ -284,18 +540,17 @@ impl MessageState {
     /// Useful for dates and numeric headers, as well as some strongly typed
     /// wrappers
     pub fn format_header<D: Display>(&mut self, buf: &mut Buf,
-        name: &str, value: D)
+        name: &str, value: D, limits: &HeaderLimits)
         -> Result<(), HeaderError>
     {
         use self::MessageState::*;
-        use self::HeaderError::*;
-        if name.eq_ignore_ascii_case("Content-Length")
-            || name.eq_ignore_ascii_case("Transfer-Encoding") {
-            return Err(BodyLengthHeader)
+        if is_body_length(name) {
+            return Err(HeaderError::BodyLengthHeader)
         }
         match *self {
-            Headers { .. } | FixedHeaders { .. } | ChunkedHeaders { .. } => {
-                self.write_formatted(buf, name, value)?;
+            Headers { .. } | FixedHeaders { .. } | ChunkedHeaders { .. } |
+            Informational { .. } => {
+                self.write_formatted(buf, name, value, limits)?;
                 Ok(())
             }
             ref state => {
@@ -305,6 +560,69 @@ impl MessageState {
         }
     }
 
+    /// Add multiple headers to the message
+    ///
+    /// Same rules as for `add_header` apply to every header. If any of the
+    /// headers is invalid, none of them are added (buffer is truncated back)
+    /// and an error is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics when `add_headers` is called in the wrong state.
+    pub fn add_headers<I, N, V>(&mut self, buf: &mut Buf, headers: I,
+        limits: &HeaderLimits)
+        -> Result<(), HeaderError>
+        where I: IntoIterator<Item=(N, V)>,
+              N: AsRef<str>,
+              V: AsRef<[u8]>,
+    {
+        use self::MessageState::*;
+        match *self {
+            Headers { .. } | FixedHeaders { .. } | ChunkedHeaders { .. } |
+            Informational { .. } => {}
+            ref state => {
+                panic!("Called add_headers() method on a message \
+                    in state {:?}", state)
+            }
+        }
+        let start = buf.len();
+        for (name, value) in headers {
+            let name = name.as_ref();
+            let result = if is_body_length(name) {
+                Err(HeaderError::BodyLengthHeader)
+            } else {
+                self.write_header(buf, name, value.as_ref(), limits)
+            };
+            if let Err(e) = result {
+                buf.remove_range(start..);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add headers which are already validated and serialized
+    ///
+    /// `data` must be a sequence of `Name: value\r\n` lines without body
+    /// length headers.
+    ///
+    /// # Panics
+    ///
+    /// Panics when `add_raw_headers` is called in the wrong state.
+    pub fn add_raw_headers(&mut self, buf: &mut Buf, data: &[u8]) {
+        use self::MessageState::*;
+        match *self {
+            Headers { .. } | FixedHeaders { .. } | ChunkedHeaders { .. } |
+            Informational { .. } => {
+                buf.write_all(data).unwrap();
+            }
+            ref state => {
+                panic!("Called add_raw_headers() method on a message \
+                    in state {:?}", state)
+            }
+        }
+    }
+
     /// Add a content length to the message.
     ///
     /// The `Content-Length` header is written to the output buffer immediately.
@@ -324,7 +642,8 @@ impl MessageState {
             ChunkedHeaders { .. } => Err(ContentLengthAfterTransferEncoding),
             Headers { body: Denied, .. } => Err(RequireBodyless),
             Headers { body, close } => {
-                self.write_formatted(buf, "Content-Length", n)?;
+                self.write_formatted(buf, "Content-Length", n,
+                                     &HeaderLimits::default())?;
                 *self = FixedHeaders { is_head: body == Head,
                                         close: close,
                                         content_length: n };
@@ -349,14 +668,12 @@ impl MessageState {
     pub fn add_chunked(&mut self, buf: &mut Buf)
         -> Result<(), HeaderError> {
             use self::MessageState::*;
-            use self::HeaderError::*;
             use self::Body::*;
+            self.check_chunked()?;
             match *self {
-                FixedHeaders { .. } => Err(TransferEncodingAfterContentLength),
-                ChunkedHeaders { .. } => Err(DuplicateTransferEncoding),
-                Headers { body: Denied, .. } => Err(RequireBodyless),
                 Headers { body, close } => {
-                    self.write_header(buf, "Transfer-Encoding", b"chunked")?;
+                    self.write_header(buf, "Transfer-Encoding", b"chunked",
+                                      &HeaderLimits::default())?;
                     *self = ChunkedHeaders { is_head: body == Head,
                                               close: close };
                     Ok(())
@@ -368,6 +685,21 @@ impl MessageState {
         }
     }
 
+    /// Returns the error `add_chunked()` would fail with in this state
+    ///
+    /// Doesn't panic in a wrong state, `add_chunked()` does.
+    pub fn check_chunked(&self) -> Result<(), HeaderError> {
+        use self::MessageState::*;
+        use self::HeaderError::*;
+        use self::Body::*;
+        match *self {
+            FixedHeaders { .. } => Err(TransferEncodingAfterContentLength),
+            ChunkedHeaders { .. } => Err(DuplicateTransferEncoding),
+            Headers { body: Denied, .. } => Err(RequireBodyless),
+            _ => Ok(()),
+        }
+    }
+
     /// Returns true if at least `status()` method has been called
     ///
     /// This is mostly useful to find out whether we can build an error page
@@ -384,6 +716,10 @@ impl MessageState {
     /// Specifically `false` is returned when status is 1xx, 204, 304 or in
     /// the response to a `HEAD` request but not if the body has zero-length.
     ///
+    /// Response to a `HEAD` request may omit both `Content-Length` and
+    /// `Transfer-Encoding`, other responses with a body must have one of
+    /// them (use `add_length(0)` for an explicitly empty body).
+    ///
     /// Similarly to `add_header()` it's fine to `unwrap()` here, unless you're
     /// doing some proxying.
     ///
@@ -399,7 +735,8 @@ impl MessageState {
                     Headers { close: true, .. } |
                     FixedHeaders { close: true, .. } |
                     ChunkedHeaders { close: true, .. }) {
-            self.add_header(buf, "Connection", b"close").unwrap();
+            self.add_header(buf, "Connection", b"close",
+                            &HeaderLimits::default()).unwrap();
         }
         let expect_body = match *self {
             Headers { body: Denied, .. } => {
@@ -413,6 +750,12 @@ impl MessageState {
             Headers { body: Normal, .. } => {
                 return Err(HeaderError::CantDetermineBodySize);
             }
+            Headers { body: Head, .. } => {
+                // Response to HEAD without body length headers (e.g.
+                // proxied as is), body is discarded as in any HEAD response
+                *self = ChunkedBody { is_head: true };
+                false
+            }
             FixedHeaders { is_head, content_length, .. } => {
                 *self = FixedBody { is_head: is_head,
                                      content_length: content_length };
@@ -484,6 +827,27 @@ impl MessageState {
             FixedBody {..} | ChunkedBody {..})
     }
 
+    /// Marks the message to be sent with `Connection: close`
+    ///
+    /// Returns `false` if it's too late to add the header (headers are
+    /// already written).
+    pub fn force_close(&mut self) -> bool {
+        use self::MessageState::*;
+        match *self {
+            ResponseStart { ref mut close, .. } |
+            FinalResponseStart { ref mut close, .. } |
+            Informational { ref mut close, .. } |
+            Headers { ref mut close, .. } |
+            FixedHeaders { ref mut close, .. } |
+            ChunkedHeaders { ref mut close, .. } => {
+                *close = true;
+                true
+            }
+            RequestStart | Bodyless | FixedBody { .. } | ChunkedBody { .. } |
+            Done => false,
+        }
+    }
+
     /// Returns true if `done()` method is already called-
     pub fn is_complete(&self) -> bool {
         matches!(*self, MessageState::Done)
@@ -525,7 +889,7 @@ impl MessageState {
 mod test {
     use tk_bufstream::{Buf};
 
-    use super::{MessageState, Body};
+    use super::{MessageState, Body, HeaderLimits};
     use enums::Version;
 
     #[test]
@@ -633,6 +997,16 @@ mod test {
         })[..], "HTTP/1.1 200 OK\r\nContent-Length: 500\r\n\r\n".as_bytes());
     }
 
+    #[test]
+    fn head_response_without_length() {
+        assert_eq!(&do_head_response11(false, |mut msg, buf| {
+            msg.response_status(buf, 200, "OK");
+            assert_eq!(msg.done_headers(buf).unwrap(), false);
+            msg.write_body(buf, b"ignored");
+            msg.done(buf);
+        })[..], "HTTP/1.1 200 OK\r\n\r\n".as_bytes());
+    }
+
     #[test]
     fn informational_response() {
         // No response with an 1xx status code may contain a body length.
@@ -642,4 +1016,85 @@ mod test {
             msg.done_headers(buf).unwrap();
         })[..], "HTTP/1.1 142 Foo\r\n\r\n".as_bytes());
     }
+
+    #[test]
+    fn informational_with_headers() {
+        let limits = HeaderLimits::default();
+        assert_eq!(&do_response11(false, |mut msg, buf| {
+            msg.start_informational(buf, 102, "Processing");
+            msg.end_informational(buf);
+            msg.start_informational(buf, 103, "Early Hints");
+            msg.add_header(buf, "Link", b"</a.css>; rel=preload",
+                           &limits).unwrap();
+            msg.add_header(buf, "Content-Length", b"1", &limits)
+                .unwrap_err();
+            msg.end_informational(buf);
+            msg.response_status(buf, 204, "No Content");
+            msg.done_headers(buf).unwrap();
+        })[..], concat!("HTTP/1.1 102 Processing\r\n\r\n",
+                        "HTTP/1.1 103 Early Hints\r\n",
+                        "Link: </a.css>; rel=preload\r\n\r\n",
+                        "HTTP/1.1 204 No Content\r\n\r\n").as_bytes());
+    }
+
+    #[test]
+    fn multiple_headers() {
+        let limits = HeaderLimits::default();
+        assert_eq!(&do_response11(false, |mut msg, buf| {
+            msg.response_status(buf, 200, "OK");
+            msg.add_headers(buf, vec![
+                ("Content-Type", "text/plain"),
+                ("X-Foo", "bar"),
+            ], &limits).unwrap();
+            msg.add_length(buf, 0).unwrap();
+            msg.done_headers(buf).unwrap();
+        })[..], concat!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n",
+                        "X-Foo: bar\r\nContent-Length: 0\r\n\r\n")
+                .as_bytes());
+    }
+
+    #[test]
+    fn multiple_headers_invalid() {
+        let limits = HeaderLimits::default();
+        assert_eq!(&do_response11(false, |mut msg, buf| {
+            msg.response_status(buf, 200, "OK");
+            msg.add_headers(buf, vec![
+                ("X-Foo", &b"bar"[..]),
+                ("X-Bar", &b"a\r\nb"[..]),
+            ], &limits).unwrap_err();
+            msg.add_headers(buf, vec![
+                ("X-Foo", "bar"),
+                ("Content-Length", "10"),
+            ], &limits).unwrap_err();
+            msg.add_length(buf, 0).unwrap();
+            msg.done_headers(buf).unwrap();
+        })[..], "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn header_limits() {
+        let limits = HeaderLimits {
+            strict_names: true,
+            max_value_size: Some(8),
+        };
+        let loose = HeaderLimits::default();
+        assert_eq!(&do_response11(false, |mut msg, buf| {
+            msg.response_status(buf, 200, "OK");
+            msg.add_header(buf, "X Foo", b"bar", &limits).unwrap_err();
+            msg.add_header(buf, "X-Bar\u{e9}", b"bar", &limits)
+                .unwrap_err();
+            msg.add_header(buf, "X Foo", b"bar", &loose).unwrap();
+            let err = msg.add_header(buf, "X-Long", b"123456789", &limits)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Value of the header \"X-Long\" \
+                contains invalid characters or is too long");
+            msg.format_header(buf, "X-Long", 123456789, &limits)
+                .unwrap_err();
+            msg.format_header(buf, "X-Num", 12345678, &limits).unwrap();
+            msg.add_length(buf, 0).unwrap();
+            msg.done_headers(buf).unwrap();
+        })[..], concat!("HTTP/1.1 200 OK\r\nX Foo: bar\r\n",
+                        "X-Num: 12345678\r\nContent-Length: 0\r\n\r\n")
+                .as_bytes());
+    }
 }
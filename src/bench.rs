@@ -0,0 +1,59 @@
+//! Internal hot paths exposed for benchmarks
+//!
+//! This module is enabled by the `bench` feature and is **not** a part
+//! of the stable API. It's only useful for benchmarks in `benches/` and for
+//! allocation-counting tests.
+//!
+use std::time::Instant;
+
+use tk_bufstream::Buf;
+use httparse::InvalidChunkSize;
+
+use chunked::State as ChunkedState;
+use server::{self, Dispatcher, BodyKind};
+use spans::Span;
+use ConnectionId;
+
+pub use base_serializer::{MessageState, HeaderError, HeaderLimits};
+pub use base_serializer::Body as MessageBody;
+
+
+/// Parse request headers from the buffer calling dispatcher on success
+///
+/// Returns `None` if headers are not fully received yet. Headers are
+/// consumed from the buffer (but not the request body).
+pub fn parse_request_headers<S, D>(buf: &mut Buf, disp: &mut D)
+    -> Result<Option<(BodyKind, D::Codec)>, server::Error>
+    where D: Dispatcher<S>,
+{
+    let config = server::Config::new();
+    let id = ConnectionId::next();
+    let now = Instant::now();
+    let info = server::ConnectionInfo::new(now);
+    Ok(server::parse_headers(buf, disp, &config, id, &info, now,
+                             &Span::none())?
+        .map(|(kind, codec, _, _)| (kind, codec)))
+}
+
+/// Decode chunked body in-place, returns number of bytes of decoded data
+///
+/// Returns `None` if the last chunk has not been received yet
+pub fn decode_chunked(buf: &mut Buf)
+    -> Result<Option<usize>, InvalidChunkSize>
+{
+    let mut state = ChunkedState::new();
+    state.parse(buf).map_err(|e| match e {
+        ::chunked::Error::Invalid(e) => e,
+        ::chunked::Error::TooLarge(_) => unreachable!(),
+    })?;
+    if state.is_done() {
+        Ok(Some(state.buffered()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Mask (or unmask) websocket frame payload in-place
+pub fn websocket_mask(data: &mut [u8], mask: [u8; 4]) {
+    ::websocket::apply_mask(data, mask)
+}
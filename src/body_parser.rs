@@ -1,4 +1,3 @@
-use httparse::InvalidChunkSize;
 use tk_bufstream::ReadBuf;
 
 
@@ -24,12 +23,22 @@ impl BodyProgress {
         }
     }
     pub fn parse<S>(&mut self, io: &mut ReadBuf<S>)
-        -> Result<(), InvalidChunkSize>
+        -> Result<(), chunked::Error>
+    {
+        self.parse_with(io, |_| {})
+    }
+    /// Same as `parse` but passes chunk framing along with the data to `raw`
+    ///
+    /// Nothing is passed for other kinds of body, as the buffer is not
+    /// modified by the parser.
+    pub fn parse_with<S, F>(&mut self, io: &mut ReadBuf<S>, raw: F)
+        -> Result<(), chunked::Error>
+        where F: FnMut(&[u8])
     {
         use self::BodyProgress::*;
         match *self {
             Fixed(_) => {},
-            Chunked(ref mut s) => s.parse(&mut io.in_buf)?,
+            Chunked(ref mut s) => s.parse_with(&mut io.in_buf, raw)?,
             Eof => {}
         }
         Ok(())
@@ -1,3 +1,5 @@
+use std::usize;
+
 use httparse::{InvalidChunkSize, parse_chunk_size};
 use tk_bufstream::Buf;
 
@@ -8,6 +10,14 @@ pub struct State {
     buffered: usize,
     pending: usize,
     done: bool,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Invalid(InvalidChunkSize),
+    /// Chunk size in the header is larger than the limit
+    TooLarge(u64),
 }
 
 impl State {
@@ -16,27 +26,57 @@ impl State {
             buffered: 0,
             pending: 0,
             done: false,
+            limit: None,
         }
     }
-    pub fn parse(&mut self, buf: &mut Buf) -> Result<(), InvalidChunkSize> {
-        let State { ref mut buffered, ref mut pending, ref mut done } = *self;
+    pub fn with_limit(limit: Option<u64>) -> State {
+        State {
+            limit: limit,
+            .. State::new()
+        }
+    }
+    pub fn parse(&mut self, buf: &mut Buf) -> Result<(), Error> {
+        self.parse_with(buf, |_| {})
+    }
+    /// Same as `parse` but passes every byte accepted by the parser to
+    /// `raw` as it was on the wire, i.e. including chunk framing
+    pub fn parse_with<F>(&mut self, buf: &mut Buf, mut raw: F)
+        -> Result<(), Error>
+        where F: FnMut(&[u8])
+    {
+        let State { ref mut buffered, ref mut pending, ref mut done,
+                    limit } = *self;
         if *done {
             return Ok(());
         }
         while *buffered < buf.len() {
             if *pending == 0 {
                 use httparse::Status::*;
-                match parse_chunk_size(&buf[*buffered..])? {
+                match parse_chunk_size(&buf[*buffered..])
+                      .map_err(Error::Invalid)?
+                {
                     Complete((bytes, 0)) => {
-                        buf.remove_range(
-                            *buffered..*buffered+bytes);
-                        *done = true;
+                        // last chunk is followed by (optional) trailer
+                        // fields and an empty line, all of them are skipped
+                        let start = *buffered + bytes;
+                        if let Some(end) = trailer_end(&buf[start..]) {
+                            raw(&buf[*buffered..start+end]);
+                            buf.remove_range(*buffered..start+end);
+                            *done = true;
+                        }
+                        return Ok(());
+                    }
+                    Complete((_, chunk_size))
+                    if limit.map(|x| chunk_size > x).unwrap_or(false) ||
+                        chunk_size > usize::MAX as u64
+                    => {
+                        return Err(Error::TooLarge(chunk_size));
                     }
                     Complete((bytes, chunk_size)) => {
                         // TODO(tailhook) optimized multiple removes
+                        raw(&buf[*buffered..*buffered+bytes]);
                         buf.remove_range(
                             *buffered..*buffered+bytes);
-                        // TODO(tailhook) check that chunk_size < u32
                         *pending = chunk_size as usize;
                     }
                     Partial => {
@@ -44,14 +84,23 @@ impl State {
                     }
                 }
             } else {
-                if *buffered + *pending + 2 <= buf.len() {
+                // chunk size is untrusted, so `buffered + pending` may
+                // overflow
+                let available = buf.len() - *buffered;
+                if *pending > available {
+                    raw(&buf[*buffered..]);
+                    *pending -= available;
+                    *buffered = buf.len();
+                } else if available - *pending >= 2 {
+                    raw(&buf[*buffered..*buffered+*pending+2]);
                     *buffered += *pending;
                     *pending = 0;
                     // TODO(tailhook) optimize this
                     buf.remove_range(*buffered..*buffered+2);
                 } else {
-                    *pending -= buf.len() - *buffered;
-                    *buffered = buf.len();
+                    // the end of chunk is split between reads, wait for
+                    // the whole CRLF (keeping last bytes of the data)
+                    return Ok(());
                 }
             }
         }
@@ -69,9 +118,16 @@ impl State {
     }
 }
 
+fn trailer_end(data: &[u8]) -> Option<usize> {
+    if data.starts_with(b"\r\n") {
+        return Some(2);
+    }
+    data.windows(4).position(|x| x == b"\r\n\r\n").map(|x| x + 4)
+}
+
 #[cfg(test)]
 mod test {
-    use super::State;
+    use super::{State, Error};
     use tk_bufstream::Buf;
 
     #[test]
@@ -80,12 +136,84 @@ mod test {
         let mut buf = Buf::new();
         buf.extend(b"4\r\nhell\r\n");
         assert_eq!(state.parse(&mut buf), Ok(()));
-        assert_eq!(state, State { buffered: 4, pending: 0, done: false });
+        assert_eq!(state, State { buffered: 4, pending: 0, done: false,
+                                 limit: None });
         state.consume(4);
         buf.consume(4);
         assert_eq!(state.buffered, 0);
         buf.extend(b"0\r\n");
         assert_eq!(state.parse(&mut buf), Ok(()));
-        assert_eq!(state, State { buffered: 0, pending: 0, done: true });
+        assert_eq!(state, State { buffered: 0, pending: 0, done: false,
+                                 limit: None });
+        buf.extend(b"\r\nGET");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert_eq!(state, State { buffered: 0, pending: 0, done: true,
+                                 limit: None });
+        assert_eq!(&buf[..], b"GET");
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let input: &[u8] = b"4\r\nhell\r\n1;ext=1\r\no\r\n0\r\nX: 1\r\n\r\n";
+        let mut state = State::new();
+        let mut buf = Buf::new();
+        let mut raw = Vec::new();
+        // byte by byte to check partial headers and split CRLF
+        for byte in input.chunks(1) {
+            buf.extend(byte);
+            state.parse_with(&mut buf, |x| raw.extend_from_slice(x))
+                .unwrap();
+        }
+        buf.extend(b"GET");
+        state.parse_with(&mut buf, |x| raw.extend_from_slice(x)).unwrap();
+        assert!(state.is_done());
+        assert_eq!(&raw[..], input);
+        assert_eq!(&buf[..], b"helloGET");
+    }
+
+    #[test]
+    fn trailer() {
+        let mut state = State::new();
+        let mut buf = Buf::new();
+        buf.extend(b"2\r\nok\r\n0\r\nX-Sum: 1\r\n");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert!(!state.is_done());
+        buf.extend(b"\r\nnext");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert!(state.is_done());
+        assert_eq!(&buf[..], b"oknext");
+    }
+
+    #[test]
+    fn limit() {
+        let mut state = State::with_limit(Some(4));
+        let mut buf = Buf::new();
+        buf.extend(b"4\r\nhell\r\n");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert_eq!(state.buffered(), 4);
+        buf.extend(b"5\r\nhello\r\n");
+        assert_eq!(state.parse(&mut buf), Err(Error::TooLarge(5)));
+    }
+
+    #[test]
+    fn huge_chunk() {
+        let mut state = State::new();
+        let mut buf = Buf::new();
+        buf.extend(b"ffffffffffffffff\r\nhello");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert_eq!(state.buffered(), 5);
+    }
+
+    #[test]
+    fn split_crlf() {
+        let mut state = State::new();
+        let mut buf = Buf::new();
+        buf.extend(b"4\r\nhell\r");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert_eq!(state.buffered(), 0);
+        buf.extend(b"\n1\r\nx\r\n");
+        assert_eq!(state.parse(&mut buf), Ok(()));
+        assert_eq!(state.buffered(), 5);
+        assert_eq!(&buf[..], b"hellx");
     }
 }
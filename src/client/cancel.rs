@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::Async;
+
+use client::{Codec, Error, Encoder, Head, RecvMode, Idempotency};
+use client::IdempotencyKey;
+
+
+/// A handle to cancel a request wrapped into `Cancelable`
+///
+/// Dropping the handle doesn't cancel the request.
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+/// A codec wrapper which can be canceled by `CancelHandle`
+///
+/// Cancellation is cooperative, the connection notices it next time it's
+/// polled:
+///
+/// * If the request isn't written yet (i.e. it's queued in a connection
+///   pool) the codec is dropped by `Proto` as soon as it's sent to the
+///   connection, nothing is written.
+/// * If the request is already written, the response is still read (there
+///   is no other way to keep the connection consistent), but no more
+///   requests are sent to the connection and it's closed with
+///   `Error::Closed` after the response.
+///
+/// The callbacks of the inner codec are still called for the response of
+/// a canceled request.
+#[derive(Debug)]
+pub struct Cancelable<C> {
+    codec: C,
+    canceled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Cancel the request
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    /// Returns `true` if `cancel()` has been called
+    pub fn is_canceled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl<C> Cancelable<C> {
+    /// Wrap a codec and return a handle to cancel it
+    pub fn new(codec: C) -> (Cancelable<C>, CancelHandle) {
+        let flag = Arc::new(AtomicBool::new(false));
+        (Cancelable { codec: codec, canceled: flag.clone() },
+         CancelHandle(flag))
+    }
+    /// Unwrap the inner codec
+    pub fn into_inner(self) -> C {
+        self.codec
+    }
+}
+
+impl<S, C: Codec<S>> Codec<S> for Cancelable<C> {
+    type Future = C::Future;
+    fn start_write(&mut self, e: Encoder<S>) -> C::Future {
+        self.codec.start_write(e)
+    }
+    fn headers_received(&mut self, headers: &Head) -> Result<RecvMode, Error> {
+        self.codec.headers_received(headers)
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        self.codec.data_received(data, end)
+    }
+    fn idempotency(&self) -> Idempotency {
+        self.codec.idempotency()
+    }
+    fn idempotency_key(&self) -> Option<IdempotencyKey> {
+        self.codec.idempotency_key()
+    }
+    fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst) || self.codec.is_canceled()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Sink, AsyncSink, Async};
+    use futures::future::lazy;
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+
+    use client::{Proto, Config, Error};
+    use client::buffered::Buffered;
+    use super::Cancelable;
+
+    fn request() -> Buffered {
+        Buffered::get("http://example.com/".parse().unwrap()).0
+    }
+
+    #[test]
+    fn not_written() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+                                   &Config::new().done());
+        core.run(lazy(|| -> Result<(), Error> {
+            let (codec, handle) = Cancelable::new(request());
+            handle.cancel();
+            assert!(matches!(proto.start_send(codec)?, AsyncSink::Ready));
+            assert_eq!(proto.poll_complete()?, Async::Ready(()));
+            Ok(())
+        })).unwrap();
+        assert_eq!(mock.output(..), b"");
+    }
+
+    #[test]
+    fn close_after_response() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+                                   &Config::new().done());
+        core.run(lazy(|| -> Result<(), Error> {
+            let (codec, handle) = Cancelable::new(request());
+            assert!(matches!(proto.start_send(codec)?, AsyncSink::Ready));
+            assert_eq!(proto.poll_complete()?, Async::NotReady);
+            assert!(mock.output(..).starts_with(b"GET / HTTP/1.1\r\n"));
+            handle.cancel();
+            assert_eq!(proto.poll_complete()?, Async::NotReady);
+            // connection doesn't accept new requests
+            let (codec, _) = Cancelable::new(request());
+            assert!(matches!(proto.start_send(codec)?,
+                             AsyncSink::NotReady(..)));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+            let err = proto.poll_complete().unwrap_err();
+            assert_eq!(format!("{:?}", err), "Error(Closed)");
+            Ok(())
+        })).unwrap();
+    }
+}
@@ -1,8 +1,10 @@
 use futures::sink::Sink;
 use futures::future::FutureResult;
 use futures::{Async, AsyncSink, Future, IntoFuture};
+use futures::sync::oneshot::Receiver;
 
-use client::{Error, Encoder, EncoderDone, Head, RecvMode};
+use client::{Error, Encoder, EncoderDone, Head, RecvMode, Idempotency};
+use client::IdempotencyKey;
 use client::errors::ErrorEnum;
 use client::buffered;
 
@@ -70,6 +72,38 @@ pub trait Codec<S> {
     ///
     fn data_received(&mut self, data: &[u8], end: bool)
         -> Result<Async<usize>, Error>;
+
+    /// Classify the request for connection pools and retry layers
+    ///
+    /// Default implementation returns `Unknown` as we don't know the
+    /// method before the request is written, such requests aren't retried
+    /// but are pipelined as usual. Override it if you want the request to
+    /// be retried on another connection.
+    ///
+    /// Protocol doesn't pipeline other requests after a `NonRetriable`
+    /// one (as RFC7230 recommends), since they are lost along with it if
+    /// connection breaks.
+    fn idempotency(&self) -> Idempotency {
+        Idempotency::Unknown
+    }
+
+    /// Returns a key to send in the `Idempotency-Key` header
+    ///
+    /// The header is added by the protocol before any header of the
+    /// request (except `Host`). Return the same key on every attempt to
+    /// send the request. Default implementation returns `None`.
+    fn idempotency_key(&self) -> Option<IdempotencyKey> {
+        None
+    }
+
+    /// Returns `true` if the request is not needed any more
+    ///
+    /// Checked by the connection every time it's polled. Default
+    /// implementation returns `false`, use `Cancelable` wrapper to get
+    /// a handle that cancels the request.
+    fn is_canceled(&self) -> bool {
+        false
+    }
 }
 
 impl<S, F> Codec<S> for Box<Codec<S, Future=F>>
@@ -87,6 +121,15 @@ impl<S, F> Codec<S> for Box<Codec<S, Future=F>>
     {
         (**self).data_received(data, end)
     }
+    fn idempotency(&self) -> Idempotency {
+        (**self).idempotency()
+    }
+    fn idempotency_key(&self) -> Option<IdempotencyKey> {
+        (**self).idempotency_key()
+    }
+    fn is_canceled(&self) -> bool {
+        (**self).is_canceled()
+    }
 }
 
 impl<S, F> Codec<S> for Box<Codec<S, Future=F>+Send>
@@ -104,6 +147,15 @@ impl<S, F> Codec<S> for Box<Codec<S, Future=F>+Send>
     {
         (**self).data_received(data, end)
     }
+    fn idempotency(&self) -> Idempotency {
+        (**self).idempotency()
+    }
+    fn idempotency_key(&self) -> Option<IdempotencyKey> {
+        (**self).idempotency_key()
+    }
+    fn is_canceled(&self) -> bool {
+        (**self).is_canceled()
+    }
 }
 
 /// A marker trait that applies to a Sink that is essentially a HTTP client
@@ -123,6 +175,43 @@ pub trait Client<S, F>: Sink<SinkItem=Box<Codec<S, Future=F>>>
     fn fetch_url(&mut self, url: &str)
         -> Box<Future<Item=buffered::Response, Error=Error>>
         where <Self as Sink>::SinkError: Into<Error>;
+
+    /// Send a fully buffered request with arbitrary method and body
+    ///
+    /// Useful for `POST` and `PUT` requests. See `Buffered::send` for
+    /// the details on how headers are written.
+    fn send_request(&mut self, method: &str, url: &str,
+        headers: Vec<(String, Vec<u8>)>, body: Vec<u8>)
+        -> Box<Future<Item=buffered::Response, Error=Error>>
+        where <Self as Sink>::SinkError: Into<Error>;
+}
+
+fn start_buffered<T, S>(sink: &mut T,
+    request: Result<(buffered::Buffered, Receiver<Result<buffered::Response,
+                                                        Error>>), Error>)
+    -> Box<Future<Item=buffered::Response, Error=Error>>
+    where T: Sink<SinkItem=Box<
+            Codec<S, Future=FutureResult<EncoderDone<S>, Error>>
+        >>,
+          T::SinkError: Into<Error>,
+{
+    let (codec, receiver) = match request {
+        Ok(pair) => pair,
+        Err(e) => return Box::new(Err(e).into_future()),
+    };
+    match sink.start_send(Box::new(codec)) {
+        Ok(AsyncSink::NotReady(_)) => {
+            Box::new(Err(ErrorEnum::Busy.into()).into_future())
+        }
+        Ok(AsyncSink::Ready) => {
+            Box::new(receiver
+                .map_err(|_| ErrorEnum::Canceled.into())
+                .and_then(|res| res))
+        }
+        Err(e) => {
+            Box::new(Err(e.into()).into_future())
+        }
+    }
 }
 
 impl<T, S> Client<S, FutureResult<EncoderDone<S>, Error>> for T
@@ -134,26 +223,21 @@ impl<T, S> Client<S, FutureResult<EncoderDone<S>, Error>> for T
         -> Box<Future<Item=buffered::Response, Error=Error>>
         where <Self as Sink>::SinkError: Into<Error>
     {
-        let url = match url.parse() {
-            Ok(u) => u,
-            Err(_) => {
-                return Box::new(Err(ErrorEnum::InvalidUrl.into())
-                    .into_future());
-            }
-        };
-        let (codec, receiver) = buffered::Buffered::get(url);
-        match self.start_send(Box::new(codec)) {
-            Ok(AsyncSink::NotReady(_)) => {
-                Box::new(Err(ErrorEnum::Busy.into()).into_future())
-            }
-            Ok(AsyncSink::Ready) => {
-                Box::new(receiver
-                    .map_err(|_| ErrorEnum::Canceled.into())
-                    .and_then(|res| res))
-            }
-            Err(e) => {
-                Box::new(Err(e.into()).into_future())
-            }
-        }
+        let request = url.parse()
+            .map_err(|e| ErrorEnum::UrlParse(e).into())
+            .and_then(buffered::Buffered::try_get);
+        start_buffered(self, request)
+    }
+    fn send_request(&mut self, method: &str, url: &str,
+        headers: Vec<(String, Vec<u8>)>, body: Vec<u8>)
+        -> Box<Future<Item=buffered::Response, Error=Error>>
+        where <Self as Sink>::SinkError: Into<Error>
+    {
+        let request = url.parse()
+            .map_err(|e| ErrorEnum::UrlParse(e).into())
+            .and_then(|url| {
+                buffered::Buffered::send(method, url, headers, body)
+            });
+        start_buffered(self, request)
     }
 }
@@ -1,7 +1,10 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use client::{Config};
+use base_serializer::HeaderLimits;
+use client::{Config, RequestObserver};
+use client::stats::ObserverRef;
+#[cfg(feature="testing")] use testing::FaultInjector;
 
 impl Config {
     /// Create a config with defaults
@@ -12,6 +15,15 @@ impl Config {
             keep_alive_timeout: Duration::new(4, 0),
             safe_pipeline_timeout: Duration::from_millis(300),
             max_request_timeout: Duration::new(15, 0),
+            connect_timeout: Duration::new(10, 0),
+            observer: None,
+            max_chunk_size: None,
+            require_body_length: false,
+            max_interim_responses: 8,
+            strict_response_headers: false,
+            header_limits: HeaderLimits::default(),
+            decompress: false,
+            raw_head: false,
         }
     }
     /// A number of inflight requests until we start returning
@@ -22,6 +34,9 @@ impl Config {
     ///
     /// Note 2: you might also need to tweak `safe_pipeline_timeout` to
     /// make pipelining work.
+    ///
+    /// Note 3: no request is pipelined after one that can't be retried
+    /// (see `Codec::idempotency`).
     pub fn inflight_request_limit(&mut self, value: usize) -> &mut Self {
         self.inflight_request_limit = value;
         self
@@ -97,6 +112,152 @@ impl Config {
         self
     }
 
+    /// Maximum time to establish a connection
+    ///
+    /// Applies to `Proto::connect_tcp`, `Proto::connect_tcp_host` and
+    /// `client::get`. Connection fails with `ConnectTimeout` error (see
+    /// `Error::is_connect_timeout`) when the timeout is reached. Without
+    /// it connecting to a blackholed address hangs for the OS default,
+    /// which is minutes.
+    ///
+    /// Default is 10 seconds.
+    pub fn connect_timeout(&mut self, dur: Duration) -> &mut Self {
+        self.connect_timeout = dur;
+        self
+    }
+
+    /// Set a hook which receives sizes and timing of every request
+    ///
+    /// Wrap observer into an `Arc` if you need to share it with other
+    /// code (or between connections in case configs differ).
+    pub fn request_observer<O>(&mut self, observer: O) -> &mut Self
+        where O: RequestObserver + 'static,
+    {
+        self.observer = Some(ObserverRef(Arc::new(observer)));
+        self
+    }
+
+    /// Maximum size of a single chunk of a chunked response body
+    ///
+    /// Response with a larger chunk fails with `ChunkTooLarge` error and
+    /// connection is closed. Default is no limit (besides one set by
+    /// `RecvMode` on whole body).
+    pub fn max_chunk_size(&mut self, value: u64) -> &mut Self {
+        self.max_chunk_size = Some(value);
+        self
+    }
+
+    /// Reject responses with a body delimited by connection close
+    ///
+    /// Such body can't be told apart from one truncated by a server crash
+    /// or by a proxy, so APIs which must detect truncation should enable
+    /// this to only accept `Content-Length` or chunked bodies. Responses
+    /// without either header fail with `BodyLengthRequired` error before
+    /// `Codec::headers_received` is called. Default is `false`.
+    ///
+    /// When disabled, a body delimited by connection close which ends with
+    /// a connection reset instead of an orderly shutdown fails with
+    /// `ResetOnResponseBody` error, and the request is reported to the
+    /// observer with `RequestStats::body_completed_cleanly() == false`.
+    pub fn require_body_length(&mut self, value: bool) -> &mut Self {
+        self.require_body_length = value;
+        self
+    }
+
+    /// Maximum number of interim (1xx) responses before the final one
+    ///
+    /// Client never asks for interim responses, but some servers send
+    /// `100 Continue` (or `102 Processing`, `103 Early Hints`) anyway.
+    /// They are skipped without calling the codec, and when there are
+    /// more than this number of them the request fails with
+    /// `TooManyInterimResponses` error. `101 Switching Protocols` is
+    /// always a final response. Default is `8`.
+    pub fn max_interim_responses(&mut self, value: usize) -> &mut Self {
+        self.max_interim_responses = value;
+        self
+    }
+
+    /// Validate response headers more strictly
+    ///
+    /// Useful for clients talking to untrusted or buggy servers, where a
+    /// response which is framed differently by us and by some proxy in
+    /// between can desync a reused connection. When enabled, responses
+    /// fail with:
+    ///
+    /// * `ConflictingBodyLength` if both `Content-Length` and
+    ///   `Transfer-Encoding` are present (otherwise connection is just
+    ///   closed after such response)
+    /// * `BadContentLength` if `Content-Length` is anything other than
+    ///   digits (e.g. has a sign)
+    /// * `InvalidHeaderValue` if a value contains control characters
+    /// * `ReasonTooLong` if reason phrase is longer than 256 bytes
+    ///
+    /// Duplicate `Content-Length` is always an error. Default is `false`.
+    pub fn strict_response_headers(&mut self, value: bool) -> &mut Self {
+        self.strict_response_headers = value;
+        self
+    }
+
+    /// Only allow tokens (RFC 7230) as names of request headers
+    ///
+    /// Useful for proxies which copy headers received from clients. When
+    /// enabled, `add_header` and `format_header` reject names with spaces,
+    /// separators or non-ASCII bytes. By default only line breaks are
+    /// rejected.
+    pub fn strict_header_names(&mut self, value: bool) -> &mut Self {
+        self.header_limits.strict_names = value;
+        self
+    }
+
+    /// Maximum size of a single request header value
+    ///
+    /// Larger values fail with `InvalidHeaderValue` error (formatted
+    /// values are cut off as soon as limit is reached, so a huge `Display`
+    /// implementation doesn't bloat the buffer). Default is no limit.
+    pub fn max_header_value_size(&mut self, value: usize) -> &mut Self {
+        self.header_limits.max_value_size = Some(value);
+        self
+    }
+
+    /// Keep raw bytes of the response head for `Head::raw_head`
+    ///
+    /// Useful for debugging proxies and conformance tools. Default is
+    /// `false`, so `Head::raw_head` returns `None`.
+    pub fn raw_head(&mut self, value: bool) -> &mut Self {
+        self.raw_head = value;
+        self
+    }
+
+    /// Decompress response bodies with `gzip` or `deflate` content coding
+    ///
+    /// Codec receives decompressed data in `data_received`, while `Head`
+    /// still has the original `Content-Encoding` and `Content-Length`
+    /// headers. `Accept-Encoding` is not added to requests automatically,
+    /// so most servers don't compress responses unless you add it. Body
+    /// which can't be decompressed (including a truncated one) fails with
+    /// `Decompress` error. In buffered mode the limit applies to both
+    /// compressed and decompressed size. Default is `false`.
+    #[cfg(feature="compression")]
+    pub fn decompress(&mut self, value: bool) -> &mut Self {
+        self.decompress = value;
+        self
+    }
+
+    /// Replace every timeout value with `FaultInjector::timeout`
+    ///
+    /// Lets you check how requests time out without waiting for real
+    /// timeouts (see `testing` module). Call it after setting timeouts,
+    /// values set afterwards are used as is.
+    #[cfg(feature="testing")]
+    pub fn inject_faults(&mut self, injector: &FaultInjector) -> &mut Self {
+        self.keep_alive_timeout = injector.timeout(self.keep_alive_timeout);
+        self.safe_pipeline_timeout =
+            injector.timeout(self.safe_pipeline_timeout);
+        self.max_request_timeout = injector.timeout(self.max_request_timeout);
+        self.connect_timeout = injector.timeout(self.connect_timeout);
+        self
+    }
+
     /// Create a Arc'd config clone to pass to the constructor
     ///
     /// This is just a convenience method.
@@ -0,0 +1,66 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+
+/// Information about a client connection
+///
+/// Available from `Proto::connection_info()`. Use `connect_time` along
+/// with `RequestStats` to find out whether a slow request is slow because
+/// of establishing a connection or because of the server.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    established_at: Instant,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    connect_time: Option<Duration>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(established_at: Instant) -> ConnectionInfo {
+        ConnectionInfo {
+            established_at: established_at,
+            peer_addr: None,
+            local_addr: None,
+            connect_time: None,
+        }
+    }
+    pub(crate) fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+    pub(crate) fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.local_addr = Some(addr);
+    }
+    pub(crate) fn set_connect_time(&mut self, time: Duration) {
+        self.connect_time = Some(time);
+    }
+    /// Time when protocol handler is created (i.e. connection is ready)
+    ///
+    /// Taken from the timer of the protocol.
+    pub fn established_at(&self) -> Instant {
+        self.established_at
+    }
+    /// Address of the server
+    ///
+    /// Set by `Proto::connect_tcp` or explicitly by `Proto::set_peer_addr`.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+    /// Local address of the connection
+    ///
+    /// Set the same way as `peer_addr`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+    /// Time it took to establish the connection
+    ///
+    /// Set by `Proto::connect_tcp`. If you establish connections yourself
+    /// (e.g. to do a TLS handshake) use `Proto::set_connect_time` with
+    /// time from the start of TCP connect to the end of the handshake.
+    pub fn connect_time(&self) -> Option<Duration> {
+        self.connect_time
+    }
+    /// Time elapsed since the connection was established
+    pub fn age(&self) -> Duration {
+        self.established_at.elapsed()
+    }
+}
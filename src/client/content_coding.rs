@@ -0,0 +1,84 @@
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+use client::client::BodyKind;
+use client::Head;
+
+
+/// Content coding of the response body which client can decompress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coding {
+    Gzip,
+    Deflate,
+}
+
+/// Returns coding of the response body if it's compressed
+///
+/// Only a single `gzip` (or `x-gzip`) or `deflate` coding is supported,
+/// response body is passed as is for anything else.
+pub(crate) fn response_coding(head: &Head) -> Option<Coding> {
+    if head.body_kind == BodyKind::Fixed(0) {
+        return None;
+    }
+    let mut codings = head.all_headers().iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("Content-Encoding"))
+        .flat_map(|h| h.value.split(|&x| x == b','))
+        .map(|x| trim(x))
+        .filter(|x| x.len() > 0 && !x.eq_ignore_ascii_case(b"identity"));
+    let coding = match codings.next() {
+        Some(x) if x.eq_ignore_ascii_case(b"gzip") ||
+                   x.eq_ignore_ascii_case(b"x-gzip") => Coding::Gzip,
+        Some(x) if x.eq_ignore_ascii_case(b"deflate") => Coding::Deflate,
+        _ => return None,
+    };
+    if codings.next().is_some() {
+        return None;
+    }
+    Some(coding)
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|&x| x != b' ' && x != b'\t')
+        .unwrap_or(value.len());
+    let end = value.iter().rposition(|&x| x != b' ' && x != b'\t')
+        .map(|x| x + 1).unwrap_or(start);
+    &value[start..end]
+}
+
+#[cfg(test)]
+mod test {
+    use httparse::{Header, Response};
+
+    use client::parser::with_head;
+    use client::{Config, Extensions};
+    use ConnectionId;
+    use super::{response_coding, Coding};
+
+    fn coding(length: &str, encoding: &str) -> Option<Coding> {
+        let mut headers = [
+            Header { name: "Content-Encoding", value: encoding.as_bytes() },
+            Header { name: "Content-Length", value: length.as_bytes() },
+        ];
+        let raw = Response {
+            version: Some(1),
+            code: Some(200),
+            reason: Some("OK"),
+            headers: &mut headers,
+        };
+        with_head(&raw, None, false, &Config::new(),
+            ConnectionId::next(), &Extensions::new(),
+            |head| Ok(response_coding(head)))
+        .unwrap().2
+    }
+
+    #[test]
+    fn codings() {
+        assert_eq!(coding("10", "gzip"), Some(Coding::Gzip));
+        assert_eq!(coding("10", "X-Gzip"), Some(Coding::Gzip));
+        assert_eq!(coding("10", " identity, deflate"), Some(Coding::Deflate));
+        assert_eq!(coding("10", "br"), None);
+        assert_eq!(coding("10", "gzip, gzip"), None);
+        assert_eq!(coding("10", "identity"), None);
+        assert_eq!(coding("0", "gzip"), None);
+    }
+}
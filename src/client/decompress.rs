@@ -0,0 +1,251 @@
+use std::cmp::max;
+use std::io::{self, Write};
+
+use flate2::{Decompress, FlushDecompress, Status};
+use flate2::write::GzDecoder;
+
+use client::content_coding::Coding;
+use client::errors::ErrorEnum;
+
+
+enum Decoder {
+    Gzip(GzDecoder<Output>),
+    /// Zlib stream is decompressed directly, as zlib writer doesn't tell
+    /// whether the stream is complete
+    Deflate {
+        stream: Decompress,
+        out: Output,
+        end: bool,
+    },
+}
+
+/// Decompressed data which is not consumed yet
+///
+/// Writes fail as soon as there is more data than the limit, so a small
+/// compressed body can't make us allocate lots of memory.
+struct Output {
+    buf: Vec<u8>,
+    limit: Option<usize>,
+    too_long: bool,
+}
+
+/// Decompresses response body with a coding from `Content-Encoding`
+///
+/// Decompressed data is accumulated until consumed by the codec.
+pub(crate) struct Decompressor {
+    decoder: Decoder,
+    finished: bool,
+}
+
+impl Output {
+    fn new(limit: Option<usize>) -> Output {
+        Output {
+            buf: Vec::new(),
+            limit: limit,
+            too_long: false,
+        }
+    }
+    fn check(&mut self) -> io::Result<()> {
+        if self.limit.map(|x| self.buf.len() > x).unwrap_or(false) {
+            self.too_long = true;
+            return Err(io::Error::new(io::ErrorKind::Other,
+                "decompressed body is too long"));
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        self.check()?;
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Decompressor {
+    /// Create a decompressor
+    ///
+    /// If `limit` is set, decompression fails with `ResponseBodyTooLong`
+    /// when there is more unconsumed data than that.
+    pub fn new(coding: Coding, limit: Option<usize>) -> Decompressor {
+        let decoder = match coding {
+            Coding::Gzip => Decoder::Gzip(GzDecoder::new(Output::new(limit))),
+            Coding::Deflate => Decoder::Deflate {
+                stream: Decompress::new(true),
+                out: Output::new(limit),
+                end: false,
+            },
+        };
+        Decompressor {
+            decoder: decoder,
+            finished: false,
+        }
+    }
+    /// Decompress a chunk of the body
+    pub fn write(&mut self, data: &[u8]) -> Result<(), ErrorEnum> {
+        let result = match self.decoder {
+            Decoder::Gzip(ref mut d) => {
+                d.write_all(data).and_then(|()| {
+                    // moves decompressed data out of the internal buffer
+                    d.flush()
+                })
+            }
+            Decoder::Deflate { ref mut stream, ref mut out, ref mut end } => {
+                if !*end {
+                    inflate(stream, out, data).map(|x| *end = x)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        result.map_err(|e| self.error(e))
+    }
+    /// Decompress the rest of the data when the whole body is received
+    ///
+    /// Fails if the compressed stream is truncated. Does nothing when
+    /// called twice.
+    pub fn finish(&mut self) -> Result<(), ErrorEnum> {
+        if self.finished {
+            return Ok(());
+        }
+        let result = match self.decoder {
+            Decoder::Gzip(ref mut d) => d.try_finish(),
+            Decoder::Deflate { end: false, .. } => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "deflate stream is truncated"))
+            }
+            Decoder::Deflate { .. } => Ok(()),
+        };
+        result.map_err(|e| self.error(e))?;
+        self.finished = true;
+        Ok(())
+    }
+    fn output(&self) -> &Output {
+        match self.decoder {
+            Decoder::Gzip(ref d) => d.get_ref(),
+            Decoder::Deflate { ref out, .. } => out,
+        }
+    }
+    fn error(&self, err: io::Error) -> ErrorEnum {
+        if self.output().too_long {
+            ErrorEnum::ResponseBodyTooLong
+        } else {
+            ErrorEnum::Decompress(err)
+        }
+    }
+    /// Decompressed data which is not consumed yet
+    pub fn data(&self) -> &[u8] {
+        &self.output().buf
+    }
+    pub fn consume(&mut self, bytes: usize) {
+        let out = match self.decoder {
+            Decoder::Gzip(ref mut d) => d.get_mut(),
+            Decoder::Deflate { ref mut out, .. } => out,
+        };
+        out.buf.drain(..bytes);
+    }
+}
+
+/// Decompress data into the buffer, returns `true` at the end of stream
+///
+/// Data after the end of stream is ignored.
+fn inflate(stream: &mut Decompress, out: &mut Output, mut data: &[u8])
+    -> io::Result<bool>
+{
+    loop {
+        let reserve = max(data.len()*4, 4096);
+        out.buf.reserve(reserve);
+        let before = stream.total_in();
+        let status = stream.decompress_vec(data, &mut out.buf,
+                                           FlushDecompress::None)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        data = &data[(stream.total_in() - before) as usize..];
+        out.check()?;
+        match status {
+            Status::StreamEnd => return Ok(true),
+            // output buffer is full, there might be more data pending
+            _ if out.buf.len() == out.buf.capacity() => continue,
+            _ if data.len() == 0 => return Ok(false),
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+
+    use client::content_coding::Coding;
+    use client::errors::ErrorEnum;
+    use super::Decompressor;
+
+    #[test]
+    fn gzip() {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(b"hello world").unwrap();
+        let data = enc.finish().unwrap();
+
+        let mut d = Decompressor::new(Coding::Gzip, None);
+        for chunk in data.chunks(3) {
+            d.write(chunk).unwrap();
+        }
+        d.finish().unwrap();
+        assert_eq!(d.data(), b"hello world");
+        d.consume(6);
+        assert_eq!(d.data(), b"world");
+        d.finish().unwrap();
+        assert_eq!(d.data(), b"world");
+    }
+
+    #[test]
+    fn truncated() {
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(b"hello world").unwrap();
+        let data = enc.finish().unwrap();
+
+        let mut d = Decompressor::new(Coding::Deflate, None);
+        d.write(&data[..data.len()-2]).unwrap();
+        assert!(d.finish().is_err());
+        d.write(&data[data.len()-2..]).unwrap();
+        d.finish().unwrap();
+        assert_eq!(d.data(), b"hello world");
+
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(b"hello world").unwrap();
+        let data = enc.finish().unwrap();
+        let mut d = Decompressor::new(Coding::Gzip, None);
+        d.write(&data[..data.len()-2]).unwrap();
+        assert!(d.finish().is_err());
+    }
+
+    #[test]
+    fn limit() {
+        let zeros = vec![0u8; 1 << 20];
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&zeros).unwrap();
+        let gzip = enc.finish().unwrap();
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&zeros).unwrap();
+        let deflate = enc.finish().unwrap();
+
+        for &(coding, ref data) in &[(Coding::Gzip, gzip),
+                                     (Coding::Deflate, deflate)]
+        {
+            let mut d = Decompressor::new(coding, Some(1000));
+            let err = d.write(data).unwrap_err();
+            assert!(matches!(err, ErrorEnum::ResponseBodyTooLong));
+            assert!(d.data().len() < 65536);
+
+            let mut d = Decompressor::new(coding, Some(1 << 20));
+            d.write(data).unwrap();
+            d.finish().unwrap();
+            assert_eq!(d.data().len(), 1 << 20);
+        }
+    }
+}
@@ -1,17 +1,24 @@
-use std::io;
+use std::io::{self, Read};
+use std::str::from_utf8;
+use std::cmp::{min, max};
+use std::i32;
 use std::fmt::Display;
 #[allow(unused_imports)]
 use std::ascii::AsciiExt;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 
+use httparse;
 use tk_bufstream::WriteBuf;
 use futures::{Future, Async};
 use tokio_io::AsyncWrite;
 
 use enums::Version;
-use headers::is_close;
-use base_serializer::{MessageState, HeaderError};
+use headers::{is_close, is_chunked};
+use base_serializer::{MessageState, HeaderError, HeaderLimits};
+use base_serializer::check_header;
+use client::Extensions;
+use spans::Span;
 
 pub enum RequestState {
     Empty = 0,
@@ -23,12 +30,26 @@ pub enum RequestState {
 ///
 /// Methods of this structure ensure that everything you write into a buffer
 /// is consistent and valid protocol
+///
+/// Encoder is `Send` and `Sync` when `S` is, so request body may be
+/// serialized in another thread.
 pub struct Encoder<S> {
     message: MessageState,
     buf: WriteBuf<S>,
     // TODO(tailhook) we could use smaller atomic, but they are unstable
     state: Arc<AtomicUsize>,
     close_signal: Arc<AtomicBool>,
+    /// Bytes in the buffer (from previous requests) when encoder created
+    start: usize,
+    /// Bytes flushed through this encoder
+    flushed: usize,
+    /// Total size of the request is stored here on `done()`
+    bytes: Arc<AtomicUsize>,
+    limits: HeaderLimits,
+    deferred: Vec<(i32, String, Vec<u8>)>,
+    deferred_host: bool,
+    extensions: Extensions,
+    span: Span,
 }
 
 /// This structure returned from `Encoder::done` and works as a continuation
@@ -42,6 +63,32 @@ pub struct EncoderDone<S> {
 /// This future is created by `Encoder::wait_flush(x)``
 pub struct WaitFlush<S>(Option<Encoder<S>>, usize);
 
+/// A future that writes the whole request body from a reader
+///
+/// This future is created by `Encoder::write_from(..)` and yields encoder
+/// back when reader is exhausted.
+pub struct WriteBody<S, R> {
+    encoder: Option<Encoder<S>>,
+    source: R,
+    read_ahead: usize,
+    chunk: Vec<u8>,
+}
+
+/// Maximum size of a single read from the body source
+const READ_CHUNK: usize = 16384;
+/// Maximum number of headers in a head passed to `raw_head`
+const MAX_RAW_HEADERS: usize = 1024;
+
+/// Returns true for `Connection` header which has `close` option
+fn is_close_header(name: &str, value: &[u8]) -> bool {
+    name.eq_ignore_ascii_case("Connection") &&
+        value.split(|&x| x == b',').any(is_close)
+}
+
+fn is_host(name: &str) -> bool {
+    name.eq_ignore_ascii_case("Host")
+}
+
 pub fn get_inner<S>(e: EncoderDone<S>) -> WriteBuf<S> {
     e.buf
 }
@@ -60,6 +107,7 @@ impl<S> Encoder<S> {
     {
         self.message.request_line(&mut self.buf.out_buf,
             method, path, version);
+        self.span.record_request(method, path);
         let nstatus = if method.eq_ignore_ascii_case("HEAD") {
             RequestState::StartedHead as usize
         } else {
@@ -71,6 +119,165 @@ impl<S> Encoder<S> {
             panic!("Request line in wrong state");
         }
     }
+    /// Write pre-serialized request line and headers as is
+    ///
+    /// This is an escape hatch for traffic replay and conformance testing:
+    /// header order and casing are preserved byte-exactly. The `head` must
+    /// be a single complete request head, i.e. end with an empty line.
+    ///
+    /// The head is parsed to find out body framing: `Content-Length` or
+    /// `Transfer-Encoding` with `chunked` as the last encoding are used
+    /// for the body written with `write_body` as usual, without both the
+    /// request has no body. Ambiguous framing (both headers, duplicates)
+    /// is rejected.
+    ///
+    /// This replaces `request_line`, all the header methods and
+    /// `done_headers`, continue with `write_body` and `done`.
+    ///
+    /// # Panics
+    ///
+    /// When request line is already written or when there are deferred
+    /// headers (they can't be inserted into a pre-serialized head).
+    pub fn raw_head(&mut self, head: &[u8]) -> Result<(), HeaderError> {
+        use base_serializer::HeaderError::*;
+
+        assert!(self.deferred.is_empty(),
+                "Called raw_head() method on a request with deferred headers");
+
+        let mut headers = vec![httparse::EMPTY_HEADER; MAX_RAW_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(head) {
+            Ok(httparse::Status::Complete(n)) if n == head.len() => {}
+            _ => return Err(InvalidRequestHead),
+        }
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut close = false;
+        for header in req.headers.iter() {
+            if header.name.eq_ignore_ascii_case("Content-Length") {
+                if content_length.is_some() {
+                    return Err(DuplicateContentLength);
+                }
+                if chunked {
+                    return Err(ContentLengthAfterTransferEncoding);
+                }
+                content_length = Some(from_utf8(header.value).ok()
+                    .and_then(|x| x.trim().parse().ok())
+                    .ok_or_else(|| {
+                        InvalidHeaderValue(header.name.to_string())
+                    })?);
+            } else if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+                if chunked {
+                    return Err(DuplicateTransferEncoding);
+                }
+                if content_length.is_some() {
+                    return Err(TransferEncodingAfterContentLength);
+                }
+                match header.value.split(|&x| x == b',').last() {
+                    Some(enc) if is_chunked(enc) => chunked = true,
+                    _ => {
+                        return Err(InvalidHeaderValue(header.name.to_string()))
+                    }
+                }
+            } else if is_close_header(header.name, header.value) {
+                close = true;
+            }
+        }
+        let is_head = req.method.unwrap().eq_ignore_ascii_case("HEAD");
+        self.message.raw_request_head(&mut self.buf.out_buf, head,
+            if chunked { None } else { Some(content_length.unwrap_or(0)) });
+        let nstatus = if is_head {
+            RequestState::StartedHead as usize
+        } else {
+            RequestState::StartedNormal as usize
+        };
+        if self.state.swap(nstatus, Ordering::SeqCst) != 0 {
+            panic!("Request line in wrong state");
+        }
+        if close {
+            self.close_signal.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+    /// Add a header that will be written at the start of the headers
+    ///
+    /// This is useful for middleware wrapping a `Codec`: it can add headers
+    /// before passing encoder to the wrapped codec, which writes request
+    /// line and other headers as usual.
+    ///
+    /// Deferred headers are written before any other header except `Host`:
+    /// `Host` is always written first, either the deferred one or the one
+    /// added by `add_header` right after `request_line`. Same restrictions
+    /// as for `add_header` apply, the header is validated immediately.
+    /// When `Host` is deferred, adding another one (deferred or not) fails
+    /// with `DuplicateHost`.
+    ///
+    /// This is a shortcut for `defer_header_with_priority` with priority `0`.
+    ///
+    /// # Panics
+    ///
+    /// When request line is already written.
+    pub fn defer_header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
+        -> Result<(), HeaderError>
+    {
+        self.defer_header_with_priority(name, value, 0)
+    }
+
+    /// Add a deferred header which is written in the order of `priority`
+    ///
+    /// Deferred headers with lower priority are written first, ones with
+    /// the same priority are written in the order they were added (but
+    /// `Host` always goes first, see `defer_header`).
+    ///
+    /// # Panics
+    ///
+    /// When request line is already written.
+    pub fn defer_header_with_priority<V: AsRef<[u8]>>(&mut self,
+        name: &str, value: V, priority: i32)
+        -> Result<(), HeaderError>
+    {
+        match self.message {
+            MessageState::RequestStart => {}
+            ref state => {
+                panic!("Called defer_header() method on a request \
+                    in state {:?}", state)
+            }
+        }
+        check_header(name, value.as_ref(), &self.limits)?;
+        self.check_host(name)?;
+        if name.eq_ignore_ascii_case("Host") {
+            self.deferred_host = true;
+        }
+        if is_close_header(name, value.as_ref()) {
+            self.close_signal.store(true, Ordering::SeqCst);
+        }
+        self.deferred.push((priority, name.to_string(),
+                            value.as_ref().to_vec()));
+        Ok(())
+    }
+
+    fn check_host(&self, name: &str) -> Result<(), HeaderError> {
+        if self.deferred_host && name.eq_ignore_ascii_case("Host") {
+            return Err(HeaderError::DuplicateHost);
+        }
+        Ok(())
+    }
+
+    fn write_deferred(&mut self) {
+        if self.deferred.is_empty() {
+            return;
+        }
+        // stable sort, so the order of equal priorities is kept
+        self.deferred.sort_by_key(|&(priority, ref name, _)| {
+            if name.eq_ignore_ascii_case("Host") { i32::MIN } else { priority }
+        });
+        for (_, name, value) in self.deferred.drain(..) {
+            self.message.add_header(&mut self.buf.out_buf, &name, &value,
+                                    &self.limits)
+                .expect("deferred header is validated");
+        }
+    }
+
     /// Add a header to the message.
     ///
     /// Header is written into the output buffer immediately. And is sent
@@ -93,11 +300,16 @@ impl<S> Encoder<S> {
     pub fn add_header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
         -> Result<(), HeaderError>
     {
-        if name.eq_ignore_ascii_case("Connection") && is_close(value.as_ref())
-        {
+        self.check_host(name)?;
+        if !name.eq_ignore_ascii_case("Host") {
+            self.write_deferred();
+        }
+        self.message.add_header(&mut self.buf.out_buf, name, value.as_ref(),
+                                &self.limits)?;
+        if is_close_header(name, value.as_ref()) {
             self.close_signal.store(true, Ordering::SeqCst);
         }
-        self.message.add_header(&mut self.buf.out_buf, name, value.as_ref())
+        Ok(())
     }
 
     /// Same as `add_header` but allows value to be formatted directly into
@@ -111,7 +323,64 @@ impl<S> Encoder<S> {
         if name.eq_ignore_ascii_case("Connection") {
             unimplemented!();
         }
-        self.message.format_header(&mut self.buf.out_buf, name, value)
+        self.check_host(name)?;
+        if !name.eq_ignore_ascii_case("Host") {
+            self.write_deferred();
+        }
+        self.message.format_header(&mut self.buf.out_buf, name, value,
+                                   &self.limits)
+    }
+
+    /// Add multiple headers to the message at once
+    ///
+    /// This is useful for proxies that copy many headers. If any of the
+    /// headers is invalid nothing is written and an error is returned.
+    /// Same restrictions as for `add_header` apply. If there are deferred
+    /// headers, `Host` from the batch is written before them and other
+    /// headers after them.
+    ///
+    /// # Panics
+    ///
+    /// Panics when `add_headers` is called in the wrong state.
+    pub fn add_headers<I, N, V>(&mut self, headers: I)
+        -> Result<(), HeaderError>
+        where I: IntoIterator<Item=(N, V)>,
+              N: AsRef<str>,
+              V: AsRef<[u8]>,
+    {
+        let headers = headers.into_iter().collect::<Vec<_>>();
+        let headers = headers.iter()
+            .map(|&(ref name, ref value)| (name.as_ref(), value.as_ref()))
+            .collect::<Vec<(&str, &[u8])>>();
+        let mut close = false;
+        let mut host = false;
+        for &(name, value) in &headers {
+            check_header(name, value, &self.limits)?;
+            host |= is_host(name);
+            close |= is_close_header(name, value);
+        }
+        if host {
+            self.check_host("Host")?;
+            self.message.add_headers(&mut self.buf.out_buf,
+                headers.iter().cloned().filter(|h| is_host(h.0)),
+                &self.limits)?;
+        }
+        self.write_deferred();
+        self.message.add_headers(&mut self.buf.out_buf,
+            headers.into_iter().filter(|h| !is_host(h.0)),
+            &self.limits)?;
+        if close {
+            self.close_signal.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Reserve space for `bytes` of headers in the output buffer
+    ///
+    /// This is an optimization to avoid multiple reallocations when
+    /// adding many headers.
+    pub fn reserve_headers(&mut self, bytes: usize) {
+        self.buf.out_buf.reserve_exact(bytes)
     }
 
     /// Add a content length to the message.
@@ -127,6 +396,7 @@ impl<S> Encoder<S> {
     pub fn add_length(&mut self, n: u64)
         -> Result<(), HeaderError>
     {
+        self.write_deferred();
         self.message.add_length(&mut self.buf.out_buf, n)
     }
     /// Sets the transfer encoding to chunked.
@@ -141,6 +411,7 @@ impl<S> Encoder<S> {
     pub fn add_chunked(&mut self)
         -> Result<(), HeaderError>
     {
+        self.write_deferred();
         self.message.add_chunked(&mut self.buf.out_buf)
     }
     /// Closes the HTTP header
@@ -152,6 +423,7 @@ impl<S> Encoder<S> {
     ///
     /// Panics when the request is in a wrong state.
     pub fn done_headers(&mut self) -> Result<(), HeaderError> {
+        self.write_deferred();
         self.message.done_headers(&mut self.buf.out_buf)
         .map(|always_support_body| assert!(always_support_body))
     }
@@ -175,6 +447,8 @@ impl<S> Encoder<S> {
     /// Panics when the request is in a wrong state.
     pub fn done(mut self) -> EncoderDone<S> {
         self.message.done(&mut self.buf.out_buf);
+        self.bytes.store((self.flushed + self.buf.out_buf.len())
+            .saturating_sub(self.start), Ordering::SeqCst);
         EncoderDone { buf: self.buf }
     }
 
@@ -188,7 +462,16 @@ impl<S> Encoder<S> {
     pub fn flush(&mut self) -> Result<(), io::Error>
         where S: AsyncWrite
     {
-        self.buf.flush()
+        let old_len = self.buf.out_buf.len();
+        let result = self.buf.flush();
+        self.flushed += old_len - self.buf.out_buf.len();
+        result
+    }
+    /// Returns per-connection storage shared with other requests
+    ///
+    /// See `Extensions` for details.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
     }
     /// Returns bytes currently lying in the buffer
     ///
@@ -204,6 +487,31 @@ impl<S> Encoder<S> {
     pub fn wait_flush(self, watermark: usize) -> WaitFlush<S> {
         WaitFlush(Some(self), watermark)
     }
+
+    /// Returns future which writes everything from `source` as a body
+    ///
+    /// This is useful for uploading files which don't fit memory: data is
+    /// read in small chunks only when there are less than `read_ahead`
+    /// bytes in the output buffer, so memory usage is bounded regardless
+    /// of the size of the file.
+    ///
+    /// Headers must be already written, and the total size must match
+    /// `add_length` unless `add_chunked` is used.
+    ///
+    /// Note: reading is done in the event loop thread, this is fine for
+    /// local files but for slow sources you should implement `Read`
+    /// returning `WouldBlock` (that is an `AsyncRead`) instead.
+    pub fn write_from<R: Read>(self, source: R, read_ahead: usize)
+        -> WriteBody<S, R>
+    {
+        let read_ahead = max(read_ahead, 1);
+        WriteBody {
+            encoder: Some(self),
+            source: source,
+            read_ahead: read_ahead,
+            chunk: vec![0; min(read_ahead, READ_CHUNK)],
+        }
+    }
 }
 
 impl<S: AsyncWrite> Future for WaitFlush<S> {
@@ -223,15 +531,51 @@ impl<S: AsyncWrite> Future for WaitFlush<S> {
     }
 }
 
+impl<S: AsyncWrite, R: Read> Future for WriteBody<S, R> {
+    type Item = Encoder<S>;
+    type Error = io::Error;
+    fn poll(&mut self) -> Result<Async<Encoder<S>>, io::Error> {
+        {
+            let enc = self.encoder.as_mut().expect("future is polled twice");
+            loop {
+                enc.flush()?;
+                if enc.buf.out_buf.len() >= self.read_ahead {
+                    return Ok(Async::NotReady);
+                }
+                match self.source.read(&mut self.chunk) {
+                    Ok(0) => break,
+                    Ok(n) => enc.write_body(&self.chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(Async::Ready(self.encoder.take().unwrap()))
+    }
+}
+
 pub fn new<S>(io: WriteBuf<S>,
-    state: Arc<AtomicUsize>, close_signal: Arc<AtomicBool>)
+    state: Arc<AtomicUsize>, close_signal: Arc<AtomicBool>,
+    bytes: Arc<AtomicUsize>, limits: HeaderLimits, extensions: Extensions,
+    span: Span)
     -> Encoder<S>
 {
     Encoder {
         message: MessageState::RequestStart,
+        start: io.out_buf.len(),
+        flushed: 0,
         buf: io,
         state: state,
         close_signal: close_signal,
+        bytes: bytes,
+        limits: limits,
+        deferred: Vec::new(),
+        deferred_host: false,
+        extensions: extensions,
+        span: span,
     }
 }
 
@@ -246,3 +590,189 @@ impl<S> io::Write for Encoder<S> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+
+    use futures::Future;
+    use tk_bufstream::{IoBuf, MockData};
+
+    use enums::Version;
+    use base_serializer::{HeaderLimits, HeaderError};
+    use client::Extensions;
+    use spans::Span;
+    use super::{Encoder, EncoderDone, WaitFlush, new};
+
+    fn encoder(mock: &MockData) -> Encoder<MockData> {
+        new(IoBuf::new(mock.clone()).split().0,
+            Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicUsize::new(0)), HeaderLimits::default(),
+            Extensions::new(), Span::none())
+    }
+
+    #[test]
+    fn send() {
+        fn send<T: Send>() {}
+        send::<Encoder<MockData>>();
+        send::<EncoderDone<MockData>>();
+        send::<WaitFlush<MockData>>();
+    }
+
+    #[test]
+    fn sync() {
+        fn sync<T: Sync>() {}
+        sync::<Encoder<MockData>>();
+        sync::<EncoderDone<MockData>>();
+        sync::<WaitFlush<MockData>>();
+    }
+
+    #[test]
+    fn write_from() {
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.request_line("POST", "/upload", Version::Http11);
+        enc.add_length(100000).unwrap();
+        enc.done_headers().unwrap();
+        let data = (0..100000).map(|x| x as u8).collect::<Vec<_>>();
+        let enc = enc.write_from(Cursor::new(&data), 4096).wait().unwrap();
+        enc.done();
+        let out = mock.output(..);
+        assert!(out.starts_with(b"POST /upload HTTP/1.1\r\n"));
+        assert!(out.ends_with(&data));
+    }
+
+    #[test]
+    fn raw_head() {
+        let head = "POST /x HTTP/1.1\r\nhOsT: a\r\nX-B: 1\r\nx-a: 2\r\n\
+                    transfer-encoding: gzip, chunked\r\n\r\n";
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.raw_head(head.as_bytes()).unwrap();
+        enc.write_body(b"hello");
+        enc.done().buf.flush().unwrap();
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+                   format!("{}5\r\nhello\r\n0\r\n\r\n", head));
+
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.raw_head(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        enc.done().buf.flush().unwrap();
+        assert_eq!(mock.output(..), b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+
+        for bad in &[
+            "GET / HTTP/1.1\r\nHost: a\r\n",
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 1\r\n\
+             Content-Length: 1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 1\r\n\
+             Transfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+        ] {
+            let mock = MockData::new();
+            assert!(encoder(&mock).raw_head(bad.as_bytes()).is_err(),
+                "{}", bad);
+            assert_eq!(mock.output(..).len(), 0);
+        }
+    }
+
+    #[test]
+    fn deferred_headers() {
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.defer_header("X-A", "1").unwrap();
+        enc.defer_header_with_priority("X-B", "2", -1).unwrap();
+        assert!(enc.defer_header("Content-Length", "1").is_err());
+        enc.request_line("GET", "/", Version::Http11);
+        enc.add_header("Host", "example.com").unwrap();
+        enc.add_header("X-C", "3").unwrap();
+        enc.done_headers().unwrap();
+        enc.done().buf.flush().unwrap();
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+                   "GET / HTTP/1.1\r\nHost: example.com\r\n\
+                    X-B: 2\r\nX-A: 1\r\nX-C: 3\r\n\r\n");
+
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.defer_header_with_priority("X-A", "1", -10).unwrap();
+        enc.defer_header("host", "example.com").unwrap();
+        enc.request_line("POST", "/", Version::Http11);
+        enc.add_length(0).unwrap();
+        enc.done_headers().unwrap();
+        enc.done().buf.flush().unwrap();
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+                   "POST / HTTP/1.1\r\nhost: example.com\r\nX-A: 1\r\n\
+                    Content-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn deferred_and_batch() {
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.defer_header("X-A", "1").unwrap();
+        enc.request_line("GET", "/", Version::Http11);
+        assert!(matches!(
+            enc.add_headers(vec![("X-B", "2"), ("X-C", "bad\r\n")]),
+            Err(HeaderError::InvalidHeaderValue(..))));
+        enc.add_headers(vec![("X-B", "2"), ("Host", "example.com")]).unwrap();
+        enc.done_headers().unwrap();
+        enc.done().buf.flush().unwrap();
+        // deferred header is written once, after `Host` from the batch
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+                   "GET / HTTP/1.1\r\nHost: example.com\r\n\
+                    X-A: 1\r\nX-B: 2\r\n\r\n");
+    }
+
+    #[test]
+    fn deferred_duplicate_host() {
+        let mock = MockData::new();
+        let mut enc = encoder(&mock);
+        enc.defer_header("Host", "example.com").unwrap();
+        assert!(matches!(enc.defer_header("host", "example.org"),
+                         Err(HeaderError::DuplicateHost)));
+        enc.request_line("GET", "/", Version::Http11);
+        assert!(matches!(enc.add_header("HOST", "example.org"),
+                         Err(HeaderError::DuplicateHost)));
+        assert!(matches!(enc.format_header("Host", "example.org"),
+                         Err(HeaderError::DuplicateHost)));
+        assert!(matches!(
+            enc.add_headers(vec![("X-A", "1"), ("Host", "example.org")]),
+            Err(HeaderError::DuplicateHost)));
+        enc.add_header("X-B", "2").unwrap();
+        enc.done_headers().unwrap();
+        enc.done().buf.flush().unwrap();
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+                   "GET / HTTP/1.1\r\nHost: example.com\r\n\
+                    X-B: 2\r\n\r\n");
+    }
+
+    #[test]
+    fn connection_close() {
+        for &(value, close) in &[("close", true), ("keep-alive, Close", true),
+                                 ("closed", false), ("x, y", false)] {
+            let mock = MockData::new();
+            let mut enc = encoder(&mock);
+            enc.defer_header("Connection", value).unwrap();
+            assert_eq!(enc.close_signal.load(Ordering::SeqCst), close);
+
+            let mut enc = encoder(&mock);
+            enc.request_line("GET", "/", Version::Http11);
+            enc.add_header("Connection", value).unwrap();
+            assert_eq!(enc.close_signal.load(Ordering::SeqCst), close);
+
+            let mut enc = encoder(&mock);
+            enc.request_line("GET", "/", Version::Http11);
+            enc.add_headers(vec![("Connection", value)]).unwrap();
+            assert_eq!(enc.close_signal.load(Ordering::SeqCst), close);
+
+            let mut enc = encoder(&mock);
+            let head = format!("GET / HTTP/1.1\r\nConnection: {}\r\n\r\n",
+                               value);
+            enc.raw_head(head.as_bytes()).unwrap();
+            assert_eq!(enc.close_signal.load(Ordering::SeqCst), close);
+        }
+    }
+}
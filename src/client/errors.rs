@@ -4,6 +4,9 @@ use std::convert::From;
 use futures::sync::mpsc::SendError;
 use httparse::Error as HttpError;
 use httparse::InvalidChunkSize;
+use url::ParseError as UrlError;
+
+use chunked;
 
 
 quick_error! {
@@ -28,6 +31,11 @@ quick_error! {
             display("invalid chunk size: {}", err)
             from()
         }
+        /// Chunk size is larger than `Config::max_chunk_size`
+        ChunkTooLarge(size: u64) {
+            description("chunk is too large")
+            display("chunk of {} bytes is too large", size)
+        }
         /// Bad `Content-Length` header
         BadContentLength {
             description("bad content length")
@@ -36,6 +44,24 @@ quick_error! {
         DuplicateContentLength {
             description("duplicate content length")
         }
+        /// Both `Content-Length` and `Transfer-Encoding` are in response
+        /// while `Config::strict_response_headers` is set
+        ConflictingBodyLength {
+            description("both content length and transfer encoding \
+                         in response")
+        }
+        /// Response header value contains control characters
+        /// (only checked when `Config::strict_response_headers` is set)
+        InvalidHeaderValue(name: String) {
+            description("invalid response header value")
+            display("invalid value of response header {:?}", name)
+        }
+        /// Reason phrase is longer than allowed when
+        /// `Config::strict_response_headers` is set
+        ReasonTooLong(len: usize) {
+            description("reason phrase is too long")
+            display("reason phrase of {} bytes is too long", len)
+        }
         /// Connection reset by peer when reading response headers
         ResetOnResponseHeaders {
             description("connection closed prematurely while reading headers")
@@ -44,11 +70,27 @@ quick_error! {
         ResetOnResponseBody {
             description("connection closed prematurely while reading body")
         }
+        /// Response body is delimited by connection close while
+        /// `Config::require_body_length` is set
+        BodyLengthRequired {
+            description("response has neither content length \
+                         nor chunked encoding")
+        }
+        /// More than `Config::max_interim_responses` interim (1xx)
+        /// responses received before the final one
+        TooManyInterimResponses {
+            description("too many interim (1xx) responses")
+        }
         /// Response headers are received while we had no request sent yet
         PrematureResponseHeaders {
             description("response headers received \
                          before request has been written")
         }
+        /// Response was matched to a request other than the oldest one
+        /// in flight (this is a bug, connection is closed)
+        ResponseOutOfOrder {
+            description("response matched to a wrong pipelined request")
+        }
         /// This means connection is busy (over the limit or not yet
         /// established when trying to send request
         Busy {
@@ -72,6 +114,26 @@ quick_error! {
         InvalidUrl {
             description("requesting an invalid url")
         }
+        /// Error parsing URL (including invalid international domain name)
+        UrlParse(err: UrlError) {
+            description("error parsing url")
+            display("error parsing url: {}", err)
+            from()
+        }
+        /// URL has no host name
+        UrlWithoutHost {
+            description("url has no host")
+        }
+        /// Header passed to `Buffered::send` has invalid name or value, or
+        /// it's a `Content-Length` or `Transfer-Encoding` header. Also
+        /// returned by `record::Replay` for invalid recorded headers
+        InvalidHeader {
+            description("invalid request header")
+        }
+        /// Method passed to `Buffered::send` is not a valid token
+        InvalidMethod {
+            description("invalid request method")
+        }
         /// Error sending a request to a connection pool
         PoolError {
             description("error sending a request to a connection pool")
@@ -80,6 +142,21 @@ quick_error! {
         ResponseBodyTooLong {
             description("response body too long")
         }
+        /// Error decompressing response body (see `Config::decompress`)
+        Decompress(err: io::Error) {
+            description("error decompressing response body")
+            display("error decompressing response body: {}", err)
+        }
+        /// `RecvMode::hijack()` returned from `Codec::headers_received`,
+        /// which is supported only by server
+        HijackUnsupported {
+            description("hijack mode is not supported by client")
+        }
+        /// `RecvMode::reject()` returned from `Codec::headers_received`,
+        /// which is supported only by server
+        RejectUnsupported {
+            description("reject mode is not supported by client")
+        }
         /// Connection header is invalid
         ConnectionInvalid {
             description("invalid connection header in response")
@@ -94,6 +171,27 @@ quick_error! {
         RequestTimeout {
             description("request timed out")
         }
+        /// Connection to the server can't be established in time
+        ConnectTimeout {
+            description("timeout establishing connection")
+        }
+        /// Too many redirects when fetching with `client::get`
+        TooManyRedirects {
+            description("too many redirects")
+        }
+        /// Url scheme is not supported by `client::get`
+        ///
+        /// Note: `https` is only supported when `tls` feature is enabled
+        UnsupportedScheme {
+            description("unsupported url scheme")
+        }
+        /// Error establishing TLS connection
+        #[cfg(feature="tls")]
+        Tls(err: ::native_tls::Error) {
+            description("TLS error")
+            display("TLS error: {}", err)
+            cause(err)
+        }
         /// Connection timed out on keep alive
         KeepAliveTimeout {
             description("connection timed out being on keep-alive")
@@ -106,6 +204,15 @@ quick_error! {
     }
 }
 
+impl From<chunked::Error> for ErrorEnum {
+    fn from(v: chunked::Error) -> ErrorEnum {
+        match v {
+            chunked::Error::Invalid(e) => ErrorEnum::ChunkSize(e),
+            chunked::Error::TooLarge(size) => ErrorEnum::ChunkTooLarge(size),
+        }
+    }
+}
+
 impl<T> From<SendError<T>> for ErrorEnum {
     fn from(_: SendError<T>) -> ErrorEnum {
         ErrorEnum::PoolError
@@ -137,6 +244,14 @@ impl Error {
             _ => false,
         }
     }
+
+    /// Connection could not be established within `Config::connect_timeout`
+    ///
+    /// No request has been sent in this case, so it's always safe to retry
+    /// (probably using another address).
+    pub fn is_connect_timeout(&self) -> bool {
+        matches!(self.0, ErrorEnum::ConnectTimeout)
+    }
 }
 
 #[test]
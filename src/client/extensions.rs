@@ -0,0 +1,112 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+
+type Map = HashMap<TypeId, Box<Any + Send>>;
+
+
+/// Per-connection storage of arbitrary values keyed by type
+///
+/// Every `client::Proto` owns one. It's available to codecs via
+/// `Encoder::extensions` and `Head::extensions`, so state which is bound
+/// to a connection (connection-based authentication like NTLM or
+/// Negotiate, tokens tied to a TLS session) can be kept between requests
+/// without global maps keyed by socket:
+///
+/// ```rust,ignore
+/// fn start_write(&mut self, mut e: Encoder<S>) -> Self::Future {
+///     e.request_line("GET", "/", Version::Http11);
+///     match e.extensions().get::<AuthToken>() {
+///         Some(token) => e.add_header("Authorization", &token.0).unwrap(),
+///         None => self.handshake = true,
+///     }
+///     // ...
+/// }
+/// ```
+///
+/// This is a cheap handle, clones share the same storage. Values must be
+/// `Send` as requests may be serialized in other threads.
+#[derive(Clone)]
+pub struct Extensions {
+    map: Arc<Mutex<Map>>,
+}
+
+impl Extensions {
+    /// Create an empty storage
+    pub fn new() -> Extensions {
+        Extensions {
+            map: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    fn lock<'a>(&'a self) -> MutexGuard<'a, Map> {
+        self.map.lock().expect("extensions are not poisoned")
+    }
+    /// Insert a value, returns previous value of the same type if any
+    pub fn insert<T: Any + Send>(&self, value: T) -> Option<T> {
+        self.lock().insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+    /// Returns a copy of the value of the specified type
+    pub fn get<T: Any + Send + Clone>(&self) -> Option<T> {
+        self.lock().get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref::<T>())
+            .cloned()
+    }
+    /// Call a function with a mutable reference to the value
+    ///
+    /// Returns `None` if there is no value of the type. Storage is locked
+    /// while function is running, so it must not access extensions.
+    pub fn with<T, F, R>(&self, f: F) -> Option<R>
+        where T: Any + Send,
+              F: FnOnce(&mut T) -> R,
+    {
+        self.lock().get_mut(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_mut::<T>())
+            .map(f)
+    }
+    /// Returns `true` if there is a value of the specified type
+    pub fn contains<T: Any + Send>(&self) -> bool {
+        self.lock().contains_key(&TypeId::of::<T>())
+    }
+    /// Remove the value of the specified type
+    pub fn remove<T: Any + Send>(&self) -> Option<T> {
+        self.lock().remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.lock().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Extensions;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Token(String);
+
+    #[test]
+    fn typed() {
+        let ext = Extensions::new();
+        assert_eq!(ext.get::<Token>(), None);
+        assert_eq!(ext.insert(Token("a".into())), None);
+        assert_eq!(ext.insert(1u32), None);
+        let shared = ext.clone();
+        assert_eq!(shared.insert(Token("b".into())), Some(Token("a".into())));
+        assert_eq!(ext.get::<Token>(), Some(Token("b".into())));
+        assert_eq!(ext.with(|x: &mut u32| { *x += 1; *x }), Some(2));
+        assert_eq!(ext.with(|_: &mut u64| ()), None);
+        assert!(ext.contains::<u32>());
+        assert_eq!(ext.remove::<u32>(), Some(2));
+        assert!(!shared.contains::<u32>());
+    }
+}
@@ -0,0 +1,135 @@
+use futures::{Async, Future};
+
+use client::{Codec, Error, Encoder, EncoderDone, Head, RecvMode};
+use client::{Idempotency, IdempotencyKey};
+
+
+/// A type-erased future returned by `ErasedCodec::start_write`
+pub type BoxFuture<S> = Box<Future<Item=EncoderDone<S>, Error=Error>>;
+
+/// A boxed codec with type-erased future
+///
+/// This is the most convenient codec type to send into connection pools
+/// because codecs of different types may be sent through the same sink.
+pub type BoxCodec<S> = Box<Codec<S, Future=BoxFuture<S>>>;
+
+/// A wrapper that boxes a future returned by the inner codec
+///
+/// Use `erase()` to create a `BoxCodec` out of any codec.
+#[derive(Debug)]
+pub struct ErasedCodec<C>(C);
+
+/// Creates codecs on demand
+///
+/// This is useful for retries: each attempt to send a request needs its own
+/// codec because codec is consumed by a connection. The factory is
+/// implemented for any `Fn() -> C` closure, so to share configuration
+/// between codecs just capture an `Arc` in the closure.
+pub trait CodecFactory<S> {
+    /// The type of codec created
+    type Codec: Codec<S>;
+    /// Create a new codec for a single request attempt
+    fn new_codec(&self) -> Self::Codec;
+}
+
+/// Wraps a codec so that it can be sent as a `BoxCodec`
+pub fn erase<S, C>(codec: C) -> BoxCodec<S>
+    where C: Codec<S> + 'static,
+          C::Future: 'static,
+          S: 'static,
+{
+    Box::new(ErasedCodec(codec))
+}
+
+impl<C> ErasedCodec<C> {
+    /// Wrap a codec
+    pub fn new(codec: C) -> ErasedCodec<C> {
+        ErasedCodec(codec)
+    }
+    /// Unwrap the inner codec
+    pub fn into_inner(self) -> C {
+        self.0
+    }
+}
+
+impl<S, C> Codec<S> for ErasedCodec<C>
+    where C: Codec<S>,
+          C::Future: 'static,
+{
+    type Future = BoxFuture<S>;
+    fn start_write(&mut self, e: Encoder<S>) -> BoxFuture<S> {
+        Box::new(self.0.start_write(e))
+    }
+    fn headers_received(&mut self, headers: &Head) -> Result<RecvMode, Error> {
+        self.0.headers_received(headers)
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        self.0.data_received(data, end)
+    }
+    fn idempotency(&self) -> Idempotency {
+        self.0.idempotency()
+    }
+    fn idempotency_key(&self) -> Option<IdempotencyKey> {
+        self.0.idempotency_key()
+    }
+    fn is_canceled(&self) -> bool {
+        self.0.is_canceled()
+    }
+}
+
+impl<S, C: Codec<S>, F: Fn() -> C> CodecFactory<S> for F {
+    type Codec = C;
+    fn new_codec(&self) -> C {
+        (self)()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use futures::{Future, Sink, AsyncSink, Async};
+    use futures::future::lazy;
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+
+    use client::{Proto, Config, Error};
+    use client::buffered::Buffered;
+    use super::{CodecFactory, erase};
+
+    fn send<F>(factory: &F, proto: &mut Proto<MockData, F::Codec>)
+        -> Result<(), Error>
+        where F: CodecFactory<MockData>,
+    {
+        assert!(matches!(proto.start_send(factory.new_codec())?,
+                         AsyncSink::Ready));
+        // request is written, response is not received yet
+        assert!(matches!(proto.poll_complete()?, Async::NotReady));
+        Ok(())
+    }
+
+    #[test]
+    fn factory() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let receivers = RefCell::new(Vec::new());
+        let factory = || {
+            let url = "http://example.com/x".parse().unwrap();
+            let (codec, rx) = Buffered::get(url);
+            receivers.borrow_mut().push(rx);
+            erase(codec)
+        };
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+            &Config::new().done());
+        core.run(lazy(|| send(&factory, &mut proto))).unwrap();
+        assert_eq!(receivers.borrow().len(), 1);
+        assert!(String::from_utf8_lossy(&mock.output(..))
+            .starts_with("GET /x HTTP/1.1\r\nHost: example.com\r\n"));
+        mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        core.run(lazy(|| proto.poll_complete())).unwrap();
+        let rx = receivers.borrow_mut().pop().unwrap();
+        assert_eq!(rx.wait().unwrap().unwrap().body(), b"ok");
+    }
+}
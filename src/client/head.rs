@@ -1,11 +1,14 @@
 #[allow(unused_imports)]
 use std::ascii::AsciiExt;
 use std::slice::Iter as SliceIter;
+use std::time::{Duration, SystemTime};
 
 use httparse::Header;
 
-use enums::{Status};
-use client::Head;
+use enums::{Status, Version};
+use client::{Head, ConnectionTokens, Extensions};
+use client::retry_after;
+use ConnectionId;
 
 
 /// Iterator over all meaningful headers for the response
@@ -36,6 +39,38 @@ impl<'a> Head<'a> {
     pub fn raw_status(&self) -> (u16, &'a str) {
         (self.code, self.reason)
     }
+    /// Returns identifier of the connection the response is received on
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+    /// Returns per-connection storage shared with other requests
+    ///
+    /// See `Extensions` for details.
+    pub fn extensions(&self) -> &'a Extensions {
+        self.extensions
+    }
+    /// Returns HTTP version of the response
+    pub fn version(&self) -> Version {
+        self.version
+    }
+    /// Returns the delay requested by the `Retry-After` header
+    ///
+    /// The header is usually sent with `429 Too Many Requests` and
+    /// `503 Service Unavailable` statuses (and with redirects). Both
+    /// delay in seconds and HTTP-date forms are supported, the latter is
+    /// converted to a delay from now (zero if the date is in the past).
+    /// Returns `None` if there is no header or it's invalid.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.headers.iter()
+            .find(|h| h.name.eq_ignore_ascii_case("Retry-After"))
+            .and_then(|h| retry_after::parse(h.value, SystemTime::now()))
+    }
+    /// Iterator over the options listed in all `Connection` headers
+    ///
+    /// Options are trimmed, but case is preserved.
+    pub fn connection_tokens(&self) -> ConnectionTokens<'a> {
+        self.connection.tokens(self.headers)
+    }
     /// Iterator over the headers of HTTP request
     ///
     /// This iterator strips the following kinds of headers:
@@ -50,7 +85,7 @@ impl<'a> Head<'a> {
     /// 1. Payload size can be fetched using `body_length()` method. Note:
     ///    this also includes cases where length is implicitly set to zero.
     /// 2. `Connection` header might be discovered with `connection_close()`
-    ///    or `connection_header()`
+    ///    or `connection_tokens()`
     pub fn headers(&self) -> HeaderIter {
         HeaderIter {
             head: self,
@@ -64,6 +99,15 @@ impl<'a> Head<'a> {
     pub fn all_headers(&self) -> &'a [Header<'a>] {
         self.headers
     }
+    /// Raw bytes of the response head exactly as received from the network
+    ///
+    /// This includes the status line, all the headers and the final empty
+    /// line. Returns `None` unless enabled by `Config::raw_head`. The slice
+    /// borrows the input buffer, so copy (and truncate, if needed) as much
+    /// as you want to keep after `headers_received` returns.
+    pub fn raw_head(&self) -> Option<&'a [u8]> {
+        self.raw
+    }
 }
 
 
@@ -78,11 +122,10 @@ impl<'a> Iterator for HeaderIter<'a> {
                 continue;
             }
 
-            if let Some(ref conn) = self.head.connection_header {
-                let mut conn_headers = conn.split(',').map(|x| x.trim());
-                if conn_headers.any(|x| x.eq_ignore_ascii_case(header.name)) {
-                    continue;
-                }
+            if self.head.connection_tokens()
+                .any(|x| x.eq_ignore_ascii_case(header.name))
+            {
+                continue;
             }
             return Some((header.name, header.value));
         }
@@ -0,0 +1,164 @@
+use std::io::Write;
+
+use httparse::{self, Response, EMPTY_HEADER};
+
+use client::parser::with_head;
+use client::errors::ErrorEnum;
+use client::{Codec, Config, Error, Head, RecvMode, Extensions};
+use {ConnectionId, Version};
+
+
+/// Builds a response `Head` for unit tests of codecs
+///
+/// Works like `server::HeadBuilder`: the response head is serialized and
+/// parsed the same way the client does it, then the `Head` is passed to
+/// `Codec::headers_received` or a closure:
+///
+/// ```rust,ignore
+/// let mode = HeadBuilder::new(200, "OK")
+///     .header("Content-Length", "5")
+///     .dispatch(&mut codec)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct HeadBuilder {
+    code: u16,
+    reason: String,
+    version: Version,
+    headers: Vec<(String, Vec<u8>)>,
+    is_head: bool,
+    config: Config,
+    extensions: Extensions,
+}
+
+impl HeadBuilder {
+    /// Create a builder for a `HTTP/1.1` response
+    pub fn new(code: u16, reason: &str) -> HeadBuilder {
+        HeadBuilder {
+            code: code,
+            reason: reason.to_string(),
+            version: Version::Http11,
+            headers: Vec::new(),
+            is_head: false,
+            config: Config::new(),
+            extensions: Extensions::new(),
+        }
+    }
+    /// Set HTTP version of the response
+    pub fn version(&mut self, version: Version) -> &mut Self {
+        self.version = version;
+        self
+    }
+    /// Add a header
+    ///
+    /// Headers are added as is, so invalid responses can be tested too.
+    pub fn header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
+        -> &mut Self
+    {
+        self.headers.push((name.to_string(), value.as_ref().to_vec()));
+        self
+    }
+    /// Parse head as a response to a `HEAD` request (default is `false`)
+    ///
+    /// This only changes how body length headers are validated.
+    pub fn response_to_head(&mut self, value: bool) -> &mut Self {
+        self.is_head = value;
+        self
+    }
+    /// Validate headers like `Config::strict_response_headers` does
+    pub fn strict_response_headers(&mut self, value: bool) -> &mut Self {
+        self.config.strict_response_headers(value);
+        self
+    }
+    /// Use the storage as per-connection extensions (default is empty)
+    pub fn extensions(&mut self, extensions: &Extensions) -> &mut Self {
+        self.extensions = extensions.clone();
+        self
+    }
+    /// Call `headers_received` of the codec with the head
+    pub fn dispatch<S, C>(&self, codec: &mut C) -> Result<RecvMode, Error>
+        where C: Codec<S>,
+    {
+        self.with_head(|head| codec.headers_received(head))
+    }
+    /// Call a function with the head
+    ///
+    /// Returns an error if the response is invalid (the same error the
+    /// client protocol would fail with) or if function returns error.
+    pub fn with_head<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&Head) -> Result<R, Error>,
+    {
+        let mut buf = Vec::new();
+        write!(buf, "{} {} {}\r\n", self.version, self.code, self.reason)
+            .unwrap();
+        for &(ref name, ref value) in &self.headers {
+            write!(buf, "{}: ", name).unwrap();
+            buf.extend_from_slice(value);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        let mut headers = vec![EMPTY_HEADER; self.headers.len()];
+        let mut raw = Response::new(&mut headers);
+        match raw.parse(&buf).map_err(ErrorEnum::Header)? {
+            // a value containing an empty line ends head early
+            httparse::Status::Complete(n) if n == buf.len() => {}
+            _ => return Err(ErrorEnum::Header(
+                httparse::Error::HeaderValue).into()),
+        }
+        with_head(&raw, Some(&buf), self.is_head, &self.config,
+                  ConnectionId::next(), &self.extensions, f)
+            .map(|(_, _, result)| result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Status, Version};
+    use super::HeadBuilder;
+
+    #[test]
+    fn head() {
+        HeadBuilder::new(503, "Service Unavailable")
+            .version(Version::Http10)
+            .header("Retry-After", "120")
+            .header("Connection", "X-Hop")
+            .header("X-Hop", "1")
+            .header("X-A", "b")
+            .with_head(|head| {
+                assert_eq!(head.status(), Some(Status::ServiceUnavailable));
+                assert_eq!(head.version(), Version::Http10);
+                assert_eq!(head.retry_after().unwrap().as_secs(), 120);
+                assert_eq!(head.headers().collect::<Vec<_>>(), vec![
+                    ("Retry-After", &b"120"[..]),
+                    ("X-A", &b"b"[..]),
+                ]);
+                assert!(head.raw_head().unwrap()
+                    .starts_with(b"HTTP/1.0 503 "));
+                Ok(())
+            }).unwrap();
+    }
+
+    #[test]
+    fn invalid() {
+        let mut builder = HeadBuilder::new(200, "OK");
+        builder.header("Content-Length", "1")
+            .header("Content-Length", "2");
+        assert!(builder.with_head(|_| Ok(())).is_err());
+        builder.response_to_head(true).with_head(|_| Ok(())).unwrap();
+    }
+
+    #[test]
+    fn strict() {
+        let mut builder = HeadBuilder::new(200, "OK");
+        builder.header("Content-Length", "+1");
+        builder.with_head(|_| Ok(())).unwrap();
+        let err = builder.strict_response_headers(true)
+            .with_head(|_| Ok(())).unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(BadContentLength)");
+        let err = HeadBuilder::new(200, &"x".repeat(300))
+            .strict_response_headers(true)
+            .with_head(|_| Ok(())).unwrap_err();
+        assert_eq!(format!("{}", err),
+                   "reason phrase of 300 bytes is too long");
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use rand::{Rng, thread_rng};
+
+
+/// Classification of a request with regards to retries
+///
+/// Connection pools and retry layers should only resend requests that
+/// are `Safe` or `Idempotent` unless application has explicitly made
+/// the request idempotent by other means (e.g. an `Idempotency-Key`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Idempotency {
+    /// Request has no side-effects (`GET`, `HEAD`, `OPTIONS`, `TRACE`)
+    Safe,
+    /// Request may be repeated with the same effect (`PUT`, `DELETE`)
+    Idempotent,
+    /// Request must not be retried automatically (`POST`, `PATCH`, ...)
+    NonRetriable,
+    /// Codec doesn't classify the request (see `Codec::idempotency`)
+    ///
+    /// Such requests are not retried, but other requests are pipelined
+    /// after them, as the protocol doesn't know better.
+    Unknown,
+}
+
+/// The `Idempotency-Key` header value
+///
+/// Generate a key once per logical request and send the same key on every
+/// attempt, so the server can deduplicate retried requests. The header is
+/// added when `Codec::idempotency_key` returns a key (see
+/// `Buffered::set_idempotency_key`), or you can add it using
+/// `enc.format_header("Idempotency-Key", key)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey([u8; 16]);
+
+impl Idempotency {
+    /// Classify request by method as defined in RFC7231 (section 4.2)
+    ///
+    /// Unknown methods are treated as `NonRetriable`
+    pub fn from_method(method: &str) -> Idempotency {
+        match method {
+            "GET" | "HEAD" | "OPTIONS" | "TRACE" => Idempotency::Safe,
+            "PUT" | "DELETE" => Idempotency::Idempotent,
+            _ => Idempotency::NonRetriable,
+        }
+    }
+    /// Returns `true` if request might be safely sent again
+    pub fn is_retriable(&self) -> bool {
+        matches!(*self, Idempotency::Safe | Idempotency::Idempotent)
+    }
+    /// Returns `true` if other requests may be pipelined after this one
+    ///
+    /// Only `NonRetriable` requests are not pipelined after.
+    pub fn allows_pipelining(&self) -> bool {
+        *self != Idempotency::NonRetriable
+    }
+}
+
+impl IdempotencyKey {
+    /// Create a new (random) key
+    pub fn new() -> IdempotencyKey {
+        let mut key = [0u8; 16];
+        thread_rng().fill_bytes(&mut key);
+        // Format as a version 4 UUID
+        key[6] = (key[6] & 0x0f) | 0x40;
+        key[8] = (key[8] & 0x3f) | 0x80;
+        return IdempotencyKey(key);
+    }
+}
+
+impl fmt::Display for IdempotencyKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Idempotency, IdempotencyKey};
+
+    #[test]
+    fn methods() {
+        assert_eq!(Idempotency::from_method("GET"), Idempotency::Safe);
+        assert_eq!(Idempotency::from_method("PUT"), Idempotency::Idempotent);
+        assert_eq!(Idempotency::from_method("POST"),
+                   Idempotency::NonRetriable);
+        assert!(!Idempotency::from_method("PATCH").is_retriable());
+        assert!(!Idempotency::Unknown.is_retriable());
+        assert!(Idempotency::Unknown.allows_pipelining());
+        assert!(!Idempotency::NonRetriable.allows_pipelining());
+    }
+
+    #[test]
+    fn key_format() {
+        let key = IdempotencyKey::new().to_string();
+        assert_eq!(key.len(), 36);
+        assert_eq!(&key[14..15], "4");
+        assert_ne!(IdempotencyKey::new(), IdempotencyKey::new());
+    }
+}
@@ -1,27 +1,55 @@
 //! The HTTP/1.x client protocol implementation
 //!
+mod cancel;
 mod client;
 mod config;
+mod connection_info;
+mod content_coding;
 mod encoder;
 mod errors;
+mod extensions;
+mod factory;
 mod head;
+mod head_builder;
+mod idempotency;
+mod oneshot;
 mod parser;
 mod proto;
-mod recv_mode;
+mod registry;
+mod retry_after;
+mod stats;
+mod url_target;
+#[cfg(feature="compression")] mod decompress;
 pub mod buffered;
 
 pub use self::errors::Error;
+pub use self::extensions::Extensions;
 pub use self::client::{Client, Codec};
-pub use self::encoder::{Encoder, EncoderDone, WaitFlush};
+pub use self::factory::{CodecFactory, ErasedCodec, BoxCodec, BoxFuture, erase};
+pub use self::encoder::{Encoder, EncoderDone, WaitFlush, WriteBody};
 pub use self::proto::{Proto};
+pub use self::head_builder::HeadBuilder;
+pub use self::registry::ConfigRegistry;
+pub use self::cancel::{Cancelable, CancelHandle};
+pub use self::idempotency::{Idempotency, IdempotencyKey};
+pub use self::stats::{RequestStats, RequestObserver};
+pub use self::connection_info::ConnectionInfo;
+pub use self::oneshot::{get, get_with, ResponseFuture, MAX_REDIRECTS};
+pub use recv_mode::RecvMode;
+pub use headers::ConnectionTokens;
+#[cfg(feature="fuzz")]
+pub(crate) use self::parser::parse_response_head;
+pub(crate) use self::errors::ErrorEnum;
 
-use std::borrow::Cow;
 use std::time::Duration;
 
 use httparse::Header;
 
 use self::client::BodyKind;
-use {Version};
+use self::stats::ObserverRef;
+use base_serializer::HeaderLimits;
+use headers::ConnectionHeaders;
+use {Version, ConnectionId};
 
 /// Fine-grained configuration of the HTTP connection
 #[derive(Debug, Clone)]
@@ -31,6 +59,15 @@ pub struct Config {
     keep_alive_timeout: Duration,
     safe_pipeline_timeout: Duration,
     max_request_timeout: Duration,
+    connect_timeout: Duration,
+    observer: Option<ObserverRef>,
+    max_chunk_size: Option<u64>,
+    require_body_length: bool,
+    max_interim_responses: usize,
+    strict_response_headers: bool,
+    header_limits: HeaderLimits,
+    decompress: bool,
+    raw_head: bool,
 }
 
 /// A borrowed structure that represents response headers
@@ -45,23 +82,9 @@ pub struct Head<'a> {
     reason: &'a str,
     headers: &'a [Header<'a>],
     body_kind: BodyKind,
-    connection_header: Option<Cow<'a, str>>,
+    connection: ConnectionHeaders,
     connection_close: bool,
-}
-
-/// This type is returned from `headers_received` handler of either
-/// client client or server protocol handler
-///
-/// The marker is used to denote whether you want to have the whole request
-/// buffered for you or read chunk by chunk.
-///
-/// The `Progressive` (chunk by chunk) mode is mostly useful for proxy servers.
-/// Or it may be useful if your handler is able to parse data without holding
-/// everything in the memory.
-///
-/// Otherwise, it's best to use `Buffered` mode (for example, comparing with
-/// using your own buffering). We do our best to optimize it for you.
-#[derive(Debug, Clone)]
-pub struct RecvMode {
-    mode: recv_mode::Mode,
+    raw: Option<&'a [u8]>,
+    connection_id: ConnectionId,
+    extensions: &'a Extensions,
 }
@@ -0,0 +1,279 @@
+//! A one-shot client: resolve, connect, fetch, follow redirects
+//!
+//! This is an on-ramp API for simple cases and scripts. It opens a new
+//! connection for every request (including redirects) and buffers the whole
+//! response in memory. Use `Proto` with a connection pool for anything
+//! serious.
+//!
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use futures::{Future, Sink};
+use futures::future::{ok, err};
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use url::Url;
+
+use enums::Status;
+use client::{Config, ConfigRegistry, Proto, Error};
+use client::buffered::{Buffered, Response};
+use client::errors::ErrorEnum;
+use client::proto::connect;
+use client::url_target;
+
+/// Maximum number of redirects followed by `get`
+pub const MAX_REDIRECTS: usize = 5;
+
+/// A boxed future returned by `get`
+pub type ResponseFuture = Box<Future<Item=Response, Error=Error>>;
+
+/// Fetch a url using `GET` method
+///
+/// This function resolves host name (in a separate thread, as resolver
+/// in the standard library is blocking), connects to the host (using TLS
+/// for `https://` urls if `tls` feature is enabled), sends request and
+/// follows up to `MAX_REDIRECTS` redirects.
+///
+/// Default timeouts are: `Config::connect_timeout` (10 seconds) for
+/// establishing connection and `Config::max_request_timeout` (15 seconds)
+/// for each request. Response
+/// is limited to 10 MiB.
+pub fn get(url: Url, handle: &Handle) -> ResponseFuture {
+    get_with(url, &ConfigRegistry::new(&Config::new().done()).done(), handle)
+}
+
+/// Same as `get` but uses connection config for each host from registry
+///
+/// Config is looked up for every redirect target separately.
+pub fn get_with(url: Url, registry: &Arc<ConfigRegistry>, handle: &Handle)
+    -> ResponseFuture
+{
+    fetch(url, registry.clone(), handle.clone(), MAX_REDIRECTS)
+}
+
+fn fetch(url: Url, registry: Arc<ConfigRegistry>, handle: Handle,
+    redirects_left: usize)
+    -> ResponseFuture
+{
+    let req = request(url.clone(), &registry, &handle);
+    Box::new(req.and_then(move |response| -> ResponseFuture {
+        match redirect_location(&url, &response) {
+            Some(Ok(next)) if redirects_left > 0 => {
+                debug!("Redirected from {} to {}", url, next);
+                fetch(next, registry, handle, redirects_left - 1)
+            }
+            Some(Ok(_)) => Box::new(err(ErrorEnum::TooManyRedirects.into())),
+            Some(Err(e)) => Box::new(err(e)),
+            None => Box::new(ok(response)),
+        }
+    }))
+}
+
+fn redirect_location(url: &Url, response: &Response)
+    -> Option<Result<Url, Error>>
+{
+    match response.status() {
+        Status::MovedPermanently | Status::Found | Status::SeeOther |
+        Status::TemporaryRedirect | Status::PermanentRedirect => {}
+        _ => return None,
+    }
+    let location = response.headers().iter()
+        .find(|&&(ref name, _)| name.eq_ignore_ascii_case("Location"))
+        .map(|&(_, ref value)| value);
+    let location = match location {
+        Some(value) => value,
+        // a redirect without location is just a response
+        None => return None,
+    };
+    Some(::std::str::from_utf8(location)
+        .map_err(|_| ErrorEnum::InvalidUrl.into())
+        .and_then(|x| url.join(x.trim())
+            .map_err(|e| ErrorEnum::UrlParse(e).into())))
+}
+
+fn resolve(host: String, port: u16)
+    -> Box<Future<Item=SocketAddr, Error=Error>>
+{
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let result = (&host[..], port).to_socket_addrs()
+            .and_then(|mut addrs| addrs.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound,
+                    format!("no addresses found for {:?}", host))
+            }));
+        tx.send(result).ok();
+    });
+    Box::new(rx
+        .map_err(|_| Error::from(ErrorEnum::Canceled))
+        .and_then(|res| res.map_err(|e| ErrorEnum::Io(e).into())))
+}
+
+fn send<S>(sock: S, url: Url, cfg: &Arc<Config>, handle: &Handle)
+    -> ResponseFuture
+    where S: AsyncRead + AsyncWrite + 'static,
+{
+    let (codec, receiver) = Buffered::get(url);
+    let proto = Proto::new(sock, handle, cfg);
+    Box::new(proto.send(codec)
+        // connection is closed right after the response is received if
+        // server sends `Connection: close`, this is fine
+        .then(|res| match res {
+            Err(ref e) if e.is_graceful() => Ok(()),
+            res => res.map(|_proto| ()),
+        })
+        .join(receiver.map_err(|_| Error::from(ErrorEnum::Canceled)))
+        .and_then(|((), result)| result))
+}
+
+#[cfg(feature="tls")]
+fn send_tls(sock: TcpStream, url: Url, cfg: &Arc<Config>, handle: &Handle)
+    -> ResponseFuture
+{
+    use native_tls::TlsConnector;
+    use tokio_tls::TlsConnectorExt;
+
+    let connector = match TlsConnector::builder().and_then(|b| b.build()) {
+        Ok(connector) => connector,
+        Err(e) => return Box::new(err(ErrorEnum::Tls(e).into())),
+    };
+    let host = url.host_str().expect("host is checked").to_string();
+    let cfg = cfg.clone();
+    let handle = handle.clone();
+    Box::new(connector.connect_async(&host, sock)
+        .map_err(|e| Error::from(ErrorEnum::Tls(e)))
+        .and_then(move |sock| send(sock, url, &cfg, &handle)))
+}
+
+#[cfg(not(feature="tls"))]
+fn send_tls(_sock: TcpStream, _url: Url, _cfg: &Arc<Config>,
+    _handle: &Handle)
+    -> ResponseFuture
+{
+    unreachable!();
+}
+
+fn request(url: Url, registry: &ConfigRegistry, handle: &Handle)
+    -> ResponseFuture
+{
+    if let Err(e) = url_target::validate(&url) {
+        return Box::new(err(e.into()));
+    }
+    let tls = match url.scheme() {
+        "https" if cfg!(feature="tls") => true,
+        "https" => return Box::new(err(ErrorEnum::UnsupportedScheme.into())),
+        _ => false,
+    };
+    let host = url.host_str().expect("host is checked").to_string();
+    let port = url.port_or_known_default().expect("scheme is checked");
+    let cfg = registry.get(&host, port).clone();
+    let handle = handle.clone();
+    Box::new(resolve(host, port)
+        .and_then({
+            let handle = handle.clone();
+            let timeout = cfg.connect_timeout;
+            move |addr| connect(addr, timeout, &handle)
+        })
+        .and_then(move |sock| if tls {
+            send_tls(sock, url, &cfg, &handle)
+        } else {
+            send(sock, url, &cfg, &handle)
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use futures::Future;
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+    use url::Url;
+
+    use client::{Codec, HeadBuilder};
+    use client::buffered::{Buffered, Response};
+    use super::{get, redirect_location, MAX_REDIRECTS};
+
+    fn response(code: u16, location: Option<&str>) -> Response {
+        let (mut codec, rx) = Buffered::get(
+            "http://example.com/".parse().unwrap());
+        let mut head = HeadBuilder::new(code, "Redirect");
+        if let Some(value) = location {
+            head.header("Location", value);
+        }
+        head.dispatch::<TcpStream, _>(&mut codec).unwrap();
+        Codec::<TcpStream>::data_received(&mut codec, b"", true).unwrap();
+        rx.wait().unwrap().unwrap()
+    }
+
+    fn location(code: u16, value: Option<&str>) -> Option<String> {
+        let url: Url = "http://example.com/a/b?c".parse().unwrap();
+        redirect_location(&url, &response(code, value))
+            .map(|x| x.unwrap().to_string())
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(location(302, Some("d")).unwrap(),
+                   "http://example.com/a/d");
+        assert_eq!(location(301, Some("/d?e")).unwrap(),
+                   "http://example.com/d?e");
+        assert_eq!(location(303, Some("//example.org/")).unwrap(),
+                   "http://example.org/");
+    }
+
+    #[test]
+    fn absolute() {
+        assert_eq!(location(307, Some(" https://example.org/x ")).unwrap(),
+                   "https://example.org/x");
+        assert_eq!(location(308, Some("http://example.com:8080/")).unwrap(),
+                   "http://example.com:8080/");
+    }
+
+    #[test]
+    fn not_redirect() {
+        assert_eq!(location(302, None), None);
+        assert_eq!(location(200, Some("/d")), None);
+        assert_eq!(location(304, Some("/d")), None);
+        let url: Url = "http://example.com/".parse().unwrap();
+        assert!(redirect_location(&url,
+            &response(302, Some("http://[::1"))).unwrap().is_err());
+    }
+
+    #[test]
+    fn max_redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = 0;
+            for sock in listener.incoming().take(MAX_REDIRECTS + 1) {
+                let mut sock = sock.unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 1024];
+                while !buf.ends_with(b"\r\n\r\n") {
+                    let n = sock.read(&mut chunk).unwrap();
+                    assert!(n > 0);
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                sock.write_all(b"HTTP/1.1 302 Found\r\n\
+                    Location: /next\r\n\
+                    Content-Length: 0\r\n\
+                    Connection: close\r\n\r\n").unwrap();
+                requests += 1;
+            }
+            requests
+        });
+        let mut core = Core::new().unwrap();
+        let url = format!("http://{}/", addr).parse().unwrap();
+        let err = core.run(get(url, &core.handle())).unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(TooManyRedirects)");
+        assert_eq!(server.join().unwrap(), MAX_REDIRECTS + 1);
+    }
+}
@@ -1,7 +1,8 @@
+use std::io;
 use std::sync::Arc;
-use std::borrow::Cow;
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 use std::str::from_utf8;
+use std::time::{Duration, Instant};
 #[allow(unused_imports)]
 use std::ascii::AsciiExt;
 
@@ -13,18 +14,26 @@ use tokio_io::AsyncRead;
 use enums::Version;
 use client::client::{BodyKind};
 use client::errors::ErrorEnum;
-use client::recv_mode::Mode;
-use headers;
+use recv_mode::{Mode, get_mode, get_timeout};
+use headers::{self, ConnectionHeaders};
 use chunked;
 use body_parser::BodyProgress;
 use client::encoder::RequestState;
-use client::{Codec, Error, Head};
+use client::{Codec, Config, Error, Head, Extensions};
+use client::content_coding::{Coding, response_coding};
+#[cfg(feature="compression")] use client::decompress::Decompressor;
+use client::stats::{self, RequestStats};
+use spans::Span;
+use timer::Timer;
+use ConnectionId;
 
 
 /// Number of headers to allocate on a stack
 const MIN_HEADERS: usize = 16;
 /// A hard limit on the number of headers
 const MAX_HEADERS: usize = 1024;
+/// Maximum length of reason phrase in strict mode
+const MAX_REASON_LENGTH: usize = 256;
 
 
 #[derive(Debug, Clone)]
@@ -36,19 +45,45 @@ enum State {
     Body {
         mode: Mode,
         progress: BodyProgress,
+        #[cfg_attr(not(feature="compression"), allow(dead_code))]
+        coding: Option<Coding>,
     },
 }
 
-pub struct Parser<S, C: Codec<S>> {
+pub struct Parser<S, C: Codec<S>, T> {
     io: Option<ReadBuf<S>>,
     codec: C,
     close: bool,
     state: State,
+    request_bytes: Arc<AtomicUsize>,
+    config: Arc<Config>,
+    #[cfg(feature="compression")]
+    decoder: Option<Decompressor>,
+    /// Number of interim (1xx) responses skipped
+    interim: usize,
+    connection_id: ConnectionId,
+    status: u16,
+    head_bytes: usize,
+    body_bytes: u64,
+    deadline: Option<Instant>,
+    timer: T,
+    extensions: Extensions,
+    reset: bool,
+    span: Span,
 }
 
 
-fn scan_headers<'x>(is_head: bool, code: u16, headers: &'x [httparse::Header])
-    -> Result<(BodyKind, Option<Cow<'x, str>>, bool), ErrorEnum>
+/// Returns `true` if header value contains control characters
+///
+/// Only horizontal tab is allowed. Parser doesn't allow line breaks and
+/// zero bytes already, but we don't want to depend on that in strict mode.
+fn invalid_value(value: &[u8]) -> bool {
+    value.iter().any(|&c| (c < b' ' && c != b'\t') || c == 0x7f)
+}
+
+fn scan_headers(is_head: bool, code: u16, headers: &[httparse::Header],
+    strict: bool)
+    -> Result<(BodyKind, ConnectionHeaders, bool), ErrorEnum>
 {
     /// Implements the body length algorithm for requests:
     /// http://httpwg.github.io/specs/rfc7230.html#message.body.length
@@ -62,31 +97,33 @@ fn scan_headers<'x>(is_head: bool, code: u16, headers: &'x [httparse::Header])
     use client::client::BodyKind::*;
     use client::errors::ErrorEnum::ConnectionInvalid;
     let mut has_content_length = false;
-    let mut connection = None::<Cow<_>>;
+    let mut connection = ConnectionHeaders::new();
     let mut close = false;
-    if is_head || (code > 100 && code < 200) || code == 204 || code == 304 {
-        for header in headers.iter() {
-            // TODO(tailhook) check for transfer encoding and content-length
-            if header.name.eq_ignore_ascii_case("Connection") {
-                let strconn = from_utf8(header.value)
-                    .map_err(|_| ConnectionInvalid)?.trim();
-                connection = match connection {
-                    Some(x) => Some(x + ", " + strconn),
-                    None => Some(strconn.into()),
-                };
-                if header.value.split(|&x| x == b',').any(headers::is_close) {
-                    close = true;
-                }
-            }
+    // Some servers send Content-Length or even Transfer-Encoding for
+    // these responses, but there is no body anyway, so the headers are
+    // ignored (reading a body would desync keep-alive connection)
+    let bodyless = is_head || (code >= 100 && code < 200) ||
+        code == 204 || code == 304;
+    let mut result = if bodyless { Fixed(0) } else { Eof };
+    for (idx, header) in headers.iter().enumerate() {
+        if strict && invalid_value(header.value) {
+            return Err(ErrorEnum::InvalidHeaderValue(header.name.to_string()));
         }
-        return Ok((Fixed(0), connection, close))
-    }
-    let mut result = BodyKind::Eof;
-    for header in headers.iter() {
-        if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+        if header.name.eq_ignore_ascii_case("Connection") {
+            from_utf8(header.value).map_err(|_| ConnectionInvalid)?;
+            connection.add(idx);
+            if header.value.split(|&x| x == b',').any(headers::is_close) {
+                close = true;
+            }
+        } else if bodyless {
+            continue;
+        } else if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
             if let Some(enc) = header.value.split(|&x| x == b',').last() {
                 if headers::is_chunked(enc) {
                     if has_content_length {
+                        if strict {
+                            return Err(ErrorEnum::ConflictingBodyLength);
+                        }
                         // override but don't allow keep-alive
                         close = true;
                     }
@@ -99,57 +136,129 @@ fn scan_headers<'x>(is_head: bool, code: u16, headers: &'x [httparse::Header])
                 return Err(ErrorEnum::DuplicateContentLength);
             }
             has_content_length = true;
+            if strict && (header.value.is_empty() ||
+                !header.value.iter().all(|c| c.is_ascii_digit()))
+            {
+                return Err(ErrorEnum::BadContentLength);
+            }
             if result != Chunked {
                 let s = from_utf8(header.value)
                     .map_err(|_| ErrorEnum::BadContentLength)?;
                 let len = s.parse()
                     .map_err(|_| ErrorEnum::BadContentLength)?;
                 result = Fixed(len);
+            } else if strict {
+                return Err(ErrorEnum::ConflictingBodyLength);
             } else {
                 // tralsfer-encoding has preference and don't allow keep-alive
                 close = true;
             }
-        } else if header.name.eq_ignore_ascii_case("Connection") {
-            let strconn = from_utf8(header.value)
-                .map_err(|_| ConnectionInvalid)?.trim();
-            connection = match connection {
-                Some(x) => Some(x + ", " + strconn),
-                None => Some(strconn.into()),
-            };
-            if header.value.split(|&x| x == b',').any(headers::is_close) {
-                close = true;
-            }
         }
     }
     Ok((result, connection, close))
 }
 
-fn new_body(mode: BodyKind, recv_mode: Mode)
+fn new_body(mode: BodyKind, recv_mode: Mode, max_chunk_size: Option<u64>)
     -> Result<BodyProgress, ErrorEnum>
 {
     use super::client::BodyKind as B;
-    use super::recv_mode::Mode as M;
+    use recv_mode::Mode as M;
     use client::errors::ErrorEnum::*;
     use body_parser::BodyProgress as P;
     match (mode, recv_mode) {
         // TODO(tailhook) check size < usize
-        (B::Fixed(x), M::Buffered(b)) if x > b as u64 => {
+        (_, M::Hijack) => Err(HijackUnsupported),
+        (_, M::Reject) => Err(RejectUnsupported),
+        (B::Fixed(x), M::BufferedUpfront(b)) if x > b as u64 => {
             Err(ResponseBodyTooLong)
         }
         (B::Fixed(x), _)  => Ok(P::Fixed(x as usize)),
-        (B::Chunked, _) => Ok(P::Chunked(chunked::State::new())),
+        (B::Chunked, _) => {
+            Ok(P::Chunked(chunked::State::with_limit(max_chunk_size)))
+        }
         (B::Eof, _) => Ok(P::Eof),
     }
 }
 
+/// Parse response headers from the buffer without a connection
+///
+/// Returns status code if headers are complete.
+#[cfg(feature="fuzz")]
+pub(crate) fn parse_response_head<S, C: Codec<S>>(buffer: &mut Buf,
+    codec: &mut C, is_head: bool)
+    -> Result<Option<u16>, Error>
+{
+    parse_headers(buffer, codec, is_head, &Config::new(), &mut 0,
+                  ConnectionId::next(), &Extensions::new(), &Span::none())
+        .map(|x| x.map(|(_, _, code, _, _)| code))
+}
+
+/// Returns version and status code of a complete response head
+fn status_line(raw: &httparse::Response) -> Result<(u8, u16), Error> {
+    // httparse always sets these for complete responses, but peer data
+    // must never make us panic
+    match (raw.version, raw.code) {
+        (Some(ver), Some(code)) => Ok((ver, code)),
+        _ => Err(ErrorEnum::Header(httparse::Error::Status).into()),
+    }
+}
+
+/// Validate parsed response headers and call a function with the `Head`
+///
+/// `raw_bytes` are the bytes of the head exposed by `Head::raw_head`.
+///
+/// Returns body kind and whether connection is closed after the response
+/// along with the result of the function.
+pub(crate) fn with_head<F, R>(raw: &httparse::Response,
+    raw_bytes: Option<&[u8]>, is_head: bool, config: &Config,
+    connection_id: ConnectionId, extensions: &Extensions, f: F)
+    -> Result<(BodyKind, bool, R), Error>
+    where F: FnOnce(&Head) -> Result<R, Error>,
+{
+    let (ver, code) = status_line(raw)?;
+    let reason = raw.reason.unwrap_or("");
+    let strict = config.strict_response_headers;
+    if strict && reason.len() > MAX_REASON_LENGTH {
+        return Err(ErrorEnum::ReasonTooLong(reason.len()).into());
+    }
+    let (body, conn, close) = try!(scan_headers(is_head, code, raw.headers,
+                                                strict));
+    let head = Head {
+        version: if ver == 1 { Version::Http11 } else { Version::Http10 },
+        code: code,
+        reason: reason,
+        headers: raw.headers,
+        body_kind: body,
+        connection: conn,
+        // For HTTP/1.0 we could implement Connection: Keep-Alive
+        // but hopefully it's rare enough to ignore nowadays
+        connection_close: close || ver == 0,
+        raw: raw_bytes,
+        connection_id: connection_id,
+        extensions: extensions,
+    };
+    let result = f(&head)?;
+    Ok((body, close, result))
+}
+
+/// Interim responses are never expected as the client doesn't send
+/// `Expect: 100-continue`, but some servers send them anyway
+///
+/// `101 Switching Protocols` is a final response.
+fn is_interim(code: u16) -> bool {
+    code >= 100 && code < 200 && code != 101
+}
+
 fn parse_headers<S, C: Codec<S>>(
-    buffer: &mut Buf, codec: &mut C, is_head: bool)
-    -> Result<Option<(State, bool)>, Error>
+    buffer: &mut Buf, codec: &mut C, is_head: bool, config: &Config,
+    interim: &mut usize, connection_id: ConnectionId,
+    extensions: &Extensions, span: &Span)
+    -> Result<Option<(State, bool, u16, usize, Option<Duration>)>, Error>
 {
-    let (mode, body, close, bytes) = {
-        let mut vec;
-        let mut headers = [httparse::EMPTY_HEADER; MIN_HEADERS];
-        let (ver, code, reason, headers, bytes) = {
+    loop {
+        let (bytes, head) = {
+            let mut vec;
+            let mut headers = [httparse::EMPTY_HEADER; MIN_HEADERS];
             let mut raw = httparse::Response::new(&mut headers);
             let mut result = raw.parse(&buffer[..]);
             if matches!(result, Err(httparse::Error::TooManyHeaders)) {
@@ -157,45 +266,128 @@ fn parse_headers<S, C: Codec<S>>(
                 raw = httparse::Response::new(&mut vec);
                 result = raw.parse(&buffer[..]);
             }
-            match result.map_err(ErrorEnum::Header)? {
-                httparse::Status::Complete(bytes) => {
-                    let ver = raw.version.unwrap();
-                    let code = raw.code.unwrap();
-                    (ver, code, raw.reason.unwrap(), raw.headers, bytes)
-                }
+            let bytes = match result.map_err(ErrorEnum::Header)? {
+                httparse::Status::Complete(bytes) => bytes,
                 _ => return Ok(None),
+            };
+            let (_, code) = status_line(&raw)?;
+            if is_interim(code) {
+                debug!("{}: skipping interim response {} {:?}",
+                    connection_id, code, raw.reason.unwrap_or(""));
+                (bytes, None)
+            } else {
+                let raw_bytes = if config.raw_head {
+                    Some(&buffer[..bytes])
+                } else {
+                    None
+                };
+                let (body, close, (mode, coding)) = with_head(&raw,
+                    raw_bytes, is_head, config, connection_id, extensions,
+                    |head| {
+                        if config.require_body_length &&
+                            head.body_kind == BodyKind::Eof
+                        {
+                            return Err(ErrorEnum::BodyLengthRequired.into());
+                        }
+                        span.record_status(code);
+                        let coding = if config.decompress {
+                            response_coding(head)
+                        } else {
+                            None
+                        };
+                        let mode = span.in_scope(
+                            || codec.headers_received(head))?;
+                        Ok((mode, coding))
+                    })?;
+                (bytes, Some((mode, coding, body, close, code)))
             }
         };
-        let (body, conn, close) = try!(scan_headers(is_head, code, &headers));
-        let head = Head {
-            version: if ver == 1
-                { Version::Http11 } else { Version::Http10 },
-            code: code,
-            reason: reason,
-            headers: headers,
-            body_kind: body,
-            connection_header: conn,
-            // For HTTP/1.0 we could implement Connection: Keep-Alive
-            // but hopefully it's rare enough to ignore nowadays
-            connection_close: close || ver == 0,
+        buffer.consume(bytes);
+        let (mode, coding, body, close, code) = match head {
+            Some(head) => head,
+            None => {
+                *interim += 1;
+                if *interim > config.max_interim_responses {
+                    return Err(ErrorEnum::TooManyInterimResponses.into());
+                }
+                continue;
+            }
         };
-        let mode = codec.headers_received(&head)?;
-        (mode, body, close, bytes)
+        return Ok(Some((
+            State::Body {
+                mode: get_mode(&mode),
+                progress: new_body(body, get_mode(&mode),
+                                   config.max_chunk_size)?,
+                coding: coding,
+            },
+            close,
+            code,
+            bytes,
+            get_timeout(&mode),
+        )));
+    }
+}
+
+/// Decompress received body and pass it to the codec
+///
+/// Returns `true` when the whole body is received and consumed by the
+/// codec, and `false` when more data should be read.
+#[cfg(feature="compression")]
+fn decompress_body<S, C: Codec<S>>(decoder: &mut Decompressor,
+    codec: &mut C, span: &Span, io: &mut ReadBuf<S>,
+    progress: &mut BodyProgress, mode: &Mode, bytes: usize, done: bool,
+    body_bytes: &mut u64)
+    -> Poll<bool, Error>
+{
+    use recv_mode::Mode::*;
+    decoder.write(&io.in_buf[..bytes])?;
+    progress.consume(io, bytes);
+    *body_bytes += bytes as u64;
+    if done {
+        decoder.finish()?;
+    }
+    let len = decoder.data().len();
+    let operation = if done {
+        Some(span.in_scope(|| codec.data_received(decoder.data(), true))?)
+    } else if io.done() {
+        return Err(ErrorEnum::ResetOnResponseBody.into());
+    } else if matches!(*mode, Progressive(x) if x <= len) {
+        Some(span.in_scope(|| codec.data_received(decoder.data(), false))?)
+    } else {
+        None
     };
-    buffer.consume(bytes);
-    Ok(Some((
-        State::Body {
-            mode: mode.mode,
-            progress: new_body(body, mode.mode)?,
-        },
-        close,
-    )))
+    match operation {
+        Some(Async::Ready(consumed)) => {
+            decoder.consume(consumed);
+            if done && consumed == len {
+                return Ok(Async::Ready(true));
+            }
+        }
+        Some(Async::NotReady) => {
+            if matches!(*mode, Progressive(x) if x > len) {
+                return Ok(Async::NotReady);
+            }
+        }
+        None => {} // Read more
+    }
+    Ok(Async::Ready(false))
+}
+
+fn is_reset(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionReset => true,
+        io::ErrorKind::ConnectionAborted => true,
+        _ => false,
+    }
 }
 
-impl<S, C: Codec<S>> Parser<S, C> {
+impl<S, C: Codec<S>, T: Timer> Parser<S, C, T> {
     pub fn new(io: ReadBuf<S>, codec: C,
-        request_state: Arc<AtomicUsize>, close_signal: Arc<AtomicBool>)
-        -> Parser<S, C>
+        request_state: Arc<AtomicUsize>, close_signal: Arc<AtomicBool>,
+        request_bytes: Arc<AtomicUsize>, config: &Arc<Config>,
+        connection_id: ConnectionId, timer: T, extensions: Extensions,
+        span: Span)
+        -> Parser<S, C, T>
     {
         Parser {
             io: Some(io),
@@ -205,13 +397,43 @@ impl<S, C: Codec<S>> Parser<S, C> {
                 request_state: request_state,
                 close_signal: close_signal,
             },
+            request_bytes: request_bytes,
+            config: config.clone(),
+            #[cfg(feature="compression")]
+            decoder: None,
+            interim: 0,
+            connection_id: connection_id,
+            status: 0,
+            head_bytes: 0,
+            body_bytes: 0,
+            deadline: None,
+            timer: timer,
+            extensions: extensions,
+            reset: false,
+            span: span,
         }
     }
+    pub fn is_canceled(&self) -> bool {
+        self.codec.is_canceled()
+    }
+    /// Deadline for reading response body if set by `RecvMode::with_timeout`
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    /// Statistics of the request
+    ///
+    /// When response isn't fully received only the part received is counted
+    pub fn stats(&self, duration: Duration) -> RequestStats {
+        stats::new(self.status,
+            self.request_bytes.load(Ordering::SeqCst) as u64,
+            self.head_bytes as u64, self.body_bytes, duration, !self.reset,
+            self.close)
+    }
     fn read_and_parse(&mut self) -> Poll<(), Error>
         where S: AsyncRead
     {
         use self::State::*;
-        use client::recv_mode::Mode::*;
+        use recv_mode::Mode::*;
         let mut io = self.io.as_mut().expect("buffer is still here");
         self.state = if let Headers {
                 ref request_state,
@@ -219,26 +441,54 @@ impl<S, C: Codec<S>> Parser<S, C> {
             } = self.state
         {
             let state;
+            // Pipelined response may already be in the buffer (read
+            // along with the previous one), so try to parse it first
+            let mut buffered = io.in_buf.len() > 0;
             loop {
-                if io.read().map_err(ErrorEnum::Io)? == 0 {
+                if !buffered && io.read().map_err(ErrorEnum::Io)? == 0 {
                     if io.done() {
                         return Err(ErrorEnum::ResetOnResponseHeaders.into());
                     } else {
                         return Ok(Async::NotReady);
                     }
                 }
+                buffered = false;
                 let reqs = request_state.load(Ordering::SeqCst);
                 if reqs == RequestState::Empty as usize {
                     return Err(ErrorEnum::PrematureResponseHeaders.into());
                 }
                 let is_head = reqs == RequestState::StartedHead as usize;
-                match parse_headers(&mut io.in_buf, &mut self.codec, is_head)? {
+                match parse_headers(&mut io.in_buf, &mut self.codec,
+                                    is_head, &self.config,
+                                    &mut self.interim, self.connection_id,
+                                    &self.extensions, &self.span)?
+                {
                     None => continue,
-                    Some((body, close)) => {
+                    Some((body, close, status, head_bytes, timeout)) => {
+                        trace!("{}: response headers received, status {}",
+                            self.connection_id, status);
+                        self.status = status;
+                        self.head_bytes = head_bytes;
+                        let now = self.timer.now();
+                        self.deadline = timeout.map(|t| now + t);
                         if close {
                             close_signal.store(true, Ordering::SeqCst);
                             self.close = true;
                         }
+                        #[cfg(feature="compression")] {
+                            if let Body { ref mode, coding: Some(c), .. } =
+                                body
+                            {
+                                // in buffered mode the limit applies to
+                                // decompressed data
+                                let limit = match *mode {
+                                    Mode::BufferedUpfront(x) => Some(x),
+                                    _ => None,
+                                };
+                                self.decoder = Some(
+                                    Decompressor::new(c, limit));
+                            }
+                        }
                         state = body;
                         break
                     },
@@ -252,26 +502,53 @@ impl<S, C: Codec<S>> Parser<S, C> {
         loop {
             match self.state {
                 Headers {..} => unreachable!(),
-                Body { ref mode, ref mut progress } => {
-                    progress.parse(&mut io).map_err(ErrorEnum::ChunkSize)?;
+                Body { ref mode, ref mut progress, .. } => {
+                    progress.parse(&mut io).map_err(ErrorEnum::from)?;
                     let (bytes, done) = progress.check_buf(&io);
-                    let operation = if done {
-                        Some(self.codec.data_received(
-                            &io.in_buf[..bytes], true)?)
+                    #[cfg(feature="compression")]
+                    let decoded = match self.decoder {
+                        Some(ref mut decoder) => {
+                            let result = decompress_body(decoder,
+                                &mut self.codec, &self.span, &mut io,
+                                progress, mode, bytes, done,
+                                &mut self.body_bytes)?;
+                            match result {
+                                Async::Ready(true) => {
+                                    self.span.record_bytes(self.body_bytes);
+                                    return Ok(Async::Ready(()));
+                                }
+                                Async::Ready(false) => true,
+                                Async::NotReady => return Ok(Async::NotReady),
+                            }
+                        }
+                        None => false,
+                    };
+                    #[cfg(not(feature="compression"))]
+                    let decoded = false;
+                    let operation = if decoded {
+                        // decompressed body is passed to the codec already
+                        None
+                    } else if done {
+                        let (codec, data) = (&mut self.codec, &io.in_buf);
+                        Some(self.span.in_scope(
+                            || codec.data_received(&data[..bytes], true))?)
                     } else if io.done() {
                         // If it's ReadUntilEof it will be detected in
                         // check_buf so we can safefully put error here
                         return Err(ErrorEnum::ResetOnResponseBody.into());
                     } else if matches!(*mode, Progressive(x) if x <= bytes) {
-                        Some(self.codec.data_received(
-                            &io.in_buf[..bytes], false)?)
+                        let (codec, data) = (&mut self.codec, &io.in_buf);
+                        Some(self.span.in_scope(
+                            || codec.data_received(&data[..bytes], false))?)
                     } else {
                         None
                     };
                     match operation {
                         Some(Async::Ready(consumed)) => {
                             progress.consume(&mut io, consumed);
+                            self.body_bytes += consumed as u64;
                             if done && consumed == bytes {
+                                self.span.record_bytes(self.body_bytes);
                                 return Ok(Async::Ready(()));
                             }
                         }
@@ -284,7 +561,23 @@ impl<S, C: Codec<S>> Parser<S, C> {
                     }
                 }
             }
-            if io.read().map_err(ErrorEnum::Io)? == 0 {
+            let bytes = match io.read() {
+                Ok(bytes) => bytes,
+                // Body delimited by connection close may be truncated
+                // if connection is reset, so we don't pass it to the codec
+                // as complete. Stats show the reset too.
+                Err(ref e) if is_reset(e) && matches!(self.state,
+                    Body { progress: BodyProgress::Eof, .. }) =>
+                {
+                    debug!("{}: connection reset while reading body \
+                        delimited by connection close", self.connection_id);
+                    self.reset = true;
+                    self.close = true;
+                    return Err(ErrorEnum::ResetOnResponseBody.into());
+                }
+                Err(e) => return Err(ErrorEnum::Io(e).into()),
+            };
+            if bytes == 0 {
                 if io.done() {
                     continue;
                 } else {
@@ -295,7 +588,7 @@ impl<S, C: Codec<S>> Parser<S, C> {
     }
 }
 
-impl<S: AsyncRead, C: Codec<S>> Future for Parser<S, C> {
+impl<S: AsyncRead, C: Codec<S>, T: Timer> Future for Parser<S, C, T> {
     type Item = Option<ReadBuf<S>>;
     type Error = Error;
     /// Returns None if response contains `Connection: close`
@@ -313,3 +606,197 @@ impl<S: AsyncRead, C: Codec<S>> Future for Parser<S, C> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, AtomicBool};
+    use std::time::Duration;
+
+    use futures::{Future, Async};
+    use futures::future::FutureResult;
+    use httparse;
+    use tk_bufstream::{IoBuf, MockData};
+
+    use client::client::BodyKind::{self, Fixed, Chunked, Eof};
+    use client::errors::ErrorEnum;
+    use client::encoder::RequestState;
+    use client::{Codec, Encoder, EncoderDone, Error, Head, RecvMode};
+    use client::{Config, Extensions};
+    use spans::Span;
+    use timer::{Timer, ManualTimer};
+    use ConnectionId;
+    use super::{Parser, scan_headers};
+
+    struct MockCodec<'a> {
+        mode: RecvMode,
+        heads: &'a Mutex<Vec<Option<Vec<u8>>>>,
+    }
+
+    impl<'a> Codec<MockData> for MockCodec<'a> {
+        type Future = FutureResult<EncoderDone<MockData>, Error>;
+        fn start_write(&mut self, _e: Encoder<MockData>) -> Self::Future {
+            unreachable!();
+        }
+        fn headers_received(&mut self, head: &Head)
+            -> Result<RecvMode, Error>
+        {
+            self.heads.lock().unwrap()
+                .push(head.raw_head().map(|x| x.to_vec()));
+            Ok(self.mode.clone())
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(data.len()))
+        }
+    }
+
+    fn parser<'a>(mock: &MockData, codec: MockCodec<'a>, raw_head: bool,
+        timer: &ManualTimer)
+        -> Parser<MockData, MockCodec<'a>, ManualTimer>
+    {
+        let (_, io) = IoBuf::new(mock.clone()).split();
+        Parser::new(io, codec,
+            Arc::new(AtomicUsize::new(RequestState::StartedNormal as usize)),
+            Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)),
+            &Config::new().raw_head(raw_head).done(), ConnectionId::next(),
+            timer.clone(), Extensions::new(), Span::none())
+    }
+
+    fn scan(is_head: bool, code: u16, headers: &[(&str, &str)])
+        -> Option<(BodyKind, bool)>
+    {
+        let headers = headers.iter().map(|&(name, value)| httparse::Header {
+            name: name,
+            value: value.as_bytes(),
+        }).collect::<Vec<_>>();
+        scan_headers(is_head, code, &headers, false).ok()
+            .map(|(body, _, close)| (body, close))
+    }
+
+    fn scan_strict(headers: &[(&[u8], &[u8])]) -> Result<BodyKind, String>
+    {
+        let headers = headers.iter().map(|&(name, value)| httparse::Header {
+            name: ::std::str::from_utf8(name).unwrap(),
+            value: value,
+        }).collect::<Vec<_>>();
+        scan_headers(false, 200, &headers, true)
+            .map(|(body, _, _)| body)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    #[test]
+    fn strict() {
+        assert_eq!(scan_strict(&[(b"Content-Length", b"10")]), Ok(Fixed(10)));
+        assert_eq!(scan_strict(&[(b"X-A", b"a\tb\x80")]), Ok(Eof));
+        assert_eq!(scan_strict(&[(b"Transfer-Encoding", b"chunked"),
+                                 (b"Content-Length", b"10")]),
+                   Err(format!("{:?}", ErrorEnum::ConflictingBodyLength)));
+        assert_eq!(scan_strict(&[(b"Content-Length", b"10"),
+                                 (b"Transfer-Encoding", b"chunked")]),
+                   Err(format!("{:?}", ErrorEnum::ConflictingBodyLength)));
+        assert_eq!(scan_strict(&[(b"Content-Length", b"+10")]),
+                   Err(format!("{:?}", ErrorEnum::BadContentLength)));
+        assert_eq!(scan_strict(&[(b"X-A", b"a\x00b")]),
+                   Err(format!("{:?}",
+                       ErrorEnum::InvalidHeaderValue("X-A".into()))));
+        assert_eq!(scan_strict(&[(b"X-A", b"a\rb")]),
+                   Err(format!("{:?}",
+                       ErrorEnum::InvalidHeaderValue("X-A".into()))));
+    }
+
+    #[test]
+    fn bodyless() {
+        for &code in &[100, 101, 199, 204, 304] {
+            assert_eq!(scan(false, code, &[]), Some((Fixed(0), false)));
+            assert_eq!(scan(false, code, &[("Content-Length", "10")]),
+                       Some((Fixed(0), false)));
+            assert_eq!(scan(false, code, &[("Transfer-Encoding", "chunked")]),
+                       Some((Fixed(0), false)));
+        }
+        assert_eq!(scan(true, 200, &[("Transfer-Encoding", "chunked")]),
+                   Some((Fixed(0), false)));
+    }
+
+    #[test]
+    fn bodyless_bad_length() {
+        // would be errors for responses with a body
+        let headers = [("Content-Length", "1"), ("Content-Length", "x")];
+        assert_eq!(scan(false, 200, &headers), None);
+        assert_eq!(scan(false, 304, &headers), Some((Fixed(0), false)));
+        assert_eq!(scan(true, 200, &headers), Some((Fixed(0), false)));
+        assert_eq!(scan(false, 204, &[("Content-Length", "-1"),
+                                      ("Connection", "close")]),
+                   Some((Fixed(0), true)));
+    }
+
+    #[test]
+    fn raw_head() {
+        let head = "HTTP/1.1 200 OK\r\nX-A:  b \r\nContent-Length: 2\r\n\r\n";
+        for &enabled in &[true, false] {
+            let heads = Mutex::new(Vec::new());
+            let mock = MockData::new();
+            let mut parser = parser(&mock, MockCodec {
+                mode: RecvMode::buffered_upfront(100),
+                heads: &heads,
+            }, enabled, &ManualTimer::new());
+            mock.add_input(head);
+            mock.add_input("ok");
+            assert!(parser.poll().unwrap().is_ready());
+            let expected = if enabled {
+                Some(head.as_bytes().to_vec())
+            } else {
+                None
+            };
+            assert_eq!(*heads.lock().unwrap(), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn body_deadline() {
+        let heads = Mutex::new(Vec::new());
+        let timer = ManualTimer::new();
+        let mock = MockData::new();
+        let mut parser = parser(&mock, MockCodec {
+            mode: RecvMode::progressive(1)
+                .with_timeout(Duration::new(5, 0)),
+            heads: &heads,
+        }, false, &timer);
+        assert_eq!(parser.deadline(), None);
+        let start = timer.now();
+        timer.advance(Duration::new(1, 0));
+        mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nok");
+        assert!(parser.poll().unwrap().is_not_ready());
+        assert_eq!(parser.deadline(), Some(start + Duration::new(6, 0)));
+        // the deadline is fixed when headers are received
+        timer.advance(Duration::new(1, 0));
+        assert!(parser.poll().unwrap().is_not_ready());
+        assert_eq!(parser.deadline(), Some(start + Duration::new(6, 0)));
+    }
+
+    #[test]
+    fn hijack_unsupported() {
+        let heads = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut parser = parser(&mock, MockCodec {
+            mode: RecvMode::hijack(),
+            heads: &heads,
+        }, false, &ManualTimer::new());
+        mock.add_input("HTTP/1.1 101 Switching Protocols\r\n\
+                        Upgrade: websocket\r\n\r\n");
+        let err = parser.poll().err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(HijackUnsupported)");
+        assert_eq!(heads.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn body() {
+        assert_eq!(scan(false, 200, &[]), Some((Eof, false)));
+        assert_eq!(scan(false, 200, &[("Content-Length", "10")]),
+                   Some((Fixed(10), false)));
+        assert_eq!(scan(false, 200, &[("Content-Length", "10"),
+                                      ("Transfer-Encoding", "chunked")]),
+                   Some((Chunked, true)));
+    }
+}
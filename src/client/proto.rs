@@ -4,54 +4,76 @@ use std::mem;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use tk_bufstream::{IoBuf, WriteBuf, ReadBuf};
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 use futures::{Future, AsyncSink, Async, Sink, StartSend, Poll};
+use futures::future::Either;
 
 use client::parser::Parser;
 use client::encoder::{self, get_inner};
 use client::errors::ErrorEnum;
-use client::{Codec, Error, Config};
+use client::{Codec, Error, Config, ConfigRegistry, Extensions};
+use client::ConnectionInfo;
+use spans::Span;
+use timer::{Timer, TokioTimer};
+use ConnectionId;
 
 
 enum OutState<S, F> {
     Idle(WriteBuf<S>, Instant),
-    Write(F, Instant),
+    Write(F, Instant, Span),
     Void,
 }
 
-enum InState<S, C: Codec<S>> {
+enum InState<S, C: Codec<S>, T> {
     Idle(ReadBuf<S>, Instant),
-    Read(Parser<S, C>, Instant),
+    /// Parser, time request was queued and request sequence number
+    Read(Parser<S, C, T>, Instant, u64),
     Void,
 }
 
 struct Waiting<C> {
     codec: C,
+    seq: u64,
     state: Arc<AtomicUsize>,  // TODO(tailhook) AtomicU8
+    request_bytes: Arc<AtomicUsize>,
     queued_at: Instant,
+    span: Span,
 }
 
-pub struct PureProto<S, C: Codec<S>> {
+pub struct PureProto<S, C: Codec<S>, T> {
+    id: ConnectionId,
     writing: OutState<S, C::Future>,
     waiting: VecDeque<Waiting<C>>,
-    reading: InState<S, C>,
+    reading: InState<S, C, T>,
     close: Arc<AtomicBool>,
+    canceled: bool,
+    /// Peer closed idle connection, found when starting a request
+    peer_closed: bool,
     config: Arc<Config>,
+    /// Sequence number of the next request written
+    next_request: u64,
+    /// The latest request written may be pipelined after
+    pipeline_after: bool,
+    /// Sequence number of the request the next response belongs to
+    next_response: u64,
+    timer: T,
+    extensions: Extensions,
+    info: ConnectionInfo,
+    span: Span,
 }
 
 /// A low-level HTTP/1.x client protocol handler
 ///
 /// Note, most of the time you need some reconnection facility and/or
 /// connection pooling on top of this interface
-pub struct Proto<S, C: Codec<S>> {
-    proto: PureProto<S, C>,
-    handle: Handle,
-    timeout: Timeout,
+pub struct Proto<S, C: Codec<S>, T: Timer = TokioTimer> {
+    proto: PureProto<S, C, T>,
+    timeout: T::Timeout,
 }
 
 
@@ -61,50 +83,153 @@ impl<S, C: Codec<S>> Proto<S, C> {
     /// You should use this protocol as a `Sink`
     pub fn new(conn: S, handle: &Handle, cfg: &Arc<Config>) -> Proto<S, C>
         where S: AsyncRead + AsyncWrite
+    {
+        Proto::with_timer(conn, cfg, TokioTimer::new(handle))
+    }
+}
+
+impl<S, C: Codec<S>, T: Timer> Proto<S, C, T> {
+    /// Same as `new` but with a custom timer (see `timer` module)
+    pub fn with_timer(conn: S, cfg: &Arc<Config>, timer: T) -> Proto<S, C, T>
+        where S: AsyncRead + AsyncWrite
     {
         let (cout, cin) = IoBuf::new(conn).split();
+        let id = ConnectionId::next();
+        debug!("{}: new connection", id);
+        let now = timer.now();
         Proto {
+            timeout: timer.timeout_at(now + cfg.keep_alive_timeout),
             proto: PureProto {
-                writing: OutState::Idle(cout, Instant::now()),
+                id: id,
+                writing: OutState::Idle(cout, now),
                 waiting: VecDeque::with_capacity(
                     cfg.inflight_request_prealloc),
-                reading: InState::Idle(cin, Instant::now()),
+                reading: InState::Idle(cin, now),
                 close: Arc::new(AtomicBool::new(false)),
+                canceled: false,
+                peer_closed: false,
                 config: cfg.clone(),
+                next_request: 0,
+                pipeline_after: true,
+                next_response: 0,
+                timer: timer,
+                extensions: Extensions::new(),
+                info: ConnectionInfo::new(now),
+                span: Span::connection("client", id),
             },
-            handle: handle.clone(),
-            timeout: Timeout::new(cfg.keep_alive_timeout, &handle)
-                .expect("can always create a timeout"),
         }
     }
+    /// Returns identifier of the connection (also passed in `Head`)
+    pub fn connection_id(&self) -> ConnectionId {
+        self.proto.id
+    }
+    /// Returns per-connection storage available to codecs
+    ///
+    /// Values may be put here before sending any requests, e.g. a token
+    /// bound to the TLS session the connection is established with.
+    pub fn extensions(&self) -> &Extensions {
+        &self.proto.extensions
+    }
+    /// Returns addresses and connect time of the connection
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.proto.info
+    }
+    /// Set address of the peer, used for diagnostics only
+    ///
+    /// It's available as `ConnectionInfo::peer_addr` and recorded in the
+    /// connection span when `tracing` feature is enabled. `connect_tcp`
+    /// sets it automatically.
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.proto.info.set_peer_addr(addr);
+        self.proto.span.record_peer(addr);
+    }
+    /// Set local address of the connection
+    ///
+    /// It's available as `ConnectionInfo::local_addr`.
+    pub fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.proto.info.set_local_addr(addr);
+    }
+    /// Set time it took to establish the connection
+    ///
+    /// It's available as `ConnectionInfo::connect_time`. Include TLS
+    /// handshake (and proxy negotiation if any) when establishing
+    /// connection yourself.
+    pub fn set_connect_time(&mut self, time: Duration) {
+        self.proto.info.set_connect_time(time);
+    }
+    fn request_timeout(&self) -> Error {
+        debug!("{}: request timed out", self.proto.id);
+        ErrorEnum::RequestTimeout.into()
+    }
 }
 
 impl<C: Codec<TcpStream>> Proto<TcpStream, C> {
     /// A convenience method to establish connection and create a protocol
     /// instance
+    ///
+    /// Fails with `ConnectTimeout` error if connection is not established
+    /// within `Config::connect_timeout`. Addresses and connect time are
+    /// available in `connection_info()`.
     pub fn connect_tcp(addr: SocketAddr, cfg: &Arc<Config>, handle: &Handle)
         -> Box<Future<Item=Self, Error=Error>>
     {
         let cfg = cfg.clone();
         let handle = handle.clone();
+        let start = Instant::now();
         Box::new(
-            TcpStream::connect(&addr, &handle)
-            .map(move |c| Proto::new(c, &handle, &cfg))
-            .map_err(ErrorEnum::Io).map_err(Error::from))
+            connect(addr, cfg.connect_timeout, &handle)
+            .map(move |c| {
+                let local = c.local_addr().ok();
+                let mut proto = Proto::new(c, &handle, &cfg);
+                proto.set_peer_addr(addr);
+                if let Some(local) = local {
+                    proto.set_local_addr(local);
+                }
+                proto.set_connect_time(start.elapsed());
+                proto
+            }))
         as Box<Future<Item=_, Error=_>>
     }
+
+    /// Same as `connect_tcp` but picks a config for `host` from registry
+    ///
+    /// The `host` is the name the address was resolved from (or the
+    /// address itself), port is taken from `addr`.
+    pub fn connect_tcp_host(addr: SocketAddr, host: &str,
+        registry: &ConfigRegistry, handle: &Handle)
+        -> Box<Future<Item=Self, Error=Error>>
+    {
+        Proto::connect_tcp(addr, registry.get(host, addr.port()), handle)
+    }
+}
+
+/// Connects to the address failing with `ConnectTimeout` after `timeout`
+pub fn connect(addr: SocketAddr, timeout: Duration, handle: &Handle)
+    -> Box<Future<Item=TcpStream, Error=Error>>
+{
+    let timeout = Timeout::new(timeout, handle)
+        .expect("can always create a timeout");
+    Box::new(TcpStream::connect(&addr, handle)
+        .select2(timeout)
+        .then(|res| match res {
+            Ok(Either::A((sock, _))) => Ok(sock),
+            Ok(Either::B(_)) => Err(ErrorEnum::ConnectTimeout.into()),
+            Err(Either::A((e, _))) | Err(Either::B((e, _)))
+            => Err(ErrorEnum::Io(e).into()),
+        }))
 }
 
-impl<S: AsyncRead + AsyncWrite, C: Codec<S>> PureProto<S, C> {
+impl<S: AsyncRead + AsyncWrite, C: Codec<S>, T: Timer> PureProto<S, C, T> {
     fn poll_writing(&mut self) -> Result<bool, Error> {
         let mut progress = false;
         self.writing = match mem::replace(&mut self.writing, OutState::Void) {
             OutState::Idle(mut io, time) => {
                 io.flush().map_err(ErrorEnum::Io)?;
-                if time.elapsed() > self.config.keep_alive_timeout &&
+                if self.timer.now() - time > self.config.keep_alive_timeout &&
                     self.waiting.len() == 0 &&
                     matches!(self.reading, InState::Idle(..))
                 {
+                    debug!("{}: keep-alive timeout", self.id);
                     return Err(ErrorEnum::KeepAliveTimeout.into());
                 }
                 OutState::Idle(io, time)
@@ -112,28 +237,54 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> PureProto<S, C> {
             // Note we break connection if serializer errored, because
             // we don't actually know if connection can be reused
             // safefully in this case
-            OutState::Write(mut fut, start) => match fut.poll()? {
-                Async::Ready(done) => {
-                    let mut io = get_inner(done);
-                    io.flush().map_err(ErrorEnum::Io)?;
-                    progress = true;
-                    OutState::Idle(io, Instant::now())
+            OutState::Write(mut fut, start, span) => {
+                match span.in_scope(|| fut.poll())? {
+                    Async::Ready(done) => {
+                        trace!("{}: request written", self.id);
+                        let mut io = get_inner(done);
+                        io.flush().map_err(ErrorEnum::Io)?;
+                        progress = true;
+                        OutState::Idle(io, self.timer.now())
+                    }
+                    Async::NotReady => OutState::Write(fut, start, span),
                 }
-                Async::NotReady => OutState::Write(fut, start),
-            },
+            }
             OutState::Void => unreachable!(),
         };
         return Ok(progress);
     }
+    /// Marks connection to be closed if any in-flight request is canceled
+    fn check_canceled(&mut self) {
+        if self.canceled {
+            return;
+        }
+        let canceled = match self.reading {
+            InState::Read(ref parser, _, _) => parser.is_canceled(),
+            _ => false,
+        } || self.waiting.iter().any(|w| w.codec.is_canceled());
+        if canceled {
+            debug!("{}: request canceled, closing connection after response",
+                self.id);
+            self.canceled = true;
+            self.close.store(true, Ordering::SeqCst);
+        }
+    }
     fn poll_reading(&mut self) -> Result<bool, Error> {
+        self.check_canceled();
         let (state, progress) =
             match mem::replace(&mut self.reading, InState::Void) {
                 InState::Idle(mut io, time) => {
                     if let Some(w) = self.waiting.pop_front() {
-                        let Waiting { codec: nr, state, queued_at } = w;
+                        let Waiting { codec: nr, seq, state, request_bytes,
+                                      queued_at, span } = w;
+                        self.check_order(seq)?;
                         let parser = Parser::new(io, nr,
-                            state, self.close.clone());
-                        (InState::Read(parser, queued_at), true)
+                            state, self.close.clone(), request_bytes,
+                            &self.config, self.id, self.timer.clone(),
+                            self.extensions.clone(), span);
+                        (InState::Read(parser, queued_at, seq), true)
+                    } else if self.canceled {
+                        return Err(ErrorEnum::Closed.into());
                     } else {
                         // This serves for two purposes:
                         // 1. Detect connection has been closed (i.e.
@@ -150,23 +301,36 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> PureProto<S, C> {
                         (InState::Idle(io, time), false)
                     }
                 }
-                InState::Read(mut parser, time) => {
-                    match parser.poll()? {
+                InState::Read(mut parser, time, seq) => {
+                    let res = parser.poll().map_err(|e| {
+                        self.report_failure(&parser, time, &e);
+                        e
+                    });
+                    match res? {
                         Async::NotReady => {
-                            (InState::Read(parser, time), false)
+                            (InState::Read(parser, time, seq), false)
                         }
                         Async::Ready(Some(io)) => {
+                            trace!("{}: response {} done", self.id, seq);
+                            self.check_order(seq)?;
+                            self.next_response += 1;
+                            self.report(&parser, time);
                             // after request is done, rearm keep-alive
                             // timeout
                             match self.writing {
                                 OutState::Idle(_, ref mut time) => {
-                                    *time = Instant::now();
+                                    *time = self.timer.now();
                                 }
                                 _ => {}
                             }
-                            (InState::Idle(io, Instant::now()), true)
+                            (InState::Idle(io, self.timer.now()), true)
                         }
                         Async::Ready(None) => {
+                            debug!("{}: closing connection after response",
+                                self.id);
+                            // response is complete, `Closed` error only
+                            // tells that connection can't be reused
+                            self.report(&parser, time);
                             return Err(ErrorEnum::Closed.into());
                         }
                     }
@@ -176,9 +340,61 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> PureProto<S, C> {
         self.reading = state;
         Ok(progress)
     }
+    /// Checks that idle connection is not closed by peer before reuse
+    ///
+    /// Server may close keep-alive connection at any moment, and we may
+    /// not have read the socket since then. Returns false and marks the
+    /// connection to be closed if it's dead, so request is not written
+    /// into it.
+    fn check_alive(&mut self) -> Result<bool, Error> {
+        if self.waiting.len() > 0 {
+            return Ok(true);
+        }
+        if let InState::Idle(ref mut io, _) = self.reading {
+            if io.read().map_err(ErrorEnum::Io)? != 0 {
+                return Err(ErrorEnum::PrematureResponseHeaders.into());
+            }
+            if io.done() {
+                debug!("{}: connection closed by peer before reuse",
+                    self.id);
+                self.peer_closed = true;
+                self.close.store(true, Ordering::SeqCst);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    /// Validates that response belongs to the oldest request in flight
+    ///
+    /// Responses are matched to requests by their order only, so if this
+    /// fails we can't trust any response on this connection any more.
+    fn check_order(&self, seq: u64) -> Result<(), Error> {
+        if seq != self.next_response {
+            error!("{}: response for request {} received when {} expected",
+                self.id, seq, self.next_response);
+            return Err(ErrorEnum::ResponseOutOfOrder.into());
+        }
+        Ok(())
+    }
+    fn report(&self, parser: &Parser<S, C, T>, queued_at: Instant) {
+        if let Some(ref observer) = self.config.observer {
+            let duration = self.timer.now() - queued_at;
+            observer.0.request_finished(&parser.stats(duration));
+        }
+    }
+    fn report_failure(&self, parser: &Parser<S, C, T>, queued_at: Instant,
+        error: &Error)
+    {
+        if let Some(ref observer) = self.config.observer {
+            let duration = self.timer.now() - queued_at;
+            observer.0.request_failed(&parser.stats(duration), error);
+        }
+    }
 }
 
-impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for Proto<S, C> {
+impl<S: AsyncRead + AsyncWrite, C: Codec<S>, T: Timer> Sink
+    for Proto<S, C, T>
+{
     type SinkItem = C;
     type SinkError = Error;
     fn start_send(&mut self, mut item: Self::SinkItem)
@@ -190,6 +406,11 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for Proto<S, C> {
                 AsyncSink::Ready => break AsyncSink::Ready,
                 AsyncSink::NotReady(item) => item,
             };
+            if self.proto.peer_closed {
+                // Give the request back, connection is reported as closed
+                // by the next `poll_complete`
+                break AsyncSink::NotReady(item);
+            }
             let wr = self.proto.poll_writing()?;
             let rd = self.proto.poll_reading()?;
             if !wr && !rd {
@@ -197,13 +418,12 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for Proto<S, C> {
             }
         };
         let new_timeout = self.proto.get_timeout();
-        let now = Instant::now();
+        let now = self.proto.timer.now();
         if new_timeout < now {
-            return Err(ErrorEnum::RequestTimeout.into());
+            return Err(self.request_timeout());
         }
         if old_timeout != new_timeout {
-            self.timeout = Timeout::new(new_timeout - now, &self.handle)
-                .expect("can always add a timeout");
+            self.timeout = self.proto.timer.timeout_at(new_timeout);
             let timeo = self.timeout.poll()
                 .expect("timeout can't fail on poll");
             match timeo {
@@ -215,7 +435,7 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for Proto<S, C> {
                         // can return error (can it happen?)
                         // TODO(tailhook) it's strange that this can happen
                         AsyncSink::Ready => {
-                            return Err(ErrorEnum::RequestTimeout.into());
+                            return Err(self.request_timeout());
                         }
                     }
                 }
@@ -228,19 +448,18 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for Proto<S, C> {
         let old_timeout = self.proto.get_timeout();
         let res = self.proto.poll_complete()?;
         let new_timeout = self.proto.get_timeout();
-        let now = Instant::now();
+        let now = self.proto.timer.now();
         if new_timeout < now {
-            return Err(ErrorEnum::RequestTimeout.into());
+            return Err(self.request_timeout());
         }
         if old_timeout != new_timeout {
-            self.timeout = Timeout::new(new_timeout - now, &self.handle)
-                .expect("can always add a timeout");
+            self.timeout = self.proto.timer.timeout_at(new_timeout);
             let timeo = self.timeout.poll()
                 .expect("timeout can't fail on poll");
             match timeo {
                 // it shouldn't be keep-alive timeout, but have to check
                 Async::Ready(()) => {
-                    return Err(ErrorEnum::RequestTimeout.into());
+                    return Err(self.request_timeout());
                 }
                 Async::NotReady => {},
             }
@@ -249,7 +468,7 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for Proto<S, C> {
     }
 }
 
-impl<S, C: Codec<S>> PureProto<S, C> {
+impl<S, C: Codec<S>, T: Timer> PureProto<S, C, T> {
     fn get_timeout(&self) -> Instant {
         match self.writing {
             OutState::Idle(_, time) => {
@@ -259,8 +478,9 @@ impl<S, C: Codec<S>> PureProto<S, C> {
                             return max(time, rtime) +
                                 self.config.keep_alive_timeout;
                         }
-                        InState::Read(_, time) => {
-                            return time + self.config.max_request_timeout;
+                        InState::Read(ref parser, time, _) => {
+                            return parser.deadline().unwrap_or(
+                                time + self.config.max_request_timeout);
                         }
                         InState::Void => unreachable!(),
                     }
@@ -269,7 +489,7 @@ impl<S, C: Codec<S>> PureProto<S, C> {
                     return req.queued_at + self.config.max_request_timeout;
                 }
             }
-            OutState::Write(_, time) => {
+            OutState::Write(_, time, _) => {
                 return time + self.config.max_request_timeout;
             }
             OutState::Void => unreachable!(),
@@ -277,12 +497,18 @@ impl<S, C: Codec<S>> PureProto<S, C> {
     }
 }
 
-impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for PureProto<S, C> {
+impl<S: AsyncRead + AsyncWrite, C: Codec<S>, T: Timer> Sink
+    for PureProto<S, C, T>
+{
     type SinkItem = C;
     type SinkError = Error;
     fn start_send(&mut self, mut item: Self::SinkItem)
         -> StartSend<Self::SinkItem, Self::SinkError>
     {
+        if item.is_canceled() {
+            // Nothing is written yet, so just forget the request
+            return Ok(AsyncSink::Ready);
+        }
         if self.waiting.len() > 0 {
             if self.waiting.len() > self.config.inflight_request_limit {
                 // Return right away if limit reached
@@ -296,21 +522,30 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for PureProto<S, C> {
                 return Ok(AsyncSink::NotReady(item));
             }
         }
-        if matches!(self.reading, InState::Read(_, time)
-            if time.elapsed() > self.config.safe_pipeline_timeout)
+        if matches!(self.reading, InState::Read(_, time, _)
+            if self.timer.now() - time > self.config.safe_pipeline_timeout)
         {
             // Return right away if request is being waited for too long
             return Ok(AsyncSink::NotReady(item));
         }
+        if !self.pipeline_after && (self.waiting.len() > 0 ||
+            matches!(self.reading, InState::Read(..)))
+        {
+            // Request in flight can't be retried, so we don't risk
+            // the next one along with it
+            return Ok(AsyncSink::NotReady(item));
+        }
         let (r, st) = match mem::replace(&mut self.writing, OutState::Void) {
             OutState::Idle(mut io, time) => {
-                if time.elapsed() > self.config.keep_alive_timeout &&
+                if self.timer.now() - time > self.config.keep_alive_timeout &&
                     self.waiting.len() == 0 &&
                     matches!(self.reading, InState::Idle(..))
                 {
                     // Too dangerous to send request now
                     (AsyncSink::NotReady(item), OutState::Idle(io, time))
-                } else if self.close.load(Ordering::SeqCst) {
+                } else if self.close.load(Ordering::SeqCst) ||
+                    !self.check_alive()?
+                {
                     // TODO(tailhook) maybe shutdown?
                     io.flush().map_err(ErrorEnum::Io)?;
                     (AsyncSink::NotReady(item), OutState::Idle(io, time))
@@ -325,25 +560,40 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for PureProto<S, C> {
                         (AsyncSink::NotReady(item), OutState::Idle(io, time))
                     } else {
                         let state = Arc::new(AtomicUsize::new(0));
-                        let e = encoder::new(io,
-                                state.clone(), self.close.clone());
-                        let fut = item.start_write(e);
+                        let bytes = Arc::new(AtomicUsize::new(0));
+                        let span = self.span.request();
+                        let mut e = encoder::new(io,
+                                state.clone(), self.close.clone(),
+                                bytes.clone(), self.config.header_limits,
+                                self.extensions.clone(), span.clone());
+                        if let Some(key) = item.idempotency_key() {
+                            e.defer_header("Idempotency-Key", key.to_string())
+                                .expect("idempotency key is a valid header");
+                        }
+                        self.pipeline_after =
+                            item.idempotency().allows_pipelining();
+                        let fut = span.in_scope(|| item.start_write(e));
                         self.waiting.push_back(Waiting {
                             codec: item,
+                            seq: self.next_request,
                             state: state,
-                            queued_at: Instant::now(),
+                            request_bytes: bytes,
+                            queued_at: self.timer.now(),
+                            span: span.clone(),
                         });
+                        self.next_request += 1;
                         (AsyncSink::Ready,
-                         OutState::Write(fut, Instant::now()))
+                         OutState::Write(fut, self.timer.now(), span))
                     }
                 }
             }
-            OutState::Write(fut, start) => {
+            OutState::Write(fut, start, span) => {
                 // TODO(tailhook) should we check "close"?
                 // Points:
                 // * Performance
                 // * Dropping future
-                (AsyncSink::NotReady(item), OutState::Write(fut, start))
+                (AsyncSink::NotReady(item),
+                 OutState::Write(fut, start, span))
             }
             OutState::Void => unreachable!(),
         };
@@ -370,3 +620,618 @@ impl<S: AsyncRead + AsyncWrite, C: Codec<S>> Sink for PureProto<S, C> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use futures::{Future, Sink, AsyncSink, Async, Poll};
+    use futures::future::{lazy, ok, FutureResult};
+    use futures::sync::oneshot::Receiver;
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    use client::{Proto, Config, Error, Codec, Encoder, EncoderDone, Head};
+    use client::{RecvMode, RequestObserver, RequestStats, IdempotencyKey};
+    use client::Client;
+    use timer::ManualTimer;
+    use Version;
+    use client::buffered::{Buffered, Response};
+
+    fn get(path: &str) -> (Buffered, Receiver<Result<Response, Error>>) {
+        Buffered::get(format!("http://example.com{}", path).parse().unwrap())
+    }
+
+    fn body(rx: Receiver<Result<Response, Error>>) -> Vec<u8> {
+        rx.wait().unwrap().unwrap().body().to_vec()
+    }
+
+    fn pipelined(mock: &MockData, core: &Core) -> Proto<MockData, Buffered> {
+        Proto::new(mock.clone(), &core.handle(),
+            &Config::new().inflight_request_limit(2).done())
+    }
+
+    #[test]
+    fn bodyless_responses_keep_alive() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+            &Config::new().inflight_request_limit(3).done());
+        let url = "http://example.com/a".parse().unwrap();
+        let (a, rx_a) = Buffered::send("HEAD", url, Vec::new(), Vec::new())
+            .unwrap();
+        let (b, rx_b) = get("/b");
+        let (c, rx_c) = get("/c");
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(c)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n\
+                            HTTP/1.1 304 Not Modified\r\n\
+                            Transfer-Encoding: chunked\r\n\
+                            Content-Length: x\r\n\r\n\
+                            HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nc");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            Ok(())
+        })).unwrap();
+        assert_eq!(body(rx_a), b"");
+        assert_eq!(body(rx_b), b"");
+        assert_eq!(body(rx_c), b"c");
+    }
+
+    #[test]
+    fn pipelined_responses() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = pipelined(&mock, &core);
+        let (a, rx_a) = get("/a");
+        let (b, rx_b) = get("/b");
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+                            HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            Ok(())
+        })).unwrap();
+        assert_eq!(body(rx_a), b"a");
+        assert_eq!(body(rx_b), b"b");
+    }
+
+    #[test]
+    fn non_retriable_not_pipelined() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = pipelined(&mock, &core);
+        let url = "http://example.com/a".parse().unwrap();
+        let (a, _rx_a) = Buffered::send("POST", url, Vec::new(),
+                                        b"x".to_vec()).unwrap();
+        let url = "http://example.com/b".parse().unwrap();
+        let (mut b, _rx_b) = Buffered::send("POST", url, Vec::new(),
+                                            b"y".to_vec()).unwrap();
+        let key = IdempotencyKey::new();
+        b.set_idempotency_key(key);
+        let (c, _rx_c) = get("/c");
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            let b = match proto.start_send(b)? {
+                AsyncSink::NotReady(b) => b,
+                AsyncSink::Ready => panic!("request is pipelined"),
+            };
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            // request with a key is retriable
+            assert!(matches!(proto.start_send(c)?, AsyncSink::Ready));
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            Ok(())
+        })).unwrap();
+        let out = String::from_utf8(mock.output(..)).unwrap();
+        assert!(out.contains(&format!("POST /b HTTP/1.1\r\n\
+            Host: example.com\r\nIdempotency-Key: {}\r\n", key)));
+        assert!(out.ends_with(
+            "GET /c HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+    }
+
+    #[test]
+    #[cfg(feature="compression")]
+    fn decompress() {
+        use std::io::Write;
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(b"hello world").unwrap();
+        let data = enc.finish().unwrap();
+        let mut response = Vec::new();
+        write!(&mut response, "HTTP/1.1 200 OK\r\n\
+            Content-Encoding: gzip\r\n\
+            Transfer-Encoding: chunked\r\n\r\n\
+            {:x}\r\n", data.len()).unwrap();
+        response.extend_from_slice(&data);
+        response.extend_from_slice(b"\r\n0\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+            &Config::new().inflight_request_limit(2).decompress(true).done());
+        let (a, rx_a) = get("/a");
+        let (b, rx_b) = get("/b");
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            mock.add_input(response);
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            Ok(())
+        })).unwrap();
+        assert_eq!(body(rx_a), b"hello world");
+        assert_eq!(body(rx_b), b"b");
+    }
+
+    #[test]
+    fn connection_info() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut core = Core::new().unwrap();
+        let proto: Proto<_, Buffered> = core.run(
+            Proto::connect_tcp(addr, &Config::new().done(), &core.handle())
+        ).unwrap();
+        let (sock, peer) = listener.accept().unwrap();
+        let info = proto.connection_info();
+        assert_eq!(info.peer_addr(), Some(addr));
+        assert_eq!(info.local_addr(), Some(peer));
+        assert!(info.connect_time().is_some());
+        drop(sock);
+    }
+
+    #[test]
+    fn early_response() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = pipelined(&mock, &core);
+        let (a, rx_a) = get("/a");
+        let (b, rx_b) = get("/b");
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            // response to the first request arrives before second is sent
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            Ok(())
+        })).unwrap();
+        assert_eq!(body(rx_a), b"a");
+        assert_eq!(body(rx_b), b"b");
+    }
+
+    #[test]
+    fn premature_response() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = pipelined(&mock, &core);
+        let err = core.run(lazy(|| {
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(PrematureResponseHeaders)");
+    }
+
+    #[test]
+    fn closed_with_pipelined_request() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = pipelined(&mock, &core);
+        let (a, rx_a) = get("/a");
+        let (b, rx_b) = get("/b");
+        let err = core.run(lazy(|| {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\
+                            Connection: close\r\n\r\na");
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(Closed)");
+        drop(proto);
+        assert_eq!(body(rx_a), b"a");
+        assert!(rx_b.wait().is_err());
+    }
+
+    #[test]
+    fn closed_before_reuse() {
+        struct Closed(MockData);
+        impl io::Read for Closed {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+        }
+        impl io::Write for Closed {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.flush()
+            }
+        }
+        impl AsyncRead for Closed {}
+        impl AsyncWrite for Closed {
+            fn shutdown(&mut self) -> Poll<(), io::Error> {
+                self.0.shutdown()
+            }
+        }
+
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(Closed(mock.clone()), &core.handle(),
+                                   &Config::new().done());
+        let (a, _rx_a) = get("/a");
+        let err = core.run(lazy(|| {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::NotReady(..)));
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(Closed)");
+        assert_eq!(mock.output(..).len(), 0);
+    }
+
+    #[test]
+    fn keep_alive_timeout() {
+        let timer = ManualTimer::new();
+        let cfg = Config::new()
+            .keep_alive_timeout(Duration::new(10, 0))
+            .done();
+        let mut proto: Proto<_, Buffered, _> = Proto::with_timer(
+            MockData::new(), &cfg, timer.clone());
+        let err = lazy(|| {
+            assert!(proto.poll_complete()?.is_ready());
+            timer.advance(Duration::new(9, 0));
+            assert!(proto.poll_complete()?.is_ready());
+            timer.advance(Duration::new(2, 0));
+            proto.poll_complete()
+        }).wait().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(KeepAliveTimeout)");
+    }
+
+    #[test]
+    fn client_send_request() {
+        type Boxed = Box<Codec<MockData,
+            Future=FutureResult<EncoderDone<MockData>, Error>>>;
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto: Proto<MockData, Boxed> = Proto::new(mock.clone(),
+            &core.handle(), &Config::new().done());
+        // `Sink` is imported too, so the name must not be ambiguous
+        let resp = core.run(lazy(|| {
+            let resp = proto.send_request("PUT", "http://example.com/x",
+                vec![("X-A".to_string(), b"1".to_vec())], b"hi".to_vec());
+            mock.add_input("HTTP/1.1 200 OK\r\n\
+                            Content-Length: 2\r\n\r\nok");
+            proto.poll_complete().map(|_| resp)
+        }).and_then(|resp| resp)).unwrap();
+        assert_eq!(resp.body(), b"ok");
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+            "PUT /x HTTP/1.1\r\nHost: example.com\r\nX-A: 1\r\n\
+             Content-Length: 2\r\n\r\nhi");
+        let err = proto.send_request("GET /", "http://example.com/",
+            Vec::new(), Vec::new()).wait().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(InvalidMethod)");
+    }
+
+    /// Limits reading of the response body by `RecvMode::with_timeout`
+    struct Timed;
+
+    impl Codec<MockData> for Timed {
+        type Future = FutureResult<EncoderDone<MockData>, Error>;
+        fn start_write(&mut self, mut e: Encoder<MockData>) -> Self::Future {
+            e.request_line("GET", "/", Version::Http11);
+            e.add_header("Host", "example.com").unwrap();
+            e.done_headers().unwrap();
+            ok(e.done())
+        }
+        fn headers_received(&mut self, _head: &Head)
+            -> Result<RecvMode, Error>
+        {
+            Ok(RecvMode::buffered_upfront(100)
+                .with_timeout(Duration::new(2, 0)))
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(data.len()))
+        }
+    }
+
+    #[test]
+    fn body_read_timeout() {
+        let timer = ManualTimer::new();
+        let mock = MockData::new();
+        let cfg = Config::new()
+            .max_request_timeout(Duration::new(10, 0))
+            .done();
+        let mut proto = Proto::with_timer(mock.clone(), &cfg, timer.clone());
+        let err = lazy(|| {
+            assert!(matches!(proto.start_send(Timed)?, AsyncSink::Ready));
+            assert!(proto.poll_complete()?.is_not_ready());
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n\
+                            ok");
+            assert!(proto.poll_complete()?.is_not_ready());
+            timer.advance(Duration::new(1, 0));
+            assert!(proto.poll_complete()?.is_not_ready());
+            // well before `max_request_timeout`
+            timer.advance(Duration::new(2, 0));
+            proto.poll_complete()
+        }).wait().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(RequestTimeout)");
+    }
+
+    #[test]
+    fn unclassified_pipelined() {
+        let mock = MockData::new();
+        let cfg = Config::new().inflight_request_limit(2).done();
+        let mut proto = Proto::with_timer(mock.clone(), &cfg,
+                                          ManualTimer::new());
+        lazy(|| -> Result<(), Error> {
+            // `Timed` doesn't override `Codec::idempotency`
+            assert!(matches!(proto.start_send(Timed)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(Timed)?, AsyncSink::Ready));
+            Ok(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn response_out_of_order() {
+        let mock = MockData::new();
+        let cfg = Config::new().inflight_request_limit(3).done();
+        let mut proto = Proto::with_timer(mock.clone(), &cfg,
+                                          ManualTimer::new());
+        lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(Timed)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(Timed)?, AsyncSink::Ready));
+            assert!(matches!(proto.start_send(Timed)?, AsyncSink::Ready));
+            // simulate a bug matching response to the third request
+            proto.proto.waiting.swap(0, 1);
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            let err = proto.poll_complete().unwrap_err();
+            assert_eq!(format!("{:?}", err), "Error(ResponseOutOfOrder)");
+            Ok(())
+        }).wait().unwrap();
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Token(String);
+
+    /// Sends token received in the previous response on the connection
+    struct Auth;
+
+    impl Codec<MockData> for Auth {
+        type Future = FutureResult<EncoderDone<MockData>, Error>;
+        fn start_write(&mut self, mut e: Encoder<MockData>) -> Self::Future {
+            e.request_line("GET", "/", Version::Http11);
+            e.add_header("Host", "example.com").unwrap();
+            if let Some(Token(token)) = e.extensions().get() {
+                e.add_header("Authorization", token).unwrap();
+            }
+            e.done_headers().unwrap();
+            ok(e.done())
+        }
+        fn headers_received(&mut self, head: &Head)
+            -> Result<RecvMode, Error>
+        {
+            let token = head.headers().find(|&(name, _)| name == "X-Token")
+                .map(|(_, value)| {
+                    String::from_utf8(value.to_vec()).unwrap()
+                });
+            if let Some(token) = token {
+                head.extensions().insert(Token(token));
+            }
+            Ok(RecvMode::buffered_upfront(0))
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(data.len()))
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+                                   &Config::new().done());
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(Auth)?, AsyncSink::Ready));
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            mock.add_input("HTTP/1.1 200 OK\r\nX-Token: t1\r\n\
+                            Content-Length: 0\r\n\r\n");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            assert!(matches!(proto.start_send(Auth)?, AsyncSink::Ready));
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            Ok(())
+        })).unwrap();
+        assert_eq!(proto.extensions().get(), Some(Token("t1".into())));
+        let output = String::from_utf8(mock.output(..)).unwrap();
+        assert_eq!(output.matches("Authorization").count(), 1);
+        assert!(output.ends_with("Authorization: t1\r\n\r\n"));
+    }
+
+    struct Reset(MockData, Arc<AtomicBool>);
+
+    impl io::Read for Reset {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock &&
+                    self.1.load(Ordering::SeqCst) =>
+                {
+                    Err(io::ErrorKind::ConnectionReset.into())
+                }
+                res => res,
+            }
+        }
+    }
+    impl io::Write for Reset {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+    impl AsyncRead for Reset {}
+    impl AsyncWrite for Reset {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            self.0.shutdown()
+        }
+    }
+
+    struct Clean(Mutex<Vec<bool>>);
+
+    impl RequestObserver for Clean {
+        fn request_finished(&self, stats: &RequestStats) {
+            self.0.lock().unwrap().push(stats.body_completed_cleanly());
+        }
+        fn request_failed(&self, stats: &RequestStats, _: &Error) {
+            self.0.lock().unwrap().push(stats.body_completed_cleanly());
+        }
+    }
+
+    #[test]
+    fn reset_on_eof_body() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let reset = Arc::new(AtomicBool::new(false));
+        let clean = Arc::new(Clean(Mutex::new(Vec::new())));
+        let mut proto = Proto::new(Reset(mock.clone(), reset.clone()),
+            &core.handle(),
+            &Config::new().request_observer(clean.clone()).done());
+        let (a, rx_a) = get("/a");
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\n\r\nhello");
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            reset.store(true, Ordering::SeqCst);
+            let err = proto.poll_complete().unwrap_err();
+            assert_eq!(format!("{:?}", err), "Error(ResetOnResponseBody)");
+            Ok(())
+        })).unwrap();
+        // truncated body is never passed as complete
+        assert!(rx_a.wait().map(|r| r.is_err()).unwrap_or(true));
+        assert_eq!(*clean.0.lock().unwrap(), vec![false]);
+    }
+
+    struct Log(Mutex<Vec<String>>);
+
+    impl RequestObserver for Log {
+        fn request_finished(&self, stats: &RequestStats) {
+            self.0.lock().unwrap().push(format!("finished {} {} {}",
+                stats.status(), stats.response_body_bytes(),
+                stats.connection_closed()));
+        }
+        fn request_failed(&self, stats: &RequestStats, error: &Error) {
+            self.0.lock().unwrap().push(format!("failed {} {:?}",
+                stats.status(), error));
+        }
+    }
+
+    #[test]
+    fn observer_errors() {
+        let mut core = Core::new().unwrap();
+        let log = Arc::new(Log(Mutex::new(Vec::new())));
+        let cfg = Config::new().request_observer(log.clone()).done();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(), &cfg);
+        let (a, _rx_a) = get("/a");
+        let (b, _rx_b) = get("/b");
+        let err = core.run(lazy(|| {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\
+                            Connection: close\r\n\r\nbb");
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(Closed)");
+
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(), &cfg);
+        let (c, _rx_c) = get("/c");
+        let err = core.run(lazy(|| {
+            assert!(matches!(proto.start_send(c)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\n\
+                            Transfer-Encoding: chunked\r\n\r\nzz\r\n");
+            proto.poll_complete()
+        })).err().unwrap();
+
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(), &cfg);
+        let (d, _rx_d) = get("/d");
+        core.run(lazy(|| {
+            assert!(matches!(proto.start_send(d)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 OK\r\n\r\n");
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(*log.0.lock().unwrap(), vec![
+            String::from("finished 200 1 false"),
+            String::from("finished 200 2 true"),
+            format!("failed 200 {:?}", err),
+            String::from("failed 0 Error(Header(Status))"),
+        ]);
+    }
+
+    #[test]
+    fn require_body_length() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+            &Config::new().require_body_length(true).done());
+        let (a, rx_a) = get("/a");
+        let (b, rx_b) = get("/b");
+        let err = core.run(lazy(|| {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 200 OK\r\n\r\nb");
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(BodyLengthRequired)");
+        assert_eq!(body(rx_a), b"a");
+        assert!(rx_b.wait().is_err());
+    }
+
+    #[test]
+    fn interim_responses() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+            &Config::new().max_interim_responses(2).done());
+        let (a, rx_a) = get("/a");
+        let (b, rx_b) = get("/b");
+        let err = core.run(lazy(|| {
+            assert!(matches!(proto.start_send(a)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 100 Continue\r\n\r\n");
+            assert!(matches!(proto.poll_complete()?, Async::NotReady));
+            mock.add_input("HTTP/1.1 103 Early Hints\r\nLink: </a>\r\n\r\n\
+                HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+            assert!(matches!(proto.poll_complete()?, Async::Ready(())));
+            assert!(matches!(proto.start_send(b)?, AsyncSink::Ready));
+            mock.add_input("HTTP/1.1 100 Continue\r\n\r\n\
+                HTTP/1.1 102 Processing\r\n\r\n\
+                HTTP/1.1 100 Continue\r\n\r\n\
+                HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+            proto.poll_complete()
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(TooManyInterimResponses)");
+        assert_eq!(body(rx_a), b"a");
+        assert!(rx_b.wait().is_err());
+    }
+}
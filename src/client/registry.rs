@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+use client::Config;
+
+
+/// A set of client configs keyed by `host:port` with a default fallback
+///
+/// This is useful when a single process talks to very different upstreams,
+/// e.g. internal services where aggressive pipelining is fine and
+/// third-party APIs which need conservative timeouts. Build it once and
+/// pass to `Proto::connect_tcp_host`, `oneshot::get_with` or your own
+/// connection pool.
+///
+/// Host names are compared case-insensitively.
+#[derive(Debug, Clone)]
+pub struct ConfigRegistry {
+    default: Arc<Config>,
+    hosts: HashMap<String, Arc<Config>>,
+}
+
+fn key(host: &str, port: u16) -> String {
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+    format!("{}:{}", host.to_ascii_lowercase(), port)
+}
+
+impl ConfigRegistry {
+    /// Create a registry which uses `default` for all the hosts
+    pub fn new(default: &Arc<Config>) -> ConfigRegistry {
+        ConfigRegistry {
+            default: default.clone(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Use `config` for connections to the `host` and `port`
+    ///
+    /// Overrides previous config for the same host and port, if any.
+    pub fn host(&mut self, host: &str, port: u16, config: &Arc<Config>)
+        -> &mut Self
+    {
+        self.hosts.insert(key(host, port), config.clone());
+        self
+    }
+
+    /// Returns config for the host and port, or the default one
+    pub fn get(&self, host: &str, port: u16) -> &Arc<Config> {
+        self.hosts.get(&key(host, port)).unwrap_or(&self.default)
+    }
+
+    /// Returns the config used for hosts that have no override
+    pub fn default_config(&self) -> &Arc<Config> {
+        &self.default
+    }
+
+    /// Create a Arc'd registry clone to share between connections
+    ///
+    /// This is just a convenience method.
+    pub fn done(&mut self) -> Arc<ConfigRegistry> {
+        Arc::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use client::Config;
+    use super::ConfigRegistry;
+
+    #[test]
+    fn lookup() {
+        let default = Config::new().done();
+        let internal = Config::new()
+            .inflight_request_limit(10)
+            .keep_alive_timeout(Duration::new(60, 0))
+            .done();
+        let reg = ConfigRegistry::new(&default)
+            .host("Backend.local", 8080, &internal)
+            .done();
+        assert!(Arc::ptr_eq(reg.get("backend.local", 8080), &internal));
+        assert!(Arc::ptr_eq(reg.get("BACKEND.LOCAL", 8080), &internal));
+        assert!(Arc::ptr_eq(reg.get("backend.local", 80), &default));
+        assert!(Arc::ptr_eq(reg.get("example.com", 8080), &default));
+        assert!(Arc::ptr_eq(reg.default_config(), &default));
+    }
+
+    #[test]
+    fn ipv6() {
+        let default = Config::new().done();
+        let local = Config::new().done();
+        let reg = ConfigRegistry::new(&default).host("::1", 80, &local).done();
+        assert!(Arc::ptr_eq(reg.get("[::1]", 80), &local));
+    }
+}
@@ -0,0 +1,46 @@
+use std::str::from_utf8;
+use std::time::{Duration, SystemTime};
+
+use httpdate::HttpDate;
+
+
+/// Parses `Retry-After` header value relative to `now`
+///
+/// Value is either a number of seconds or an HTTP-date. Dates in the past
+/// give zero duration.
+pub fn parse(value: &[u8], now: SystemTime) -> Option<Duration> {
+    let value = from_utf8(value).ok()?.trim();
+    if value.len() > 0 && value.bytes().all(|x| x >= b'0' && x <= b'9') {
+        return value.parse().ok().map(Duration::from_secs);
+    }
+    let date = SystemTime::from(value.parse::<HttpDate>().ok()?);
+    Some(date.duration_since(now).unwrap_or(Duration::new(0, 0)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::parse;
+
+    #[test]
+    fn seconds() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(parse(b"120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse(b" 0 ", now), Some(Duration::from_secs(0)));
+        assert_eq!(parse(b"-1", now), None);
+        assert_eq!(parse(b"1.5", now), None);
+        assert_eq!(parse(b"", now), None);
+        assert_eq!(parse(b"99999999999999999999999", now), None);
+    }
+
+    #[test]
+    fn date() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(parse(b"Sun, 06 Nov 1994 08:50:37 GMT", now),
+                   Some(Duration::from_secs(60)));
+        assert_eq!(parse(b"Sun, 06 Nov 1994 08:00:00 GMT", now),
+                   Some(Duration::from_secs(0)));
+        assert_eq!(parse(b"tomorrow", now), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use client::Error;
+
+
+/// Sizes and timing of a single request
+///
+/// Passed to `RequestObserver::request_finished` when the whole response
+/// is received, and to `RequestObserver::request_failed` when receiving
+/// the response fails.
+#[derive(Debug, Clone)]
+pub struct RequestStats {
+    status: u16,
+    request_bytes: u64,
+    response_head_bytes: u64,
+    response_body_bytes: u64,
+    duration: Duration,
+    body_completed_cleanly: bool,
+    connection_closed: bool,
+}
+
+/// Receives statistics of every request finished on a connection
+///
+/// This is a lightweight hook to feed histograms of a metrics system
+/// (Prometheus, StatsD, ...). It's called synchronously from the protocol
+/// handler, so should be fast. Set it with `Config::request_observer`.
+pub trait RequestObserver: Send + Sync {
+    /// Called when the response is fully received
+    fn request_finished(&self, stats: &RequestStats);
+    /// Called when receiving the response fails
+    ///
+    /// Stats contain data received until the error: status is zero if
+    /// the response headers were not received, body size is the part of
+    /// the body passed to the codec. The error is also returned from the
+    /// connection. Default implementation does nothing.
+    fn request_failed(&self, stats: &RequestStats, error: &Error) {
+        let _ = (stats, error);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ObserverRef(pub Arc<RequestObserver>);
+
+impl RequestStats {
+    /// Status code of the response
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+    /// Size of the serialized request (line, headers and body)
+    pub fn request_bytes(&self) -> u64 {
+        self.request_bytes
+    }
+    /// Size of the response status line and headers
+    pub fn response_head_bytes(&self) -> u64 {
+        self.response_head_bytes
+    }
+    /// Size of the response body as passed to the codec
+    ///
+    /// This doesn't include chunked encoding overhead.
+    pub fn response_body_bytes(&self) -> u64 {
+        self.response_body_bytes
+    }
+    /// Time from the request being queued until response is received
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+    /// Returns `false` if body was ended by a connection reset
+    ///
+    /// This only happens to bodies delimited by connection close (without
+    /// `Content-Length` and chunked encoding): when the socket reports a
+    /// reset rather than an orderly shutdown, the body may be truncated,
+    /// so request fails with `ResetOnResponseBody` error (see
+    /// `RequestObserver::request_failed`).
+    pub fn body_completed_cleanly(&self) -> bool {
+        self.body_completed_cleanly
+    }
+    /// Returns `true` if connection is closed after the response
+    ///
+    /// This is the case for responses with `Connection: close` or bodies
+    /// delimited by connection close. The response itself is complete,
+    /// but no more requests are sent on the connection.
+    pub fn connection_closed(&self) -> bool {
+        self.connection_closed
+    }
+}
+
+impl<T: RequestObserver> RequestObserver for Arc<T> {
+    fn request_finished(&self, stats: &RequestStats) {
+        (**self).request_finished(stats)
+    }
+    fn request_failed(&self, stats: &RequestStats, error: &Error) {
+        (**self).request_failed(stats, error)
+    }
+}
+
+impl fmt::Debug for ObserverRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RequestObserver")
+    }
+}
+
+pub fn new(status: u16, request_bytes: u64, response_head_bytes: u64,
+    response_body_bytes: u64, duration: Duration,
+    body_completed_cleanly: bool, connection_closed: bool)
+    -> RequestStats
+{
+    RequestStats {
+        status: status,
+        request_bytes: request_bytes,
+        response_head_bytes: response_head_bytes,
+        response_body_bytes: response_body_bytes,
+        duration: duration,
+        body_completed_cleanly: body_completed_cleanly,
+        connection_closed: connection_closed,
+    }
+}
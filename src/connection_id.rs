@@ -0,0 +1,45 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Process-wide unique identifier of a connection
+///
+/// Identifiers are assigned sequentially to both server and client
+/// connections when protocol handler is created, and are included into
+/// log messages of the protocol, so messages related to the same connection
+/// can be found among interleaved logs of many connections.
+///
+/// Displayed as `#123`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(usize);
+
+impl ConnectionId {
+    pub(crate) fn next() -> ConnectionId {
+        ConnectionId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+    /// Numeric value of the identifier
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectionId;
+
+    #[test]
+    fn sequential() {
+        let a = ConnectionId::next();
+        let b = ConnectionId::next();
+        assert!(b > a);
+        assert_eq!(format!("{}", ConnectionId(12)), "#12");
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+/// Enum representing HTTP request method
+///
+/// Method names are case-sensitive, so `Other("get")` is not the same
+/// as `Get`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method<'a> {
+    /// GET method
+    Get,
+    /// HEAD method
+    Head,
+    /// POST method
+    Post,
+    /// PUT method
+    Put,
+    /// DELETE method
+    Delete,
+    /// CONNECT method
+    Connect,
+    /// OPTIONS method
+    Options,
+    /// TRACE method
+    Trace,
+    /// PATCH method
+    Patch,
+    /// Any other (extension) method
+    Other(&'a str),
+}
+
+impl<'a> Method<'a> {
+    /// Returns method as it's written in the request line
+    pub fn as_str(&self) -> &'a str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Other(x) => x,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Method<'a> {
+    fn from(value: &'a str) -> Method<'a> {
+        match value {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            x => Method::Other(x),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Method<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
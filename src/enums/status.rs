@@ -8,6 +8,8 @@ pub enum Status {
     //  1xx status codes
     Continue,                       // 100
     SwitchingProtocol,              // 101
+    Processing,                     // 102
+    EarlyHints,                     // 103
     //  2xx status codes
     Ok,                             // 200
     Created,                        // 201
@@ -64,6 +66,8 @@ impl Status {
             //  1xx Status codes
             Status::Continue                        => 100,
             Status::SwitchingProtocol               => 101,
+            Status::Processing                      => 102,
+            Status::EarlyHints                      => 103,
             //  2xx status codes
             Status::Ok                              => 200,
             Status::Created                         => 201,
@@ -118,6 +122,8 @@ impl Status {
             // 1xx codes;
             100 => "Continue",
             101 => "Switching Protocol",
+            102 => "Processing",
+            103 => "Early Hints",
             //  2xx codes
             200 => "OK",
             201 => "Created",
@@ -183,6 +189,8 @@ impl Status {
             //  1xx
             100 => Continue,
             101 => SwitchingProtocol,
+            102 => Processing,
+            103 => EarlyHints,
             //  2xx
             200 => Ok,
             201 => Created,
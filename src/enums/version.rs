@@ -1,12 +1,53 @@
 use std::fmt;
 
 /// Enum reprsenting HTTP version.
+///
+/// More versions might be added in future (i.e. HTTP/2), so make sure you
+/// have a wildcard arm when matching on it, or better use `is_at_least`.
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 pub enum Version {
     /// Version 1.0 of the HTTP protocol
     Http10,
     /// Version 1.1 of the HTTP protocol
     Http11,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+impl Version {
+    /// Create a version from the minor version of HTTP/1.x
+    ///
+    /// Returns `None` for unknown versions (`httparse` only accepts
+    /// HTTP/1.0 and HTTP/1.1 anyway).
+    pub fn from_minor(minor: u8) -> Option<Version> {
+        match minor {
+            0 => Some(Version::Http10),
+            1 => Some(Version::Http11),
+            _ => None,
+        }
+    }
+    /// Major version number, i.e. `1` for HTTP/1.1
+    pub fn major(&self) -> u8 {
+        match *self {
+            Version::Http10 | Version::Http11 => 1,
+            Version::__Nonexhaustive => unreachable!(),
+        }
+    }
+    /// Minor version number, i.e. `1` for HTTP/1.1
+    pub fn minor(&self) -> u8 {
+        match *self {
+            Version::Http10 => 0,
+            Version::Http11 => 1,
+            Version::__Nonexhaustive => unreachable!(),
+        }
+    }
+    /// Returns true if this version is same or newer than `major.minor`
+    ///
+    /// E.g. `version.is_at_least(1, 1)` is a check for features like
+    /// keep-alive by default or chunked encoding.
+    pub fn is_at_least(&self, major: u8, minor: u8) -> bool {
+        (self.major(), self.minor()) >= (major, minor)
+    }
 }
 
 impl fmt::Display for Version {
@@ -14,7 +55,22 @@ impl fmt::Display for Version {
         match *self {
             Version::Http10 => f.write_str("HTTP/1.0"),
             Version::Http11 => f.write_str("HTTP/1.1"),
+            Version::__Nonexhaustive => unreachable!(),
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::Version;
+
+    #[test]
+    fn compare() {
+        assert!(Version::Http11.is_at_least(1, 0));
+        assert!(Version::Http11.is_at_least(1, 1));
+        assert!(!Version::Http10.is_at_least(1, 1));
+        assert!(!Version::Http11.is_at_least(2, 0));
+        assert_eq!(Version::from_minor(1), Some(Version::Http11));
+        assert_eq!(Version::from_minor(2), None);
+    }
+}
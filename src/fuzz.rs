@@ -0,0 +1,197 @@
+//! Entry points for fuzzing the parsers
+//!
+//! This module is enabled by the `fuzz` feature and is **not** a part of
+//! the stable API. It's used by the `cargo fuzz` targets in the `fuzz/`
+//! directory:
+//!
+//! ```sh
+//! cargo +nightly fuzz run request_head
+//! ```
+//!
+//! Every function here must return normally for any input: errors are
+//! expected (and ignored), panics are bugs.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+use futures::Async;
+use futures::future::FutureResult;
+use tk_bufstream::Buf;
+
+use base_serializer::{MessageState, Body, HeaderLimits};
+use chunked;
+use client;
+use server::{self, ContentCoding};
+use spans::Span;
+use websocket::{Frame, Utf8Policy};
+use {ConnectionId, RecvMode, Version};
+
+
+/// Limit of the websocket frame and the response body
+const MAX_SIZE: usize = 1 << 20;
+
+struct Dispatcher {
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+struct Nop;
+
+impl server::Dispatcher<()> for Dispatcher {
+    type Codec = Nop;
+    fn headers_received(&mut self, head: &server::Head)
+        -> Result<Nop, server::Error>
+    {
+        head.path();
+        head.host();
+        head.body_length();
+        head.connection_tokens().count();
+        head.upgrade_protocols();
+        head.get_websocket_upgrade().ok();
+        head.preferred_coding(&[ContentCoding::Gzip]);
+        head.forwarded_client(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        self.headers.extend(head.headers().map(|(name, value)| {
+            (name.to_string(), value.to_vec())
+        }));
+        Ok(Nop)
+    }
+}
+
+impl server::Codec<()> for Nop {
+    type ResponseFuture = FutureResult<server::EncoderDone<()>,
+                                       server::Error>;
+    fn recv_mode(&mut self) -> RecvMode {
+        RecvMode::buffered_upfront(MAX_SIZE)
+    }
+    fn data_received(&mut self, data: &[u8], _end: bool)
+        -> Result<Async<usize>, server::Error>
+    {
+        Ok(Async::Ready(data.len()))
+    }
+    fn start_response(&mut self, _e: server::Encoder<()>)
+        -> Self::ResponseFuture
+    {
+        unreachable!("only headers are parsed");
+    }
+}
+
+impl client::Codec<()> for Nop {
+    type Future = FutureResult<client::EncoderDone<()>, client::Error>;
+    fn start_write(&mut self, _e: client::Encoder<()>) -> Self::Future {
+        unreachable!("only headers are parsed");
+    }
+    fn headers_received(&mut self, head: &client::Head)
+        -> Result<RecvMode, client::Error>
+    {
+        head.status();
+        head.retry_after();
+        head.connection_tokens().count();
+        head.headers().count();
+        Ok(RecvMode::buffered_upfront(MAX_SIZE))
+    }
+    fn data_received(&mut self, data: &[u8], _end: bool)
+        -> Result<Async<usize>, client::Error>
+    {
+        Ok(Async::Ready(data.len()))
+    }
+}
+
+/// Parse request head, then write its headers into a response as a proxy
+/// would do
+pub fn request_head(data: &[u8]) {
+    let mut buf = Buf::new();
+    buf.extend(data);
+    let mut disp = Dispatcher { headers: Vec::new() };
+    let config = server::Config::new();
+    let now = Instant::now();
+    let info = server::ConnectionInfo::new(now);
+    match server::parse_headers(&mut buf, &mut disp, &config,
+                                ConnectionId::next(), &info, now,
+                                &Span::none())
+    {
+        Ok(Some(_)) => {}
+        Ok(None) | Err(_) => return,
+    }
+    let mut out = Buf::new();
+    let mut msg = MessageState::ResponseStart {
+        version: Version::Http11,
+        body: Body::Normal,
+        close: false,
+    };
+    let limits = HeaderLimits::default();
+    msg.response_status(&mut out, 200, "OK");
+    for &(ref name, ref value) in &disp.headers {
+        msg.add_header(&mut out, name, value, &limits).ok();
+    }
+    msg.add_length(&mut out, 0).unwrap();
+    msg.done_headers(&mut out).unwrap();
+    msg.done(&mut out);
+}
+
+/// Parse response head (both as a response to GET and to HEAD)
+pub fn response_head(data: &[u8]) {
+    for &is_head in &[false, true] {
+        let mut buf = Buf::new();
+        buf.extend(data);
+        client::parse_response_head(&mut buf, &mut Nop, is_head).ok();
+    }
+}
+
+/// Decode chunked body, feeding data in small pieces
+pub fn chunked_body(data: &[u8]) {
+    let mut state = chunked::State::new();
+    let mut buf = Buf::new();
+    for piece in data.chunks(7) {
+        buf.extend(piece);
+        if state.parse(&mut buf).is_err() {
+            return;
+        }
+        let bytes = state.buffered();
+        state.consume(bytes);
+        buf.consume(bytes);
+        if state.is_done() {
+            return;
+        }
+    }
+}
+
+/// Parse websocket frame, then check that it's serialized back the same
+pub fn websocket_frame(data: &[u8]) {
+    let mut buf = Buf::new();
+    buf.extend(data);
+    let frame = match Frame::parse_frame(&mut buf, MAX_SIZE, MAX_SIZE, None,
+                                         Utf8Policy::Fail)
+    {
+        Ok(Some((frame, _))) => frame,
+        Ok(None) | Err(_) => return,
+    };
+    let mut out = Buf::new();
+    frame.write(&mut out, false);
+    let size = out.len();
+    match Frame::parse(&mut out, MAX_SIZE, false) {
+        Ok(Some((copy, bytes))) => {
+            assert_eq!(frame, copy);
+            assert_eq!(bytes, size);
+        }
+        result => panic!("Serialized frame {:?} is parsed as {:?}",
+                         frame, result),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{request_head, response_head, chunked_body, websocket_frame};
+
+    #[test]
+    fn smoke() {
+        request_head(b"GET /x HTTP/1.1\r\nHost: a\r\nX-A: b\r\n\r\n");
+        request_head(b"GET http://a/ HTTP/1.1\r\nHost: b\r\n\
+                       Forwarded: for=[::1\r\nContent-Length: 1\r\n\r\n");
+        response_head(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+                        Retry-After: 99999999999999999999\r\n\r\n");
+        response_head(b"HTTP/1.1 204\r\n\r\n");
+        chunked_body(b"ffffffffffffffff\r\nhello");
+        chunked_body(b"5\r\nhello\r\n0\r\nX: y\r\n\r\n");
+        websocket_frame(b"\x88\x01x");
+        websocket_frame(b"\x81\x85\x01\x02\x03\x04iguhn");
+        websocket_frame(b"\x82\x7F\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF");
+    }
+}
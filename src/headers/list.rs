@@ -0,0 +1,295 @@
+//! Parser of comma-separated header lists (RFC 7230, section 7)
+//!
+//! Headers like `Accept`, `Accept-Encoding`, `TE` or `Cache-Control`
+//! contain a list of elements, each optionally followed by parameters.
+//! Parameter values may be quoted strings containing commas and semicolons,
+//! so splitting the value by comma is not enough:
+//!
+//! ```rust
+//! use tk_http::headers::list;
+//!
+//! let mut items = list::items(
+//!     r#"text/html;q=0.8, text/plain;format="a,b""#);
+//! let html = items.next().unwrap();
+//! assert_eq!(html.value(), "text/html");
+//! assert_eq!(html.quality(), Some(800));
+//! let plain = items.next().unwrap();
+//! assert_eq!(plain.param("format").unwrap(), "a,b");
+//! assert_eq!(plain.quality(), Some(1000));
+//! assert!(items.next().is_none());
+//! ```
+//!
+//! Values are expected to be already decoded from bytes (usually with
+//! `std::str::from_utf8`), headers which are repeated should be parsed
+//! one by one as if they were joined by comma.
+use std::borrow::Cow;
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+
+/// Iterator over parts of a header value separated by a character
+///
+/// Separators inside quoted strings are skipped. Parts are trimmed and
+/// empty ones are skipped. Created by `split()`.
+#[derive(Debug, Clone)]
+pub struct Split<'a> {
+    rest: Option<&'a str>,
+    separator: u8,
+}
+
+/// Iterator over elements of the list, created by `items()`
+#[derive(Debug, Clone)]
+pub struct Items<'a> {
+    split: Split<'a>,
+}
+
+/// Single element of the list with its parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Item<'a> {
+    raw: &'a str,
+}
+
+/// Iterator over `name=value` parameters of the list element
+#[derive(Debug, Clone)]
+pub struct Params<'a> {
+    split: Split<'a>,
+}
+
+/// Split `value` by `separator` taking quoted strings into account
+///
+/// `separator` must be an ASCII character (usually `,`, `;` or `=`).
+pub fn split<'a>(value: &'a str, separator: char) -> Split<'a> {
+    assert!((separator as u32) < 128);
+    Split {
+        rest: Some(value),
+        separator: separator as u8,
+    }
+}
+
+/// Iterate over elements of a comma-separated list
+pub fn items<'a>(value: &'a str) -> Items<'a> {
+    Items { split: split(value, ',') }
+}
+
+/// Remove quotes and escapes from a (possibly) quoted string
+///
+/// Values which aren't quoted are returned as is.
+pub fn unquote<'a>(value: &'a str) -> Cow<'a, str> {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return Cow::Borrowed(value);
+    }
+    let inner = &value[1..value.len()-1];
+    if !inner.contains('\\') {
+        return Cow::Borrowed(inner);
+    }
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                result.push(c);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Split `name=value` pair, value is unquoted
+///
+/// Returns `None` as a value if there is no equals sign (e.g. for
+/// `no-cache` directive of the `Cache-Control` header).
+pub fn parameter<'a>(pair: &'a str) -> (&'a str, Option<Cow<'a, str>>) {
+    let mut parts = pair.splitn(2, '=');
+    let name = parts.next().unwrap().trim();
+    (name, parts.next().map(|v| unquote(v.trim())))
+}
+
+/// Parse quality value (the value of `q` parameter)
+///
+/// Quality is returned in thousandths, i.e. `0.5` is `500` and `1` is
+/// `1000`. `None` is returned for invalid values.
+pub fn quality(value: &str) -> Option<u16> {
+    let mut parts = value.trim().splitn(2, '.');
+    let int = parts.next().unwrap();
+    let frac = parts.next().unwrap_or("");
+    if frac.len() > 3 || !frac.bytes().all(|x| x >= b'0' && x <= b'9') {
+        return None;
+    }
+    let mut q = match int {
+        "0" => 0,
+        "1" => 1000,
+        _ => return None,
+    };
+    let mut mul = 100;
+    for c in frac.bytes() {
+        q += (c - b'0') as u16 * mul;
+        mul /= 10;
+    }
+    if q > 1000 {
+        return None;
+    }
+    return Some(q);
+}
+
+impl<'a> Iterator for Split<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        while let Some(value) = self.rest.take() {
+            let mut quoted = false;
+            let mut escaped = false;
+            let mut end = None;
+            for (idx, c) in value.bytes().enumerate() {
+                if escaped {
+                    escaped = false;
+                } else if quoted && c == b'\\' {
+                    escaped = true;
+                } else if c == b'"' {
+                    quoted = !quoted;
+                } else if !quoted && c == self.separator {
+                    end = Some(idx);
+                    break;
+                }
+            }
+            let part = match end {
+                Some(idx) => {
+                    self.rest = Some(&value[idx+1..]);
+                    value[..idx].trim()
+                }
+                None => value.trim(),
+            };
+            if part.len() > 0 {
+                return Some(part);
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Item<'a>;
+    fn next(&mut self) -> Option<Item<'a>> {
+        self.split.next().map(|raw| Item { raw: raw })
+    }
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = (&'a str, Option<Cow<'a, str>>);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.split.next().map(parameter)
+    }
+}
+
+impl<'a> Item<'a> {
+    /// Whole element including parameters
+    pub fn raw(&self) -> &'a str {
+        self.raw
+    }
+    /// Value of the element without parameters (e.g. `text/html`)
+    pub fn value(&self) -> &'a str {
+        split(self.raw, ';').next().unwrap_or("")
+    }
+    /// Value of the element split as `name=value` pair
+    ///
+    /// Useful for directives, like `max-age=60` in `Cache-Control`.
+    pub fn directive(&self) -> (&'a str, Option<Cow<'a, str>>) {
+        parameter(self.value())
+    }
+    /// Parameters following the value
+    pub fn params(&self) -> Params<'a> {
+        let mut split = split(self.raw, ';');
+        split.next();  // value
+        Params { split: split }
+    }
+    /// Value of the first parameter named `name` (case-insensitive)
+    ///
+    /// Parameters without value give an empty string.
+    pub fn param(&self, name: &str) -> Option<Cow<'a, str>> {
+        self.params()
+            .find(|&(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.unwrap_or(Cow::Borrowed("")))
+    }
+    /// Quality of the element in thousandths
+    ///
+    /// Returns `1000` if there is no `q` parameter and `None` if it's
+    /// invalid (such elements are usually ignored).
+    pub fn quality(&self) -> Option<u16> {
+        match self.param("q") {
+            Some(value) => quality(&value),
+            None => Some(1000),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split, items, unquote, parameter, quality};
+
+    #[test]
+    fn split_quoted() {
+        assert_eq!(split(r#"a, "b,c" ,,d"#, ',').collect::<Vec<_>>(),
+                   vec!["a", r#""b,c""#, "d"]);
+        assert_eq!(split(r#"a="x\",y", b"#, ',').collect::<Vec<_>>(),
+                   vec![r#"a="x\",y""#, "b"]);
+        assert_eq!(split("", ',').count(), 0);
+        assert_eq!(split(" , ", ',').count(), 0);
+        assert_eq!(split(r#"a, "unterminated, b"#, ',').collect::<Vec<_>>(),
+                   vec!["a", r#""unterminated, b"#]);
+    }
+
+    #[test]
+    fn unquote_values() {
+        assert_eq!(unquote("abc"), "abc");
+        assert_eq!(unquote(r#""abc""#), "abc");
+        assert_eq!(unquote(r#""a\"b\\c""#), r#"a"b\c"#);
+        assert_eq!(unquote(r#"""#), r#"""#);
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(parameter("no-cache"), ("no-cache", None));
+        assert_eq!(parameter(r#"private="a, b""#),
+                   ("private", Some("a, b".into())));
+        assert_eq!(parameter(" max-age = 60 "),
+                   ("max-age", Some("60".into())));
+    }
+
+    #[test]
+    fn qualities() {
+        assert_eq!(quality("1"), Some(1000));
+        assert_eq!(quality("1.000"), Some(1000));
+        assert_eq!(quality("0.5"), Some(500));
+        assert_eq!(quality("0.005"), Some(5));
+        assert_eq!(quality("0"), Some(0));
+        assert_eq!(quality("1.5"), None);
+        assert_eq!(quality("0.0001"), None);
+        assert_eq!(quality("x"), None);
+    }
+
+    #[test]
+    fn accept() {
+        let list = items("text/html;level=1;q=0.7, */*;q=x, \
+                          text/plain; charset=\"utf-8\"")
+            .collect::<Vec<_>>();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0].value(), "text/html");
+        assert_eq!(list[0].param("LEVEL").unwrap(), "1");
+        assert_eq!(list[0].quality(), Some(700));
+        assert_eq!(list[1].value(), "*/*");
+        assert_eq!(list[1].quality(), None);
+        assert_eq!(list[2].param("charset").unwrap(), "utf-8");
+        assert_eq!(list[2].params().count(), 1);
+        assert_eq!(list[2].quality(), Some(1000));
+    }
+
+    #[test]
+    fn cache_control() {
+        let list = items(r#"no-cache="Set-Cookie, Vary", max-age=60"#)
+            .collect::<Vec<_>>();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].directive(),
+                   ("no-cache", Some("Set-Cookie, Vary".into())));
+        assert_eq!(list[1].directive(), ("max-age", Some("60".into())));
+    }
+}
@@ -0,0 +1,268 @@
+//! Utilities for parsing header values
+
+use std::borrow::Cow;
+use std::slice::Iter as SliceIter;
+use std::str::{from_utf8, Split};
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+use httparse::Header;
+
+pub mod list;
+
+
+/// Positions of the `Connection` headers in the list of headers
+///
+/// Only the index of the first header and the number of headers are
+/// stored, so values are not copied (or joined) to look up tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ConnectionHeaders {
+    first: usize,
+    count: usize,
+}
+
+/// Iterator over comma-separated tokens of all `Connection` headers
+///
+/// Tokens are trimmed and empty ones are skipped. Created by
+/// `connection_tokens()` method of request or response head.
+#[derive(Debug, Clone)]
+pub struct ConnectionTokens<'a> {
+    headers: SliceIter<'a, Header<'a>>,
+    left: usize,
+    tokens: Option<Split<'a, char>>,
+}
+
+impl ConnectionHeaders {
+    pub fn new() -> ConnectionHeaders {
+        ConnectionHeaders { first: 0, count: 0 }
+    }
+    /// Register `Connection` header at the index `idx` (in order)
+    pub fn add(&mut self, idx: usize) {
+        if self.count == 0 {
+            self.first = idx;
+        }
+        self.count += 1;
+    }
+    pub fn tokens<'a>(&self, headers: &'a [Header<'a>])
+        -> ConnectionTokens<'a>
+    {
+        ConnectionTokens {
+            headers: headers[self.first..].iter(),
+            left: self.count,
+            tokens: None,
+        }
+    }
+    /// Value of the first header
+    ///
+    /// Values must be checked to be valid utf-8 when scanning headers.
+    pub fn first<'a>(&self, headers: &'a [Header<'a>]) -> Option<&'a str> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(from_utf8(headers[self.first].value).unwrap_or("").trim())
+    }
+    /// Value of the header, values of multiple headers are joined by comma
+    ///
+    /// Values must be checked to be valid utf-8 when scanning headers.
+    pub fn value<'a>(&self, headers: &'a [Header<'a>])
+        -> Option<Cow<'a, str>>
+    {
+        let mut result = None::<Cow<_>>;
+        for header in headers[self.first..].iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("Connection"))
+            .take(self.count)
+        {
+            let value = from_utf8(header.value).unwrap_or("").trim();
+            result = match result {
+                Some(x) => Some(x + ", " + value),
+                None => Some(value.into()),
+            };
+        }
+        result
+    }
+}
+
+impl<'a> Iterator for ConnectionTokens<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            if let Some(ref mut tokens) = self.tokens {
+                while let Some(token) = tokens.next() {
+                    let token = token.trim();
+                    if token.len() > 0 {
+                        return Some(token);
+                    }
+                }
+            }
+            if self.left == 0 {
+                return None;
+            }
+            loop {
+                let header = self.headers.next()?;
+                if header.name.eq_ignore_ascii_case("Connection") {
+                    self.left -= 1;
+                    self.tokens = Some(from_utf8(header.value)
+                        .unwrap_or("").split(','));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// header value is byte sequence
+// we need case insensitive comparison and strip out of the whitespace
+pub(crate) fn is_close(val: &[u8]) -> bool {
+    if val.len() < "close".len() {
+        return false;
+    }
+    let mut iter = val.iter();
+    for (idx, &ch) in iter.by_ref().enumerate() {
+        match ch {
+            b'\r' | b'\n' | b' ' | b'\t' => continue,
+            b'c' | b'C' => {
+                if idx + "close".len() > val.len() {
+                    return false;
+                }
+                break;
+            }
+            _ => return false,
+        }
+    }
+    for (idx, ch) in iter.by_ref().take(4).enumerate() {
+        if b"lose"[idx] != ch.to_ascii_lowercase() {
+            return false;
+        }
+    }
+    for &ch in iter {
+        if !matches!(ch, b'\r' | b'\n' | b' ' | b'\t') {
+            return false;
+        }
+    }
+    return true;
+}
+
+// header value is byte sequence
+// we need case insensitive comparison and strip out of the whitespace
+pub(crate) fn is_chunked(val: &[u8]) -> bool {
+    if val.len() < "chunked".len() {
+        return false;
+    }
+    let mut iter = val.iter();
+    for (idx, &ch) in iter.by_ref().enumerate() {
+        match ch {
+            b'\r' | b'\n' | b' ' | b'\t' => continue,
+            b'c' | b'C' => {
+                if idx + "chunked".len() > val.len() {
+                    return false;
+                }
+                break;
+            }
+            _ => return false,
+        }
+    }
+    for (idx, ch) in iter.by_ref().take(6).enumerate() {
+        if b"hunked"[idx] != ch.to_ascii_lowercase() {
+            return false;
+        }
+    }
+    for &ch in iter {
+        if !matches!(ch, b'\r' | b'\n' | b' ' | b'\t') {
+            return false;
+        }
+    }
+    return true;
+}
+
+// header value is byte sequence
+// we need case insensitive comparison and strip out of the whitespace
+pub(crate) fn is_continue(val: &[u8]) -> bool {
+    if val.len() < "100-continue".len() {
+        return false;
+    }
+    let mut iter = val.iter();
+    for (idx, &ch) in iter.by_ref().enumerate() {
+        match ch {
+            b'\r' | b'\n' | b' ' | b'\t' => continue,
+            b'1' => {
+                if idx + "100-continue".len() > val.len() {
+                    return false;
+                }
+                break;
+            }
+            _ => return false,
+        }
+    }
+    for (idx, ch) in iter.by_ref().take(11).enumerate() {
+        if b"00-continue"[idx] != ch.to_ascii_lowercase() {
+            return false;
+        }
+    }
+    for &ch in iter {
+        if !matches!(ch, b'\r' | b'\n' | b' ' | b'\t') {
+            return false;
+        }
+    }
+    return true;
+}
+
+#[cfg(test)]
+mod test {
+    use httparse::Header;
+    use super::{is_chunked, is_close, is_continue, ConnectionHeaders};
+
+    #[test]
+    fn connection_tokens() {
+        let headers = [
+            Header { name: "Host", value: b"example.com" },
+            Header { name: "Connection", value: b" Keep-Alive, ,X-Hop " },
+            Header { name: "X-Hop", value: b"1" },
+            Header { name: "connection", value: b"close" },
+        ];
+        let mut conn = ConnectionHeaders::new();
+        assert_eq!(conn.tokens(&headers).count(), 0);
+        assert_eq!(conn.value(&headers), None);
+        conn.add(1);
+        conn.add(3);
+        assert_eq!(conn.tokens(&headers).collect::<Vec<_>>(),
+                   vec!["Keep-Alive", "X-Hop", "close"]);
+        assert_eq!(conn.value(&headers).unwrap(), "Keep-Alive, ,X-Hop, close");
+    }
+
+    #[test]
+    fn test_chunked() {
+        assert!(is_chunked(b"chunked"));
+        assert!(is_chunked(b"Chunked"));
+        assert!(is_chunked(b"chuNKED"));
+        assert!(is_chunked(b"CHUNKED"));
+        assert!(is_chunked(b"   CHUNKED"));
+        assert!(is_chunked(b"   CHUNKED  "));
+        assert!(is_chunked(b"chunked  "));
+        assert!(is_chunked(b"   CHUNKED"));
+        assert!(!is_chunked(b"   CHUNKED 1 "));
+    }
+
+    #[test]
+    fn test_close() {
+        assert!(is_close(b"close"));
+        assert!(is_close(b"Close"));
+        assert!(is_close(b"clOSE"));
+        assert!(is_close(b"CLOSE"));
+        assert!(is_close(b" CLOSE"));
+        assert!(is_close(b"   close   "));
+        assert!(!is_close(b"Close  1 "));
+        assert!(!is_close(b" xclose   "));
+    }
+
+    #[test]
+    fn test_continue() {
+        assert!(is_continue(b"100-continue"));
+        assert!(is_continue(b"100-Continue"));
+        assert!(is_continue(b"100-conTINUE"));
+        assert!(is_continue(b"100-CONTINUE"));
+        assert!(is_continue(b"  100-CONTINUE"));
+        assert!(is_continue(b"   100-continue   "));
+        assert!(!is_continue(b"100-continue y  "));
+        assert!(!is_continue(b"100-coztinue   "));
+    }
+}
@@ -7,31 +7,81 @@
 //! For client implementation it's recommended to use the library
 //! together with [tk-pool](https://crates.io/crates/tk-pool).
 //!
+//! Modules `server`, `client` and `websocket` are enabled by cargo features
+//! of the same name (all of them are on by default). Websocket support of
+//! the server (handshake, `BufferedDispatcher::new_with_websockets` and
+//! `server::polling`) is only available when `websocket` is enabled too.
+//!
+//! With `tracing` feature both server and client protocols create
+//! [tracing](https://crates.io/crates/tracing) spans for every connection
+//! and request (with `method`, `path`, `status` and `bytes` fields), codec
+//! callbacks are run inside of the request span.
+//!
+//! The `compression` feature enables `Encoder::add_compression` (gzip
+//! response bodies) in server and `Config::decompress` in client.
+//!
 #![recursion_limit="200"]
 #![warn(missing_docs)]
 
 extern crate futures;
-extern crate url;
-extern crate sha1;
-extern crate rand;
 extern crate httparse;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate netbuf;
 extern crate tk_bufstream;
-extern crate byteorder;
+#[cfg(feature="client")] extern crate url;
+#[cfg(any(feature="server", feature="websocket"))] extern crate bytes;
+#[cfg(feature="websocket")] extern crate sha1;
+#[cfg(feature="websocket")] extern crate byteorder;
+#[cfg(any(feature="server", feature="client", feature="websocket",
+          feature="testing"))]
+extern crate rand;
 #[macro_use(quick_error)] extern crate quick_error;
 #[macro_use] extern crate matches;
 #[macro_use] extern crate log;
-#[cfg(feature="date_header")]extern crate httpdate;
+#[cfg(any(feature="date_header", feature="client"))]
+extern crate httpdate;
+#[cfg(feature="tls")]extern crate native_tls;
+#[cfg(feature="tls")]extern crate tokio_tls;
+#[cfg(feature="listener")] extern crate tk_listen;
+#[cfg(feature="tracing")] extern crate tracing;
+#[cfg(feature="compression")] extern crate flate2;
 
-pub mod server;
-pub mod client;
-pub mod websocket;
+#[cfg(feature="server")] pub mod server;
+#[cfg(feature="client")] pub mod client;
+#[cfg(feature="websocket")] pub mod websocket;
+#[cfg(feature="testing")] pub mod testing;
+pub mod timer;
+#[cfg(any(feature="server", feature="client"))] pub mod record;
 mod enums;
-mod headers;
+// Modules shared by server, client and websockets, parts of them are unused
+// when only some of the features are enabled
+#[cfg_attr(not(all(feature="server", feature="client")), allow(dead_code))]
 mod base_serializer;
+#[cfg(any(feature="server", feature="client"))]
+#[cfg_attr(not(all(feature="server", feature="client")), allow(dead_code))]
+pub mod headers;
+#[cfg(any(feature="server", feature="client"))]
 mod chunked;
+#[cfg(any(feature="server", feature="client"))]
+mod connection_id;
+#[cfg(any(feature="server", feature="client"))]
+#[cfg_attr(not(all(feature="server", feature="client")), allow(dead_code))]
+mod spans;
+#[cfg(any(feature="server", feature="client"))]
+mod recv_mode;
+#[cfg(any(feature="server", feature="client"))]
+#[cfg_attr(not(all(feature="server", feature="client")), allow(dead_code))]
 mod body_parser;
+#[cfg(feature="bench")]
+#[doc(hidden)]
+pub mod bench;
+#[cfg(feature="fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
 
-pub use enums::{Version, Status};
+pub use enums::{Version, Status, Method};
+#[cfg(any(feature="server", feature="client"))]
+pub use connection_id::ConnectionId;
+#[cfg(any(feature="server", feature="client"))]
+pub use recv_mode::RecvMode;
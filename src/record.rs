@@ -0,0 +1,809 @@
+//! Recording of HTTP traffic for debugging and integration tests
+//!
+//! `Recorded` wraps a connection (before it's passed to `server::Proto` or
+//! `client::Proto`) and reconstructs requests and responses from the bytes
+//! going through it. Every finished request-response pair is passed
+//! to a `Writer` as an `Entry`, which is modelled after an entry of the HAR
+//! (HTTP Archive) format:
+//!
+//! ```rust,ignore
+//! let entries = Arc::new(Mutex::new(Vec::new()));
+//! let log = entries.clone();
+//! let conn = Recorded::server(socket, move |e| log.lock().unwrap().push(e))
+//!     .max_body_size(4096);
+//! let proto = server::Proto::new(conn, &cfg, dispatcher, &handle);
+//! ```
+//!
+//! Recorded requests can be sent again with the `Replay` client codec.
+//!
+//! Pipelined requests are matched with responses in order. Interim (1xx)
+//! responses are skipped. Nothing is recorded after a protocol switch
+//! (`101 Switching Protocols` or successful `CONNECT`), or if the traffic
+//! can't be parsed as HTTP.
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::str::from_utf8;
+use std::time::{Duration, Instant, SystemTime};
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+use futures::Poll;
+#[cfg(feature="client")]
+use futures::Async;
+#[cfg(feature="client")]
+use futures::future::{FutureResult, ok, err};
+#[cfg(feature="client")]
+use futures::sync::oneshot::{channel, Sender, Receiver};
+use httparse::{self, Header, EMPTY_HEADER};
+use netbuf::Buf;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use chunked;
+use headers;
+use enums::Version;
+#[cfg(feature="client")]
+use client::{self, Encoder, EncoderDone, Head, RecvMode, Idempotency};
+#[cfg(feature="client")]
+use client::ErrorEnum as ClientError;
+
+
+/// Default limit of the recorded body size
+pub const MAX_BODY_SIZE: usize = 65536;
+/// Maximum number of headers in a message that can be recorded
+const MAX_HEADERS: usize = 128;
+
+
+/// A recorded request
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// Request method
+    pub method: String,
+    /// Request target as sent in the request line
+    pub target: String,
+    /// HTTP version
+    pub version: Version,
+    /// All headers (including hop-by-hop ones)
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Body (decoded from chunked encoding) truncated to the limit
+    pub body: Vec<u8>,
+    /// Full size of the body, may be larger than `body.len()`
+    pub body_size: u64,
+}
+
+/// A recorded response
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// Status code
+    pub status: u16,
+    /// Reason phrase
+    pub reason: String,
+    /// HTTP version
+    pub version: Version,
+    /// All headers (including hop-by-hop ones)
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Body (decoded from chunked encoding) truncated to the limit
+    pub body: Vec<u8>,
+    /// Full size of the body, may be larger than `body.len()`
+    pub body_size: u64,
+}
+
+/// Timings of a request, like `timings` of the HAR entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// From the first to the last byte of the request
+    pub send: Duration,
+    /// From the last byte of the request to the first byte of the response
+    ///
+    /// Zero if response started before request has been sent fully.
+    pub wait: Duration,
+    /// From the first to the last byte of the response
+    pub receive: Duration,
+}
+
+/// A request with its response
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Time when first byte of request has been seen
+    pub started: SystemTime,
+    /// The request
+    pub request: Request,
+    /// The response, `None` if connection closed before it's received
+    pub response: Option<Response>,
+    /// Timings, only the ones already passed are non-zero
+    pub timings: Timings,
+}
+
+/// Receiver of recorded entries
+///
+/// Implemented for closures, so you can store entries into a vector,
+/// serialize them, or log.
+pub trait Writer {
+    /// Called for every request when response is finished
+    ///
+    /// Requests that have no response are passed when connection
+    /// is closed.
+    fn entry(&mut self, entry: Entry);
+}
+
+impl<F: FnMut(Entry)> Writer for F {
+    fn entry(&mut self, entry: Entry) {
+        (self)(entry)
+    }
+}
+
+/// A connection wrapper which records the traffic
+///
+/// See module-level documentation for details.
+pub struct Recorded<S, W: Writer> {
+    io: S,
+    server: bool,
+    recorder: Recorder<W>,
+}
+
+enum Framing {
+    Fixed(u64),
+    Chunked(chunked::State),
+    Eof,
+}
+
+enum State {
+    Head,
+    Body(Framing),
+    Opaque,
+}
+
+struct Direction {
+    buf: Buf,
+    state: State,
+    started: Option<Instant>,
+}
+
+struct Pending {
+    started: SystemTime,
+    request: Request,
+    request_start: Instant,
+    request_end: Option<Instant>,
+    response: Option<Response>,
+    response_start: Option<Instant>,
+}
+
+struct Recorder<W: Writer> {
+    requests: Direction,
+    responses: Direction,
+    pending: VecDeque<Pending>,
+    max_body_size: usize,
+    writer: W,
+}
+
+impl<S, W: Writer> Recorded<S, W> {
+    /// Wrap an accepted (server-side) connection
+    pub fn server(io: S, writer: W) -> Recorded<S, W> {
+        Recorded::new(io, true, writer)
+    }
+    /// Wrap an outgoing (client-side) connection
+    pub fn client(io: S, writer: W) -> Recorded<S, W> {
+        Recorded::new(io, false, writer)
+    }
+    fn new(io: S, server: bool, writer: W) -> Recorded<S, W> {
+        Recorded {
+            io: io,
+            server: server,
+            recorder: Recorder {
+                requests: Direction::new(),
+                responses: Direction::new(),
+                pending: VecDeque::new(),
+                max_body_size: MAX_BODY_SIZE,
+                writer: writer,
+            },
+        }
+    }
+    /// Set maximum size of request and response body to keep
+    ///
+    /// Larger bodies are truncated (see `body_size` field). Default is
+    /// `MAX_BODY_SIZE`.
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.recorder.max_body_size = bytes;
+        self
+    }
+    /// Returns a reference to the wrapped connection
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+}
+
+impl Direction {
+    fn new() -> Direction {
+        Direction {
+            buf: Buf::new(),
+            state: State::Head,
+            started: None,
+        }
+    }
+    fn stop(&mut self) {
+        self.state = State::Opaque;
+        let len = self.buf.len();
+        self.buf.consume(len);
+    }
+}
+
+fn convert_headers(headers: &[Header]) -> Vec<(String, Vec<u8>)> {
+    headers.iter()
+        .map(|h| (h.name.to_string(), h.value.to_vec()))
+        .collect()
+}
+
+fn version(ver: Option<u8>) -> Version {
+    if ver == Some(0) { Version::Http10 } else { Version::Http11 }
+}
+
+/// Returns `None` if there is no framing headers
+fn body_framing(headers: &[Header]) -> Result<Option<Framing>, ()> {
+    let mut result = None;
+    for header in headers {
+        if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            if header.value.split(|&x| x == b',').last()
+                .map(headers::is_chunked).unwrap_or(false)
+            {
+                return Ok(Some(Framing::Chunked(chunked::State::new())));
+            }
+        } else if header.name.eq_ignore_ascii_case("Content-Length") {
+            let len = from_utf8(header.value).ok()
+                .and_then(|x| x.trim().parse().ok())
+                .ok_or(())?;
+            result = Some(Framing::Fixed(len));
+        }
+    }
+    Ok(result)
+}
+
+/// Moves body data from the buffer, returns `true` when body is finished
+fn read_body(framing: &mut Framing, buf: &mut Buf,
+    body: &mut Vec<u8>, body_size: &mut u64, max_body_size: usize)
+    -> Result<bool, ()>
+{
+    let (bytes, done) = match *framing {
+        Framing::Fixed(ref mut left) => {
+            let bytes = min(*left, buf.len() as u64) as usize;
+            *left -= bytes as u64;
+            (bytes, *left == 0)
+        }
+        Framing::Chunked(ref mut state) => {
+            state.parse(buf).map_err(|_| ())?;
+            let bytes = state.buffered();
+            state.consume(bytes);
+            (bytes, state.is_done())
+        }
+        Framing::Eof => (buf.len(), false),
+    };
+    let keep = min(bytes, max_body_size.saturating_sub(body.len()));
+    body.extend_from_slice(&buf[..keep]);
+    *body_size += bytes as u64;
+    buf.consume(bytes);
+    Ok(done)
+}
+
+impl<W: Writer> Recorder<W> {
+    fn requests_received(&mut self, data: &[u8]) {
+        if matches!(self.requests.state, State::Opaque) {
+            return;
+        }
+        if self.requests.started.is_none() && data.len() > 0 {
+            self.requests.started = Some(Instant::now());
+        }
+        self.requests.buf.extend(data);
+        if self.parse_requests().is_err() {
+            self.requests.stop();
+        }
+    }
+    fn responses_received(&mut self, data: &[u8]) {
+        if matches!(self.responses.state, State::Opaque) {
+            return;
+        }
+        if self.responses.started.is_none() && data.len() > 0 {
+            self.responses.started = Some(Instant::now());
+        }
+        self.responses.buf.extend(data);
+        if self.parse_responses().is_err() {
+            self.responses.stop();
+        }
+    }
+    fn parse_requests(&mut self) -> Result<(), ()> {
+        loop {
+            let done = match self.requests.state {
+                State::Opaque => return Ok(()),
+                State::Head => {
+                    let (bytes, pending, body) = {
+                        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+                        let mut raw = httparse::Request::new(&mut headers);
+                        let bytes = match raw.parse(&self.requests.buf[..]) {
+                            Ok(httparse::Status::Complete(bytes)) => bytes,
+                            Ok(httparse::Status::Partial) => return Ok(()),
+                            Err(_) => return Err(()),
+                        };
+                        let method = raw.method.unwrap();
+                        let body = if method.eq_ignore_ascii_case("CONNECT") {
+                            Framing::Fixed(0)
+                        } else {
+                            body_framing(raw.headers)?
+                                .unwrap_or(Framing::Fixed(0))
+                        };
+                        let started = self.requests.started.take()
+                            .unwrap_or_else(Instant::now);
+                        (bytes, Pending {
+                            started: SystemTime::now() - started.elapsed(),
+                            request: Request {
+                                method: method.to_string(),
+                                target: raw.path.unwrap().to_string(),
+                                version: version(raw.version),
+                                headers: convert_headers(raw.headers),
+                                body: Vec::new(),
+                                body_size: 0,
+                            },
+                            request_start: started,
+                            request_end: None,
+                            response: None,
+                            response_start: None,
+                        }, body)
+                    };
+                    self.requests.buf.consume(bytes);
+                    self.pending.push_back(pending);
+                    self.requests.state = State::Body(body);
+                    continue;
+                }
+                State::Body(ref mut framing) => {
+                    let pending = self.pending.back_mut()
+                        .expect("request is pending");
+                    read_body(framing, &mut self.requests.buf,
+                        &mut pending.request.body,
+                        &mut pending.request.body_size,
+                        self.max_body_size)?
+                }
+            };
+            if !done {
+                return Ok(());
+            }
+            if let Some(pending) = self.pending.back_mut() {
+                pending.request_end = Some(Instant::now());
+            }
+            self.requests.state = State::Head;
+            if self.requests.buf.len() > 0 {
+                self.requests.started = Some(Instant::now());
+            }
+        }
+    }
+    fn parse_responses(&mut self) -> Result<(), ()> {
+        loop {
+            let done = match self.responses.state {
+                State::Opaque => return Ok(()),
+                State::Head => {
+                    let (bytes, response, body, switch) = {
+                        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+                        let mut raw = httparse::Response::new(&mut headers);
+                        let bytes = match raw.parse(&self.responses.buf[..]) {
+                            Ok(httparse::Status::Complete(bytes)) => bytes,
+                            Ok(httparse::Status::Partial) => return Ok(()),
+                            Err(_) => return Err(()),
+                        };
+                        let code = raw.code.unwrap();
+                        let method = match self.pending.front() {
+                            Some(p) => &p.request.method[..],
+                            None => return Err(()),
+                        };
+                        let switch = code == 101 ||
+                            method.eq_ignore_ascii_case("CONNECT") &&
+                            code >= 200 && code < 300;
+                        if code >= 100 && code < 200 && !switch {
+                            // interim response
+                            (bytes, None, Framing::Fixed(0), false)
+                        } else {
+                            let body = if switch ||
+                                method.eq_ignore_ascii_case("HEAD") ||
+                                code == 204 || code == 304
+                            {
+                                Framing::Fixed(0)
+                            } else {
+                                body_framing(raw.headers)?
+                                    .unwrap_or(Framing::Eof)
+                            };
+                            (bytes, Some(Response {
+                                status: code,
+                                reason: raw.reason.unwrap_or("").to_string(),
+                                version: version(raw.version),
+                                headers: convert_headers(raw.headers),
+                                body: Vec::new(),
+                                body_size: 0,
+                            }), body, switch)
+                        }
+                    };
+                    self.responses.buf.consume(bytes);
+                    if let Some(response) = response {
+                        let pending = self.pending.front_mut()
+                            .expect("request is pending");
+                        pending.response = Some(response);
+                        pending.response_start = self.responses.started;
+                        self.responses.state = State::Body(body);
+                        if switch {
+                            self.finish_response();
+                            self.requests.stop();
+                            self.responses.stop();
+                            return Ok(());
+                        }
+                    }
+                    continue;
+                }
+                State::Body(ref mut framing) => {
+                    let pending = self.pending.front_mut()
+                        .expect("request is pending");
+                    let response = pending.response.as_mut()
+                        .expect("response is started");
+                    read_body(framing, &mut self.responses.buf,
+                        &mut response.body, &mut response.body_size,
+                        self.max_body_size)?
+                }
+            };
+            if !done {
+                return Ok(());
+            }
+            self.finish_response();
+            self.responses.state = State::Head;
+            self.responses.started = if self.responses.buf.len() > 0 {
+                Some(Instant::now())
+            } else {
+                None
+            };
+        }
+    }
+    fn finish_response(&mut self) {
+        if let Some(pending) = self.pending.pop_front() {
+            self.emit(pending);
+        }
+    }
+    fn emit(&mut self, p: Pending) {
+        let now = Instant::now();
+        let request_end = p.request_end.unwrap_or(now);
+        let (wait, receive) = match p.response_start {
+            Some(start) if start > request_end => {
+                (start - request_end, now - start)
+            }
+            Some(start) => (Duration::new(0, 0), now - start),
+            None => (Duration::new(0, 0), Duration::new(0, 0)),
+        };
+        self.writer.entry(Entry {
+            started: p.started,
+            request: p.request,
+            response: p.response,
+            timings: Timings {
+                send: request_end - p.request_start,
+                wait: wait,
+                receive: receive,
+            },
+        });
+    }
+    /// Connection is closed, response delimited by EOF is done and
+    /// all other requests have no response
+    fn finish(&mut self) {
+        if matches!(self.responses.state, State::Body(Framing::Eof)) {
+            self.finish_response();
+        }
+        while let Some(mut pending) = self.pending.pop_front() {
+            pending.response = None;
+            pending.response_start = None;
+            self.emit(pending);
+        }
+        self.requests.stop();
+        self.responses.stop();
+    }
+}
+
+impl<S: Read, W: Writer> Read for Recorded<S, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.io.read(buf)?;
+        if self.server {
+            self.recorder.requests_received(&buf[..bytes]);
+        } else if bytes == 0 && buf.len() > 0 {
+            self.recorder.finish();
+        } else {
+            self.recorder.responses_received(&buf[..bytes]);
+        }
+        Ok(bytes)
+    }
+}
+
+impl<S: Write, W: Writer> Write for Recorded<S, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.io.write(buf)?;
+        if self.server {
+            self.recorder.responses_received(&buf[..bytes]);
+        } else {
+            self.recorder.requests_received(&buf[..bytes]);
+        }
+        Ok(bytes)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<S: AsyncRead, W: Writer> AsyncRead for Recorded<S, W> {}
+
+impl<S: AsyncWrite, W: Writer> AsyncWrite for Recorded<S, W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+impl<W: Writer> Drop for Recorder<W> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// A client codec which sends a recorded request again
+///
+/// The response is recorded in the same form as by `Recorded`, so they
+/// can be compared. Request is sent as is, except `Content-Length` and
+/// `Transfer-Encoding` headers which are replaced by `Content-Length` of
+/// the recorded body. Note: if the body was truncated when recording
+/// (`body_size` is larger than `body.len()`) truncated body is sent.
+#[cfg(feature="client")]
+pub struct Replay {
+    request: Request,
+    sender: Option<Sender<Result<Response, client::Error>>>,
+    response: Option<Response>,
+    max_body_size: usize,
+}
+
+#[cfg(feature="client")]
+impl Replay {
+    /// Create a codec for the request and a future that yields response
+    pub fn new(request: &Request)
+        -> (Replay, Receiver<Result<Response, client::Error>>)
+    {
+        let (tx, rx) = channel();
+        (Replay {
+            request: request.clone(),
+            sender: Some(tx),
+            response: None,
+            max_body_size: MAX_BODY_SIZE,
+         }, rx)
+    }
+    /// Set maximum size of response body (default `MAX_BODY_SIZE`)
+    ///
+    /// Unlike in `Recorded` larger responses are not truncated but fail.
+    pub fn max_body_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_body_size = bytes;
+        self
+    }
+}
+
+#[cfg(feature="client")]
+impl<S> client::Codec<S> for Replay {
+    type Future = FutureResult<EncoderDone<S>, client::Error>;
+    fn start_write(&mut self, mut e: Encoder<S>) -> Self::Future {
+        let req = &self.request;
+        e.request_line(&req.method, &req.target, req.version);
+        for &(ref name, ref value) in &req.headers {
+            if name.eq_ignore_ascii_case("Content-Length") ||
+               name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            if e.add_header(name, value).is_err() {
+                return err(ClientError::InvalidHeader.into());
+            }
+        }
+        if req.body.len() > 0 || req.body_size > 0 {
+            e.add_length(req.body.len() as u64).unwrap();
+        }
+        e.done_headers().unwrap();
+        e.write_body(&req.body);
+        ok(e.done())
+    }
+    fn headers_received(&mut self, headers: &Head)
+        -> Result<RecvMode, client::Error>
+    {
+        let (status, reason) = headers.raw_status();
+        self.response = Some(Response {
+            status: status,
+            reason: reason.to_string(),
+            version: headers.version(),
+            headers: convert_headers(headers.all_headers()),
+            body: Vec::new(),
+            body_size: 0,
+        });
+        Ok(RecvMode::buffered(self.max_body_size))
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, client::Error>
+    {
+        assert!(end);
+        let mut response = self.response.take().expect("headers received");
+        response.body = data.to_vec();
+        response.body_size = data.len() as u64;
+        self.sender.take().expect("response is sent once")
+            .send(Ok(response))
+            .map_err(|_| debug!("Unused replayed response")).ok();
+        Ok(Async::Ready(data.len()))
+    }
+    fn idempotency(&self) -> Idempotency {
+        Idempotency::from_method(&self.request.method)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::io::{Read, Write};
+
+    use tk_bufstream::MockData;
+    #[cfg(feature="client")] use futures::{Future, Sink, AsyncSink};
+    #[cfg(feature="client")] use futures::future::lazy;
+    #[cfg(feature="client")] use tokio_core::reactor::Core;
+
+    use enums::Version;
+    #[cfg(feature="client")] use client::{Proto, Config, Error};
+    use super::{Recorded, Entry};
+    #[cfg(feature="client")] use super::{Replay, Request};
+
+    fn record<F>(server: bool, fun: F) -> Vec<Entry>
+        where F: FnOnce(&mut Recorded<MockData, Box<FnMut(Entry)>>,
+                        &MockData)
+    {
+        let entries = Rc::new(RefCell::new(Vec::new()));
+        let log = entries.clone();
+        let mock = MockData::new();
+        {
+            let writer: Box<FnMut(Entry)> = Box::new(move |e| {
+                log.borrow_mut().push(e)
+            });
+            let mut conn = if server {
+                Recorded::server(mock.clone(), writer)
+            } else {
+                Recorded::client(mock.clone(), writer)
+            }.max_body_size(4);
+            fun(&mut conn, &mock);
+        }
+        let result = entries.borrow().clone();
+        result
+    }
+
+    fn read_all(conn: &mut Recorded<MockData, Box<FnMut(Entry)>>) {
+        let mut buf = [0u8; 1024];
+        while conn.read(&mut buf).is_ok() {}
+    }
+
+    #[test]
+    fn server() {
+        let entries = record(true, |conn, mock| {
+            mock.add_input("POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\n\
+                            hello\
+                            GET /b HTTP/1.0\r\n\r\n");
+            read_all(conn);
+            conn.write_all(b"HTTP/1.1 200 OK\r\n\
+                             Transfer-Encoding: chunked\r\n\r\n\
+                             2\r\nok\r\n0\r\n\r\n").unwrap();
+            conn.write_all(b"HTTP/1.1 404 Not Found\r\n").unwrap();
+        });
+        assert_eq!(entries.len(), 2);
+        let req = &entries[0].request;
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.target, "/a");
+        assert_eq!(req.version, Version::Http11);
+        assert_eq!(req.headers,
+            vec![("Content-Length".to_string(), b"5".to_vec())]);
+        assert_eq!(req.body, b"hell");
+        assert_eq!(req.body_size, 5);
+        let resp = entries[0].response.as_ref().unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.reason, "OK");
+        assert_eq!(resp.body, b"ok");
+        assert_eq!(resp.body_size, 2);
+        // connection closed before response is complete
+        assert_eq!(entries[1].request.target, "/b");
+        assert_eq!(entries[1].request.version, Version::Http10);
+        assert!(entries[1].response.is_none());
+    }
+
+    #[test]
+    fn client() {
+        let entries = record(false, |conn, mock| {
+            conn.write_all(b"HEAD / HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                             GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .unwrap();
+            mock.add_input("HTTP/1.1 100 Continue\r\n\r\n\
+                            HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n\
+                            HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n\
+                            body");
+            read_all(conn);
+        });
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].request.method, "HEAD");
+        let resp = entries[0].response.as_ref().unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body_size, 0);
+        // response delimited by connection close
+        let resp = entries[1].response.as_ref().unwrap();
+        assert_eq!(resp.body, b"body");
+    }
+
+    #[test]
+    fn upgrade() {
+        let entries = record(true, |conn, mock| {
+            mock.add_input("GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\
+                            Connection: upgrade\r\n\r\n");
+            read_all(conn);
+            conn.write_all(b"HTTP/1.1 101 Switching Protocols\r\n\
+                             Upgrade: websocket\r\n\r\n\
+                             \x81\x02hi").unwrap();
+            mock.add_input("GET /ignored HTTP/1.1\r\n\r\n");
+            read_all(conn);
+        });
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].response.as_ref().unwrap().status, 101);
+    }
+
+    #[cfg(feature="client")]
+    fn request(headers: Vec<(&str, &[u8])>) -> Request {
+        Request {
+            method: "POST".to_string(),
+            target: "/a".to_string(),
+            version: Version::Http11,
+            headers: headers.into_iter()
+                .map(|(n, v)| (n.to_string(), v.to_vec())).collect(),
+            body: b"hell".to_vec(),
+            body_size: 5,
+        }
+    }
+
+    #[test]
+    #[cfg(feature="client")]
+    fn replay() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+                                   &Config::new().done());
+        let (codec, rx) = Replay::new(&request(vec![
+            ("Host", b"example.com"),
+            ("Transfer-Encoding", b"chunked"),
+            ("X-A", b"1"),
+        ]));
+        core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(codec)?, AsyncSink::Ready));
+            proto.poll_complete()?;
+            Ok(())
+        })).unwrap();
+        assert_eq!(String::from_utf8(mock.output(..)).unwrap(),
+            "POST /a HTTP/1.1\r\nHost: example.com\r\nX-A: 1\r\n\
+             Content-Length: 4\r\n\r\nhell");
+        mock.add_input("HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\n\
+                        ok");
+        core.run(lazy(|| proto.poll_complete())).unwrap();
+        let resp = rx.wait().unwrap().unwrap();
+        assert_eq!(resp.status, 201);
+        assert_eq!(resp.reason, "Created");
+        assert_eq!(resp.headers,
+            vec![("Content-Length".to_string(), b"2".to_vec())]);
+        assert_eq!(resp.body, b"ok");
+        assert_eq!(resp.body_size, 2);
+    }
+
+    #[test]
+    #[cfg(feature="client")]
+    fn replay_invalid_header() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(), &core.handle(),
+                                   &Config::new().done());
+        let (codec, _rx) = Replay::new(&request(vec![
+            ("X-A", b"a\r\nX-B: b"),
+        ]));
+        let err = core.run(lazy(|| -> Result<(), Error> {
+            assert!(matches!(proto.start_send(codec)?, AsyncSink::Ready));
+            proto.poll_complete()?;
+            Ok(())
+        })).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(InvalidHeader)");
+    }
+}
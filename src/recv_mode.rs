@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+
+/// This type is returned from `headers_received` handler of either
+/// client or server protocol handler
+///
+/// The marker is used to denote whether you want to have the whole message
+/// body buffered for you or read chunk by chunk.
+///
+/// The `Progressive` (chunk by chunk) mode is mostly useful for proxy servers.
+/// Or it may be useful if your handler is able to parse data without holding
+/// everything in the memory.
+///
+/// Otherwise, it's best to use `Buffered` mode (for example, comparing with
+/// using your own buffering). We do our best to optimize it for you.
+///
+/// The same type is used by both `server::Codec` and `client::Codec` (it's
+/// also reexported as `server::RecvMode` and `client::RecvMode`), so a
+/// middleware wrapping codecs of both kinds can pass it through as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMode {
+    mode: Mode,
+    timeout: Option<Duration>,
+    full_duplex: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    BufferedUpfront(usize),
+    Progressive(usize),
+    Hijack,
+    Reject,
+}
+
+impl RecvMode {
+    /// Download whole message body (request or response) into the memory
+    /// before passing it to the codec
+    ///
+    /// For server it also means that response is started only after the
+    /// whole request body is received.
+    ///
+    /// The argument is maximum size of the body. The Buffered variant
+    /// works equally well for Chunked encoding and for read-util-end-of-stream
+    /// mode of HTTP/1.0, so sometimes you can't know the size of the request
+    /// in advance. Note this is just an upper limit it's neither buffer size
+    /// nor *minimum* size of the body.
+    pub fn buffered_upfront(max_body_size: usize) -> RecvMode {
+        RecvMode {
+            mode: Mode::BufferedUpfront(max_body_size),
+            timeout: None,
+            full_duplex: false,
+        }
+    }
+    /// Same as `buffered_upfront`, the name used by the client before
+    /// the type was shared with the server
+    pub fn buffered(max_body_size: usize) -> RecvMode {
+        RecvMode::buffered_upfront(max_body_size)
+    }
+    /// Fetch data chunk-by-chunk.
+    ///
+    /// Note, for server your response handler can start either before or
+    /// after progressive body has started or ended to read. I mean they are
+    /// completely independent, and actual sequence of events depends on other
+    /// requests coming in and performance of a client.
+    ///
+    /// The parameter denotes minimum number of bytes that may be passed
+    /// to the protocol handler. This is for performance tuning (i.e. less
+    /// wake-ups of protocol parser). But it's not an input buffer size. The
+    /// use of `Progressive(1)` is perfectly okay (for example if you use http
+    /// request body as a persistent connection for sending multiple messages
+    /// on-demand)
+    pub fn progressive(min_chunk_size_hint: usize) -> RecvMode {
+        RecvMode {
+            mode: Mode::Progressive(min_chunk_size_hint),
+            timeout: None,
+            full_duplex: false,
+        }
+    }
+    /// Don't read request body and hijack connection after response headers
+    /// are sent. Useful for connection upgrades, including websockets and
+    /// for CONNECT method.
+    ///
+    /// Note: `data_received` method of Codec is never called for `Hijack`d
+    /// connection.
+    ///
+    /// Only supported by server, client fails the request with
+    /// `HijackUnsupported` error.
+    pub fn hijack() -> RecvMode {
+        RecvMode { mode: Mode::Hijack, timeout: None, full_duplex: false }
+    }
+    /// Respond without reading request body
+    ///
+    /// This is how a request is rejected (e.g. with `417 Expectation
+    /// Failed`, `413 Payload Too Large` or `401 Unauthorized`) when codec
+    /// can decide by request headers alone. `100 Continue` is never sent,
+    /// so a client that waits for it (see `Head::expect_continue`) doesn't
+    /// send the body at all. `Codec::start_response` is called as soon as
+    /// previous responses are written, and `data_received` is never called.
+    ///
+    /// Unless request has no body, the connection is closed after the
+    /// response (the body is discarded if `Config::drain_timeout` is set).
+    ///
+    /// Only supported by server, client fails the request with
+    /// `RejectUnsupported` error.
+    pub fn reject() -> RecvMode {
+        RecvMode { mode: Mode::Reject, timeout: None, full_duplex: false }
+    }
+    /// Change timeout for reading the whole message body to this value
+    /// instead of configured default
+    ///
+    /// This might be useful if you have some specific slow routes and you
+    /// can authenticate that request is valid enough. This is also useful for
+    /// streaming large bodies and similar things.
+    ///
+    /// Or vice versa if you what shorter timeouts for suspicious host.
+    ///
+    /// For server the timeout replaces `Config::input_body_whole_timeout`.
+    /// For client it's counted from the moment response headers are received
+    /// and replaces `Config::max_request_timeout` for the rest of the request.
+    pub fn with_timeout(mut self, duration: Duration) -> RecvMode {
+        self.timeout = Some(duration);
+        self
+    }
+    /// Same as `with_timeout`
+    pub fn body_read_timeout(self, duration: Duration) -> RecvMode {
+        self.with_timeout(duration)
+    }
+    /// Start response while request body is still being received
+    ///
+    /// By default server calls `Codec::start_response` when the whole
+    /// request body is passed to the codec. In full-duplex mode response
+    /// is started as soon as responses to the previous requests are written
+    /// (after `100 Continue` if client expects one), and `data_received` is
+    /// called for the rest of the body afterwards. This is useful for
+    /// echo-like services and for proxying. The codec is dropped when both
+    /// response is started and request body is received.
+    ///
+    /// Only has effect for `progressive` mode on the server side.
+    pub fn full_duplex(mut self) -> RecvMode {
+        self.full_duplex = true;
+        self
+    }
+}
+
+pub fn get_mode(mode: &RecvMode) -> Mode {
+    mode.mode
+}
+
+pub fn get_timeout(mode: &RecvMode) -> Option<Duration> {
+    mode.timeout
+}
+
+#[cfg_attr(not(feature="server"), allow(dead_code))]
+pub fn is_full_duplex(mode: &RecvMode) -> bool {
+    mode.full_duplex && matches!(mode.mode, Mode::Progressive(_))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{RecvMode, Mode, get_mode, get_timeout, is_full_duplex};
+
+    #[test]
+    fn constructors() {
+        assert_eq!(RecvMode::buffered(10), RecvMode::buffered_upfront(10));
+        assert_eq!(get_mode(&RecvMode::progressive(1)), Mode::Progressive(1));
+        assert_eq!(get_mode(&RecvMode::hijack()), Mode::Hijack);
+        assert_eq!(get_timeout(&RecvMode::hijack()), None);
+        assert_eq!(get_mode(&RecvMode::reject()), Mode::Reject);
+        let mode = RecvMode::progressive(1)
+            .with_timeout(Duration::from_secs(3));
+        assert_eq!(get_mode(&mode), Mode::Progressive(1));
+        assert_eq!(get_timeout(&mode), Some(Duration::from_secs(3)));
+        assert!(!is_full_duplex(&mode));
+        assert!(is_full_duplex(&mode.full_duplex()));
+        assert!(!is_full_duplex(&RecvMode::buffered(10).full_duplex()));
+    }
+}
@@ -0,0 +1,136 @@
+//! Protocol selection based on the result of ALPN negotiation
+//!
+//! TLS libraries expose the protocol negotiated via ALPN in different ways,
+//! so `Proto` accepts it as plain bytes, e.g. with `tokio-rustls`:
+//!
+//! ```rust,ignore
+//! let alpn = stream.get_ref().1.get_alpn_protocol()
+//!     .map(|p| p.as_bytes().to_vec());
+//! let proto = auto::Proto::new(stream, alpn.as_ref().map(|x| &x[..]),
+//!     &cfg, dispatcher, &handle)?;
+//! handle.spawn(proto.map_err(|e| error!("Connection error: {}", e)));
+//! ```
+//!
+//! Only HTTP/1.x is implemented for now, so TLS acceptor should advertise
+//! `PROTOCOLS` only. Connections negotiated to something else (`h2` in
+//! particular) are rejected with an `UnsupportedProtocol` error. No ALPN
+//! at all (older clients or plain TCP) means HTTP/1.x.
+//!
+use std::str::from_utf8;
+use std::sync::Arc;
+
+use futures::{Future, Poll};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use server::error::ErrorEnum;
+use server::{self, Config, Dispatcher, Error, ShutdownHandle};
+use timer::{Timer, TokioTimer};
+
+
+/// Protocol names (ALPN identifiers) supported by `Proto`
+///
+/// In the order of preference, to pass to the TLS library.
+pub const PROTOCOLS: &'static [&'static str] = &["http/1.1", "http/1.0"];
+
+/// Protocol selected for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP/1.0 or HTTP/1.1 (version is determined by the request line)
+    Http1,
+}
+
+enum Inner<S, D: Dispatcher<S>, T: Timer> {
+    Http1(server::Proto<S, D, T>),
+}
+
+/// A server protocol selected by ALPN
+///
+/// This is a future which serves a connection, like `server::Proto`.
+pub struct Proto<S, D: Dispatcher<S>, T: Timer = TokioTimer> {
+    inner: Inner<S, D, T>,
+}
+
+/// Select a protocol by the name negotiated with ALPN
+///
+/// Returns `UnsupportedProtocol` error for unknown and not implemented
+/// protocols.
+pub fn select(alpn: Option<&[u8]>) -> Result<Protocol, Error> {
+    match alpn {
+        None => Ok(Protocol::Http1),
+        Some(name) if name == b"http/1.1" || name == b"http/1.0" => {
+            Ok(Protocol::Http1)
+        }
+        Some(name) => {
+            let name = from_utf8(name).map(|x| x.to_string())
+                .unwrap_or_else(|_| format!("{:?}", name));
+            Err(ErrorEnum::UnsupportedProtocol(name).into())
+        }
+    }
+}
+
+impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>> Proto<S, D> {
+    /// Create a protocol implementation for a connection
+    ///
+    /// `alpn` is the protocol name negotiated by TLS, if any.
+    pub fn new(conn: S, alpn: Option<&[u8]>, cfg: &Arc<Config>,
+        dispatcher: D, handle: &Handle)
+        -> Result<Proto<S, D>, Error>
+    {
+        Proto::with_timer(conn, alpn, cfg, dispatcher,
+                          TokioTimer::new(handle))
+    }
+}
+
+impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>, T: Timer> Proto<S, D, T> {
+    /// Same as `new` but with a custom timer (see `timer` module)
+    pub fn with_timer(conn: S, alpn: Option<&[u8]>, cfg: &Arc<Config>,
+        dispatcher: D, timer: T)
+        -> Result<Proto<S, D, T>, Error>
+    {
+        let inner = match select(alpn)? {
+            Protocol::Http1 => Inner::Http1(
+                server::Proto::with_timer(conn, cfg, dispatcher, timer)),
+        };
+        Ok(Proto { inner: inner })
+    }
+    /// Returns the protocol selected for the connection
+    pub fn protocol(&self) -> Protocol {
+        match self.inner {
+            Inner::Http1(..) => Protocol::Http1,
+        }
+    }
+    /// Returns a handle to shut down connection gracefully
+    pub fn shutdown_handle(&mut self) -> ShutdownHandle {
+        match self.inner {
+            Inner::Http1(ref mut proto) => proto.shutdown_handle(),
+        }
+    }
+}
+
+impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>, T: Timer> Future
+    for Proto<S, D, T>
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        match self.inner {
+            Inner::Http1(ref mut proto) => proto.poll(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{select, Protocol};
+
+    #[test]
+    fn selection() {
+        assert_eq!(select(None).unwrap(), Protocol::Http1);
+        assert_eq!(select(Some(b"http/1.1")).unwrap(), Protocol::Http1);
+        assert_eq!(select(Some(b"http/1.0")).unwrap(), Protocol::Http1);
+        assert_eq!(format!("{}", select(Some(b"h2")).err().unwrap()),
+                   "unsupported protocol \"h2\"");
+    }
+}
@@ -0,0 +1,235 @@
+use std::cmp::min;
+use std::io::{self, Write};
+use std::mem;
+
+use futures::{Future, Async, Poll, Stream, Sink};
+use futures::sink::Wait;
+use futures::sync::mpsc::{channel, Sender, Receiver};
+use tokio_io::AsyncWrite;
+
+use super::{Encoder, EncoderDone, Error};
+use super::error::ErrorEnum;
+
+
+enum Message {
+    Data(Vec<u8>),
+    Done,
+}
+
+/// A future which streams response body written by a `BlockingWriter`
+///
+/// This is a bridge for libraries which can only write into a blocking
+/// `io::Write` (templates, serializers). Writer is meant to be moved to
+/// a thread or a `CpuPool`, and this future is returned from
+/// `Codec::start_response`:
+///
+/// ```rust,ignore
+/// fn start_response(&mut self, mut e: Encoder<S>) -> Self::ResponseFuture {
+///     e.status(Status::Ok);
+///     e.add_chunked().unwrap();
+///     e.done_headers().unwrap();
+///     let (body, mut writer) = BlockingBody::new(e, 65536);
+///     let data = self.data.clone();
+///     self.pool.spawn_fn(move || {
+///         serde_json::to_writer(&mut writer, &*data)?;
+///         writer.done()
+///     }).forget();
+///     Box::new(body)
+/// }
+/// ```
+///
+/// Memory usage is bounded: writer blocks while a chunk of `buffer_size`
+/// bytes is waiting to be picked up by the future, and the future doesn't
+/// pick it up until output buffer is flushed below `buffer_size`.
+///
+/// The same rules as for `Encoder::write_body` apply, i.e. headers must be
+/// done and the body must match `Content-Length` if any.
+pub struct BlockingBody<S> {
+    encoder: Option<Encoder<S>>,
+    receiver: Receiver<Message>,
+    watermark: usize,
+}
+
+/// A blocking `io::Write` which sends data to the `BlockingBody`
+///
+/// Data is buffered until `buffer_size` bytes are collected or `flush()`
+/// is called. Call `done()` when whole body is written. If writer is
+/// dropped without calling `done()` (e.g. because of an error or a panic)
+/// the response is considered incomplete and the connection is closed.
+///
+/// Writes fail with `BrokenPipe` when the response is canceled
+/// (connection closed).
+pub struct BlockingWriter {
+    sender: Wait<Sender<Message>>,
+    buf: Vec<u8>,
+    buffer_size: usize,
+}
+
+impl<S> BlockingBody<S> {
+    /// Create a bridge for writing body of the response from a blocking
+    /// code
+    ///
+    /// # Panics
+    ///
+    /// When `buffer_size` is zero.
+    pub fn new(encoder: Encoder<S>, buffer_size: usize)
+        -> (BlockingBody<S>, BlockingWriter)
+    {
+        assert!(buffer_size > 0);
+        let (tx, rx) = channel(0);
+        let body = BlockingBody {
+            encoder: Some(encoder),
+            receiver: rx,
+            watermark: buffer_size,
+        };
+        let writer = BlockingWriter {
+            sender: tx.wait(),
+            buf: Vec::with_capacity(buffer_size),
+            buffer_size: buffer_size,
+        };
+        (body, writer)
+    }
+}
+
+impl<S: AsyncWrite> Future for BlockingBody<S> {
+    type Item = EncoderDone<S>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<EncoderDone<S>, Error> {
+        loop {
+            {
+                let enc = self.encoder.as_mut()
+                    .expect("future is polled twice");
+                enc.flush().map_err(ErrorEnum::Io)?;
+                if enc.bytes_buffered() >= self.watermark {
+                    return Ok(Async::NotReady);
+                }
+            }
+            match self.receiver.poll() {
+                Ok(Async::Ready(Some(Message::Data(data)))) => {
+                    self.encoder.as_mut().unwrap().write_body(&data);
+                }
+                Ok(Async::Ready(Some(Message::Done))) => {
+                    let enc = self.encoder.take().unwrap();
+                    return Ok(Async::Ready(enc.done()));
+                }
+                Ok(Async::Ready(None)) => {
+                    return Err(ErrorEnum::ResponseAborted.into());
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(()) => unreachable!(),
+            }
+        }
+    }
+}
+
+fn canceled() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "response is canceled")
+}
+
+impl BlockingWriter {
+    fn send_buf(&mut self) -> io::Result<()> {
+        let data = mem::replace(&mut self.buf,
+            Vec::with_capacity(self.buffer_size));
+        self.sender.send(Message::Data(data)).map_err(|_| canceled())
+    }
+    /// Send the rest of the data and finish the response
+    ///
+    /// This doesn't wait until data is written to the socket.
+    pub fn done(mut self) -> io::Result<()> {
+        if self.buf.len() > 0 {
+            self.send_buf()?;
+        }
+        self.sender.send(Message::Done).map_err(|_| canceled())
+    }
+}
+
+impl Write for BlockingWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() >= self.buffer_size {
+            self.send_buf()?;
+        }
+        let bytes = min(data.len(), self.buffer_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..bytes]);
+        Ok(bytes)
+    }
+    // Note: this passes buffered data to the event loop but doesn't wait
+    // until it's written to the socket
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.len() > 0 {
+            self.send_buf()?;
+        }
+        self.sender.flush().map_err(|_| canceled())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::thread;
+
+    use futures::Future;
+    use tk_bufstream::{IoBuf, MockData};
+
+    use base_serializer::HeaderLimits;
+    use server::encoder::{self, ResponseConfig, AutoHeaders, get_inner};
+    use server::Encoder;
+    use {Status, Version};
+    use super::BlockingBody;
+
+    fn encoder(mock: &MockData) -> Encoder<MockData> {
+        let (io, _) = IoBuf::new(mock.clone()).split();
+        let mut e = encoder::new(io, ResponseConfig {
+            is_head: false,
+            do_close: false,
+            version: Version::Http11,
+            expect_continue: false,
+            continue_sent: false,
+            header_limits: HeaderLimits::default(),
+            auto_headers: AutoHeaders::default(),
+        });
+        e.status(Status::Ok);
+        e.add_chunked().unwrap();
+        e.done_headers().unwrap();
+        e
+    }
+
+    #[test]
+    fn stream() {
+        let mock = MockData::new();
+        let (body, mut writer) = BlockingBody::new(encoder(&mock), 1024);
+        let thread = thread::spawn(move || {
+            for i in 0..1000 {
+                write!(writer, "line {}\n", i)?;
+            }
+            writer.done()
+        });
+        let mut io = get_inner(body.wait().unwrap());
+        io.flush().unwrap();
+        thread.join().unwrap().unwrap();
+        let output = String::from_utf8(mock.output(..)).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("\r\n\r\n400\r\nline 0\nline 1\n"));
+        assert!(output.ends_with("line 999\n\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn aborted() {
+        let mock = MockData::new();
+        let (body, mut writer) = BlockingBody::new(encoder(&mock), 1024);
+        let thread = thread::spawn(move || {
+            writer.write_all(b"partial").unwrap();
+        });
+        let err = body.wait().err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(ResponseAborted)");
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn canceled() {
+        let mock = MockData::new();
+        let (body, mut writer) = BlockingBody::new(encoder(&mock), 4);
+        drop(body);
+        writer.write_all(b"abcd").unwrap();
+        assert!(writer.write_all(b"efgh").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::io;
+
+use futures::{Async, AsyncSink, Poll, Sink, Stream};
+use futures::sync::mpsc::{channel, Sender, Receiver};
+
+use server::Error;
+use server::error::ErrorEnum;
+
+
+/// Number of chunks buffered between the connection and the stream
+const CHUNKS_BUFFERED: usize = 4;
+
+
+/// A stream of chunks of request body as they are received
+///
+/// Created by `BufferedDispatcher` when `streaming_body` is enabled, see
+/// `Request::take_body_stream`. Chunks are not of any specific size, each
+/// one is the data received from the network since the previous one.
+///
+/// Only a few chunks are buffered, so reading from the connection is
+/// paused until the stream is polled. Dropping the stream discards the
+/// rest of the body. The stream fails with `UnexpectedEof` if connection
+/// is closed before the whole body is received.
+#[derive(Debug)]
+pub struct BodyStream {
+    receiver: Receiver<(Vec<u8>, bool)>,
+    done: bool,
+}
+
+pub struct BodySender {
+    sender: Sender<(Vec<u8>, bool)>,
+    max_length: u64,
+    received: u64,
+}
+
+pub fn new(max_length: u64) -> (BodySender, BodyStream) {
+    let (tx, rx) = channel(CHUNKS_BUFFERED);
+    let sender = BodySender {
+        sender: tx,
+        max_length: max_length,
+        received: 0,
+    };
+    (sender, BodyStream { receiver: rx, done: false })
+}
+
+impl BodySender {
+    /// Works like `Codec::data_received`
+    pub fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        if self.received + data.len() as u64 > self.max_length {
+            return Err(ErrorEnum::RequestTooLong.into());
+        }
+        match self.sender.start_send((data.to_vec(), end)) {
+            Ok(AsyncSink::Ready) => {}
+            Ok(AsyncSink::NotReady(_)) => return Ok(Async::NotReady),
+            // stream is dropped, so the rest of the body is discarded
+            Err(_) => {}
+        }
+        self.received += data.len() as u64;
+        Ok(Async::Ready(data.len()))
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        while !self.done {
+            match self.receiver.poll() {
+                Ok(Async::Ready(Some((chunk, end)))) => {
+                    self.done = end;
+                    if chunk.len() > 0 {
+                        return Ok(Async::Ready(Some(chunk)));
+                    }
+                }
+                Ok(Async::Ready(None)) | Err(()) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        "request body is not fully received"));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }
+        Ok(Async::Ready(None))
+    }
+}
@@ -1,19 +1,32 @@
 //! Higher-level interface for serving fully buffered requests
 //!
+use std::env::temp_dir;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::marker::PhantomData;
 
 use futures::{Async, Future, IntoFuture};
 use futures::future::FutureResult;
 use tokio_core::reactor::Handle;
-use tk_bufstream::{ReadBuf, WriteBuf, ReadFramed, WriteFramed};
+use tk_bufstream::{ReadFramed, WriteFramed};
+#[cfg(feature="websocket")] use tk_bufstream::{ReadBuf, WriteBuf};
 
+#[cfg(feature="websocket")]
 use websocket::{ServerCodec as WebsocketCodec};
 use super::{Error, Encoder, EncoderDone, Dispatcher, Codec, Head, RecvMode};
-use super::{WebsocketHandshake};
+use super::ForwardedClient;
+#[cfg(feature="websocket")] use super::WebsocketHandshake;
+use server::error::ErrorEnum;
+use server::body_stream::{self, BodySender};
 use {Version};
 
+pub use super::lazy_body::{LazyBody, BodyChunks};
+pub use super::body_stream::BodyStream;
+
+/// Bytes buffered before passing body chunk to the codec for lazy bodies
+const LAZY_CHUNK_SIZE: usize = 16384;
+
 /// Buffered request struct
 ///
 /// some known headers may be moved to upper structure (ie, Host)
@@ -21,20 +34,36 @@ use {Version};
 #[derive(Debug)]
 pub struct Request {
     peer_addr: SocketAddr,
-    method: String,
-    path: String,
-    host: Option<String>,
+    client: ForwardedClient,
+    /// Method, path and host are stored in a single buffer to make just
+    /// one allocation per request for all of them
+    line: String,
+    method_end: usize,
+    path_end: usize,
+    host_end: Option<usize>,
     version: Version,
     headers: Vec<(String, Vec<u8>)>,
     body: Vec<u8>,
+    lazy_body: Option<LazyBody>,
+    body_stream: Option<BodyStream>,
+    #[cfg(feature="websocket")]
     websocket_handshake: Option<WebsocketHandshake>,
 }
 
+#[derive(Debug)]
+struct LazyConfig {
+    threshold: usize,
+    max_length: u64,
+    dir: PathBuf,
+}
+
 /// A dispatcher that allows to process request and return response using
 /// a one single function
 pub struct BufferedDispatcher<S, N: NewService<S>> {
     addr: SocketAddr,
     max_request_length: usize,
+    lazy: Option<Arc<LazyConfig>>,
+    streaming: Option<u64>,
     service: N,
     handle: Handle,
     phantom: PhantomData<S>,
@@ -44,20 +73,34 @@ pub struct BufferedDispatcher<S, N: NewService<S>> {
 /// own dispatcher too
 pub struct BufferedCodec<R> {
     max_request_length: usize,
+    lazy: Option<Arc<LazyConfig>>,
+    streaming: Option<u64>,
+    body_sender: Option<BodySender>,
+    body_length: Option<u64>,
     service: R,
     request: Option<Request>,
+    #[cfg_attr(not(feature="websocket"), allow(dead_code))]
     handle: Handle,
 }
 
+/// Stands for websocket codec when `websocket` feature is disabled
+///
+/// There are no values of this type, as `Service::start_websocket` is
+/// never called without websocket support.
+#[cfg(not(feature="websocket"))]
+pub enum WebsocketCodec {}
+
 /// A helper to create a simple websocket (and HTTP) service
 ///
 /// It's internally created by `BufferedDispatcher::new_with_websockets()`
+#[cfg(feature="websocket")]
 pub struct WebsocketFactory<H, I> {
     service: Arc<H>,
     websockets: Arc<I>,
 }
 
 /// An instance of websocket factory, created by WebsocketFactory itself
+#[cfg(feature="websocket")]
 pub struct WebsocketService<H, I, T, U> {
     service: Arc<H>,
     websockets: Arc<I>,
@@ -90,11 +133,17 @@ pub trait Service<S> {
     fn call(&mut self, request: Request, encoder: Encoder<S>) -> Self::Future;
 
     /// A method which is called when websocket connection established
+    ///
+    /// Frames written by `Encoder::write_websocket_frame` in `call()` are
+    /// already in the `output` buffer, so they are sent first.
+    ///
+    /// This is never called when `websocket` feature is disabled.
     fn start_websocket(&mut self, output: WriteFramed<S, WebsocketCodec>,
                                   input: ReadFramed<S, WebsocketCodec>)
         -> Self::WebsocketFuture;
 }
 
+#[cfg(feature="websocket")]
 impl<H, I, T, U, S> NewService<S> for WebsocketFactory<H, I>
     where H: Fn(Request, Encoder<S>) -> T,
           I: Fn(WriteFramed<S, WebsocketCodec>,
@@ -113,6 +162,7 @@ impl<H, I, T, U, S> NewService<S> for WebsocketFactory<H, I>
     }
 }
 
+#[cfg(feature="websocket")]
 impl<S, H, I, T, U> Service<S> for WebsocketService<H, I, T, U>
     where H: Fn(Request, Encoder<S>) -> T,
           I: Fn(WriteFramed<S, WebsocketCodec>,
@@ -138,17 +188,27 @@ impl Request {
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
+    /// Returns effective client address, scheme and host
+    ///
+    /// This takes `Forwarded` headers set by trusted proxies into account,
+    /// see `Head::forwarded_client` for details.
+    pub fn forwarded_client(&self) -> &ForwardedClient {
+        &self.client
+    }
     /// Returns method of a request
     pub fn method(&self) -> &str {
-        &self.method
+        &self.line[..self.method_end]
     }
     /// Returns path of a request
+    ///
+    /// For requests without a path (`OPTIONS *` or `CONNECT` to
+    /// `host:port`) this is the request-target as is.
     pub fn path(&self) -> &str {
-        &self.path
+        &self.line[self.method_end..self.path_end]
     }
     /// Returns the host header of a request
     pub fn host(&self) -> Option<&str> {
-        self.host.as_ref().map(|s| s.as_ref())
+        self.host_end.map(|end| &self.line[self.path_end..end])
     }
     /// Returns HTTP version used in request
     pub fn version(&self) -> Version {
@@ -159,10 +219,34 @@ impl Request {
         &self.headers
     }
     /// Returns request body
+    ///
+    /// Note: body is empty if it's stored in a file, see `lazy_body()`.
     pub fn body(&self) -> &[u8] {
         &self.body
     }
+    /// Returns request body stored in a temporary file
+    ///
+    /// This is only used when enabled by `BufferedDispatcher::lazy_body`
+    /// and the body is larger than the threshold.
+    pub fn lazy_body(&self) -> Option<&LazyBody> {
+        self.lazy_body.as_ref()
+    }
+    /// Take ownership of the request body stored in a temporary file
+    ///
+    /// Useful to keep the body (and the file) after the response is sent,
+    /// e.g. to process it in a thread pool.
+    pub fn take_lazy_body(&mut self) -> Option<LazyBody> {
+        self.lazy_body.take()
+    }
+    /// Take the stream of the request body
+    ///
+    /// This is only used when enabled by
+    /// `BufferedDispatcher::streaming_body`, `body()` is empty in this case.
+    pub fn take_body_stream(&mut self) -> Option<BodyStream> {
+        self.body_stream.take()
+    }
     /// Returns websocket handshake if exists
+    #[cfg(feature="websocket")]
     pub fn websocket_handshake(&self) -> Option<&WebsocketHandshake> {
         self.websocket_handshake.as_ref()
     }
@@ -207,6 +291,8 @@ impl<S, N: NewService<S>> BufferedDispatcher<S, N> {
         BufferedDispatcher {
             addr: addr,
             max_request_length: 10_485_760,
+            lazy: None,
+            streaming: None,
             service: service,
             handle: handle.clone(),
             phantom: PhantomData,
@@ -216,8 +302,54 @@ impl<S, N: NewService<S>> BufferedDispatcher<S, N> {
     pub fn max_request_length(&mut self, value: usize) {
         self.max_request_length = value;
     }
+    /// Store request bodies larger than `threshold` in temporary files
+    ///
+    /// By default bodies larger than `max_request_length` are rejected.
+    /// With this setting bodies up to `max_length` bytes are accepted, and
+    /// ones larger than `threshold` are written to a temporary file (in
+    /// `std::env::temp_dir()` unless `lazy_body_dir` is set) and can be
+    /// accessed with `Request::lazy_body()`, `Request::body()` is empty in
+    /// this case.
+    ///
+    /// Note: files are written synchronously in the event loop.
+    pub fn lazy_body(&mut self, threshold: usize, max_length: u64) {
+        let dir = self.lazy.as_ref().map(|x| x.dir.clone())
+            .unwrap_or_else(temp_dir);
+        self.lazy = Some(Arc::new(LazyConfig {
+            threshold: threshold,
+            max_length: max_length,
+            dir: dir,
+        }));
+    }
+    /// Sets directory for temporary files of lazy bodies
+    ///
+    /// Only makes sense in combination with `lazy_body`.
+    pub fn lazy_body_dir(&mut self, dir: &Path) {
+        self.lazy = Some(Arc::new(match self.lazy.take() {
+            Some(cfg) => LazyConfig {
+                threshold: cfg.threshold,
+                max_length: cfg.max_length,
+                dir: dir.to_path_buf(),
+            },
+            None => LazyConfig {
+                threshold: self.max_request_length,
+                max_length: self.max_request_length as u64,
+                dir: dir.to_path_buf(),
+            },
+        }));
+    }
+    /// Call the service before request body is received
+    ///
+    /// The body is passed as a stream (see `Request::take_body_stream`),
+    /// so uploads of up to `max_length` bytes don't have to fit in memory.
+    /// Response may be started before the body is read entirely. This
+    /// setting takes precedence over `lazy_body` and `max_request_length`.
+    pub fn streaming_body(&mut self, max_length: u64) {
+        self.streaming = Some(max_length);
+    }
 }
 
+#[cfg(feature="websocket")]
 impl<S, H, I, T, U> BufferedDispatcher<S, WebsocketFactory<H, I>>
     where H: Fn(Request, Encoder<S>) -> T,
           I: Fn(WriteFramed<S, WebsocketCodec>,
@@ -234,6 +366,8 @@ impl<S, H, I, T, U> BufferedDispatcher<S, WebsocketFactory<H, I>>
         BufferedDispatcher {
             addr: addr,
             max_request_length: 10_485_760,
+            lazy: None,
+            streaming: None,
             service: WebsocketFactory {
                 service: Arc::new(http),
                 websockets: Arc::new(websockets),
@@ -251,22 +385,55 @@ impl<S, N: NewService<S>> Dispatcher<S> for BufferedDispatcher<S, N> {
         -> Result<Self::Codec, Error>
     {
         // TODO(tailhook) strip hop-by-hop headers
-        let up = headers.get_websocket_upgrade();
+        let method = headers.raw_method();
+        // asterisk and authority forms are passed as is
+        let path = headers.effective_path_or(headers.raw_request_target());
+        let host = headers.host();
+        let mut line = String::with_capacity(
+            method.len() + path.len() + host.map(|x| x.len()).unwrap_or(0));
+        line.push_str(method);
+        line.push_str(path);
+        let host_end = host.map(|host| {
+            line.push_str(host);
+            line.len()
+        });
+        #[cfg(feature="websocket")]
+        let websocket_handshake = headers.get_websocket_upgrade()
+            .unwrap_or(None);
+        #[cfg(feature="websocket")]
+        let upgrade = websocket_handshake.is_some();
+        #[cfg(not(feature="websocket"))]
+        let upgrade = false;
+        let (body_sender, body_stream) = match self.streaming {
+            Some(max) if !upgrade => {
+                let (tx, rx) = body_stream::new(max);
+                (Some(tx), Some(rx))
+            }
+            _ => (None, None),
+        };
         Ok(BufferedCodec {
             max_request_length: self.max_request_length,
+            lazy: self.lazy.clone(),
+            streaming: self.streaming,
+            body_sender: body_sender,
+            body_length: headers.body_length(),
             service: self.service.new(),
             request: Some(Request {
                 peer_addr: self.addr,
-                method: headers.method().to_string(),
-                // TODO(tailhook) process other forms of path
-                path: headers.path().unwrap().to_string(),
-                host: headers.host().map(|x| x.to_string()),
+                client: headers.forwarded_client(self.addr.ip()),
+                method_end: method.len(),
+                path_end: method.len() + path.len(),
+                host_end: host_end,
+                line: line,
                 version: headers.version(),
                 headers: headers.headers().map(|(name, value)| {
                     (name.to_string(), value.to_vec())
                 }).collect(),
                 body: Vec::new(),
-                websocket_handshake: up.unwrap_or(None),
+                lazy_body: None,
+                body_stream: body_stream,
+                #[cfg(feature="websocket")]
+                websocket_handshake: websocket_handshake,
             }),
             handle: self.handle.clone(),
         })
@@ -276,22 +443,69 @@ impl<S, N: NewService<S>> Dispatcher<S> for BufferedDispatcher<S, N> {
 impl<S, R: Service<S>> Codec<S> for BufferedCodec<R> {
     type ResponseFuture = R::Future;
     fn recv_mode(&mut self) -> RecvMode {
-        if self.request.as_ref().unwrap().websocket_handshake.is_some() {
-            RecvMode::hijack()
-        } else {
-            RecvMode::buffered_upfront(self.max_request_length)
+        #[cfg(feature="websocket")]
+        {
+            let request = self.request.as_ref().unwrap();
+            if request.websocket_handshake.is_some() {
+                return RecvMode::hijack();
+            }
+        }
+        if let Some(max) = self.streaming {
+            return match self.body_length {
+                // rejected when checking the length
+                Some(len) if len > max => RecvMode::buffered_upfront(0),
+                _ => RecvMode::progressive(1).full_duplex(),
+            };
+        }
+        match (self.lazy.as_ref(), self.body_length) {
+            (Some(lazy), Some(len)) if len > lazy.max_length => {
+                // rejected when checking the length
+                RecvMode::buffered_upfront(0)
+            }
+            (Some(lazy), Some(len)) if len <= lazy.threshold as u64 => {
+                RecvMode::buffered_upfront(lazy.threshold)
+            }
+            (Some(_), _) => RecvMode::progressive(LAZY_CHUNK_SIZE),
+            (None, _) => RecvMode::buffered_upfront(self.max_request_length),
         }
     }
     fn data_received(&mut self, data: &[u8], end: bool)
         -> Result<Async<usize>, Error>
     {
-        assert!(end);
-        self.request.as_mut().unwrap().body = data.to_vec();
+        if let Some(ref mut sender) = self.body_sender {
+            return sender.data_received(data, end);
+        }
+        let request = self.request.as_mut().unwrap();
+        let lazy = match self.lazy {
+            Some(ref lazy) => lazy,
+            None => {
+                assert!(end);
+                request.body = data.to_vec();
+                return Ok(Async::Ready(data.len()));
+            }
+        };
+        let total = request.body.len() as u64 +
+            request.lazy_body.as_ref().map(|b| b.len()).unwrap_or(0) +
+            data.len() as u64;
+        if total > lazy.max_length {
+            return Err(ErrorEnum::RequestTooLong.into());
+        }
+        if request.lazy_body.is_none() && total > lazy.threshold as u64 {
+            let mut file = LazyBody::new(&lazy.dir)?;
+            file.append(&request.body)?;
+            request.body = Vec::new();
+            request.lazy_body = Some(file);
+        }
+        match request.lazy_body {
+            Some(ref mut file) => file.append(data)?,
+            None => request.body.extend(data),
+        }
         Ok(Async::Ready(data.len()))
     }
     fn start_response(&mut self, e: Encoder<S>) -> R::Future {
         self.service.call(self.request.take().unwrap(), e)
     }
+    #[cfg(feature="websocket")]
     fn hijack(&mut self, write_buf: WriteBuf<S>, read_buf: ReadBuf<S>){
         let inp = read_buf.framed(WebsocketCodec);
         let out = write_buf.framed(WebsocketCodec);
@@ -5,13 +5,29 @@ use super::{Error, Encoder, EncoderDone, Head};
 use super::RecvMode;
 
 
+/// Framing of the request body as declared in request headers
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum BodyKind {
+    /// Body has `Content-Length` (or is known to be empty)
     Fixed(u64),
+    /// Body uses `Transfer-Encoding: chunked`
     Chunked,
+    /// Body has transfer encoding we can't handle (request is rejected)
     Unsupported,
 }
 
+/// Progress of receiving a request body
+///
+/// This is passed to `Codec::body_progress` right before each
+/// `data_received` call, so it's useful for upload progress reporting and
+/// preallocation of buffers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BodyInfo {
+    kind: BodyKind,
+    consumed: u64,
+    buffered: usize,
+}
+
 /// This is a low-level interface to the http server
 pub trait Dispatcher<S> {
     /// The codec type  for this dispatcher
@@ -55,8 +71,18 @@ pub trait Codec<S> {
     /// `end` equals to `true` for the last chunk of the data.
     ///
     /// Method returns `Async::Ready(x)` to denote that it has consumed `x`
-    /// bytes. If there are some bytes left in the buffer they will be passed
-    /// again on the call.
+    /// bytes. Rules for the returned value depend on the `RecvMode`:
+    ///
+    /// * In buffered mode the method is called once with the whole body
+    ///   and `end == true`. It must either consume all of it or return
+    ///   `Async::NotReady` (and arrange the task to be woken up, then data
+    ///   is passed again). Consuming part of the data is an error.
+    /// * In progressive mode bytes left in the buffer are passed again:
+    ///   right away if there are still at least as many bytes as the
+    ///   chunk size of the mode (or it's the end of the body), otherwise
+    ///   when more data arrives. Returning `Async::Ready(0)` for non-empty
+    ///   data means waiting for more data too, so at the end of the body
+    ///   it stalls until the task is woken up.
     ///
     /// If the response is empty, or last chunk arrives later and it's empty
     /// we call `c.data_received(b"", true)` on every wakeup,
@@ -69,6 +95,26 @@ pub trait Codec<S> {
     fn data_received(&mut self, data: &[u8], end: bool)
         -> Result<Async<usize>, Error>;
 
+    /// Called with the current progress of the request body
+    ///
+    /// This is called right before every `data_received()` call. Default
+    /// implementation does nothing.
+    fn body_progress(&mut self, _info: &BodyInfo) {
+    }
+
+    /// Called with request body bytes exactly as they are received
+    ///
+    /// Unlike `data_received()` chunked encoding isn't decoded here: chunk
+    /// sizes, extensions, and trailers are passed too. This is useful for
+    /// middlewares that need to see the exact bytes (e.g. to verify
+    /// a signature) while the wrapped codec receives the decoded body.
+    ///
+    /// Bytes are passed in order as soon as they are read from the network,
+    /// regardless of `RecvMode`, so it's usually called before the
+    /// respective `data_received()`. Default implementation does nothing.
+    fn raw_body_received(&mut self, _data: &[u8]) {
+    }
+
     /// Start writing a response
     ///
     /// This method is called when there all preceding requests are either
@@ -79,12 +125,23 @@ pub trait Codec<S> {
     /// Everything you write into a buffer might be flushed to the network
     /// immediately (or as fast as you yield to main loop). On the other
     /// hand we might buffer/pipeline multiple responses at once.
+    ///
+    /// If request has `Expect: 100-continue` (see `Head::expect_continue`)
+    /// protocol sends `100 Continue` by itself, as soon as all previous
+    /// responses are written and request body is still being received.
+    /// It's never put in the middle of a previous (pipelined) response.
+    /// If the body is received before that, it's up to the codec whether
+    /// to call `Encoder::response_continue`.
+    /// To reject request without `100 Continue` return `RecvMode::reject()`
+    /// from `recv_mode`.
     fn start_response(&mut self, e: Encoder<S>) -> Self::ResponseFuture;
 
     /// Called after future retunrted by `start_response` done if recv mode
     /// is `Hijack`
     ///
-    /// Note: both input and output buffers can contain some data.
+    /// Note: both input and output buffers can contain some data. Use
+    /// `HijackedStream` to join them into a single stream (e.g. to start
+    /// TLS on the same connection).
     fn hijack(&mut self, _output: WriteBuf<S>,  _input: ReadBuf<S>) {
         panic!("`Codec::recv_mode` returned `Hijack` but \
             no hijack() method implemented");
@@ -103,6 +160,12 @@ impl<S, F> Codec<S> for Box<Codec<S, ResponseFuture=F>>
     {
         (**self).data_received(data, end)
     }
+    fn body_progress(&mut self, info: &BodyInfo) {
+        (**self).body_progress(info)
+    }
+    fn raw_body_received(&mut self, data: &[u8]) {
+        (**self).raw_body_received(data)
+    }
     fn start_response(&mut self, e: Encoder<S>) -> Self::ResponseFuture {
         (**self).start_response(e)
     }
@@ -110,3 +173,38 @@ impl<S, F> Codec<S> for Box<Codec<S, ResponseFuture=F>>
         (**self).hijack(output, input)
     }
 }
+
+impl BodyInfo {
+    /// Framing of the request body
+    pub fn kind(&self) -> BodyKind {
+        self.kind
+    }
+    /// Total size of the body if known in advance (has `Content-Length`)
+    pub fn total(&self) -> Option<u64> {
+        match self.kind {
+            BodyKind::Fixed(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Number of body bytes already consumed by the codec
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+    /// Number of body bytes received so far
+    ///
+    /// This includes bytes that are going to be passed to `data_received`
+    /// right now.
+    pub fn received(&self) -> u64 {
+        self.consumed + self.buffered as u64
+    }
+    /// Number of bytes that are still expected to arrive from the network
+    ///
+    /// Returns `None` for chunked bodies
+    pub fn remaining(&self) -> Option<u64> {
+        self.total().map(|x| x.saturating_sub(self.received()))
+    }
+}
+
+pub fn body_info(kind: BodyKind, consumed: u64, buffered: usize) -> BodyInfo {
+    BodyInfo { kind: kind, consumed: consumed, buffered: buffered }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+
+/// Gzip encoder of the response body
+///
+/// Compressed data is accumulated in a vector which is cleared on each
+/// call, so the caller must copy the returned bytes into the output
+/// buffer before feeding more data.
+pub(crate) struct Compressor {
+    encoder: GzEncoder<Vec<u8>>,
+}
+
+impl fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Compressor").finish()
+    }
+}
+
+impl Compressor {
+    pub fn gzip() -> Compressor {
+        Compressor {
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+        }
+    }
+    /// Compress a chunk of data, returns compressed bytes available so far
+    ///
+    /// Usually nothing is returned for small chunks, as the compressor
+    /// needs some data to work efficiently.
+    pub fn write(&mut self, data: &[u8]) -> &[u8] {
+        self.encoder.get_mut().clear();
+        self.encoder.write_all(data)
+            .expect("compressing into a vector never fails");
+        self.encoder.get_ref()
+    }
+    /// Returns all the data compressed so far, so that peer can
+    /// decompress everything written before the call
+    pub fn flush(&mut self) -> &[u8] {
+        self.encoder.get_mut().clear();
+        self.encoder.flush()
+            .expect("compressing into a vector never fails");
+        self.encoder.get_ref()
+    }
+    /// Returns the rest of compressed data and gzip trailer
+    ///
+    /// Nothing is returned when called twice.
+    pub fn finish(&mut self) -> &[u8] {
+        self.encoder.get_mut().clear();
+        self.encoder.try_finish()
+            .expect("compressing into a vector never fails");
+        self.encoder.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use super::Compressor;
+
+    #[test]
+    fn roundtrip() {
+        let mut c = Compressor::gzip();
+        let mut out = Vec::new();
+        out.extend_from_slice(c.write(b"hello "));
+        out.extend_from_slice(c.flush());
+        // everything written so far can be decompressed after flush
+        let mut partial = Vec::new();
+        GzDecoder::new(&out[..]).read_to_end(&mut partial).ok();
+        assert_eq!(partial, b"hello ");
+
+        out.extend_from_slice(c.write(b"world"));
+        out.extend_from_slice(c.finish());
+        assert_eq!(c.finish(), b"");
+        let mut result = String::new();
+        GzDecoder::new(&out[..]).read_to_string(&mut result).unwrap();
+        assert_eq!(result, "hello world");
+    }
+}
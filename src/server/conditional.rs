@@ -0,0 +1,215 @@
+//! Conditional GET (cache validation) helpers
+//!
+//! Describe validators of the resource with `Validators`, then check
+//! the request against them and either reply with `304 Not Modified` or
+//! send a full response with the same validators:
+//!
+//! ```rust,ignore
+//! let mut validators = Validators::new();
+//! validators.etag(&digest).last_modified(mtime);
+//! if validators.is_not_modified(head) {
+//!     return ok(e.not_modified(&validators));
+//! }
+//! e.status(Status::Ok);
+//! validators.add_headers(&mut e);
+//! ```
+//!
+//! `If-None-Match` takes precedence over `If-Modified-Since` and uses the
+//! weak comparison of entity tags as required by RFC 7232. Dates are
+//! compared with one second precision (the one of `HTTP-date`). Both
+//! headers are ignored for methods other than `GET` and `HEAD`.
+//!
+//! Requires `date_header` feature (enabled by default).
+use std::str::from_utf8;
+use std::time::SystemTime;
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+use httpdate::HttpDate;
+
+use enums::Method;
+use super::{Head, Encoder};
+
+
+/// Validators of the resource: entity tag and/or modification time
+#[derive(Debug, Clone)]
+pub struct Validators<'a> {
+    etag: Option<(bool, &'a str)>,
+    last_modified: Option<HttpDate>,
+}
+
+impl<'a> Validators<'a> {
+    /// Create an empty set of validators
+    ///
+    /// Without validators request is never considered not modified.
+    pub fn new() -> Validators<'a> {
+        Validators {
+            etag: None,
+            last_modified: None,
+        }
+    }
+    /// Set a strong entity tag, without quotes
+    ///
+    /// # Panics
+    ///
+    /// When tag contains a double quote or characters that are not allowed
+    /// in entity tag.
+    pub fn etag(&mut self, tag: &'a str) -> &mut Self {
+        assert!(is_etag(tag), "invalid entity tag {:?}", tag);
+        self.etag = Some((false, tag));
+        self
+    }
+    /// Set a weak entity tag, without quotes and `W/` prefix
+    ///
+    /// # Panics
+    ///
+    /// When tag contains a double quote or characters that are not allowed
+    /// in entity tag.
+    pub fn weak_etag(&mut self, tag: &'a str) -> &mut Self {
+        assert!(is_etag(tag), "invalid entity tag {:?}", tag);
+        self.etag = Some((true, tag));
+        self
+    }
+    /// Set modification time of the resource
+    pub fn last_modified(&mut self, time: SystemTime) -> &mut Self {
+        self.last_modified = Some(HttpDate::from(time));
+        self
+    }
+    /// Returns `true` if `304 Not Modified` should be sent for the request
+    pub fn is_not_modified(&self, head: &Head) -> bool {
+        if !matches!(head.method(), Method::Get | Method::Head) {
+            return false;
+        }
+        let mut if_none_match = Vec::new();
+        let mut if_modified_since = None;
+        for header in head.all_headers() {
+            if header.name.eq_ignore_ascii_case("If-None-Match") {
+                if_none_match.push(header.value);
+            } else if header.name.eq_ignore_ascii_case("If-Modified-Since") {
+                if_modified_since = Some(header.value);
+            }
+        }
+        self.check(&if_none_match, if_modified_since)
+    }
+    /// Add `ETag` and `Last-Modified` headers for validators which are set
+    ///
+    /// Should be called after the status line is written, both for `200 OK`
+    /// and for `304 Not Modified` responses.
+    pub fn add_headers<S>(&self, e: &mut Encoder<S>) {
+        if let Some((weak, tag)) = self.etag {
+            e.format_header("ETag", format_args!("{}\"{}\"",
+                if weak { "W/" } else { "" }, tag))
+                .expect("entity tag is validated");
+        }
+        if let Some(ref date) = self.last_modified {
+            e.format_header("Last-Modified", date)
+                .expect("always valid to add a date");
+        }
+    }
+
+    fn check(&self, if_none_match: &[&[u8]], if_modified_since: Option<&[u8]>)
+        -> bool
+    {
+        if if_none_match.len() > 0 {
+            let tag = match self.etag {
+                Some((_, tag)) => tag,
+                None => return false,
+            };
+            return if_none_match.iter()
+                .any(|value| etag_list_matches(value, tag));
+        }
+        match (if_modified_since, self.last_modified) {
+            (Some(value), Some(modified)) => {
+                from_utf8(value).ok()
+                .and_then(|x| x.trim().parse::<HttpDate>().ok())
+                .map(|since| modified <= since)
+                .unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_etag(tag: &str) -> bool {
+    tag.bytes().all(|c| c == 0x21 || (c >= 0x23 && c != 0x7F))
+}
+
+/// Weak comparison of every tag in `If-None-Match` value with `tag`
+fn etag_list_matches(value: &[u8], tag: &str) -> bool {
+    let mut rest = value;
+    loop {
+        while let Some((&c, tail)) = rest.split_first() {
+            if c == b' ' || c == b'\t' || c == b',' {
+                rest = tail;
+            } else {
+                break;
+            }
+        }
+        if rest.len() == 0 {
+            return false;
+        }
+        if rest[0] == b'*' {
+            return true;
+        }
+        if rest.starts_with(b"W/") {
+            rest = &rest[2..];
+        }
+        if rest.first() != Some(&b'"') {
+            // invalid list, ignore the rest of it
+            return false;
+        }
+        let end = match rest[1..].iter().position(|&c| c == b'"') {
+            Some(end) => end + 1,
+            None => return false,
+        };
+        if &rest[1..end] == tag.as_bytes() {
+            return true;
+        }
+        rest = &rest[end+1..];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{UNIX_EPOCH, Duration};
+    use super::{Validators, etag_list_matches};
+
+    #[test]
+    fn etag_list() {
+        assert!(etag_list_matches(b"\"abc\"", "abc"));
+        assert!(etag_list_matches(b"W/\"abc\"", "abc"));
+        assert!(etag_list_matches(b"\"x\", W/\"abc\"", "abc"));
+        assert!(etag_list_matches(b"\"a,b\" , \"abc\"", "abc"));
+        assert!(etag_list_matches(b"*", "abc"));
+        assert!(!etag_list_matches(b"\"a,b\"", "a"));
+        assert!(!etag_list_matches(b"\"abcd\"", "abc"));
+        assert!(!etag_list_matches(b"abc", "abc"));
+        assert!(!etag_list_matches(b"", "abc"));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let mut v = Validators::new();
+        v.weak_etag("abc");
+        assert!(v.check(&[b"\"abc\""], None));
+        assert!(v.check(&[b"\"x\"", b"W/\"abc\""], None));
+        assert!(!v.check(&[b"\"x\""], None));
+        // If-Modified-Since is ignored when If-None-Match is present
+        v.last_modified(UNIX_EPOCH);
+        assert!(!v.check(&[b"\"x\""],
+            Some(b"Thu, 01 Jan 1970 00:00:00 GMT")));
+        assert!(!Validators::new().check(&[b"*"], None));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let mut v = Validators::new();
+        assert!(!v.check(&[], Some(b"Sun, 06 Nov 1994 08:49:37 GMT")));
+        v.last_modified(UNIX_EPOCH + Duration::new(784111777, 500));
+        assert!(v.check(&[], Some(b"Sun, 06 Nov 1994 08:49:37 GMT")));
+        assert!(v.check(&[], Some(b"Sun, 06 Nov 1994 08:49:38 GMT")));
+        assert!(!v.check(&[], Some(b"Sun, 06 Nov 1994 08:49:36 GMT")));
+        assert!(!v.check(&[], Some(b"yesterday")));
+        assert!(!v.check(&[], None));
+    }
+}
@@ -1,7 +1,11 @@
+use std::net::IpAddr;
 use std::time::Duration;
 use std::sync::Arc;
 
-use server::{Config};
+use base_serializer::{HeaderLimits, check_header};
+use server::{Config, MemoryBudget, LoadShedding};
+use server::encoder::AutoHeaders;
+#[cfg(feature="testing")] use testing::FaultInjector;
 
 impl Config {
     /// Create a config with defaults
@@ -16,6 +20,20 @@ impl Config {
             input_body_whole_timeout: Duration::new(3600, 0),
             output_body_byte_timeout: Duration::new(15, 0),
             output_body_whole_timeout: Duration::new(3600, 0),
+            hijack_write_timeout: Duration::new(10, 0),
+            memory_budget: None,
+            load_shedding: None,
+            allow_get_body: true,
+            trusted_proxies: Vec::new(),
+            max_chunk_size: None,
+            origin_server: None,
+            linger_timeout: Duration::new(5, 0),
+            drain_timeout: None,
+            require_host: true,
+            reject_conflicting_host: false,
+            max_connection_age: None,
+            header_limits: HeaderLimits::default(),
+            auto_headers: AutoHeaders::default(),
         }
     }
     /// A number of inflight requests until we stop reading more requests
@@ -83,4 +101,216 @@ impl Config {
         self.output_body_whole_timeout = value;
         self
     }
+    /// Timeout for writing response which precedes hijacking a connection
+    ///
+    /// This is mostly a timeout of sending `101 Switching Protocols`
+    /// response of a websocket handshake. When timeout expires connection is
+    /// closed with `HijackTimeout` error.
+    pub fn hijack_write_timeout(&mut self, value: Duration) -> &mut Self {
+        self.hijack_write_timeout = value;
+        self
+    }
+    /// Time to flush pending output when connection is about to be closed
+    ///
+    /// This applies both when a response requested closing the connection
+    /// and when client closed its side of the connection: already received
+    /// requests are responded to and the output is flushed before `Proto`
+    /// resolves successfully. If that takes longer, connection is closed
+    /// with an `OutputDropped` error (see `Error::dropped_bytes`).
+    ///
+    /// Default is 5 seconds.
+    pub fn linger_timeout(&mut self, value: Duration) -> &mut Self {
+        self.linger_timeout = value;
+        self
+    }
+    /// Discard input for this long before closing the connection
+    ///
+    /// When server closes the connection (a response requested closing it,
+    /// or a request was rejected) while client is still sending data, e.g.
+    /// pipelined requests or a request body, closing a socket with unread
+    /// data makes the kernel send RST. Some clients then discard the
+    /// response even if it was already delivered.
+    ///
+    /// With this setting, when all the output is flushed, `Proto` shuts
+    /// down the write half of the connection and reads and discards input
+    /// until client closes the connection or the timeout expires, then
+    /// resolves the same way it would without draining. Default is to close
+    /// the connection right away.
+    ///
+    /// Write half is shut down with `HalfClose` if it's enabled by
+    /// `Proto::enable_half_close` (`Proto::from_tcp` and `spawn_listener`
+    /// do that), otherwise `AsyncWrite::shutdown` is called, which for
+    /// `TcpStream` doesn't send anything to the client.
+    pub fn drain_timeout(&mut self, value: Duration) -> &mut Self {
+        self.drain_timeout = Some(value);
+        self
+    }
+    /// Close connections that are open for longer than this
+    ///
+    /// Responses started after connection reached this age are sent with
+    /// `Connection: close` and connection is closed after the response
+    /// (requests already pipelined after it are dropped). This helps to
+    /// rebalance long-living keep-alive connections among backends.
+    ///
+    /// By default connection age is not limited.
+    pub fn max_connection_age(&mut self, value: Duration) -> &mut Self {
+        self.max_connection_age = Some(value);
+        self
+    }
+    /// Set process-wide memory budget for buffered requests
+    ///
+    /// Same budget should be shared by all connections (i.e. create it
+    /// once and pass to each config). See `MemoryBudget` for details.
+    pub fn memory_budget(&mut self, budget: &Arc<MemoryBudget>) -> &mut Self {
+        self.memory_budget = Some(budget.clone());
+        self
+    }
+    /// Disable keep-alive when server is overloaded
+    ///
+    /// Same policy should be shared by all connections (i.e. create it
+    /// once and pass to each config). See `LoadShedding` for details.
+    pub fn load_shedding(&mut self, policy: &Arc<LoadShedding>) -> &mut Self {
+        self.load_shedding = Some(policy.clone());
+        self
+    }
+    /// Allow requests with body for `GET` and `HEAD` methods
+    ///
+    /// By default body is allowed (and passed to the codec as usual),
+    /// because specification doesn't prohibit it. Disable it to close
+    /// connections with `BodyNotAllowed` error on such requests, as some
+    /// proxies might interpret the body differently (request smuggling).
+    ///
+    /// Note: body is never allowed for `TRACE` requests.
+    pub fn allow_get_body(&mut self, value: bool) -> &mut Self {
+        self.allow_get_body = value;
+        self
+    }
+    /// Addresses of proxies which are trusted to set `Forwarded` headers
+    ///
+    /// See `Head::forwarded_client`. Default is empty list, i.e. forwarding
+    /// headers are never taken into account.
+    pub fn trusted_proxies(&mut self, value: &[IpAddr]) -> &mut Self {
+        self.trusted_proxies = value.to_vec();
+        self
+    }
+    /// Maximum size of a single chunk of a chunked request body
+    ///
+    /// Request is rejected with `ChunkTooLarge` error as soon as a chunk
+    /// header with larger size is received. This mostly matters for
+    /// progressive codecs, where whole body size isn't limited by
+    /// `RecvMode`. By default there is no limit.
+    pub fn max_chunk_size(&mut self, value: u64) -> &mut Self {
+        self.max_chunk_size = Some(value);
+        self
+    }
+    /// Canonicalize requests in absolute-form for an origin server
+    ///
+    /// By default request-target like `http://example.com/path` is passed
+    /// to the dispatcher as is, and each dispatcher has to decide whether
+    /// it serves this host. With this setting, when scheme and authority
+    /// match `scheme` and one of `authorities` (port is optional if it's
+    /// the default one), request-target is rewritten to origin-form
+    /// (`/path`), so `Head::path()` and `Head::request_target()` look the
+    /// same as for usual requests. Otherwise connection is closed with
+    /// `ForeignRequestTarget` error.
+    ///
+    /// Note: host of the request still comes from the request-target.
+    pub fn origin_server(&mut self, scheme: &str, authorities: &[&str])
+        -> &mut Self
+    {
+        self.origin_server = Some((scheme.to_string(),
+            authorities.iter().map(|x| x.to_string()).collect()));
+        self
+    }
+    /// Reject HTTP/1.1 requests without `Host` header
+    ///
+    /// RFC 7230 requires it, so this is enabled by default. Rejected
+    /// request gets `400 Bad Request` response (after responses to all
+    /// the previous requests on the connection), then the connection is
+    /// closed with `MissingHost` error. HTTP/1.0 requests are allowed to
+    /// omit the header regardless of this setting.
+    pub fn require_host(&mut self, value: bool) -> &mut Self {
+        self.require_host = value;
+        self
+    }
+    /// Reject requests where `Host` header differs from request-target
+    ///
+    /// By default host from the request-target is preferred (as allowed by
+    /// the specification) and `Head::has_conflicting_host()` returns true.
+    /// When enabled, such requests get `400 Bad Request` response and
+    /// connection is closed with `ConflictingHost` error.
+    pub fn reject_conflicting_host(&mut self, value: bool) -> &mut Self {
+        self.reject_conflicting_host = value;
+        self
+    }
+    /// Only allow tokens (RFC 7230) as names of response headers
+    ///
+    /// By default header names are only checked for line breaks. Enable
+    /// this when proxying headers from untrusted upstreams, so that
+    /// `add_header` and `format_header` fail with `InvalidHeaderName`
+    /// instead of writing garbage like spaces or non-ASCII bytes.
+    pub fn strict_header_names(&mut self, value: bool) -> &mut Self {
+        self.header_limits.strict_names = value;
+        self
+    }
+    /// Maximum size of a single response header value
+    ///
+    /// Values added by `add_header` and `format_header` (including ones
+    /// formatted from `Display`) larger than this fail with
+    /// `InvalidHeaderValue` and nothing is written. By default there is no
+    /// limit.
+    pub fn max_header_value_size(&mut self, value: usize) -> &mut Self {
+        self.header_limits.max_value_size = Some(value);
+        self
+    }
+    /// Add `Date` header with the current time to every response
+    ///
+    /// The header is added by `Encoder::done_headers` unless application
+    /// has already added one (e.g. with `Encoder::add_date`). Disabled by
+    /// default.
+    #[cfg(feature="date_header")]
+    pub fn auto_date_header(&mut self, value: bool) -> &mut Self {
+        self.auto_headers.date = value;
+        self
+    }
+    /// Add `Server: <name>` header to every response
+    ///
+    /// Similarly to `auto_date_header`, responses which already have
+    /// a `Server` header (e.g. proxied ones) are left intact. By default
+    /// no `Server` header is added.
+    ///
+    /// # Panics
+    ///
+    /// When `name` is not a valid header value (i.e. contains line breaks)
+    pub fn auto_server_header(&mut self, name: &str) -> &mut Self {
+        check_header("Server", name.as_bytes(), &HeaderLimits::default())
+            .expect("valid Server header");
+        self.auto_headers.server = Some(Arc::from(name));
+        self
+    }
+    /// Replace every `*_timeout` value with `FaultInjector::timeout`
+    ///
+    /// This is for testing timeout handling without waiting for real
+    /// timeouts (see `testing` module). Timeouts set after this call are
+    /// not affected.
+    #[cfg(feature="testing")]
+    pub fn inject_faults(&mut self, injector: &FaultInjector) -> &mut Self {
+        self.first_byte_timeout = injector.timeout(self.first_byte_timeout);
+        self.keep_alive_timeout = injector.timeout(self.keep_alive_timeout);
+        self.headers_timeout = injector.timeout(self.headers_timeout);
+        self.input_body_byte_timeout =
+            injector.timeout(self.input_body_byte_timeout);
+        self.input_body_whole_timeout =
+            injector.timeout(self.input_body_whole_timeout);
+        self.output_body_byte_timeout =
+            injector.timeout(self.output_body_byte_timeout);
+        self.output_body_whole_timeout =
+            injector.timeout(self.output_body_whole_timeout);
+        self.hijack_write_timeout =
+            injector.timeout(self.hijack_write_timeout);
+        self.linger_timeout = injector.timeout(self.linger_timeout);
+        self.drain_timeout = self.drain_timeout
+            .map(|value| injector.timeout(value));
+        self
+    }
 }
@@ -0,0 +1,128 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use server::Config;
+
+
+/// A shared config which can be replaced while server is running
+///
+/// Pass it to `spawn_listener` (or `Proto::set_config_handle`) instead of
+/// `Arc<Config>` to adjust timeouts and limits at runtime:
+///
+/// ```rust,ignore
+/// let config = ConfigHandle::new(&Config::new().done());
+/// spawn_listener(listener, config.clone(), dispatcher_factory, &handle);
+/// // later, e.g. on SIGHUP
+/// config.set(&Config::new().keep_alive_timeout(dur).done());
+/// ```
+///
+/// New connections use the latest config. Existing connections pick it up
+/// when they are idle (no requests in progress) and the next request
+/// arrives, so no request ever sees two different configs.
+///
+/// Memory budget and load shedding account connections, so the config of
+/// an existing connection is only replaced if the new one refers to the
+/// same `MemoryBudget` and `LoadShedding` objects (or to none of them).
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: RwLock<Arc<Config>>,
+    generation: AtomicUsize,
+}
+
+fn same_arc<T>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+    match (a, b) {
+        (&Some(ref a), &Some(ref b)) => Arc::ptr_eq(a, b),
+        (&None, &None) => true,
+        _ => false,
+    }
+}
+
+impl ConfigHandle {
+    /// Create a handle holding the config
+    pub fn new(config: &Arc<Config>) -> ConfigHandle {
+        ConfigHandle {
+            inner: Arc::new(Inner {
+                config: RwLock::new(config.clone()),
+                generation: AtomicUsize::new(1),
+            }),
+        }
+    }
+    /// Returns current config
+    pub fn get(&self) -> Arc<Config> {
+        self.inner.config.read().expect("config lock").clone()
+    }
+    /// Replace the config for new connections and idle existing ones
+    pub fn set(&self, config: &Arc<Config>) {
+        *self.inner.config.write().expect("config lock") = config.clone();
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+    }
+    /// Replace `config` if handle has been updated since `generation`
+    ///
+    /// Zero `generation` means config has never been loaded from the
+    /// handle. Returns `true` if config is replaced.
+    pub(crate) fn reload(&self, generation: &mut usize,
+        config: &mut Arc<Config>)
+        -> bool
+    {
+        let current = self.inner.generation.load(Ordering::SeqCst);
+        if current == *generation {
+            return false;
+        }
+        *generation = current;
+        let new = self.get();
+        if !same_arc(&new.memory_budget, &config.memory_budget) ||
+           !same_arc(&new.load_shedding, &config.load_shedding)
+        {
+            debug!("Config is not reloaded: memory budget or load shedding \
+                    differs");
+            return false;
+        }
+        *config = new;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use server::{Config, MemoryBudget};
+    use super::ConfigHandle;
+
+    #[test]
+    fn reload() {
+        let mut cfg = Config::new().done();
+        let handle = ConfigHandle::new(&cfg);
+        let mut generation = 0;
+        assert!(handle.reload(&mut generation, &mut cfg));
+        assert!(!handle.reload(&mut generation, &mut cfg));
+
+        let new = Config::new().keep_alive_timeout(Duration::new(1, 0)).done();
+        handle.set(&new);
+        assert!(Arc::ptr_eq(&handle.get(), &new));
+        assert!(handle.reload(&mut generation, &mut cfg));
+        assert!(Arc::ptr_eq(&cfg, &new));
+        assert!(!handle.reload(&mut generation, &mut cfg));
+    }
+
+    #[test]
+    fn different_budget() {
+        let budget = MemoryBudget::new(1000);
+        let mut cfg = Config::new().memory_budget(&budget).done();
+        let handle = ConfigHandle::new(&cfg);
+        let mut generation = 0;
+        assert!(handle.reload(&mut generation, &mut cfg));
+        handle.set(&Config::new().done());
+        assert!(!handle.reload(&mut generation, &mut cfg));
+        handle.set(&Config::new().memory_budget(&budget)
+            .allow_get_body(false).done());
+        assert!(handle.reload(&mut generation, &mut cfg));
+        assert!(!cfg.allow_get_body);
+    }
+}
@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+
+/// Statistics of a server connection
+///
+/// A snapshot is available in `Head::connection_info()` when request
+/// headers are received (so dispatcher can use it for logging or
+/// load-balancing decisions) and at any time from `Proto::connection_info()`.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    established_at: Instant,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    requests_served: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(established_at: Instant) -> ConnectionInfo {
+        ConnectionInfo {
+            established_at: established_at,
+            peer_addr: None,
+            local_addr: None,
+            requests_served: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        }
+    }
+    pub(crate) fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+    pub(crate) fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.local_addr = Some(addr);
+    }
+    pub(crate) fn add_received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+    }
+    pub(crate) fn add_sent(&mut self, bytes: u64) {
+        self.bytes_sent += bytes;
+    }
+    pub(crate) fn request_served(&mut self) {
+        self.requests_served += 1;
+    }
+    /// Time when connection was accepted (i.e. protocol handler created)
+    ///
+    /// Taken from the timer of the protocol, like `Head::received_at`.
+    pub fn established_at(&self) -> Instant {
+        self.established_at
+    }
+    /// Address of the peer
+    ///
+    /// Set by `Proto::from_tcp`, `spawn_listener` or explicitly by
+    /// `Proto::set_peer_addr` (e.g. for TLS streams). This is the address
+    /// of the socket, see `Head::forwarded_client` for requests coming
+    /// through a proxy.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+    /// Local address the connection is accepted on
+    ///
+    /// Useful when server listens on multiple addresses. Set the same way
+    /// as `peer_addr`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+    /// Time elapsed since the connection was established
+    pub fn age(&self) -> Duration {
+        self.established_at.elapsed()
+    }
+    /// Number of responses completely written on the connection
+    ///
+    /// Zero for the first request on the connection, so `> 0` means
+    /// connection was reused by keep-alive.
+    pub fn requests_served(&self) -> u64 {
+        self.requests_served
+    }
+    /// Number of bytes read from the connection
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+    /// Number of bytes of responses written to the output buffer
+    ///
+    /// This includes bytes that are not flushed to the network yet.
+    /// Bytes written into `RawBody` count once they are written to the
+    /// socket.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+}
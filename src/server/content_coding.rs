@@ -0,0 +1,189 @@
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+use std::str::from_utf8;
+
+use headers::list;
+use super::Head;
+
+
+/// Content coding of a (usually pre-compressed) response body
+///
+/// This is used to serve `.gz` or `.br` sibling files of static assets
+/// when the client's `Accept-Encoding` permits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ContentCoding {
+    /// No encoding, original file
+    Identity,
+    /// `gzip` encoding, usually a file with `.gz` extension
+    Gzip,
+    /// `br` (brotli) encoding, usually a file with `.br` extension
+    Brotli,
+}
+
+impl ContentCoding {
+    /// Token used in `Accept-Encoding` and `Content-Encoding` headers
+    pub fn token(&self) -> &'static str {
+        match *self {
+            ContentCoding::Identity => "identity",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Brotli => "br",
+        }
+    }
+    /// Conventional file name suffix for a pre-compressed sibling file
+    ///
+    /// Returns an empty string for identity encoding
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ContentCoding::Identity => "",
+            ContentCoding::Gzip => ".gz",
+            ContentCoding::Brotli => ".br",
+        }
+    }
+    /// Returns a variant of the entity tag for this encoding
+    ///
+    /// Compressed representations must have distinct entity tags, so we
+    /// append encoding token into the tag: `"abc"` becomes `"abc-gzip"`
+    /// (weak tags keep their `W/` prefix). Tag is returned unchanged for
+    /// identity encoding.
+    pub fn etag(&self, etag: &str) -> String {
+        if *self == ContentCoding::Identity {
+            return etag.to_string();
+        }
+        if etag.len() >= 2 && etag.ends_with('"') {
+            format!("{}-{}\"", &etag[..etag.len()-1], self.token())
+        } else {
+            format!("{}-{}", etag, self.token())
+        }
+    }
+}
+
+/// Select the best encoding from `available` using `Accept-Encoding` values
+///
+/// Encodings with equal quality are preferred in the order of `available`.
+/// Identity is used as a fallback unless client explicitly forbids it, in
+/// which case `None` is returned (`406 Not Acceptable` should be sent).
+pub fn negotiate<'x, I>(accept_encoding: I, available: &[ContentCoding])
+    -> Option<ContentCoding>
+    where I: IntoIterator<Item=&'x [u8]>,
+{
+    let mut any = false;
+    let mut wildcard = None;
+    let mut explicit = [None; 3];
+    for value in accept_encoding {
+        let value = match from_utf8(value) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        for item in list::items(value) {
+            let token = item.value();
+            any = true;
+            let q = match item.quality() {
+                Some(q) => q,
+                None => continue,
+            };
+            if token == "*" {
+                wildcard = Some(q);
+            } else if token.eq_ignore_ascii_case("identity") {
+                explicit[0] = Some(q);
+            } else if token.eq_ignore_ascii_case("gzip") ||
+                      token.eq_ignore_ascii_case("x-gzip")
+            {
+                explicit[1] = Some(q);
+            } else if token.eq_ignore_ascii_case("br") {
+                explicit[2] = Some(q);
+            }
+        }
+    }
+    if !any {
+        // No header (or an empty one) means only identity is expected
+        return Some(ContentCoding::Identity);
+    }
+    let q = |c: ContentCoding| {
+        let idx = match c {
+            ContentCoding::Identity => 0,
+            ContentCoding::Gzip => 1,
+            ContentCoding::Brotli => 2,
+        };
+        match (explicit[idx], c) {
+            (Some(q), _) => q,
+            (None, ContentCoding::Identity) => match wildcard {
+                Some(0) => 0,
+                _ => 1,  // acceptable, but least preferred
+            },
+            (None, _) => wildcard.unwrap_or(0),
+        }
+    };
+    let mut best = None;
+    for &c in available {
+        let cur = q(c);
+        if cur > 0 && best.map(|(_, bq)| cur > bq).unwrap_or(true) {
+            best = Some((c, cur));
+        }
+    }
+    if let Some((c, _)) = best {
+        return Some(c);
+    }
+    if q(ContentCoding::Identity) > 0 {
+        return Some(ContentCoding::Identity);
+    }
+    return None;
+}
+
+pub fn preferred(head: &Head, available: &[ContentCoding])
+    -> Option<ContentCoding>
+{
+    negotiate(head.all_headers().iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("Accept-Encoding"))
+        .map(|h| h.value), available)
+}
+
+#[cfg(test)]
+mod test {
+    use super::negotiate;
+    use super::ContentCoding::{self, Identity, Gzip, Brotli};
+
+    const ALL: &[ContentCoding] = &[Brotli, Gzip, Identity];
+
+    fn neg(value: &str, available: &[ContentCoding])
+        -> Option<ContentCoding>
+    {
+        negotiate(vec![value.as_bytes()], available)
+    }
+
+    #[test]
+    fn no_header() {
+        assert_eq!(negotiate(Vec::<&[u8]>::new(), ALL), Some(Identity));
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(neg("gzip", ALL), Some(Gzip));
+        assert_eq!(neg("gzip, deflate, br", ALL), Some(Brotli));
+        assert_eq!(neg("gzip, deflate, br", &[Gzip, Brotli]), Some(Gzip));
+        assert_eq!(neg("deflate", ALL), Some(Identity));
+        assert_eq!(neg("gzip", &[Brotli]), Some(Identity));
+    }
+
+    #[test]
+    fn quality() {
+        assert_eq!(neg("br;q=0.5, gzip", ALL), Some(Gzip));
+        assert_eq!(neg("br;q=0, gzip;q=0.001", ALL), Some(Gzip));
+        assert_eq!(neg("br;q=0, gzip;q=0", ALL), Some(Identity));
+        assert_eq!(neg("*;q=0.5", ALL), Some(Brotli));
+        assert_eq!(neg("gzip;q=2", ALL), Some(Identity));
+    }
+
+    #[test]
+    fn identity_forbidden() {
+        assert_eq!(neg("gzip, identity;q=0", &[Identity]), None);
+        assert_eq!(neg("*;q=0", &[Identity]), None);
+        assert_eq!(neg("*;q=0, gzip", ALL), Some(Gzip));
+    }
+
+    #[test]
+    fn etag() {
+        assert_eq!(Gzip.etag("\"abc\""), "\"abc-gzip\"");
+        assert_eq!(Brotli.etag("W/\"abc\""), "W/\"abc-br\"");
+        assert_eq!(Identity.etag("\"abc\""), "\"abc\"");
+    }
+}
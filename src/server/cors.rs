@@ -0,0 +1,456 @@
+//! Cross-Origin Resource Sharing (CORS) middleware
+//!
+//! `Cors` wraps a `Dispatcher` and does two things:
+//!
+//! 1. Answers preflight requests (`OPTIONS` with `Origin` and
+//!    `Access-Control-Request-Method` headers) by itself, the wrapped
+//!    dispatcher never sees them
+//! 2. Adds `Access-Control-Allow-Origin` and related headers to responses
+//!    of actual cross-origin requests (using `Encoder::defer_header`, so
+//!    wrapped codec writes response as usual)
+//!
+//! Requests without `Origin` header or from origins that are not allowed
+//! are passed to the wrapped dispatcher unchanged. `Vary: Origin` is added
+//! to all the responses though, as they depend on the origin for caches.
+//!
+//! ```rust,ignore
+//! let cors = cors::Config::new()
+//!     .allow_origin("https://example.com")
+//!     .allow_methods(&["GET", "POST", "DELETE"])
+//!     .allow_headers(&["Content-Type", "Authorization"])
+//!     .max_age(Duration::new(3600, 0))
+//!     .done();
+//! let dispatcher = Cors::new(dispatcher, &cors);
+//! ```
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Async;
+use futures::future::{FutureResult, Either, ok};
+use tk_bufstream::{ReadBuf, WriteBuf};
+
+use enums::{Method, Status};
+use super::{Dispatcher, Codec, Head, Encoder, EncoderDone, Error, RecvMode};
+use super::BodyInfo;
+
+
+/// Configuration of the CORS middleware
+#[derive(Debug, Clone)]
+pub struct Config {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    max_age: Option<Duration>,
+    credentials: bool,
+}
+
+/// A dispatcher wrapper that implements CORS
+///
+/// See module-level documentation for details.
+pub struct Cors<D> {
+    config: Arc<Config>,
+    dispatcher: D,
+}
+
+/// A codec returned by the `Cors` dispatcher
+pub struct CorsCodec<C> {
+    state: State<C>,
+}
+
+enum State<C> {
+    Preflight { headers: Vec<(&'static str, String)>, allowed: bool },
+    Actual { codec: C, headers: Vec<(&'static str, String)> },
+}
+
+impl Config {
+    /// Create a config with defaults
+    ///
+    /// By default no origins are allowed, methods allowed are `GET`, `HEAD`
+    /// and `POST`, no request headers are allowed except CORS-safelisted
+    /// ones.
+    pub fn new() -> Config {
+        Config {
+            any_origin: false,
+            origins: Vec::new(),
+            methods: vec!["GET".into(), "HEAD".into(), "POST".into()],
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            max_age: None,
+            credentials: false,
+        }
+    }
+    /// Allow requests from the origin, e.g. `https://example.com`
+    ///
+    /// May be called multiple times to allow multiple origins.
+    pub fn allow_origin(&mut self, origin: &str) -> &mut Self {
+        self.origins.push(origin.to_string());
+        self
+    }
+    /// Allow requests from any origin
+    ///
+    /// Note: when credentials are allowed, the actual origin is sent back
+    /// instead of `*`, as browsers don't accept wildcard in this case.
+    pub fn allow_any_origin(&mut self) -> &mut Self {
+        self.any_origin = true;
+        self
+    }
+    /// Set list of methods allowed for cross-origin requests
+    pub fn allow_methods(&mut self, methods: &[&str]) -> &mut Self {
+        self.methods = methods.iter().map(|x| x.to_string()).collect();
+        self
+    }
+    /// Set list of request headers allowed for cross-origin requests
+    pub fn allow_headers(&mut self, headers: &[&str]) -> &mut Self {
+        self.headers = headers.iter().map(|x| x.to_string()).collect();
+        self
+    }
+    /// Set list of response headers javascript is allowed to read
+    pub fn expose_headers(&mut self, headers: &[&str]) -> &mut Self {
+        self.expose_headers = headers.iter().map(|x| x.to_string()).collect();
+        self
+    }
+    /// Time browser may cache the result of a preflight request
+    ///
+    /// By default `Access-Control-Max-Age` is not sent.
+    pub fn max_age(&mut self, value: Duration) -> &mut Self {
+        self.max_age = Some(value);
+        self
+    }
+    /// Allow cookies and HTTP authentication for cross-origin requests
+    pub fn allow_credentials(&mut self, value: bool) -> &mut Self {
+        self.credentials = value;
+        self
+    }
+    /// Create a Arc'd config clone to pass to the constructor
+    ///
+    /// This is just a convenience method.
+    pub fn done(&mut self) -> Arc<Config> {
+        Arc::new(self.clone())
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.any_origin ||
+            self.origins.iter().any(|x| x.eq_ignore_ascii_case(origin))
+    }
+
+    fn method_allowed(&self, method: &str) -> bool {
+        self.methods.iter().any(|x| x == method)
+    }
+
+    fn headers_allowed(&self, headers: &str) -> bool {
+        headers.split(',').map(|x| x.trim()).filter(|x| x.len() > 0)
+            .all(|name| {
+                self.headers.iter().any(|x| x.eq_ignore_ascii_case(name))
+            })
+    }
+
+    fn origin_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let mut result = Vec::with_capacity(4);
+        if self.any_origin && !self.credentials {
+            result.push(("Access-Control-Allow-Origin", "*".into()));
+        } else {
+            result.push(("Access-Control-Allow-Origin", origin.into()));
+        }
+        // even `*` is not sent when there is no `Origin` header
+        result.push(("Vary", "Origin".into()));
+        if self.credentials {
+            result.push(("Access-Control-Allow-Credentials", "true".into()));
+        }
+        return result;
+    }
+}
+
+fn header<'x>(head: &'x Head, name: &str) -> Option<&'x str> {
+    head.headers()
+        .find(|&(hname, _)| hname.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| ::std::str::from_utf8(value).ok())
+        .map(|x| x.trim())
+}
+
+impl<D> Cors<D> {
+    /// Wrap a dispatcher with CORS middleware
+    pub fn new(dispatcher: D, config: &Arc<Config>) -> Cors<D> {
+        Cors {
+            config: config.clone(),
+            dispatcher: dispatcher,
+        }
+    }
+}
+
+impl<S, D: Dispatcher<S>> Dispatcher<S> for Cors<D> {
+    type Codec = CorsCodec<D::Codec>;
+    fn headers_received(&mut self, head: &Head)
+        -> Result<Self::Codec, Error>
+    {
+        let cfg = &*self.config;
+        let origin = match header(head, "Origin") {
+            Some(origin) if cfg.origin_allowed(origin) => origin,
+            _ => {
+                return Ok(CorsCodec { state: State::Actual {
+                    codec: self.dispatcher.headers_received(head)?,
+                    headers: vec![("Vary", "Origin".into())],
+                }});
+            }
+        };
+        let req_method = header(head, "Access-Control-Request-Method");
+        match (head.method(), req_method) {
+            (Method::Options, Some(method)) => {
+                let req_headers = header(head,
+                    "Access-Control-Request-Headers").unwrap_or("");
+                let allowed = cfg.method_allowed(method) &&
+                    cfg.headers_allowed(req_headers);
+                let mut headers = cfg.origin_headers(origin);
+                if allowed {
+                    headers.push(("Access-Control-Allow-Methods",
+                                  cfg.methods.join(", ")));
+                    if cfg.headers.len() > 0 {
+                        headers.push(("Access-Control-Allow-Headers",
+                                      cfg.headers.join(", ")));
+                    }
+                    if let Some(max_age) = cfg.max_age {
+                        headers.push(("Access-Control-Max-Age",
+                                      max_age.as_secs().to_string()));
+                    }
+                } else {
+                    debug!("CORS preflight rejected for {:?}: {:?} {:?}",
+                        origin, method, req_headers);
+                }
+                Ok(CorsCodec { state: State::Preflight {
+                    headers: headers,
+                    allowed: allowed,
+                }})
+            }
+            _ => {
+                let mut headers = cfg.origin_headers(origin);
+                if cfg.expose_headers.len() > 0 {
+                    headers.push(("Access-Control-Expose-Headers",
+                                  cfg.expose_headers.join(", ")));
+                }
+                Ok(CorsCodec { state: State::Actual {
+                    codec: self.dispatcher.headers_received(head)?,
+                    headers: headers,
+                }})
+            }
+        }
+    }
+}
+
+impl<S, C: Codec<S>> Codec<S> for CorsCodec<C> {
+    type ResponseFuture = Either<C::ResponseFuture,
+                                 FutureResult<EncoderDone<S>, Error>>;
+    fn recv_mode(&mut self) -> RecvMode {
+        match self.state {
+            State::Preflight { .. } => RecvMode::buffered_upfront(0),
+            State::Actual { ref mut codec, .. } => codec.recv_mode(),
+        }
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        match self.state {
+            State::Preflight { .. } => Ok(Async::Ready(data.len())),
+            State::Actual { ref mut codec, .. } => {
+                codec.data_received(data, end)
+            }
+        }
+    }
+    fn body_progress(&mut self, info: &BodyInfo) {
+        match self.state {
+            State::Preflight { .. } => {}
+            State::Actual { ref mut codec, .. } => codec.body_progress(info),
+        }
+    }
+    fn raw_body_received(&mut self, data: &[u8]) {
+        match self.state {
+            State::Preflight { .. } => {}
+            State::Actual { ref mut codec, .. } => {
+                codec.raw_body_received(data)
+            }
+        }
+    }
+    fn start_response(&mut self, mut e: Encoder<S>) -> Self::ResponseFuture {
+        match self.state {
+            State::Preflight { ref headers, allowed } => {
+                if allowed {
+                    e.status(Status::NoContent);
+                } else {
+                    e.status(Status::Forbidden);
+                    e.add_length(0).unwrap();
+                }
+                for &(name, ref value) in headers {
+                    e.add_header(name, value).unwrap();
+                }
+                e.done_headers().unwrap();
+                Either::B(ok(e.done()))
+            }
+            State::Actual { ref mut codec, ref headers } => {
+                for &(name, ref value) in headers {
+                    e.defer_header(name, value).unwrap();
+                }
+                Either::A(codec.start_response(e))
+            }
+        }
+    }
+    fn hijack(&mut self, output: WriteBuf<S>, input: ReadBuf<S>) {
+        match self.state {
+            State::Preflight { .. } => unreachable!(),
+            State::Actual { ref mut codec, .. } => codec.hijack(output, input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::Future;
+    use futures::future::{ok, lazy};
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+
+    use server::{self, Proto, Encoder};
+    use server::buffered::{BufferedDispatcher, Request};
+    use Status;
+    use super::{Config, Cors};
+
+    /// Returns the response to the request and number of requests the
+    /// wrapped dispatcher received
+    fn request(cfg: &Arc<Config>, request: &str) -> (String, usize) {
+        let mut core = Core::new().unwrap();
+        let counter = Arc::new(AtomicUsize::new(0));
+        let hits = counter.clone();
+        let disp = BufferedDispatcher::new("127.0.0.1:80".parse().unwrap(),
+            &core.handle(), move || {
+                let hits = hits.clone();
+                move |_: Request, mut e: Encoder<MockData>| {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    e.status(Status::Ok);
+                    e.add_length(2).unwrap();
+                    e.done_headers().unwrap();
+                    e.write_body(b"ok");
+                    ok(e.done())
+                }
+            });
+        let mock = MockData::new();
+        let mut proto = Proto::new(mock.clone(),
+            &server::Config::new().done(), Cors::new(disp, cfg),
+            &core.handle());
+        mock.add_input(request);
+        core.run(lazy(|| proto.poll())).unwrap();
+        let output = String::from_utf8(mock.output(..)).unwrap();
+        (output, counter.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn origins() {
+        let cfg = Config::new().allow_origin("https://example.com").done();
+        assert!(cfg.origin_allowed("https://example.com"));
+        assert!(cfg.origin_allowed("HTTPS://EXAMPLE.COM"));
+        assert!(!cfg.origin_allowed("https://example.org"));
+        assert!(Config::new().allow_any_origin()
+            .done().origin_allowed("https://example.org"));
+    }
+
+    #[test]
+    fn preflight_checks() {
+        let cfg = Config::new()
+            .allow_methods(&["GET", "PUT"])
+            .allow_headers(&["Content-Type", "X-Token"])
+            .max_age(Duration::new(600, 0))
+            .done();
+        assert!(cfg.method_allowed("PUT"));
+        assert!(!cfg.method_allowed("DELETE"));
+        assert!(cfg.headers_allowed(""));
+        assert!(cfg.headers_allowed("content-type, x-token"));
+        assert!(!cfg.headers_allowed("content-type, x-other"));
+    }
+
+    #[test]
+    fn credentials() {
+        let cfg = Config::new().allow_any_origin().done();
+        assert_eq!(cfg.origin_headers("https://a.com"), vec![
+            ("Access-Control-Allow-Origin", "*".to_string()),
+            ("Vary", "Origin".to_string()),
+        ]);
+        let cfg = Config::new().allow_any_origin()
+            .allow_credentials(true).done();
+        assert_eq!(cfg.origin_headers("https://a.com"), vec![
+            ("Access-Control-Allow-Origin", "https://a.com".to_string()),
+            ("Vary", "Origin".to_string()),
+            ("Access-Control-Allow-Credentials", "true".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn preflight() {
+        let cfg = Config::new()
+            .allow_origin("https://example.com")
+            .allow_methods(&["GET", "PUT"])
+            .allow_headers(&["Content-Type"])
+            .max_age(Duration::new(600, 0))
+            .done();
+        let (output, hits) = request(&cfg, "OPTIONS /x HTTP/1.1\r\n\
+            Host: example.org\r\n\
+            Origin: https://example.com\r\n\
+            Access-Control-Request-Method: PUT\r\n\
+            Access-Control-Request-Headers: content-type\r\n\r\n");
+        assert_eq!(output, "HTTP/1.1 204 No Content\r\n\
+            Access-Control-Allow-Origin: https://example.com\r\n\
+            Vary: Origin\r\n\
+            Access-Control-Allow-Methods: GET, PUT\r\n\
+            Access-Control-Allow-Headers: Content-Type\r\n\
+            Access-Control-Max-Age: 600\r\n\r\n");
+        assert_eq!(hits, 0);
+
+        let (output, hits) = request(&cfg, "OPTIONS /x HTTP/1.1\r\n\
+            Host: example.org\r\n\
+            Origin: https://example.com\r\n\
+            Access-Control-Request-Method: DELETE\r\n\r\n");
+        assert_eq!(output, "HTTP/1.1 403 Forbidden\r\n\
+            Content-Length: 0\r\n\
+            Access-Control-Allow-Origin: https://example.com\r\n\
+            Vary: Origin\r\n\r\n");
+        assert_eq!(hits, 0);
+    }
+
+    #[test]
+    fn actual_request() {
+        let cfg = Config::new()
+            .allow_origin("https://example.com")
+            .expose_headers(&["X-Total"])
+            .done();
+        let (output, hits) = request(&cfg, "GET /x HTTP/1.1\r\n\
+            Host: example.org\r\n\
+            Origin: https://example.com\r\n\r\n");
+        assert_eq!(output, "HTTP/1.1 200 OK\r\n\
+            Access-Control-Allow-Origin: https://example.com\r\n\
+            Vary: Origin\r\n\
+            Access-Control-Expose-Headers: X-Total\r\n\
+            Content-Length: 2\r\n\r\nok");
+        assert_eq!(hits, 1);
+
+        // `OPTIONS` without `Access-Control-Request-Method` is not
+        // a preflight, and other origins are passed through unchanged
+        for req in &["OPTIONS /x HTTP/1.1\r\n\
+                      Host: example.org\r\n\
+                      Origin: https://example.com\r\n\r\n",
+                     "GET /x HTTP/1.1\r\n\
+                      Host: example.org\r\n\
+                      Origin: https://example.org\r\n\r\n",
+                     "GET /x HTTP/1.1\r\n\
+                      Host: example.org\r\n\r\n"]
+        {
+            let (output, hits) = request(&cfg, req);
+            assert_eq!(hits, 1);
+            assert_eq!(output.contains("Access-Control-Allow-Origin"),
+                       req.starts_with("OPTIONS"));
+            assert!(output.contains("\r\nVary: Origin\r\n"));
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use server::{Codec, Dispatcher, Error, Head};
+
+
+/// A dispatcher which calls a function for every request
+///
+/// The function receives a per-connection context (peer address, TLS
+/// info, whatever you pass to `DispatcherFn::new`) by mutable reference,
+/// so it can also be used to keep per-connection state. State shared by
+/// all connections (database handles, metrics) is usually captured by the
+/// function itself, which is shared with `Arc` by `DispatcherFactory`:
+///
+/// ```rust,ignore
+/// let metrics = Arc::new(Metrics::new());
+/// let factory = DispatcherFactory::new(move |addr: &mut SocketAddr, head| {
+///     metrics.request();
+///     Ok(MyCodec::new(*addr, head))
+/// });
+/// spawn_listener(listener, cfg, move |addr| factory.dispatcher(addr),
+///                &handle);
+/// ```
+pub struct DispatcherFn<C, F> {
+    context: C,
+    func: Arc<F>,
+}
+
+/// Creates `DispatcherFn` for every connection sharing the function
+///
+/// This is needed because closures can't be cloned, use
+/// `DispatcherFactory::dispatcher` in accept loop.
+pub struct DispatcherFactory<F> {
+    func: Arc<F>,
+}
+
+impl<C, F> DispatcherFn<C, F> {
+    /// Create a dispatcher with a connection context and a function
+    pub fn new<K>(context: C, func: F) -> DispatcherFn<C, F>
+        where F: Fn(&mut C, &Head) -> Result<K, Error>,
+    {
+        DispatcherFn {
+            context: context,
+            func: Arc::new(func),
+        }
+    }
+    /// Returns connection context
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+    /// Returns mutable reference to the connection context
+    pub fn context_mut(&mut self) -> &mut C {
+        &mut self.context
+    }
+}
+
+impl<F> DispatcherFactory<F> {
+    /// Create a factory from a function which creates a codec
+    pub fn new<C, K>(func: F) -> DispatcherFactory<F>
+        where F: Fn(&mut C, &Head) -> Result<K, Error>,
+    {
+        DispatcherFactory {
+            func: Arc::new(func),
+        }
+    }
+    /// Create a dispatcher for a connection with specified context
+    pub fn dispatcher<C>(&self, context: C) -> DispatcherFn<C, F> {
+        DispatcherFn {
+            context: context,
+            func: self.func.clone(),
+        }
+    }
+}
+
+impl<F> Clone for DispatcherFactory<F> {
+    fn clone(&self) -> DispatcherFactory<F> {
+        DispatcherFactory {
+            func: self.func.clone(),
+        }
+    }
+}
+
+impl<S, C, F, K> Dispatcher<S> for DispatcherFn<C, F>
+    where F: Fn(&mut C, &Head) -> Result<K, Error>,
+          K: Codec<S>,
+{
+    type Codec = K;
+
+    fn headers_received(&mut self, headers: &Head) -> Result<K, Error> {
+        (self.func)(&mut self.context, headers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{Async, Future};
+    use futures::future::{FutureResult, ok, lazy};
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+
+    use server::{Codec, Config, Encoder, EncoderDone, Error, Proto};
+    use server::{RecvMode};
+    use Status;
+    use super::DispatcherFactory;
+
+    struct Reply(SocketAddr, usize);
+
+    impl Codec<MockData> for Reply {
+        type ResponseFuture = FutureResult<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::buffered_upfront(0)
+        }
+        fn data_received(&mut self, data: &[u8], end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            assert!(end);
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, mut e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            let body = format!("{} {}", self.0, self.1);
+            e.status(Status::Ok);
+            e.add_length(body.len() as u64).unwrap();
+            e.done_headers().unwrap();
+            e.write_body(body.as_bytes());
+            ok(e.done())
+        }
+    }
+
+    #[test]
+    fn context() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let shared = counter.clone();
+        let factory = DispatcherFactory::new(move |addr: &mut SocketAddr, _| {
+            Ok(Reply(*addr, shared.fetch_add(1, Ordering::SeqCst)))
+        });
+        let mut core = Core::new().unwrap();
+        let cfg = Config::new().done();
+        for &(addr, num) in &[("127.0.0.1:1000", 0), ("127.0.0.2:2000", 1)] {
+            let mock = MockData::new();
+            let disp = factory.dispatcher(addr.parse().unwrap());
+            let mut proto = Proto::new(mock.clone(), &cfg, disp,
+                                       &core.handle());
+            mock.add_input("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+            core.run(lazy(|| proto.poll())).unwrap();
+            let output = String::from_utf8(mock.output(..)).unwrap();
+            assert!(output.ends_with(&format!("\r\n\r\n{} {}", addr, num)),
+                    "{}", output);
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,35 +1,73 @@
-use std::io;
+use std::io::{self, Cursor};
 use std::fmt::Display;
+use std::mem;
+use std::sync::Arc;
 
+use bytes::Buf as BytesBuf;
 use futures::{Future, Poll, Async};
+use netbuf::Buf;
 use tk_bufstream::{WriteBuf, WriteRaw, FutureWriteRaw};
 use tokio_io::AsyncWrite;
 
-use base_serializer::{MessageState, HeaderError};
-use enums::{Version, Status};
+use base_serializer::{MessageState, HeaderError, HeaderLimits};
+use base_serializer::{check_header, check_reason};
+use spans::Span;
+use enums::{Version, Status, Method};
 use super::headers::Head;
+use super::content_coding::ContentCoding;
+use super::ValidatedHeaders;
+#[cfg(feature="websocket")] use websocket::Frame;
+#[cfg(feature="compression")]
+use super::compression::Compressor;
+#[cfg(feature="date_header")]
+use super::conditional::Validators;
 
 
 /// This a response writer that you receive in `Codec`
 ///
 /// Methods of this structure ensure that everything you write into a buffer
 /// is consistent and valid protocol
+///
+/// Encoder (as well as `EncoderDone` and `RawBody`) is `Send` and `Sync`
+/// when `S` is, so CPU-heavy response can be prepared on a thread pool (e.g.
+/// `futures-cpupool`) and `EncoderDone` sent back to the connection.
 pub struct Encoder<S> {
     state: MessageState,
     io: WriteBuf<S>,
+    deferred: Vec<(i32, String, Vec<u8>)>,
+    limits: HeaderLimits,
+    close: bool,
+    continue_sent: bool,
+    auto_headers: AutoHeaders,
+    /// `Date` header is written in the final response
+    has_date: bool,
+    /// `Server` header is written in the final response
+    has_server: bool,
+    /// Final response is `101 Switching Protocols`
+    #[cfg_attr(not(feature="websocket"), allow(dead_code))]
+    switching_protocols: bool,
+    #[cfg(feature="compression")]
+    compressor: Option<Compressor>,
+    /// Bytes in the output buffer when encoder was created
+    start: usize,
+    /// Bytes flushed through this encoder
+    flushed: usize,
+    span: Span,
 }
 
 /// This structure returned from `Encoder::done` and works as a continuation
 /// that should be returned from the future that writes request.
 pub struct EncoderDone<S> {
     buf: WriteBuf<S>,
+    close: bool,
+    bytes: u64,
 }
 
 /// This structure contains all needed info to start response of the request
 /// in a correct manner
 ///
 /// This is ought to be used in serializer only
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct ResponseConfig {
     /// Whether request is a HEAD request
     pub is_head: bool,
@@ -37,12 +75,31 @@ pub struct ResponseConfig {
     pub do_close: bool,
     /// Version of HTTP request
     pub version: Version,
+    /// Client waits for `100 Continue` before sending request body
+    pub expect_continue: bool,
+    /// Protocol has already sent `100 Continue` for this request
+    pub continue_sent: bool,
+    /// Validation of headers added by the application
+    pub header_limits: HeaderLimits,
+    /// Headers added by `done_headers` if application hasn't added them
+    pub auto_headers: AutoHeaders,
 }
 
-/// A future that yields `RawBody` after buffer is empty
+/// Headers which are added to every response unless already present
+///
+/// See `Config::auto_date_header` and `Config::auto_server_header`.
+#[derive(Debug, Clone, Default)]
+pub struct AutoHeaders {
+    /// Add `Date` with the current time
+    pub date: bool,
+    /// Value of the `Server` header
+    pub server: Option<Arc<str>>,
+}
+
+/// A future that yields `RawBody`
 ///
 /// This future is created by `Encoder::raw_body()``
-pub struct FutureRawBody<S>(FutureWriteRaw<S>);
+pub struct FutureRawBody<S>(FutureWriteRaw<S>, Buf, bool, u64, Option<u64>);
 
 /// A future that yields `Encoder` again after buffer has less bytes
 ///
@@ -58,8 +115,19 @@ pub struct WaitFlush<S>(Option<Encoder<S>>, usize);
 /// This is a tiny wrapper around `WriteRaw` which is basically tiny wrapper
 /// around TcpStream or whatever `S` represents. Wrappers are used to
 /// reconstruct original object, `EncoderDone` in this case.
+///
+/// Bytes which are still buffered when raw body is created (i.e. response
+/// headers) are written along with the first write to the raw body using
+/// vectored IO (`writev`), so small response followed by a payload costs
+/// a single syscall. This only applies to `io::Write`: `sendfile` can't
+/// send anything but the file, so buffered bytes are written with
+/// a separate syscall before the first `sendfile` (and on `flush()`).
 pub struct RawBody<S> {
     io: WriteRaw<S>,
+    head: Buf,
+    close: bool,
+    bytes: u64,
+    expected: Option<u64>,
 }
 
 
@@ -70,14 +138,51 @@ impl<S> Encoder<S> {
     /// A server should respond with the 100 status code if it receives a
     /// 100-continue expectation.
     ///
+    /// Protocol sends `100 Continue` by itself when the request body is
+    /// still being received at the time the response is due, in that case
+    /// this method does nothing.
+    ///
     /// # Panics
     ///
     /// When the response is already started. It's expected that your response
     /// handler state machine will never call the method twice.
     pub fn response_continue(&mut self) {
+        if self.continue_sent {
+            self.continue_sent = false;
+            return;
+        }
         self.state.response_continue(&mut self.io.out_buf)
     }
 
+    /// Start an informational (1xx) response that has headers
+    ///
+    /// For example `103 Early Hints` with `Link` headers. Add headers with
+    /// `add_header` as usual, then call `end_informational`, after which
+    /// either another informational response or a final status line may
+    /// be written. The response is buffered and is sent along with
+    /// the following data unless you `flush` it explicitly.
+    ///
+    /// Note: informational responses must not be sent to HTTP/1.0 clients.
+    ///
+    /// # Panics
+    ///
+    /// When the final status line is already written, when `code` is not
+    /// in 1xx range or when it's 101 (Switching Protocols).
+    pub fn start_informational(&mut self, code: u16) {
+        let reason = Status::from(code).map(|s| s.reason())
+            .unwrap_or("Unknown");
+        self.state.start_informational(&mut self.io.out_buf, code, reason)
+    }
+
+    /// Finish headers of the informational response
+    ///
+    /// # Panics
+    ///
+    /// When `start_informational` wasn't called before.
+    pub fn end_informational(&mut self) {
+        self.state.end_informational(&mut self.io.out_buf)
+    }
+
     /// Write status line using `Status` enum
     ///
     /// This puts status line into a buffer immediately. If you don't
@@ -92,11 +197,18 @@ impl<S> Encoder<S> {
     /// as a final status code.
     pub fn status(&mut self, status: Status) {
         self.state.response_status(&mut self.io.out_buf,
-            status.code(), status.reason())
+            status.code(), status.reason());
+        self.span.record_status(status.code());
+        self.final_response_started(status.code());
+        self.write_deferred();
     }
 
     /// Write custom status line
     ///
+    /// Returns `InvalidReason` error if reason phrase contains CR or LF
+    /// characters, nothing is written in this case (so you can retry with
+    /// a sanitized reason or use `status()` instead).
+    ///
     /// # Panics
     ///
     /// When status line is already written. It's expected that your request
@@ -104,8 +216,114 @@ impl<S> Encoder<S> {
     ///
     /// When the status code is 100 (Continue). 100 is not allowed
     /// as a final status code.
-    pub fn custom_status(&mut self, code: u16, reason: &str) {
-        self.state.response_status(&mut self.io.out_buf, code, reason)
+    pub fn custom_status(&mut self, code: u16, reason: &str)
+        -> Result<(), HeaderError>
+    {
+        check_reason(reason)?;
+        self.state.response_status(&mut self.io.out_buf, code, reason);
+        self.span.record_status(code);
+        self.final_response_started(code);
+        self.write_deferred();
+        Ok(())
+    }
+
+    /// Add a header that will be written right after the status line
+    ///
+    /// This is useful for middleware wrapping a `Codec`: it can add headers
+    /// (like CORS ones) before passing encoder to the wrapped codec, which
+    /// writes status line and other headers as usual.
+    ///
+    /// Same restrictions as for `add_header` apply, the header is validated
+    /// immediately.
+    ///
+    /// This is a shortcut for `defer_header_with_priority` with priority `0`.
+    ///
+    /// # Panics
+    ///
+    /// When status line is already written.
+    pub fn defer_header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
+        -> Result<(), HeaderError>
+    {
+        self.defer_header_with_priority(name, value, 0)
+    }
+
+    /// Add a header that will be written right after the status line in
+    /// the order of `priority`
+    ///
+    /// Deferred headers with lower priority are written first, ones with
+    /// the same priority are written in the order they were added. Use
+    /// this when intermediaries expect some headers to go first.
+    ///
+    /// # Panics
+    ///
+    /// When status line is already written.
+    pub fn defer_header_with_priority<V: AsRef<[u8]>>(&mut self,
+        name: &str, value: V, priority: i32)
+        -> Result<(), HeaderError>
+    {
+        match self.state {
+            MessageState::ResponseStart { .. } |
+            MessageState::FinalResponseStart { .. } => {}
+            ref state => {
+                panic!("Called defer_header() method on a response \
+                    in state {:?}", state)
+            }
+        }
+        check_header(name, value.as_ref(), &self.limits)?;
+        self.deferred.push((priority, name.to_string(),
+                            value.as_ref().to_vec()));
+        Ok(())
+    }
+
+    fn write_deferred(&mut self) {
+        // stable sort, so the order of equal priorities is kept
+        self.deferred.sort_by_key(|&(priority, _, _)| priority);
+        let deferred = mem::replace(&mut self.deferred, Vec::new());
+        for (_, name, value) in deferred {
+            self.state.add_header(&mut self.io.out_buf, &name, &value,
+                                  &self.limits)
+                .expect("deferred header is validated");
+            self.header_written(&name);
+        }
+    }
+
+    /// Headers of the informational responses don't count
+    fn final_response_started(&mut self, code: u16) {
+        self.has_date = false;
+        self.has_server = false;
+        self.switching_protocols = code == 101;
+    }
+
+    /// Track headers which `done_headers` would add otherwise
+    fn header_written(&mut self, name: &str) {
+        if name.eq_ignore_ascii_case("Date") {
+            self.has_date = true;
+        } else if name.eq_ignore_ascii_case("Server") {
+            self.has_server = true;
+        }
+    }
+
+    fn add_auto_headers(&mut self) -> Result<(), HeaderError> {
+        if self.auto_headers.date && !self.has_date {
+            self.add_auto_date();
+        }
+        if !self.has_server {
+            if let Some(ref server) = self.auto_headers.server {
+                self.state.add_header(&mut self.io.out_buf, "Server",
+                                      server.as_bytes(), &self.limits)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="date_header")]
+    fn add_auto_date(&mut self) {
+        self.add_date();
+    }
+
+    // `Config::auto_date_header` requires `date_header` feature
+    #[cfg(not(feature="date_header"))]
+    fn add_auto_date(&mut self) {
     }
 
     /// Add a header to the message.
@@ -130,7 +348,10 @@ impl<S> Encoder<S> {
     pub fn add_header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
         -> Result<(), HeaderError>
     {
-        self.state.add_header(&mut self.io.out_buf, name, value.as_ref())
+        self.state.add_header(&mut self.io.out_buf, name, value.as_ref(),
+                              &self.limits)?;
+        self.header_written(name);
+        Ok(())
     }
 
     /// Same as `add_header` but allows value to be formatted directly into
@@ -141,7 +362,67 @@ impl<S> Encoder<S> {
     pub fn format_header<D: Display>(&mut self, name: &str, value: D)
         -> Result<(), HeaderError>
     {
-        self.state.format_header(&mut self.io.out_buf, name, value)
+        self.state.format_header(&mut self.io.out_buf, name, value,
+                                 &self.limits)?;
+        self.header_written(name);
+        Ok(())
+    }
+
+    /// Add multiple headers to the message at once
+    ///
+    /// This is useful for proxies that copy many headers. If any of the
+    /// headers is invalid nothing is written and an error is returned.
+    /// Same restrictions as for `add_header` apply.
+    ///
+    /// # Panics
+    ///
+    /// Panics when `add_headers` is called in the wrong state.
+    pub fn add_headers<I, N, V>(&mut self, headers: I)
+        -> Result<(), HeaderError>
+        where I: IntoIterator<Item=(N, V)>,
+              N: AsRef<str>,
+              V: AsRef<[u8]>,
+    {
+        let mut has_date = false;
+        let mut has_server = false;
+        let headers = headers.into_iter().inspect(|&(ref name, _)| {
+            let name = name.as_ref();
+            has_date |= name.eq_ignore_ascii_case("Date");
+            has_server |= name.eq_ignore_ascii_case("Server");
+        });
+        self.state.add_headers(&mut self.io.out_buf, headers, &self.limits)?;
+        self.has_date |= has_date;
+        self.has_server |= has_server;
+        Ok(())
+    }
+
+    /// Add a block of headers validated in advance
+    ///
+    /// This is the fastest way to add the same set of headers to many
+    /// responses: bytes are copied to the output buffer as is. Only the
+    /// `Config::max_header_value_size` limit is checked, everything else
+    /// is validated when `ValidatedHeaders` is built.
+    ///
+    /// # Panics
+    ///
+    /// Panics when called in the wrong state.
+    pub fn add_static_headers(&mut self, headers: &ValidatedHeaders)
+        -> Result<(), HeaderError>
+    {
+        headers.check(&self.limits)?;
+        self.state.add_raw_headers(&mut self.io.out_buf, headers.bytes());
+        let (date, server) = headers.has_date_server();
+        self.has_date |= date;
+        self.has_server |= server;
+        Ok(())
+    }
+
+    /// Reserve space for `bytes` of headers in the output buffer
+    ///
+    /// This is an optimization to avoid multiple reallocations when
+    /// adding many headers.
+    pub fn reserve_headers(&mut self, bytes: usize) {
+        self.io.out_buf.reserve_exact(bytes)
     }
 
     /// Add a content length to the message.
@@ -173,6 +454,74 @@ impl<S> Encoder<S> {
         self.state.add_chunked(&mut self.io.out_buf)
     }
 
+    /// Add `Content-Encoding` and `Vary` headers for a selected coding
+    ///
+    /// This is useful when serving pre-compressed files. `Vary:
+    /// Accept-Encoding` is added even for identity encoding, as response
+    /// depends on the request headers anyway. Note that if you add `ETag`
+    /// it should be unique for each encoding (see `ContentCoding::etag`).
+    ///
+    /// # Panics
+    ///
+    /// Panics when called in the wrong state.
+    pub fn add_content_coding(&mut self, coding: ContentCoding)
+        -> Result<(), HeaderError>
+    {
+        if coding != ContentCoding::Identity {
+            self.add_header("Content-Encoding", coding.token())?;
+        }
+        self.add_header("Vary", "Accept-Encoding")
+    }
+
+    /// Compress the response body on the fly using `coding`
+    ///
+    /// Adds `Content-Encoding` and `Vary` headers (see
+    /// `add_content_coding`) and sets chunked transfer encoding, so don't
+    /// add the body length yourself. Data passed to `write_body` is
+    /// compressed from then on. Usually the coding is selected using
+    /// request headers:
+    ///
+    /// ```rust,ignore
+    /// let coding = head.preferred_coding(
+    ///     &[ContentCoding::Gzip, ContentCoding::Identity]);
+    /// e.status(Status::Ok);
+    /// e.add_compression(coding.unwrap_or(ContentCoding::Identity))?;
+    /// e.done_headers()?;
+    /// e.write_body(data);
+    /// ```
+    ///
+    /// Identity coding writes body as is. Compressed data is buffered by
+    /// the compressor, use `io::Write::flush` on the encoder to send
+    /// everything written so far (e.g. for streaming responses).
+    ///
+    /// `raw_body()` can't be used with compressed responses.
+    ///
+    /// Fails with `UnsupportedCoding` for `Brotli`, and the same way as
+    /// `add_chunked()` if response can't have a chunked body (e.g. it's
+    /// `204 No Content` or `Content-Length` is already added). Nothing is
+    /// written to the headers in these cases.
+    ///
+    /// # Panics
+    ///
+    /// Panics when called in the wrong state.
+    #[cfg(feature="compression")]
+    pub fn add_compression(&mut self, coding: ContentCoding)
+        -> Result<(), HeaderError>
+    {
+        let compressor = match coding {
+            ContentCoding::Identity => None,
+            ContentCoding::Gzip => Some(Compressor::gzip()),
+            ContentCoding::Brotli => {
+                return Err(HeaderError::UnsupportedCoding);
+            }
+        };
+        self.state.check_chunked()?;
+        self.add_content_coding(coding)?;
+        self.add_chunked()?;
+        self.compressor = compressor;
+        Ok(())
+    }
+
     /// Add a date header with the current date
     ///
     /// This is barely a shortcut for:
@@ -199,6 +548,9 @@ impl<S> Encoder<S> {
     /// Specifically `false` is returned when status is 1xx, 204, 304 or in
     /// the response to a `HEAD` request but not if the body has zero-length.
     ///
+    /// `Date` and `Server` headers are added here if they are enabled in
+    /// the config and weren't added by the application.
+    ///
     /// Similarly to `add_header()` it's fine to `unwrap()` here, unless you're
     /// doing some proxying.
     ///
@@ -206,6 +558,13 @@ impl<S> Encoder<S> {
     ///
     /// Panics when the response is in a wrong state.
     pub fn done_headers(&mut self) -> Result<bool, HeaderError> {
+        let final_response = matches!(self.state,
+            MessageState::Headers { .. } |
+            MessageState::FixedHeaders { .. } |
+            MessageState::ChunkedHeaders { .. });
+        if final_response {
+            self.add_auto_headers()?;
+        }
         self.state.done_headers(&mut self.io.out_buf)
     }
     /// Write a chunk of the message body.
@@ -229,6 +588,13 @@ impl<S> Encoder<S> {
     /// determine response body length (either Content-Length or
     /// Transfer-Encoding).
     pub fn write_body(&mut self, data: &[u8]) {
+        #[cfg(feature="compression")] {
+            if let Some(ref mut compressor) = self.compressor {
+                let data = compressor.write(data);
+                self.state.write_body(&mut self.io.out_buf, data);
+                return;
+            }
+        }
         self.state.write_body(&mut self.io.out_buf, data)
     }
     /// Returns true if `done()` method is already called and everything
@@ -245,8 +611,83 @@ impl<S> Encoder<S> {
     ///
     /// When the response is in the wrong state.
     pub fn done(mut self) -> EncoderDone<S> {
+        #[cfg(feature="compression")] {
+            if let Some(ref mut compressor) = self.compressor {
+                let data = compressor.finish();
+                self.state.write_body(&mut self.io.out_buf, data);
+            }
+        }
         self.state.done(&mut self.io.out_buf);
-        EncoderDone { buf: self.io }
+        let bytes = self.bytes_written();
+        EncoderDone { buf: self.io, close: self.close, bytes: bytes }
+    }
+    /// Reply with `426 Upgrade Required` listing `protocols` we support
+    ///
+    /// This writes the whole response (with empty body). Use it when
+    /// a resource can't be served without switching protocols, e.g. when
+    /// client sends `Upgrade` which doesn't match any of `protocols`
+    /// (see `Head::upgrade_protocols`). Protocols that aren't supported
+    /// may also be ignored by just serving request as a plain HTTP one.
+    ///
+    /// # Panics
+    ///
+    /// When status line is already written or protocol names are invalid
+    /// header values.
+    pub fn upgrade_required(mut self, protocols: &[&str]) -> EncoderDone<S> {
+        self.status(Status::UpgradeRequired);
+        self.add_header("Upgrade", protocols.join(", ")).unwrap();
+        self.add_header("Connection", "upgrade").unwrap();
+        self.add_length(0).unwrap();
+        self.done_headers().unwrap();
+        self.done()
+    }
+    /// Write a websocket frame after `101 Switching Protocols` response
+    ///
+    /// The frame is put into the same buffer as the response headers, so
+    /// it's sent along with them and before anything sent by the
+    /// `websocket::Loop` created in `hijack()` (or in
+    /// `Service::start_websocket` of the `BufferedDispatcher`). This is
+    /// useful to send a hello message or an initial state snapshot to the
+    /// client.
+    ///
+    /// # Panics
+    ///
+    /// When headers are not done yet or response status is not 101.
+    #[cfg(feature="websocket")]
+    pub fn write_websocket_frame(&mut self, frame: &Frame) {
+        assert!(self.switching_protocols && self.state.is_after_headers(),
+            "websocket frame can only be written after headers of \
+             101 Switching Protocols response");
+        frame.write(&mut self.io.out_buf, false);
+    }
+    /// Reply with `304 Not Modified` and validators of the resource
+    ///
+    /// This writes the whole response. Use it when
+    /// `Validators::is_not_modified` returns true.
+    ///
+    /// # Panics
+    ///
+    /// When status line is already written.
+    #[cfg(feature="date_header")]
+    pub fn not_modified(mut self, validators: &Validators) -> EncoderDone<S> {
+        self.status(Status::NotModified);
+        validators.add_headers(&mut self);
+        self.done_headers().unwrap();
+        self.done()
+    }
+    /// Close the connection after this response
+    ///
+    /// `Connection: close` header is added if headers are not written yet
+    /// (returns `false` if it's too late for that). In any case connection
+    /// is closed as soon as this response is sent, requests that are already
+    /// pipelined after this one are dropped without a response.
+    ///
+    /// This is useful when handler discovers that connection must not be
+    /// reused, e.g. on authentication failure or when the request body can't
+    /// be read to the end.
+    pub fn force_close(&mut self) -> bool {
+        self.close = true;
+        self.state.force_close()
     }
     /// Returns a raw body for zero-copy writing techniques
     ///
@@ -267,11 +708,42 @@ impl<S> Encoder<S> {
     /// # Panics
     ///
     /// This method panics if it's called when headers are not written yet.
-    pub fn raw_body(self) -> FutureRawBody<S> {
+    pub fn raw_body(mut self) -> FutureRawBody<S> {
+        assert!(self.state.is_after_headers());
+        self.assert_uncompressed();
+        let bytes = self.bytes_written();
+        let head = mem::replace(&mut self.io.out_buf, Buf::new());
+        FutureRawBody(self.io.borrow_raw(), head, self.close, bytes, None)
+    }
+    /// Returns a raw body which must have exactly `bytes` bytes written
+    ///
+    /// Same as `raw_body()`, but the number of bytes written is tracked
+    /// (including `sendfile`): writes past the limit fail and `done()`
+    /// closes the connection if the body is incomplete, so that the
+    /// peer never sees a response that doesn't match `Content-Length`
+    /// (or `Content-Range`). Use `RawBody::try_done()` to get an error
+    /// instead.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if it's called when headers are not written yet.
+    pub fn raw_body_exact(mut self, bytes: u64) -> FutureRawBody<S> {
         assert!(self.state.is_after_headers());
-        FutureRawBody(self.io.borrow_raw())
+        self.assert_uncompressed();
+        let written = self.bytes_written();
+        let head = mem::replace(&mut self.io.out_buf, Buf::new());
+        FutureRawBody(self.io.borrow_raw(), head, self.close, written,
+                      Some(written + bytes))
     }
 
+    #[cfg(feature="compression")]
+    fn assert_uncompressed(&self) {
+        assert!(self.compressor.is_none(),
+            "raw body can't be used with compression");
+    }
+    #[cfg(not(feature="compression"))]
+    fn assert_uncompressed(&self) {}
+
     /// Flush the data to underlying socket
     ///
     /// If the whole buffer could not be flushed it schedules a wakeup of
@@ -282,7 +754,13 @@ impl<S> Encoder<S> {
     pub fn flush(&mut self) -> Result<(), io::Error>
         where S: AsyncWrite
     {
-        self.io.flush()
+        let old_len = self.io.out_buf.len();
+        let result = self.io.flush();
+        self.flushed += old_len - self.io.out_buf.len();
+        result
+    }
+    fn bytes_written(&self) -> u64 {
+        (self.flushed + self.io.out_buf.len() - self.start) as u64
     }
     /// Returns bytes currently lying in the buffer
     ///
@@ -303,11 +781,95 @@ impl<S> Encoder<S> {
 impl<S> RawBody<S> {
     /// Returns `EncoderDone` object that might be passed back to the HTTP
     /// protocol
+    ///
+    /// If body was created by `raw_body_exact` and not all bytes are
+    /// written, connection is closed after the response.
     pub fn done(self) -> EncoderDone<S> {
-        EncoderDone { buf: self.io.into_buf() }
+        let mut close = self.close;
+        if let Some(left) = self.bytes_left() {
+            if left > 0 {
+                error!("Raw body is incomplete, {} bytes left. \
+                    Closing connection.", left);
+                close = true;
+            }
+        }
+        let mut buf = self.io.into_buf();
+        // raw body is only created when output buffer is empty
+        buf.out_buf = self.head;
+        EncoderDone { buf: buf, close: close, bytes: self.bytes }
+    }
+    /// Same as `done()` but returns an error if body is incomplete
+    ///
+    /// Returning the error from the codec closes the connection.
+    pub fn try_done(self) -> Result<EncoderDone<S>, io::Error> {
+        match self.bytes_left() {
+            Some(left) if left > 0 => {
+                Err(io::Error::new(io::ErrorKind::WriteZero,
+                    format!("raw body is incomplete, {} bytes left", left)))
+            }
+            _ => Ok(self.done()),
+        }
+    }
+    /// Returns number of bytes left to write in the body created by
+    /// `raw_body_exact`
+    pub fn bytes_left(&self) -> Option<u64> {
+        self.expected.map(|x| x.saturating_sub(self.bytes))
+    }
+    fn limit(&self, len: usize) -> io::Result<usize> {
+        match self.bytes_left() {
+            Some(0) if len > 0 => Err(too_long()),
+            Some(left) if (len as u64) > left => Ok(left as usize),
+            _ => Ok(len),
+        }
+    }
+}
+
+impl<S: AsyncWrite> RawBody<S> {
+    /// Writes bytes buffered before raw body with the `data` in a single
+    /// vectored write, returns number of bytes of `data` written
+    fn write_vectored(&mut self, data: &[u8]) -> io::Result<usize> {
+        while self.head.len() > 0 {
+            let head = self.head.len();
+            let written = {
+                let mut bufs = Cursor::new(&self.head[..])
+                    .chain(Cursor::new(data));
+                match self.io.get_mut().write_buf(&mut bufs)? {
+                    Async::Ready(0) => {
+                        return Err(io::ErrorKind::WriteZero.into());
+                    }
+                    Async::Ready(n) => n,
+                    Async::NotReady => {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                }
+            };
+            if written > head {
+                self.head.consume(head);
+                return Ok(written - head);
+            }
+            self.head.consume(written);
+        }
+        self.io.get_mut().write(data)
+    }
+}
+
+impl<S: io::Write> RawBody<S> {
+    fn flush_head(&mut self) -> io::Result<()> {
+        while self.head.len() > 0 {
+            match self.io.get_mut().write(&self.head[..])? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => self.head.consume(n),
+            }
+        }
+        Ok(())
     }
 }
 
+fn too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   "raw body is longer than expected")
+}
+
 impl<S> io::Write for Encoder<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         // TODO(tailhook) we might want to propatage error correctly
@@ -315,7 +877,17 @@ impl<S> io::Write for Encoder<S> {
         self.write_body(buf);
         Ok(buf.len())
     }
+    /// Sends out the data buffered by compressor (if any)
+    ///
+    /// This doesn't flush the data to the socket, use `Encoder::flush`
+    /// for that.
     fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature="compression")] {
+            if let Some(ref mut compressor) = self.compressor {
+                let data = compressor.flush();
+                self.state.write_body(&mut self.io.out_buf, data);
+            }
+        }
         Ok(())
     }
 }
@@ -328,9 +900,16 @@ impl<S: AsyncWrite> AsyncWrite for Encoder<S> {
 
 impl<S: AsyncWrite> io::Write for RawBody<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.io.get_mut().write(buf)
+        let limit = self.limit(buf.len())?;
+        if limit == 0 {
+            return Ok(0);
+        }
+        let bytes = self.write_vectored(&buf[..limit])?;
+        self.bytes += bytes as u64;
+        Ok(bytes)
     }
     fn flush(&mut self) -> io::Result<()> {
+        self.flush_head()?;
         self.io.get_mut().flush()
     }
 }
@@ -345,27 +924,61 @@ pub fn get_inner<S>(e: EncoderDone<S>) -> WriteBuf<S> {
     e.buf
 }
 
+pub fn wants_close<S>(e: &EncoderDone<S>) -> bool {
+    e.close
+}
+
+pub fn bytes_written<S>(e: &EncoderDone<S>) -> u64 {
+    e.bytes
+}
+
 pub fn new<S>(io: WriteBuf<S>, cfg: ResponseConfig) -> Encoder<S> {
     use base_serializer::Body::*;
 
     // TODO(tailhook) implement Connection: Close,
     // (including explicit one in HTTP/1.0) and maybe others
+    let body = if cfg.is_head { Head } else { Normal };
+    let close = cfg.do_close || cfg.version == Version::Http10;
     Encoder {
-        state: MessageState::ResponseStart {
-            body: if cfg.is_head { Head } else { Normal },
-            version: cfg.version,
-            close: cfg.do_close || cfg.version == Version::Http10,
+        state: if cfg.continue_sent {
+            MessageState::FinalResponseStart {
+                body: body, version: cfg.version, close: close }
+        } else {
+            MessageState::ResponseStart {
+                body: body, version: cfg.version, close: close }
         },
+        start: io.out_buf.len(),
+        flushed: 0,
         io: io,
+        deferred: Vec::new(),
+        limits: cfg.header_limits,
+        close: false,
+        continue_sent: cfg.continue_sent,
+        auto_headers: cfg.auto_headers,
+        has_date: false,
+        has_server: false,
+        switching_protocols: false,
+        #[cfg(feature="compression")]
+        compressor: None,
+        span: Span::none(),
     }
 }
 
+/// Set the request span which the response status is recorded into
+pub fn set_span<S>(e: &mut Encoder<S>, span: Span) {
+    e.span = span;
+}
+
 impl ResponseConfig {
     pub fn from(req: &Head) -> ResponseConfig {
         ResponseConfig {
             version: req.version(),
-            is_head: req.method() == "HEAD",
+            is_head: req.method() == Method::Head,
             do_close: req.connection_close(),
+            expect_continue: req.expect_continue(),
+            continue_sent: false,
+            header_limits: HeaderLimits::default(),
+            auto_headers: AutoHeaders::default(),
         }
     }
 }
@@ -374,7 +987,17 @@ impl<S: AsyncWrite> Future for FutureRawBody<S> {
     type Item = RawBody<S>;
     type Error = io::Error;
     fn poll(&mut self) -> Poll<RawBody<S>, io::Error> {
-        self.0.poll().map(|x| x.map(|y| RawBody { io: y }))
+        let io = match self.0.poll()? {
+            Async::Ready(io) => io,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        Ok(Async::Ready(RawBody {
+            io: io,
+            head: mem::replace(&mut self.1, Buf::new()),
+            close: self.2,
+            bytes: self.3,
+            expected: self.4,
+        }))
     }
 }
 
@@ -399,17 +1022,27 @@ impl<S: AsyncWrite> Future for WaitFlush<S> {
 mod sendfile {
     extern crate tk_sendfile;
 
-    use std::io;
+    use std::io::{self, Write};
     use futures::{Async};
     use self::tk_sendfile::{Destination, FileOpener, Sendfile};
-    use super::RawBody;
+    use super::{RawBody, too_long};
 
-    impl<T: Destination> Destination for RawBody<T> {
+    impl<T: Destination + Write> Destination for RawBody<T> {
         fn write_file<O: FileOpener>(&mut self, file: &mut Sendfile<O>)
             -> Result<usize, io::Error>
         {
-            // TODO(tailhook) check the data written
-            self.io.get_mut().write_file(file)
+            // `sendfile` can't carry the buffered head, so it's written
+            // by a separate syscall first (no vectored write here).
+            // Sendfile decides on the size of the write itself, so we can
+            // only check it afterwards. Peer gets extra bytes in this case,
+            // but the error closes connection, so no responses are mixed up
+            self.flush_head()?;
+            let bytes = self.io.get_mut().write_file(file)?;
+            self.bytes += bytes as u64;
+            if self.expected.map(|x| self.bytes > x).unwrap_or(false) {
+                return Err(too_long());
+            }
+            Ok(bytes)
         }
         fn poll_write(&self) -> Async<()> {
             self.io.get_ref().poll_write()
@@ -419,15 +1052,77 @@ mod sendfile {
 
 #[cfg(test)]
 mod test {
+    use std::io;
+    use bytes::Buf;
+    use futures::{Async, Poll};
     use tk_bufstream::{MockData, IoBuf};
+    use tokio_io::AsyncWrite;
     use {Status};
 
-    use base_serializer::{MessageState, Body};
-    use super::{Encoder, EncoderDone};
+    use base_serializer::{MessageState, Body, HeaderLimits};
+    use super::{Encoder, EncoderDone, RawBody, FutureRawBody, WaitFlush};
+    use super::AutoHeaders;
+    use server::ValidatedHeaders;
+    #[cfg(feature="websocket")] use websocket::Frame;
     use enums::Version;
+    use spans::Span;
+
+    #[test]
+    fn send() {
+        fn send<T: Send>() {}
+        send::<Encoder<MockData>>();
+        send::<EncoderDone<MockData>>();
+        send::<RawBody<MockData>>();
+        send::<FutureRawBody<MockData>>();
+        send::<WaitFlush<MockData>>();
+    }
+
+    #[test]
+    fn sync() {
+        fn sync<T: Sync>() {}
+        sync::<Encoder<MockData>>();
+        sync::<EncoderDone<MockData>>();
+        sync::<RawBody<MockData>>();
+        sync::<FutureRawBody<MockData>>();
+        sync::<WaitFlush<MockData>>();
+    }
+
+    fn do_raw_body(mock: &MockData) -> Encoder<MockData> {
+        let mut enc = Encoder {
+            state: MessageState::ResponseStart {
+                body: Body::Normal,
+                version: Version::Http11,
+                close: false,
+            },
+            io: IoBuf::new(mock.clone()).split().0,
+            deferred: Vec::new(),
+            limits: HeaderLimits::default(),
+            close: false,
+            continue_sent: false,
+            auto_headers: AutoHeaders::default(),
+            has_date: false,
+            has_server: false,
+            switching_protocols: false,
+            #[cfg(feature="compression")]
+            compressor: None,
+            start: 0,
+            flushed: 0,
+            span: Span::none(),
+        };
+        enc.status(Status::Ok);
+        enc.add_length(5).unwrap();
+        enc.done_headers().unwrap();
+        enc
+    }
 
     fn do_response11_str<F>(fun: F) -> String
         where F: FnOnce(Encoder<MockData>) -> EncoderDone<MockData>
+    {
+        String::from_utf8_lossy(&do_response11(fun)).to_string()
+    }
+
+    fn do_response11<F>(fun: F) -> Vec<u8>
+        where F: FnOnce(Encoder<MockData>) -> EncoderDone<MockData>
     {
         let mock = MockData::new();
         let done = fun(Encoder {
@@ -437,9 +1132,22 @@ mod test {
                     close: false,
                 },
                 io: IoBuf::new(mock.clone()).split().0,
+                deferred: Vec::new(),
+                limits: HeaderLimits::default(),
+                close: false,
+                continue_sent: false,
+                auto_headers: AutoHeaders::default(),
+                has_date: false,
+                has_server: false,
+                switching_protocols: false,
+                #[cfg(feature="compression")]
+                compressor: None,
+                start: 0,
+                flushed: 0,
+                span: Span::none(),
             });
         {done}.buf.flush().unwrap();
-        String::from_utf8_lossy(&mock.output(..)).to_string()
+        mock.output(..)
     }
 
     #[test]
@@ -452,4 +1160,360 @@ mod test {
                 enc.done()
             }).starts_with("HTTP/1.1 200 OK\r\nDate: "));
     }
+
+    #[test]
+    #[cfg(feature="compression")]
+    fn compression() {
+        use std::io::Read;
+        use std::str::from_utf8;
+        use flate2::read::GzDecoder;
+        use server::ContentCoding;
+
+        let output = do_response11(|mut enc| {
+                enc.status(Status::Ok);
+                enc.add_compression(ContentCoding::Gzip).unwrap();
+                enc.done_headers().unwrap();
+                enc.write_body(b"hello ");
+                // sends compressed data, not to be confused with
+                // `Encoder::flush` which writes to the socket
+                io::Write::flush(&mut enc).unwrap();
+                enc.write_body(b"world");
+                enc.done()
+            });
+        let head = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\
+                     Vary: Accept-Encoding\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(&output[..head.len()], &head[..]);
+        let mut chunks = &output[head.len()..];
+        let mut body = Vec::new();
+        loop {
+            let line = chunks.iter().position(|&x| x == b'\r').unwrap();
+            let size = usize::from_str_radix(
+                from_utf8(&chunks[..line]).unwrap(), 16).unwrap();
+            body.extend_from_slice(&chunks[line+2..line+2+size]);
+            chunks = &chunks[line+2+size+2..];
+            if size == 0 {
+                break;
+            }
+        }
+        assert_eq!(chunks, b"");
+        let mut result = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut result).unwrap();
+        assert_eq!(result, "hello world");
+    }
+
+    #[test]
+    #[cfg(feature="compression")]
+    fn compression_errors() {
+        use base_serializer::HeaderError;
+        use server::ContentCoding;
+
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.status(Status::NoContent);
+                assert!(matches!(enc.add_compression(ContentCoding::Gzip),
+                    Err(HeaderError::RequireBodyless)));
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.status(Status::Ok);
+                assert!(matches!(enc.add_compression(ContentCoding::Brotli),
+                    Err(HeaderError::UnsupportedCoding)));
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn deferred_header() {
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.defer_header("Vary", "Origin").unwrap();
+                assert!(enc.defer_header("Content-Length", "1").is_err());
+                enc.status(Status::Ok);
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nVary: Origin\r\n\
+                 Content-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn deferred_header_priority() {
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.defer_header("X-A", "1").unwrap();
+                enc.defer_header_with_priority("X-B", "2", 10).unwrap();
+                enc.defer_header("X-C", "3").unwrap();
+                enc.defer_header_with_priority("Server", "x", -1).unwrap();
+                enc.status(Status::Ok);
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nServer: x\r\nX-A: 1\r\nX-C: 3\r\n\
+                 X-B: 2\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn auto_server_header() {
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.auto_headers.server = Some("tk-http".into());
+                enc.start_informational(103);
+                enc.add_header("Server", "x").unwrap();
+                enc.end_informational();
+                enc.status(Status::Ok);
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 103 Early Hints\r\nServer: x\r\n\r\n\
+                 HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+                 Server: tk-http\r\n\r\n");
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.auto_headers.server = Some("tk-http".into());
+                enc.defer_header("server", "upstream").unwrap();
+                enc.status(Status::Ok);
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nserver: upstream\r\n\
+                 Content-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    #[cfg(feature="date_header")]
+    fn auto_date_header() {
+        let auto = AutoHeaders { date: true, server: None };
+        let response = do_response11_str(|mut enc| {
+                enc.auto_headers = auto.clone();
+                enc.status(Status::Ok);
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            });
+        assert!(response.starts_with(
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nDate: "));
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.auto_headers = auto.clone();
+                enc.status(Status::Ok);
+                enc.add_headers(vec![("DATE", "x")]).unwrap();
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nDATE: x\r\n\
+                 Content-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn static_headers() {
+        let mut headers = ValidatedHeaders::new();
+        headers.add_header("X-Frame-Options", "DENY").unwrap();
+        headers.add_header("server", "static").unwrap();
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.auto_headers.server = Some("tk-http".into());
+                enc.status(Status::Ok);
+                enc.add_static_headers(&headers).unwrap();
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nX-Frame-Options: DENY\r\n\
+                 server: static\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.limits.max_value_size = Some(5);
+                enc.status(Status::Ok);
+                assert!(enc.add_static_headers(&headers).is_err());
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn early_hints() {
+        assert_eq!(do_response11_str(|mut enc| {
+                enc.start_informational(103);
+                enc.add_header("Link", "</style.css>; rel=preload").unwrap();
+                enc.end_informational();
+                enc.status(Status::Ok);
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 103 Early Hints\r\n\
+                 Link: </style.css>; rel=preload\r\n\r\n\
+                 HTTP/1.1 200 OK\r\n\
+                 Content-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    #[cfg(feature="date_header")]
+    fn not_modified() {
+        use std::time::UNIX_EPOCH;
+        use server::conditional::Validators;
+
+        let mut validators = Validators::new();
+        validators.etag("v1").last_modified(UNIX_EPOCH);
+        assert_eq!(do_response11_str(|enc| {
+                enc.not_modified(&validators)
+            }), "HTTP/1.1 304 Not Modified\r\n\
+                 ETag: \"v1\"\r\n\
+                 Last-Modified: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n");
+    }
+
+    #[test]
+    fn upgrade_required() {
+        assert_eq!(do_response11_str(|enc| {
+                enc.upgrade_required(&["websocket", "h2c"])
+            }), "HTTP/1.1 426 Upgrade Required\r\n\
+                 Upgrade: websocket, h2c\r\n\
+                 Connection: upgrade\r\n\
+                 Content-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    #[cfg(feature="websocket")]
+    fn websocket_frames() {
+        assert_eq!(&do_response11(|mut enc| {
+                enc.status(Status::SwitchingProtocol);
+                enc.add_header("Connection", "upgrade").unwrap();
+                enc.add_header("Upgrade", "websocket").unwrap();
+                enc.done_headers().unwrap();
+                enc.write_websocket_frame(&Frame::Text("hello"));
+                enc.write_websocket_frame(&Frame::Binary(b"\x01"));
+                enc.done()
+            })[..], &b"HTTP/1.1 101 Switching Protocol\r\n\
+                 Connection: upgrade\r\n\
+                 Upgrade: websocket\r\n\r\n\
+                 \x81\x05hello\x82\x01\x01"[..]);
+    }
+
+    #[test]
+    #[cfg(feature="websocket")]
+    #[should_panic(expected="101 Switching Protocols")]
+    fn websocket_frame_wrong_status() {
+        do_response11(|mut enc| {
+            enc.status(Status::NoContent);
+            enc.done_headers().unwrap();
+            enc.write_websocket_frame(&Frame::Text("hello"));
+            enc.done()
+        });
+    }
+
+    #[test]
+    fn custom_status() {
+        assert_eq!(do_response11_str(|mut enc| {
+                assert_eq!(format!("{:?}",
+                    enc.custom_status(200, "OK\r\nX-Injected: 1")),
+                    "Err(InvalidReason)");
+                enc.custom_status(299, "Fine").unwrap();
+                enc.add_length(0).unwrap();
+                enc.done_headers().unwrap();
+                enc.done()
+            }), "HTTP/1.1 299 Fine\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn raw_body_exact() {
+        use std::io::Write;
+        use futures::Future;
+        use server::encoder::{bytes_written, wants_close};
+
+        let mock = MockData::new();
+        let enc = do_raw_body(&mock);
+        let head = enc.bytes_written();
+        let mut raw = enc.raw_body_exact(5).wait().unwrap();
+        assert_eq!(raw.write(b"hel").unwrap(), 3);
+        assert_eq!(raw.bytes_left(), Some(2));
+        assert_eq!(raw.write(b"lo world").unwrap(), 2);
+        assert_eq!(raw.write(b"!").unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+        let done = raw.try_done().unwrap();
+        assert!(!wants_close(&done));
+        assert_eq!(bytes_written(&done), head + 5);
+        assert!(String::from_utf8_lossy(&mock.output(..))
+                .ends_with("\r\n\r\nhello"));
+
+        let mut raw = do_raw_body(&mock).raw_body_exact(5).wait().unwrap();
+        raw.write_all(b"hel").unwrap();
+        assert!(wants_close(&raw.done()));
+        let mut raw = do_raw_body(&mock).raw_body_exact(5).wait().unwrap();
+        raw.write_all(b"hel").unwrap();
+        assert_eq!(raw.try_done().err().unwrap().kind(),
+                   io::ErrorKind::WriteZero);
+        let raw = do_raw_body(&mock).raw_body().wait().unwrap();
+        assert_eq!(raw.bytes_left(), None);
+        assert!(!wants_close(&raw.done()));
+    }
+
+    /// Socket which writes all the buffers at once like `writev`
+    struct Vectored(Vec<u8>, usize);
+
+    impl io::Write for Vectored {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 += 1;
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Vectored {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+        fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error>
+        {
+            self.1 += 1;
+            let mut bytes = 0;
+            while buf.has_remaining() {
+                let n = buf.bytes().len();
+                self.0.extend_from_slice(buf.bytes());
+                buf.advance(n);
+                bytes += n;
+            }
+            Ok(Async::Ready(bytes))
+        }
+    }
+
+    #[test]
+    fn raw_body_vectored() {
+        use std::io::Write;
+        use futures::Future;
+        use server::encoder::get_inner;
+
+        let mut enc = Encoder {
+            state: MessageState::ResponseStart {
+                body: Body::Normal,
+                version: Version::Http11,
+                close: false,
+            },
+            io: IoBuf::new(Vectored(Vec::new(), 0)).split().0,
+            deferred: Vec::new(),
+            limits: HeaderLimits::default(),
+            close: false,
+            continue_sent: false,
+            auto_headers: AutoHeaders::default(),
+            has_date: false,
+            has_server: false,
+            switching_protocols: false,
+            #[cfg(feature="compression")]
+            compressor: None,
+            start: 0,
+            flushed: 0,
+            span: Span::none(),
+        };
+        enc.status(Status::Ok);
+        enc.add_length(5).unwrap();
+        enc.done_headers().unwrap();
+        let mut raw = enc.raw_body_exact(5).wait().unwrap();
+        assert_eq!(raw.io.get_ref().1, 0);
+        assert_eq!(raw.write(b"hello").unwrap(), 5);
+        assert_eq!(raw.write(b"").unwrap(), 0);
+        // headers and body are written by a single call
+        assert_eq!(raw.io.get_ref().1, 1);
+        assert!(raw.io.get_ref().0.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(raw.io.get_ref().0.ends_with(b"\r\n\r\nhello"));
+        let done = raw.try_done().unwrap();
+        let buf = get_inner(done);
+        assert_eq!(buf.out_buf.len(), 0);
+    }
 }
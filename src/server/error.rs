@@ -3,6 +3,8 @@ use std::convert::From;
 
 use httparse;
 
+use chunked;
+
 
 quick_error! {
     /// HTTP server error
@@ -25,6 +27,11 @@ quick_error! {
             description("chunk size parse error")
             from()
         }
+        /// Chunk size is larger than `Config::max_chunk_size`
+        ChunkTooLarge(size: u64) {
+            description("chunk is too large")
+            display("chunk of {} bytes is too large", size)
+        }
         /// Connection reset
         ConnectionReset {
             description("connection reset")
@@ -33,6 +40,11 @@ quick_error! {
         BadRequestTarget {
             description("error parsing request target")
         }
+        /// Request target in absolute-form doesn't match
+        /// `Config::origin_server`
+        ForeignRequestTarget {
+            description("request target refers to a different server")
+        }
         /// Host header is invalid (non-utf-8 for example)
         HostInvalid {
             description("invalid host header")
@@ -41,6 +53,18 @@ quick_error! {
         DuplicateHost {
             description("duplicate host header")
         }
+        /// HTTP/1.1 request has no host header
+        ///
+        /// See `Config::require_host`.
+        MissingHost {
+            description("no host header in HTTP/1.1 request")
+        }
+        /// Host header differs from host in request-target
+        ///
+        /// See `Config::reject_conflicting_host`.
+        ConflictingHost {
+            description("host header conflicts with request target")
+        }
         /// Connection header is invalid (non-utf-8 for example)
         ConnectionInvalid {
             description("invalid connection header")
@@ -53,6 +77,17 @@ quick_error! {
         DuplicateContentLength {
             description("duplicate content length header")
         }
+        /// Method contains characters not allowed in a token
+        MethodInvalid {
+            description("invalid request method")
+        }
+        /// Request has a body but method doesn't allow one
+        ///
+        /// This is always the case for `TRACE` and also for `GET` and `HEAD`
+        /// when `Config::allow_get_body(false)` is set.
+        BodyNotAllowed {
+            description("request body is not allowed for this method")
+        }
         /// Unsupported kind of request body
         ///
         /// We allow CONNECT requests in the library but drop them if you
@@ -64,9 +99,80 @@ quick_error! {
         RequestTooLong {
             description("request body is too big")
         }
+        /// Request with a buffered body is received when memory budget
+        /// is exhausted
+        ///
+        /// The request is responded with `503 Service Unavailable` and
+        /// connection is closed, see `Config::memory_budget`.
+        MemoryExhausted {
+            description("memory budget is exhausted")
+        }
         Timeout {
             description("timeout while reading or writing request")
         }
+        /// Timeout writing response before hijacking the connection
+        ///
+        /// This usually means that `101 Switching Protocols` response to
+        /// a websocket upgrade has not been flushed in time
+        HijackTimeout {
+            description("timeout writing response before connection upgrade")
+        }
+        /// Error writing response before hijacking the connection
+        HijackWrite(err: Box<Error>) {
+            description("error writing response before connection upgrade")
+            display("error writing response before connection upgrade: {}",
+                err)
+            cause(&**err)
+        }
+        /// Peer didn't accept response data within the linger timeout
+        ///
+        /// Connection is closed (by peer, or by the protocol) but some
+        /// buffered output (`bytes`) and possibly some of the `responses`
+        /// that aren't serialized yet were never sent.
+        OutputDropped(bytes: usize, responses: usize) {
+            description("response data dropped on connection close")
+            display("{} bytes of response data and {} more responses \
+                dropped on connection close", bytes, responses)
+        }
+        /// Codec consumed only part of the buffered request body
+        ///
+        /// In buffered mode `data_received` must either consume all the
+        /// data or return `Async::NotReady`.
+        PartialConsume(consumed: usize, total: usize) {
+            description("codec consumed only part of the buffered body")
+            display("codec consumed {} bytes out of {} of the buffered \
+                request body", consumed, total)
+        }
+        /// Codec reported more bytes consumed than were passed to
+        /// `data_received`
+        OverConsume(consumed: usize, total: usize) {
+            description("codec consumed more than received body data")
+            display("codec consumed {} bytes out of {} bytes of the \
+                request body passed to it", consumed, total)
+        }
+        /// `BlockingWriter` was dropped without calling `done()`
+        ///
+        /// Connection is closed because response body is incomplete.
+        ResponseAborted {
+            description("response body writer is dropped before done()")
+        }
+        /// Request is rejected by the protocol as malformed
+        ///
+        /// The `index` is the number of requests received on the connection
+        /// before this one and `prefix` is the beginning of the raw request
+        /// (for logging). Responses to all the previous requests are sent
+        /// before `400 Bad Request` and closing the connection.
+        MalformedRequest(index: u64, prefix: String, err: Box<Error>) {
+            description("malformed request")
+            display("request #{} ({:?}) is malformed: {}",
+                index, prefix, err)
+            cause(&**err)
+        }
+        /// Protocol negotiated via ALPN is not supported (see `auto`)
+        UnsupportedProtocol(name: String) {
+            description("unsupported protocol")
+            display("unsupported protocol {:?}", name)
+        }
         Custom(err: Box<::std::error::Error + Send + Sync>) {
             description("custom error")
             display("custom error: {}", err)
@@ -82,6 +188,45 @@ impl Error {
     {
         Error(ErrorEnum::Custom(err.into()))
     }
+    /// Number of buffered response bytes that were never sent, if the
+    /// connection was closed because of the linger timeout
+    ///
+    /// See `Config::linger_timeout`.
+    pub fn dropped_bytes(&self) -> Option<usize> {
+        match self.0 {
+            ErrorEnum::OutputDropped(bytes, _) => Some(bytes),
+            _ => None,
+        }
+    }
+    /// Returns index and the beginning of the request on the connection
+    /// if the error is caused by a malformed request
+    pub fn malformed_request(&self) -> Option<(u64, &str)> {
+        match self.0 {
+            ErrorEnum::MalformedRequest(index, ref prefix, _) => {
+                Some((index, prefix))
+            }
+            _ => None,
+        }
+    }
+    /// Returns true if the error is sent to the client as a
+    /// `400 Bad Request` response before closing the connection
+    pub(crate) fn is_bad_request(&self) -> bool {
+        use self::ErrorEnum::*;
+        matches!(self.0,
+            ParseError(..) | BadRequestTarget | ForeignRequestTarget |
+            HostInvalid | DuplicateHost | MissingHost | ConflictingHost |
+            ConnectionInvalid | ContentLengthInvalid |
+            DuplicateContentLength | MethodInvalid | BodyNotAllowed)
+    }
+}
+
+impl From<chunked::Error> for ErrorEnum {
+    fn from(v: chunked::Error) -> ErrorEnum {
+        match v {
+            chunked::Error::Invalid(e) => ErrorEnum::ChunkParseError(e),
+            chunked::Error::TooLarge(size) => ErrorEnum::ChunkTooLarge(size),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -0,0 +1,268 @@
+//! Parsing of `Forwarded` (RFC 7239) and `X-Forwarded-*` headers
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+use std::net::IpAddr;
+use std::str::{from_utf8, FromStr};
+
+use headers::list;
+use super::Head;
+
+
+/// Effective client of the request as reported by trusted proxies
+///
+/// Returned by `Head::forwarded_client`. Proxies are trusted only if they
+/// are listed in `Config::trusted_proxies`, the chain of addresses is
+/// walked from the nearest proxy backwards until an untrusted address is
+/// found. So if there are no trusted proxies, this is just a peer address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedClient {
+    ip: IpAddr,
+    scheme: Option<String>,
+    host: Option<String>,
+    hops: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+impl ForwardedClient {
+    /// IP address of the client
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+    /// Scheme (`http` or `https`) which client used to connect to the
+    /// outermost trusted proxy
+    ///
+    /// Returns `None` if no trusted proxy reported a scheme.
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_ref().map(|x| &x[..])
+    }
+    /// Original `Host` requested by client, if reported by a trusted proxy
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_ref().map(|x| &x[..])
+    }
+    /// Number of trusted proxies the request passed through
+    pub fn hops(&self) -> usize {
+        self.hops
+    }
+}
+
+fn parse_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if value.starts_with('[') {
+        // IPv6 with optional port: `[2001:db8::1]:8080`
+        return value[1..].split(']').next()
+            .and_then(|x| IpAddr::from_str(x).ok());
+    }
+    if let Ok(ip) = IpAddr::from_str(value) {
+        return Some(ip);
+    }
+    // IPv4 with port: `192.0.2.1:8080`
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(ip), Some(_)) => IpAddr::from_str(ip).ok(),
+        _ => None,
+    }
+}
+
+fn parse_forwarded(value: &str, hops: &mut Vec<Hop>) {
+    for element in list::split(value, ',') {
+        let mut hop = Hop { addr: None, proto: None, host: None };
+        for pair in list::split(element, ';') {
+            let (key, value) = match list::parameter(pair) {
+                (key, Some(value)) => (key, value),
+                _ => continue,
+            };
+            if key.eq_ignore_ascii_case("for") {
+                hop.addr = parse_addr(&value);
+            } else if key.eq_ignore_ascii_case("proto") {
+                hop.proto = Some(value.to_ascii_lowercase());
+            } else if key.eq_ignore_ascii_case("host") {
+                hop.host = Some(value.into_owned());
+            }
+        }
+        hops.push(hop);
+    }
+}
+
+fn hops(head: &Head) -> Vec<Hop> {
+    let mut result = Vec::new();
+    let mut has_forwarded = false;
+    for (name, value) in head.headers() {
+        if name.eq_ignore_ascii_case("Forwarded") {
+            if let Ok(value) = from_utf8(value) {
+                has_forwarded = true;
+                parse_forwarded(value, &mut result);
+            }
+        }
+    }
+    if has_forwarded {
+        return result;
+    }
+    let mut proto = None;
+    let mut host = None;
+    for (name, value) in head.headers() {
+        let value = match from_utf8(value) {
+            Ok(value) => value.trim(),
+            Err(_) => continue,
+        };
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            result.extend(value.split(',').map(|x| Hop {
+                addr: parse_addr(x),
+                proto: None,
+                host: None,
+            }));
+        } else if name.eq_ignore_ascii_case("X-Forwarded-Proto") {
+            proto = Some(value.to_ascii_lowercase());
+        } else if name.eq_ignore_ascii_case("X-Forwarded-Host") {
+            host = Some(value.to_string());
+        }
+    }
+    // Legacy headers are (re)written by the nearest proxy, so only the
+    // last hop may be trusted to report them
+    if let Some(last) = result.last_mut() {
+        last.proto = proto;
+        last.host = host;
+    }
+    return result;
+}
+
+fn walk(mut hops: Vec<Hop>, peer: IpAddr, trusted: &[IpAddr])
+    -> ForwardedClient
+{
+    let mut client = ForwardedClient {
+        ip: peer,
+        scheme: None,
+        host: None,
+        hops: 0,
+    };
+    while trusted.contains(&client.ip) {
+        let hop = match hops.pop() {
+            Some(hop) => hop,
+            None => break,
+        };
+        let addr = match hop.addr {
+            Some(addr) => addr,
+            // `unknown` or obfuscated identifier, can't go further
+            None => break,
+        };
+        client.ip = addr;
+        client.hops += 1;
+        if hop.proto.is_some() {
+            client.scheme = hop.proto;
+        }
+        if hop.host.is_some() {
+            client.host = hop.host;
+        }
+    }
+    return client;
+}
+
+pub fn forwarded_client(head: &Head, peer: IpAddr, trusted: &[IpAddr])
+    -> ForwardedClient
+{
+    if !trusted.contains(&peer) {
+        // fast path, don't parse headers
+        return walk(Vec::new(), peer, trusted);
+    }
+    walk(hops(head), peer, trusted)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use server::HeadBuilder;
+    use super::{Hop, parse_addr, parse_forwarded, walk, hops};
+
+    fn ip(x: &str) -> IpAddr {
+        x.parse().unwrap()
+    }
+
+    fn chain(value: &str) -> Vec<Hop> {
+        let mut hops = Vec::new();
+        parse_forwarded(value, &mut hops);
+        hops
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_addr("192.0.2.43"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_addr(" 192.0.2.43:8080"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_addr("2001:db8::17"), Some(ip("2001:db8::17")));
+        assert_eq!(parse_addr("[2001:db8::17]:47011"),
+                   Some(ip("2001:db8::17")));
+        assert_eq!(parse_addr("unknown"), None);
+        assert_eq!(parse_addr("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded() {
+        let hops = chain("for=192.0.2.60;proto=HTTPS;by=203.0.113.43, \
+                          for=\"[2001:db8:cafe::17]:4711\";host=example.com");
+        assert_eq!(hops, vec![
+            Hop { addr: Some(ip("192.0.2.60")),
+                  proto: Some("https".into()), host: None },
+            Hop { addr: Some(ip("2001:db8:cafe::17")),
+                  proto: None, host: Some("example.com".into()) },
+        ]);
+        // separators in quoted strings don't split hops
+        let hops = chain("for=192.0.2.60;host=\"a,b;c\", for=192.0.2.61");
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].host, Some("a,b;c".into()));
+    }
+
+    #[test]
+    fn trusted_chain() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let hops = chain("for=1.1.1.1, for=6.6.6.6;proto=https, \
+                          for=10.0.0.2;proto=http");
+        // untrusted peer
+        let client = walk(hops.clone(), ip("8.8.8.8"), &trusted);
+        assert_eq!(client.ip(), ip("8.8.8.8"));
+        assert_eq!(client.hops(), 0);
+        // spoofed 1.1.1.1 is not taken into account
+        let client = walk(hops, ip("10.0.0.1"), &trusted);
+        assert_eq!(client.ip(), ip("6.6.6.6"));
+        assert_eq!(client.scheme(), Some("https"));
+        assert_eq!(client.hops(), 2);
+    }
+
+    #[test]
+    fn legacy_headers() {
+        let hops = HeadBuilder::new("GET", "/")
+            .header("Host", "example.com")
+            .header("X-Forwarded-For", "6.6.6.6, 1.2.3.4")
+            .header("X-Forwarded-Proto", "HTTPS")
+            .header("X-Forwarded-Host", "example.org")
+            .with_head(|head| Ok(hops(head)))
+            .unwrap();
+        assert_eq!(hops, vec![
+            Hop { addr: Some(ip("6.6.6.6")), proto: None, host: None },
+            Hop { addr: Some(ip("1.2.3.4")),
+                  proto: Some("https".into()),
+                  host: Some("example.org".into()) },
+        ]);
+        let client = walk(hops.clone(), ip("10.0.0.1"), &[ip("10.0.0.1")]);
+        assert_eq!(client.ip(), ip("1.2.3.4"));
+        assert_eq!(client.scheme(), Some("https"));
+        assert_eq!(client.host(), Some("example.org"));
+        // spoofed client-most entry doesn't change the scheme
+        let client = walk(hops, ip("10.0.0.1"),
+                          &[ip("10.0.0.1"), ip("1.2.3.4")]);
+        assert_eq!(client.ip(), ip("6.6.6.6"));
+        assert_eq!(client.scheme(), Some("https"));
+        assert_eq!(client.hops(), 2);
+    }
+
+    #[test]
+    fn unknown_hop() {
+        let trusted = [ip("10.0.0.1")];
+        let client = walk(chain("for=1.1.1.1, for=unknown"),
+                          ip("10.0.0.1"), &trusted);
+        assert_eq!(client.ip(), ip("10.0.0.1"));
+    }
+}
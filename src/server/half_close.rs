@@ -0,0 +1,40 @@
+use std::io;
+use std::net::Shutdown;
+
+use futures::{Async, Poll};
+use tokio_io::AsyncWrite;
+use tokio_core::net::TcpStream;
+
+
+/// A connection which can shut down its write half
+///
+/// Used by `Proto` when `Config::drain_timeout` is set, to tell the client
+/// that the response is complete while input is still being read.
+/// `AsyncWrite::shutdown` is not enough for that, because for `TcpStream`
+/// it only flushes data and doesn't send FIN.
+///
+/// For TLS streams the implementation should send `close_notify` and then
+/// shut down the write half of the underlying socket. It may return
+/// `Async::NotReady` if the stream is not writable yet, the method is
+/// called again when the connection is polled next time.
+pub trait HalfClose: AsyncWrite {
+    /// Shut down write half of the connection
+    fn shutdown_write(&mut self) -> Poll<(), io::Error>;
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&mut self) -> Poll<(), io::Error> {
+        TcpStream::shutdown(self, Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Shutdown function used by `Proto` unless `HalfClose` is enabled
+pub fn shutdown<S: AsyncWrite>(conn: &mut S) -> Poll<(), io::Error> {
+    conn.shutdown()
+}
+
+/// Shutdown function used by `Proto` when `HalfClose` is enabled
+pub fn shutdown_write<S: HalfClose>(conn: &mut S) -> Poll<(), io::Error> {
+    conn.shutdown_write()
+}
@@ -0,0 +1,307 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+use httparse::{self, Request, EMPTY_HEADER};
+
+use server::headers::with_head;
+use server::error::ErrorEnum;
+use server::{Config, ConnectionInfo, Dispatcher, Error, Head};
+use {ConnectionId, Version};
+
+
+/// Builds a request `Head` for unit tests of dispatchers and codecs
+///
+/// `Head` borrows from the parser buffers, so it can't be returned by
+/// value. Instead the builder serializes the request head, parses it
+/// exactly like the server does (including validation according to
+/// `Config`) and passes the `Head` to a dispatcher or a closure:
+///
+/// ```rust,ignore
+/// let codec = HeadBuilder::new("GET", "/hello")
+///     .header("Host", "example.com")
+///     .dispatch(&mut MyDispatcher::new())
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct HeadBuilder {
+    method: String,
+    target: String,
+    version: Version,
+    headers: Vec<(String, Vec<u8>)>,
+    config: Arc<Config>,
+}
+
+impl HeadBuilder {
+    /// Create a builder for a `HTTP/1.1` request
+    pub fn new(method: &str, target: &str) -> HeadBuilder {
+        HeadBuilder {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: Version::Http11,
+            headers: Vec::new(),
+            config: Config::new().done(),
+        }
+    }
+    /// Set HTTP version of the request
+    pub fn version(&mut self, version: Version) -> &mut Self {
+        self.version = version;
+        self
+    }
+    /// Add a header
+    ///
+    /// Headers are added as is, including `Content-Length`, `Connection`
+    /// and duplicates, so invalid requests can be tested too.
+    pub fn header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
+        -> &mut Self
+    {
+        self.headers.push((name.to_string(), value.as_ref().to_vec()));
+        self
+    }
+    /// Add headers of a websocket upgrade request with the specified key
+    pub fn websocket_upgrade(&mut self, key: &str) -> &mut Self {
+        self.header("Connection", "upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", key)
+    }
+    /// Use server config to validate the request (default is
+    /// `Config::new()`)
+    pub fn config(&mut self, config: &Arc<Config>) -> &mut Self {
+        self.config = config.clone();
+        self
+    }
+    /// Call `headers_received` of the dispatcher with the head
+    pub fn dispatch<S, D>(&self, dispatcher: &mut D)
+        -> Result<D::Codec, Error>
+        where D: Dispatcher<S>,
+    {
+        self.with_head(|head| dispatcher.headers_received(head))
+    }
+    /// Call a function with the head
+    ///
+    /// Returns an error if the request is invalid (the same error the
+    /// server protocol would fail with) or if function returns error.
+    pub fn with_head<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&Head) -> Result<R, Error>,
+    {
+        let mut buf = Vec::new();
+        write!(buf, "{} {} {}\r\n", self.method, self.target, self.version)
+            .unwrap();
+        for &(ref name, ref value) in &self.headers {
+            write!(buf, "{}: ", name).unwrap();
+            buf.extend_from_slice(value);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        let mut headers = vec![EMPTY_HEADER; self.headers.len()];
+        let mut raw = Request::new(&mut headers);
+        match raw.parse(&buf).map_err(ErrorEnum::ParseError)? {
+            // a value containing an empty line ends head early
+            httparse::Status::Complete(n) if n == buf.len() => {}
+            _ => return Err(ErrorEnum::ParseError(
+                httparse::Error::HeaderValue).into()),
+        }
+        let now = Instant::now();
+        with_head(&raw, &self.config, ConnectionId::next(),
+                  &ConnectionInfo::new(now), now, f)
+            .map(|(_, result)| result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Async;
+    use futures::future::FutureResult;
+
+    use server::{Config, Dispatcher, Codec, Head, Error, Encoder};
+    use server::{EncoderDone, RecvMode};
+    use {Method, Version};
+    use super::HeadBuilder;
+
+    struct Paths(Vec<String>);
+
+    #[derive(Debug, PartialEq)]
+    struct Path(String);
+
+    impl Dispatcher<()> for Paths {
+        type Codec = Path;
+        fn headers_received(&mut self, head: &Head) -> Result<Path, Error> {
+            let path = head.path().unwrap().to_string();
+            self.0.push(path.clone());
+            Ok(Path(path))
+        }
+    }
+
+    impl Codec<()> for Path {
+        type ResponseFuture = FutureResult<EncoderDone<()>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            unreachable!();
+        }
+        fn data_received(&mut self, _data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            unreachable!();
+        }
+        fn start_response(&mut self, _e: Encoder<()>)
+            -> Self::ResponseFuture
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn head() {
+        let mut builder = HeadBuilder::new("POST", "/x?y=1");
+        builder.header("Host", "example.com")
+            .header("X-A", "b")
+            .header("Content-Length", "10");
+        builder.with_head(|head| {
+            assert_eq!(head.method(), Method::Post);
+            assert_eq!(head.path(), Some("/x?y=1"));
+            assert_eq!(head.host(), Some("example.com"));
+            assert_eq!(head.body_length(), Some(10));
+            assert_eq!(head.version(), Version::Http11);
+            assert_eq!(head.headers().collect::<Vec<_>>(),
+                       vec![("X-A", &b"b"[..])]);
+            assert_eq!(head.connection_header(), None);
+            Ok(())
+        }).unwrap();
+        HeadBuilder::new("GET", "/").header("Host", "example.com")
+            .header("Connection", " keep-alive ")
+            .header("X-A", "b")
+            .header("Connection", "x-b")
+            .with_head(|head| {
+                assert_eq!(head.connection_header(), Some("keep-alive"));
+                assert_eq!(head.connection_value().unwrap(),
+                           "keep-alive, x-b");
+                Ok(())
+            }).unwrap();
+        HeadBuilder::new("OPTIONS", "*").header("Host", "example.com")
+            .with_head(|head| {
+                assert_eq!(head.path(), None);
+                assert!(head.request_target().is_asterisk());
+                assert_eq!(head.effective_path_or("*"), "*");
+                Ok(())
+            }).unwrap();
+        #[cfg(feature="websocket")]
+        {
+            let ws = HeadBuilder::new("GET", "/ws")
+                .header("Host", "example.com")
+                .websocket_upgrade("dGhlIHNhbXBsZSBub25jZQ==")
+                .with_head(|head| Ok(head.get_websocket_upgrade().unwrap()))
+                .unwrap().unwrap();
+            assert_eq!(ws.accept.to_string(),
+                       "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        }
+    }
+
+    #[test]
+    fn upgrade() {
+        HeadBuilder::new("GET", "/").header("Host", "example.com")
+            .header("Connection", "Upgrade, HTTP2-Settings")
+            .header("Upgrade", "h2c")
+            .header("HTTP2-Settings", "AAMAAABkAAQCAAAAAAIAAAAA")
+            .with_head(|head| {
+                assert_eq!(head.upgrade_protocols(), vec!["h2c"]);
+                assert!(head.headers().all(|(name, _)| name != "Upgrade"));
+                #[cfg(feature="websocket")]
+                {
+                    assert!(head.get_websocket_upgrade().unwrap().is_none());
+                }
+                Ok(())
+            }).unwrap();
+        HeadBuilder::new("GET", "/").header("Host", "example.com")
+            .header("Connection", "upgrade")
+            .header("Upgrade", "HTTP/2.0, foo/1")
+            .header("Upgrade", " websocket ")
+            .with_head(|head| {
+                assert_eq!(head.upgrade_protocols(),
+                           vec!["HTTP/2.0", "foo/1", "websocket"]);
+                Ok(())
+            }).unwrap();
+        // `Connection: upgrade` without `Upgrade` header
+        HeadBuilder::new("GET", "/").header("Host", "example.com")
+            .header("Connection", "upgrade")
+            .with_head(|head| {
+                assert_eq!(head.upgrade_protocols(), Vec::<&str>::new());
+                #[cfg(feature="websocket")]
+                {
+                    assert!(head.get_websocket_upgrade().unwrap().is_none());
+                }
+                Ok(())
+            }).unwrap();
+        // `Upgrade` must be ignored without `Connection: upgrade`
+        HeadBuilder::new("GET", "/").header("Host", "example.com")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .with_head(|head| {
+                assert_eq!(head.upgrade_protocols(), Vec::<&str>::new());
+                #[cfg(feature="websocket")]
+                {
+                    assert!(head.get_websocket_upgrade().unwrap().is_none());
+                }
+                Ok(())
+            }).unwrap();
+    }
+
+    #[test]
+    fn typed_headers() {
+        let mut builder = HeadBuilder::new("POST", "/");
+        builder.header("Host", "example.com")
+            .header("content-type", "text/plain; charset=\"utf-8\"")
+            .header("Accept", "text/html, application/json;q=0.5")
+            .header("Cookie", "a=1; b=\"x y\"")
+            .header("accept", "*/*;q=0.1")
+            .header("Cookie", "c=; a=2");
+        builder.with_head(|head| {
+            assert_eq!(head.get_header("HOST"), Some(&b"example.com"[..]));
+            assert_eq!(head.get_header("X-Missing"), None);
+            assert_eq!(head.get_all("cookie").collect::<Vec<_>>(),
+                       vec![&b"a=1; b=\"x y\""[..], &b"c=; a=2"[..]]);
+            let ctype = head.content_type().unwrap();
+            assert_eq!(ctype.value(), "text/plain");
+            assert_eq!(ctype.param("charset").unwrap(), "utf-8");
+            assert_eq!(head.accept().iter()
+                .map(|x| (x.value(), x.quality().unwrap()))
+                .collect::<Vec<_>>(),
+                vec![("text/html", 1000), ("application/json", 500),
+                     ("*/*", 100)]);
+            assert_eq!(head.cookies().collect::<Vec<_>>(),
+                vec![("a", "1"), ("b", "\"x y\""), ("c", ""), ("a", "2")]);
+            assert_eq!(head.cookie("a"), Some("1"));
+            assert_eq!(head.cookie("A"), None);
+            Ok(())
+        }).unwrap();
+        HeadBuilder::new("GET", "/").header("Host", "a").with_head(|head| {
+            assert!(head.content_type().is_none());
+            assert_eq!(head.accept().len(), 0);
+            assert_eq!(head.cookies().count(), 0);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut disp = Paths(Vec::new());
+        let codec = HeadBuilder::new("GET", "/a").header("Host", "x")
+            .dispatch(&mut disp).unwrap();
+        assert_eq!(codec, Path("/a".to_string()));
+        assert_eq!(disp.0, vec!["/a"]);
+    }
+
+    #[test]
+    fn invalid() {
+        let err = HeadBuilder::new("GET", "/")
+            .config(&Config::new().require_host(true).done())
+            .dispatch(&mut Paths(Vec::new())).err().unwrap();
+        assert_eq!(format!("{:?}", err), "Error(MissingHost)");
+        HeadBuilder::new("GET", "/").version(Version::Http10)
+            .config(&Config::new().require_host(true).done())
+            .dispatch(&mut Paths(Vec::new())).unwrap();
+        assert!(HeadBuilder::new("GET", "/")
+            .header("X-A", "a\r\n\r\nb")
+            .with_head(|_| Ok(())).is_err());
+    }
+}
@@ -3,18 +3,26 @@ use std::slice::Iter as SliceIter;
 #[allow(unused_imports)]
 use std::ascii::AsciiExt;
 use std::borrow::Cow;
+use std::net::IpAddr;
+use std::time::Instant;
 
 use httparse::{self, EMPTY_HEADER, Request, Header};
 use tk_bufstream::Buf;
 
 use server::error::{Error, ErrorEnum};
-use super::{RequestTarget, Dispatcher};
+use super::{RequestTarget, Dispatcher, Config, ConnectionInfo};
 use super::codec::BodyKind;
 use super::encoder::ResponseConfig;
+#[cfg(feature="websocket")]
 use super::websocket::{self, WebsocketHandshake};
+use super::content_coding::{self, ContentCoding};
+use super::forwarded::{self, ForwardedClient};
 use super::request_target;
-use headers;
-use {Version};
+use base_serializer::is_token;
+use spans::Span;
+use headers::{self, ConnectionHeaders, ConnectionTokens};
+use headers::list::{self, Item, Split};
+use {Version, Method, ConnectionId};
 
 
 /// Number of headers to allocate on a stack
@@ -25,10 +33,10 @@ const MAX_HEADERS: usize = 1024;
 
 struct RequestConfig<'a> {
     body: BodyKind,
-    #[allow(dead_code)] // TODO(tailhook) implement Expect support
+    content_length: bool,
     expect_continue: bool,
     connection_close: bool,
-    connection: Option<Cow<'a, str>>,
+    connection: ConnectionHeaders,
     host: Option<&'a str>,
     target: RequestTarget<'a>,
     /// If this is true, then Host header differs from host value in
@@ -44,7 +52,7 @@ struct RequestConfig<'a> {
 /// discard any needed fields and headers from it.
 #[derive(Debug)]
 pub struct Head<'a> {
-    method: &'a str,
+    method: Method<'a>,
     raw_target: &'a str,
     target: RequestTarget<'a>,
     host: Option<&'a str>,
@@ -52,8 +60,14 @@ pub struct Head<'a> {
     version: Version,
     headers: &'a [Header<'a>],
     body_kind: BodyKind,
+    content_length: bool,
+    expect_continue: bool,
     connection_close: bool,
-    connection_header: Option<Cow<'a, str>>,
+    connection: ConnectionHeaders,
+    trusted_proxies: &'a [IpAddr],
+    connection_id: ConnectionId,
+    connection_info: ConnectionInfo,
+    received_at: Instant,
 }
 
 /// Iterator over all meaningful headers for the request
@@ -67,11 +81,58 @@ pub struct HeaderIter<'a> {
     iter: SliceIter<'a, Header<'a>>,
 }
 
+/// Iterator over values of all headers with the specified name
+///
+/// This iterator is created by `Head::get_all`. Values are returned in
+/// the order headers are received.
+#[derive(Debug, Clone)]
+pub struct HeaderValues<'a> {
+    iter: SliceIter<'a, Header<'a>>,
+    name: &'a str,
+}
+
+/// Iterator over `(name, value)` pairs of all `Cookie` headers
+///
+/// This iterator is created by `Head::cookies`. Values are returned as is,
+/// i.e. quotes (if any) are not stripped. Headers which aren't valid
+/// utf-8 are skipped.
+#[derive(Debug, Clone)]
+pub struct Cookies<'a> {
+    headers: SliceIter<'a, Header<'a>>,
+    pairs: Option<Split<'a>>,
+}
+
 impl<'a> Head<'a> {
+    /// Returns identifier of the connection the request is received on
+    ///
+    /// Useful to correlate log messages of the request handler with ones
+    /// of the protocol.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+    /// Returns statistics of the connection the request is received on
+    ///
+    /// This is a snapshot taken when headers of this request are received.
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.connection_info
+    }
+    /// Returns the time when the request head was received and parsed
+    ///
+    /// This is taken from the same clock protocol uses for timeouts (see
+    /// `timer` module), so handler can count its own deadlines or log
+    /// latency from the same point, regardless of how long the request
+    /// has been waiting in a queue before being handled.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
     /// Returns a HTTP method
-    pub fn method(&self) -> &str {
+    pub fn method(&self) -> Method<'a> {
         self.method
     }
+    /// Returns a HTTP method as a string
+    pub fn raw_method(&self) -> &'a str {
+        self.method.as_str()
+    }
     /// Request-target (the middle part of the first line of request)
     pub fn request_target(&self) -> &RequestTarget<'a> {
         &self.target
@@ -87,13 +148,15 @@ impl<'a> Head<'a> {
     ///
     /// If the request target is in asterisk form this returns None
     pub fn path(&self) -> Option<&str> {
-        use super::RequestTarget::*;
-        match self.target {
-            Origin(x) => Some(x),
-            Absolute { path, .. } => Some(path),
-            Authority(..) => None,
-            Asterisk => None,
-        }
+        self.target.path()
+    }
+    /// Returns path of request uri or `default` if there is no path
+    ///
+    /// Use it instead of unwrapping `path()`, so that `OPTIONS *` (and
+    /// `CONNECT` in authority form) don't crash the handler. See
+    /// `RequestTarget::effective_path_or` for details.
+    pub fn effective_path_or<'b>(&'b self, default: &'b str) -> &'b str {
+        self.target.effective_path_or(default)
     }
     /// Return host of a request
     ///
@@ -109,7 +172,7 @@ impl<'a> Head<'a> {
     /// Returns true if `Host` header conflicts with host in request-uri
     ///
     /// By spec this fact may be ignored in proxy, but better to reply
-    /// BadRequest in this case
+    /// BadRequest in this case (see `Config::reject_conflicting_host`)
     pub fn has_conflicting_host(&self) -> bool {
         self.conflicting_host
     }
@@ -153,24 +216,112 @@ impl<'a> Head<'a> {
     pub fn all_headers(&self) -> &'a [Header<'a>] {
         self.headers
     }
+    /// Returns value of the first header named `name` (case-insensitive)
+    ///
+    /// Unlike `headers()` this looks through all headers, including
+    /// hop-by-hop ones. Use `get_all()` for headers that may be repeated.
+    pub fn get_header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers.iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+    /// Iterator over values of all headers named `name` (case-insensitive)
+    pub fn get_all<'b>(&self, name: &'b str) -> HeaderValues<'b>
+        where 'a: 'b
+    {
+        HeaderValues {
+            iter: self.headers.iter(),
+            name: name,
+        }
+    }
+    /// Returns parsed `Content-Type` header
+    ///
+    /// Media type is in `value()` and parameters (like `charset`) are
+    /// accessible with `param()`. Returns `None` if there is no such
+    /// header or it isn't valid utf-8.
+    pub fn content_type(&self) -> Option<Item<'a>> {
+        self.get_header("Content-Type")
+            .and_then(|v| from_utf8(v).ok())
+            .and_then(|v| list::items(v).next())
+    }
+    /// Media ranges listed in all `Accept` headers
+    ///
+    /// Elements are returned in the order they are listed, use
+    /// `Item::quality` to pick the best one. Headers which aren't valid
+    /// utf-8 are skipped.
+    pub fn accept(&self) -> Vec<Item<'a>> {
+        self.headers.iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("Accept"))
+            .filter_map(|h| from_utf8(h.value).ok())
+            .flat_map(|v| list::items(v))
+            .collect()
+    }
+    /// Iterator over cookies sent in all `Cookie` headers
+    pub fn cookies(&self) -> Cookies<'a> {
+        Cookies {
+            headers: self.headers.iter(),
+            pairs: None,
+        }
+    }
+    /// Returns value of the first cookie named `name` (case-sensitive)
+    pub fn cookie(&self, name: &str) -> Option<&'a str> {
+        self.cookies().find(|&(n, _)| n == name).map(|(_, v)| v)
+    }
+    /// Return `true` if client waits for `100 Continue` before sending body
+    ///
+    /// This is only true for HTTP/1.1 requests having `Expect: 100-continue`
+    /// header and a non-empty body. Protocol sends `100 Continue` itself
+    /// when it's the request's turn to be responded and body isn't fully
+    /// received yet (see `Codec::start_response`).
+    pub fn expect_continue(&self) -> bool {
+        self.expect_continue
+    }
     /// Return `true` if `Connection: close` header exists
     pub fn connection_close(&self) -> bool {
         self.connection_close
     }
-    /// Returns the value of the `Connection` header (all of them, if multiple)
-    pub fn connection_header(&'a self) -> Option<&'a str> {
-        self.connection_header.as_ref().map(|x| &x[..])
+    /// Returns the value of the `Connection` header
+    ///
+    /// If there are multiple `Connection` headers, this is the value of the
+    /// first one. Use `connection_value()` to get all of them or
+    /// `connection_tokens()` to look up specific options.
+    pub fn connection_header(&self) -> Option<&'a str> {
+        self.connection.first(self.headers)
+    }
+    /// Returns values of all `Connection` headers joined by comma
+    ///
+    /// The value is built on each call and only allocates if there are
+    /// multiple headers.
+    pub fn connection_value(&self) -> Option<Cow<'a, str>> {
+        self.connection.value(self.headers)
+    }
+    /// Iterator over the options listed in all `Connection` headers
+    ///
+    /// Options are trimmed, but case is preserved.
+    pub fn connection_tokens(&self) -> ConnectionTokens<'a> {
+        self.connection.tokens(self.headers)
     }
 
     /// Returns true if there was transfer-encoding or content-length != 0
     ///
     /// I.e. `false` may mean either `Content-Length: 0` or there were no
-    /// content length. This is mostly important to check for requests which
+    /// content length (use `has_content_length` to distinguish).
+    /// This is mostly important to check for requests which
     /// must not have body (`HEAD`, `CONNECT`, `Upgrade: websocket` ...)
     pub fn has_body(&self) -> bool {
         self.body_kind != BodyKind::Fixed(0)
     }
 
+    /// Returns true if body length is determined by `Content-Length` header
+    ///
+    /// This distinguishes explicit `Content-Length: 0` from a request
+    /// without any body headers (both have `body_length() == Some(0)`),
+    /// so proxies can forward the request with the same framing. It's
+    /// `false` when `Transfer-Encoding` overrides `Content-Length`.
+    pub fn has_content_length(&self) -> bool {
+        self.content_length
+    }
+
     /// Returns size of the request body if either `Content-Length` is set
     /// or it is safe to assume that request body is zero-length
     ///
@@ -182,24 +333,94 @@ impl<'a> Head<'a> {
             _ => None,
         }
     }
+    /// Returns framing of the request body
+    ///
+    /// This is useful to preallocate buffers for the request body or to
+    /// report upload progress (see also `Codec::body_progress`)
+    pub fn body_kind(&self) -> BodyKind {
+        self.body_kind
+    }
     /// Check if connection is a websocket and return hanshake info
     ///
     /// `Err(())` is returned when there was handshake but where was something
     /// wrong with it (so you should return `BadRequest` even if you support
     /// plain http on the resource).
     ///
+    /// Upgrade to any other protocol (`Upgrade` not listing `websocket`)
+    /// is treated as plain HTTP request and `Ok(None)` is returned, use
+    /// `upgrade_protocols()` to find out what was requested.
+    ///
     /// `Ok(None)` is returned when it's a plain HTTP request (no upgrade).
     ///
     /// Note: this method computes handshake again, so it's better not to
     /// call it multiple times.
+    #[cfg(feature="websocket")]
     pub fn get_websocket_upgrade(&self)
         -> Result<Option<WebsocketHandshake>, ()>
     {
         websocket::get_handshake(self)
     }
+    /// Protocols requested in the `Upgrade` header
+    ///
+    /// Returns an empty list unless `upgrade` is also listed in the
+    /// `Connection` header (otherwise `Upgrade` must be ignored). Tokens
+    /// are returned as is, e.g. `websocket`, `h2c` or `HTTP/2.0`.
+    ///
+    /// Note: `Upgrade` header is never returned by `headers()` as it's
+    /// hop-by-hop, so it isn't passed further by proxies either.
+    pub fn upgrade_protocols(&self) -> Vec<&'a str> {
+        if !self.connection_tokens()
+            .any(|tok| tok.eq_ignore_ascii_case("upgrade"))
+        {
+            return Vec::new();
+        }
+        self.headers.iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("Upgrade"))
+            .filter_map(|h| from_utf8(h.value).ok())
+            .flat_map(|v| v.split(','))
+            .map(|x| x.trim())
+            .filter(|x| x.len() > 0)
+            .collect()
+    }
+    /// Select best content coding for the response using `Accept-Encoding`
+    ///
+    /// `available` lists encodings that we can serve (i.e. pre-compressed
+    /// sibling files that exist) in the order of preference. Identity
+    /// encoding is returned if nothing in the list is acceptable, unless
+    /// client has explicitly forbidden it. In the latter case `None` is
+    /// returned and you should reply with `406 Not Acceptable`.
+    pub fn preferred_coding(&self, available: &[ContentCoding])
+        -> Option<ContentCoding>
+    {
+        content_coding::preferred(self, available)
+    }
+    /// Returns effective client address using `Forwarded` headers
+    ///
+    /// `peer` is the address of the connection. If it's one of the
+    /// `Config::trusted_proxies`, the `Forwarded` header (or legacy
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` if
+    /// there is no `Forwarded` header) is used to find out the real client.
+    pub fn forwarded_client(&self, peer: IpAddr) -> ForwardedClient {
+        forwarded::forwarded_client(self, peer, self.trusted_proxies)
+    }
+}
+
+/// Returns method, request-target and version of a parsed request
+///
+/// They are always set by `httparse` for complete requests, but we don't
+/// want to rely on that for untrusted input.
+fn request_line<'h, 'b>(raw: &Request<'h, 'b>)
+    -> Result<(&'b str, &'b str, u8), ErrorEnum>
+{
+    match (raw.method, raw.path, raw.version) {
+        (Some(method), Some(path), Some(version)) => {
+            Ok((method, path, version))
+        }
+        _ => Err(ErrorEnum::ParseError(httparse::Error::Token)),
+    }
 }
 
-fn scan_headers<'x>(raw_request: &'x Request)
+fn scan_headers<'x>(raw_request: &'x Request, config: &Config)
     -> Result<RequestConfig<'x>, ErrorEnum>
 {
     // Implements the body length algorithm for requests:
@@ -221,13 +442,14 @@ fn scan_headers<'x>(raw_request: &'x Request)
     use super::codec::BodyKind::*;
     use server::error::ErrorEnum::*;
 
+    let (method, path, version) = request_line(raw_request)?;
     let mut has_content_length = false;
-    let mut close = raw_request.version.unwrap() == 0;
+    let mut close = version == 0;
     let mut expect_continue = false;
     let mut body = Fixed(0);
-    let mut connection = None::<Cow<_>>;
+    let mut connection = ConnectionHeaders::new();
     let mut host_header = false;
-    let target = request_target::parse(raw_request.path.unwrap())
+    let mut target = request_target::parse(path)
         .ok_or(BadRequestTarget)?;
     let mut conflicting_host = false;
     let mut host = match target {
@@ -235,7 +457,11 @@ fn scan_headers<'x>(raw_request: &'x Request)
         RequestTarget::Absolute { authority, .. } => Some(authority),
         _ => None,
     };
-    for header in raw_request.headers.iter() {
+    if let Some((ref scheme, ref authorities)) = config.origin_server {
+        target = request_target::canonicalize(target, scheme, authorities)
+            .ok_or(ForeignRequestTarget)?;
+    }
+    for (idx, header) in raw_request.headers.iter().enumerate() {
         if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
             if let Some(enc) = header.value.split(|&x| x == b',').last() {
                 if headers::is_chunked(enc) {
@@ -262,12 +488,8 @@ fn scan_headers<'x>(raw_request: &'x Request)
                 close = true;
             }
         } else if header.name.eq_ignore_ascii_case("Connection") {
-            let strconn = from_utf8(header.value)
-                .map_err(|_| ConnectionInvalid)?.trim();
-            connection = match connection {
-                Some(x) => Some(x + ", " + strconn),
-                None => Some(strconn.into()),
-            };
+            from_utf8(header.value).map_err(|_| ConnectionInvalid)?;
+            connection.add(idx);
             if header.value.split(|&x| x == b',').any(headers::is_close) {
                 close = true;
             }
@@ -290,11 +512,26 @@ fn scan_headers<'x>(raw_request: &'x Request)
             }
         }
     }
-    if raw_request.method.unwrap() == "CONNECT" {
-        body = Unsupported;
+    if !is_token(method) {
+        return Err(MethodInvalid);
+    }
+    match Method::from(method) {
+        Method::Connect => body = Unsupported,
+        Method::Trace if body != Fixed(0) => return Err(BodyNotAllowed),
+        Method::Get | Method::Head
+        if !config.allow_get_body && body != Fixed(0)
+        => return Err(BodyNotAllowed),
+        _ => {}
+    }
+    if config.require_host && !host_header && version == 1 {
+        return Err(MissingHost);
+    }
+    if config.reject_conflicting_host && conflicting_host {
+        return Err(ConflictingHost);
     }
     Ok(RequestConfig {
         body: body,
+        content_length: has_content_length && matches!(body, Fixed(_)),
         expect_continue: expect_continue,
         connection: connection,
         host: host,
@@ -304,11 +541,48 @@ fn scan_headers<'x>(raw_request: &'x Request)
     })
 }
 
-pub fn parse_headers<S, D>(buffer: &mut Buf, disp: &mut D)
-    -> Result<Option<(BodyKind, D::Codec, ResponseConfig)>, Error>
+/// Validates parsed request and calls `f` with its `Head`
+pub fn with_head<F, R>(raw: &Request, config: &Config,
+    connection_id: ConnectionId, connection_info: &ConnectionInfo,
+    received_at: Instant, f: F)
+    -> Result<(BodyKind, R), Error>
+    where F: FnOnce(&Head) -> Result<R, Error>,
+{
+    let cfg = scan_headers(raw, config)?;
+    let (method, path, ver) = request_line(raw)?;
+    let head = Head {
+        method: Method::from(method),
+        raw_target: path,
+        target: cfg.target,
+        version: if ver == 1
+            { Version::Http11 } else { Version::Http10 },
+        host: cfg.host,
+        conflicting_host: cfg.conflicting_host,
+        headers: raw.headers,
+        body_kind: cfg.body,
+        content_length: cfg.content_length,
+        expect_continue: cfg.expect_continue && ver == 1 &&
+            cfg.body != BodyKind::Fixed(0),
+        // For HTTP/1.0 we could implement
+        // Connection: Keep-Alive but hopefully it's rare
+        // enough to ignore nowadays
+        connection_close: cfg.connection_close || ver == 0,
+        connection: cfg.connection,
+        trusted_proxies: &config.trusted_proxies,
+        connection_id: connection_id,
+        connection_info: *connection_info,
+        received_at: received_at,
+    };
+    Ok((cfg.body, f(&head)?))
+}
+
+pub fn parse_headers<S, D>(buffer: &mut Buf, disp: &mut D, config: &Config,
+    connection_id: ConnectionId, connection_info: &ConnectionInfo,
+    received_at: Instant, connection_span: &Span)
+    -> Result<Option<(BodyKind, D::Codec, ResponseConfig, Span)>, Error>
     where D: Dispatcher<S>,
 {
-    let (body_kind, codec, cfg, bytes) = {
+    let (body_kind, codec, cfg, span, bytes) = {
         let mut vec;
         let mut headers = [EMPTY_HEADER; MIN_HEADERS];
 
@@ -321,34 +595,62 @@ pub fn parse_headers<S, D>(buffer: &mut Buf, disp: &mut D)
         }
         match result.map_err(ErrorEnum::ParseError)? {
             httparse::Status::Complete(bytes) => {
-                let cfg = scan_headers(&raw)?;
-                let ver = raw.version.unwrap();
-                let head = Head {
-                    method: raw.method.unwrap(),
-                    raw_target: raw.path.unwrap(),
-                    target: cfg.target,
-                    version: if ver == 1
-                        { Version::Http11 } else { Version::Http10 },
-                    host: cfg.host,
-                    conflicting_host: cfg.conflicting_host,
-                    headers: raw.headers,
-                    body_kind: cfg.body,
-                    // For HTTP/1.0 we could implement
-                    // Connection: Keep-Alive but hopefully it's rare
-                    // enough to ignore nowadays
-                    connection_close: cfg.connection_close || ver == 0,
-                    connection_header: cfg.connection,
-                };
-                let codec = disp.headers_received(&head)?;
-                // TODO(tailhook) send 100-expect response headers
-                let response_config = ResponseConfig::from(&head);
-                (cfg.body, codec, response_config, bytes)
+                let span = connection_span.request();
+                let (body_kind, (codec, response_config)) = with_head(
+                    &raw, config, connection_id, connection_info,
+                    received_at, |head| {
+                        span.record_request(head.raw_method(),
+                                            head.raw_request_target());
+                        let codec = span.in_scope(
+                            || disp.headers_received(head))?;
+                        let mut response_config = ResponseConfig::from(head);
+                        response_config.header_limits = config.header_limits;
+                        response_config.auto_headers =
+                            config.auto_headers.clone();
+                        Ok((codec, response_config))
+                    })?;
+                (body_kind, codec, response_config, span, bytes)
             }
             _ => return Ok(None),
         }
     };
     buffer.consume(bytes);
-    Ok(Some((body_kind, codec, cfg)))
+    Ok(Some((body_kind, codec, cfg, span)))
+}
+
+impl<'a> Iterator for HeaderValues<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while let Some(header) = self.iter.next() {
+            if header.name.eq_ignore_ascii_case(self.name) {
+                return Some(header.value);
+            }
+        }
+        return None;
+    }
+}
+
+impl<'a> Iterator for Cookies<'a> {
+    type Item = (&'a str, &'a str);
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        loop {
+            if let Some(ref mut pairs) = self.pairs {
+                while let Some(pair) = pairs.next() {
+                    let mut parts = pair.splitn(2, '=');
+                    let name = parts.next().unwrap().trim();
+                    if let Some(value) = parts.next() {
+                        return Some((name, value.trim()));
+                    }
+                }
+            }
+            let header = self.headers.next()?;
+            if header.name.eq_ignore_ascii_case("Cookie") {
+                if let Ok(value) = from_utf8(header.value) {
+                    self.pairs = Some(list::split(value, ';'));
+                }
+            }
+        }
+    }
 }
 
 impl<'a> Iterator for HeaderIter<'a> {
@@ -364,11 +666,10 @@ impl<'a> Iterator for HeaderIter<'a> {
                 continue;
             }
 
-            if let Some(ref conn) = self.head.connection_header {
-                let mut conn_headers = conn.split(',').map(|x| x.trim());
-                if conn_headers.any(|x| x.eq_ignore_ascii_case(header.name)) {
-                    continue;
-                }
+            if self.head.connection_tokens()
+                .any(|x| x.eq_ignore_ascii_case(header.name))
+            {
+                continue;
             }
             return Some((header.name, header.value));
         }
@@ -0,0 +1,137 @@
+use std::cmp::min;
+use std::io::{self, Read, Write};
+
+use futures::{Async, Poll};
+use tk_bufstream::{ReadBuf, WriteBuf};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+
+/// A byte stream made of the buffers of a hijacked connection
+///
+/// `Codec::hijack` receives the connection as a pair of buffers, and the
+/// input buffer may already contain bytes sent by the peer right after
+/// the request (for example, a TLS `ClientHello` for STARTTLS-like upgrades).
+/// This type joins both halves back into a single `AsyncRead + AsyncWrite`
+/// stream which returns those buffered bytes first, so it can be passed to
+/// a TLS acceptor (`tokio-tls`, `tokio-rustls`) and then to a new `Proto`:
+///
+/// ```rust,ignore
+/// fn hijack(&mut self, output: WriteBuf<TcpStream>,
+///           input: ReadBuf<TcpStream>)
+/// {
+///     let stream = HijackedStream::new(output, input);
+///     handle.spawn(acceptor.accept_async(stream)
+///         .map_err(|e| error!("TLS error: {}", e))
+///         .and_then(move |tls| Proto::new(tls, &cfg, dispatcher, &h2)
+///             .map_err(|e| error!("HTTP error: {}", e))));
+/// }
+/// ```
+///
+/// Writes are buffered in the output buffer and flushed opportunistically.
+/// Shutting down the stream only flushes the buffer, the socket itself is
+/// closed when the stream is dropped.
+pub struct HijackedStream<S> {
+    output: WriteBuf<S>,
+    input: ReadBuf<S>,
+}
+
+/// Writes are rejected with `WouldBlock` while this many bytes are pending
+const MAX_BUFFERED_OUTPUT: usize = 65536;
+
+impl<S> HijackedStream<S> {
+    /// Join the buffers passed to `Codec::hijack`
+    pub fn new(output: WriteBuf<S>, input: ReadBuf<S>) -> HijackedStream<S> {
+        HijackedStream {
+            output: output,
+            input: input,
+        }
+    }
+    /// Bytes received from the peer but not read from the stream yet
+    pub fn buffered_input(&self) -> &[u8] {
+        &self.input.in_buf[..]
+    }
+    /// Split the stream back into the buffers
+    pub fn into_parts(self) -> (WriteBuf<S>, ReadBuf<S>) {
+        (self.output, self.input)
+    }
+}
+
+impl<S: AsyncRead> Read for HijackedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.input.in_buf.len() == 0 && self.input.read()? == 0 {
+            if self.input.done() {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let bytes = min(buf.len(), self.input.in_buf.len());
+        buf[..bytes].copy_from_slice(&self.input.in_buf[..bytes]);
+        self.input.in_buf.consume(bytes);
+        Ok(bytes)
+    }
+}
+
+impl<S: AsyncWrite> Write for HijackedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.output.out_buf.len() >= MAX_BUFFERED_OUTPUT {
+            self.output.flush()?;
+            if self.output.out_buf.len() >= MAX_BUFFERED_OUTPUT {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+        self.output.out_buf.extend(buf);
+        self.output.flush()?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()?;
+        if self.output.out_buf.len() > 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for HijackedStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for HijackedStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use tk_bufstream::{IoBuf, MockData};
+    use super::HijackedStream;
+
+    #[test]
+    fn buffered_bytes_first() {
+        let mock = MockData::new();
+        let (output, mut input) = IoBuf::new(mock.clone()).split();
+        input.in_buf.extend(b"hello");
+        mock.add_input(" world");
+        let mut stream = HijackedStream::new(output, input);
+        assert_eq!(stream.buffered_input(), b"hello");
+        let mut buf = [0u8; 3];
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        let mut rest = [0u8; 16];
+        assert_eq!(stream.read(&mut rest).unwrap(), 2);
+        assert_eq!(&rest[..2], b"lo");
+        assert_eq!(stream.read(&mut rest).unwrap(), 6);
+        assert_eq!(&rest[..6], b" world");
+        assert!(stream.read(&mut rest).is_err());  // would block
+
+        stream.write_all(b"response").unwrap();
+        stream.flush().unwrap();
+        assert_eq!(mock.output(..), b"response");
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use futures::{Async, Poll, Stream};
+use rand::random;
+
+
+/// Request body stored in a temporary file
+///
+/// Created by `BufferedDispatcher` for request bodies larger than
+/// the threshold passed to `BufferedDispatcher::lazy_body`. The file is
+/// removed when the body is dropped. On unix it's readable only by the
+/// owner (mode `0600`), so it's safe to use a shared temporary directory.
+#[derive(Debug)]
+pub struct LazyBody {
+    file: File,
+    path: PathBuf,
+    len: u64,
+}
+
+/// A stream of chunks of the `LazyBody`
+///
+/// Note: file is read synchronously on each poll, which is fine for local
+/// files in most cases. Pass the body to a thread pool if that's
+/// not acceptable.
+#[derive(Debug)]
+pub struct BodyChunks {
+    file: File,
+    chunk_size: usize,
+    left: u64,
+}
+
+impl LazyBody {
+    pub(crate) fn new(dir: &Path) -> io::Result<LazyBody> {
+        loop {
+            let path = dir.join(format!("tk-http-body-{:016x}",
+                                        random::<u64>()));
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create_new(true);
+            owner_only(&mut options);
+            match options.open(&path) {
+                Ok(file) => {
+                    return Ok(LazyBody { file: file, path: path, len: 0 });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    pub(crate) fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+    /// Size of the body in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Path of the temporary file
+    ///
+    /// The file is removed when `LazyBody` is dropped, so either copy or
+    /// hard-link it if you want to keep it.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Returns a new handle to the file positioned at the start of the body
+    pub fn reader(&self) -> io::Result<File> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+    /// Returns a stream of chunks of body of at most `chunk_size` bytes
+    pub fn chunks(&self, chunk_size: usize) -> io::Result<BodyChunks> {
+        assert!(chunk_size > 0);
+        Ok(BodyChunks {
+            file: self.reader()?,
+            chunk_size: chunk_size,
+            left: self.len,
+        })
+    }
+    /// Read the whole body into memory
+    pub fn read_to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut result = Vec::with_capacity(self.len as usize);
+        self.reader()?.read_to_end(&mut result)?;
+        Ok(result)
+    }
+}
+
+#[cfg(unix)]
+fn owner_only(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn owner_only(_options: &mut OpenOptions) {
+}
+
+impl Drop for LazyBody {
+    fn drop(&mut self) {
+        remove_file(&self.path)
+            .map_err(|e| error!("Can't remove temporary file {:?}: {}",
+                                self.path, e))
+            .ok();
+    }
+}
+
+impl Stream for BodyChunks {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        if self.left == 0 {
+            return Ok(Async::Ready(None));
+        }
+        let size = if self.left < self.chunk_size as u64 {
+            self.left as usize
+        } else {
+            self.chunk_size
+        };
+        let mut chunk = vec![0u8; size];
+        self.file.read_exact(&mut chunk)?;
+        self.left -= size as u64;
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use futures::{Stream, Future};
+    use super::LazyBody;
+
+    #[test]
+    fn write_and_read() {
+        let mut body = LazyBody::new(&temp_dir()).unwrap();
+        body.append(b"hello ").unwrap();
+        body.append(b"world").unwrap();
+        assert_eq!(body.len(), 11);
+        assert_eq!(body.read_to_vec().unwrap(), b"hello world");
+        let chunks = body.chunks(4).unwrap().collect().wait().unwrap();
+        assert_eq!(chunks, vec![b"hell".to_vec(), b"o wo".to_vec(),
+                                b"rld".to_vec()]);
+        let path = body.path().to_path_buf();
+        assert!(path.exists());
+        drop(body);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let body = LazyBody::new(&temp_dir()).unwrap();
+        let mode = body.path().metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
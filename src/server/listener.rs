@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Stream, Async, Poll};
+use futures::sync::oneshot::{channel, Sender, Receiver};
+use tk_listen::ListenExt;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+
+use super::{Config, ConfigHandle, Dispatcher, Proto};
+
+
+/// Configuration of connections accepted by `spawn_listener`
+///
+/// It's implemented for `Arc<Config>` which uses the same config for every
+/// connection. Implement it yourself to choose config by the peer address
+/// (e.g. larger limits for internal network) or to reject connections.
+/// It's also implemented for `ConfigHandle` to change config at runtime.
+pub trait ListenerConfig {
+    /// Returns config for the connection from `peer`
+    ///
+    /// Connection is closed right away if `None` is returned.
+    fn connection_config(&mut self, peer: &SocketAddr)
+        -> Option<Arc<Config>>;
+    /// Maximum number of connections served at once
+    ///
+    /// When the limit is reached, we don't accept new connections until
+    /// some of the existing ones are closed. Default is 10000.
+    fn max_connections(&self) -> usize {
+        10000
+    }
+    /// Delay before accepting next connection after an accept error
+    ///
+    /// Errors are usually caused by running out of file descriptors, so
+    /// retrying immediately would make a busy loop. Default is 100 ms.
+    fn accept_error_delay(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+    /// Handle which accepted connections should reload config from
+    ///
+    /// Default is `None`: config of a connection never changes.
+    fn config_handle(&self) -> Option<ConfigHandle> {
+        None
+    }
+}
+
+impl ListenerConfig for Arc<Config> {
+    fn connection_config(&mut self, _peer: &SocketAddr)
+        -> Option<Arc<Config>>
+    {
+        Some(self.clone())
+    }
+}
+
+impl ListenerConfig for ConfigHandle {
+    fn connection_config(&mut self, _peer: &SocketAddr)
+        -> Option<Arc<Config>>
+    {
+        Some(self.get())
+    }
+    fn config_handle(&self) -> Option<ConfigHandle> {
+        Some(self.clone())
+    }
+}
+
+/// A handle to the listener spawned by `spawn_listener`
+///
+/// The handle is a future which resolves when listener is stopped and
+/// all of its connections are closed. Dropping the handle doesn't stop
+/// the listener.
+pub struct ListenerHandle {
+    stop: Option<Sender<()>>,
+    done: Receiver<()>,
+}
+
+struct Stoppable<S> {
+    stream: S,
+    stop: Option<Receiver<()>>,
+}
+
+/// Accept connections from `listener` and serve them in the background
+///
+/// This is a shortcut for the usual `incoming()`, `sleep_on_error()`,
+/// `Proto::new()`, `listen()` chain (see `tk-listen` crate) spawned on
+/// the `handle`. Dispatcher factory is called with the peer address for
+/// every accepted connection. Connection errors are logged at debug level.
+///
+/// ```rust,ignore
+/// let listener = TcpListener::bind(&addr, &handle).unwrap();
+/// let mut server = spawn_listener(listener, Config::new().done(),
+///     |addr| BufferedDispatcher::new(addr, &h1, || service),
+///     &handle);
+/// // later
+/// server.stop();
+/// core.run(server).unwrap();
+/// ```
+pub fn spawn_listener<C, F, D>(listener: TcpListener, mut config: C,
+    mut dispatcher_factory: F, handle: &Handle)
+    -> ListenerHandle
+    where C: ListenerConfig + 'static,
+          F: FnMut(SocketAddr) -> D + 'static,
+          D: Dispatcher<TcpStream> + 'static,
+{
+    let (stop_tx, stop_rx) = channel();
+    let (done_tx, done_rx) = channel();
+    let max_connections = config.max_connections();
+    let incoming = Stoppable {
+        stream: listener.incoming()
+            .sleep_on_error(config.accept_error_delay(), handle),
+        stop: Some(stop_rx),
+    };
+    let h1 = handle.clone();
+    handle.spawn(incoming
+        .filter_map(move |(socket, addr)| {
+            let cfg = match config.connection_config(&addr) {
+                Some(cfg) => cfg,
+                None => {
+                    debug!("Connection from {} rejected", addr);
+                    return None;
+                }
+            };
+            let local = socket.local_addr().ok();
+            let mut proto = Proto::new(socket, &cfg,
+                dispatcher_factory(addr), &h1);
+            proto.enable_half_close();
+            proto.set_peer_addr(addr);
+            if let Some(local) = local {
+                proto.set_local_addr(local);
+            }
+            if let Some(reload) = config.config_handle() {
+                proto.set_config_handle(&reload);
+            }
+            Some(proto
+                .map_err(move |e| debug!("Connection error {}: {}", addr, e)))
+        })
+        .listen(max_connections)
+        .then(move |_| {
+            done_tx.send(()).ok();
+            Ok(())
+        }));
+    ListenerHandle {
+        stop: Some(stop_tx),
+        done: done_rx,
+    }
+}
+
+impl ListenerHandle {
+    /// Stop accepting new connections
+    ///
+    /// Connections which are already accepted are served until closed by
+    /// peer or by keep-alive timeout.
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+    }
+}
+
+impl Future for ListenerHandle {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.done.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // canceled means the main loop is gone, nothing to wait for
+            Ok(Async::Ready(())) | Err(_) => Ok(Async::Ready(())),
+        }
+    }
+}
+
+impl<S: Stream> Stream for Stoppable<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if let Some(mut stop) = self.stop.take() {
+            match stop.poll() {
+                Ok(Async::Ready(())) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => self.stop = Some(stop),
+                // handle is dropped, listen forever
+                Err(_) => {}
+            }
+        }
+        self.stream.poll()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::FutureResult;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    use server::buffered::{BufferedDispatcher, Request};
+    use server::{Config, Encoder, EncoderDone, Error};
+    use super::spawn_listener;
+
+    fn service<S>(_: Request, _: Encoder<S>)
+        -> FutureResult<EncoderDone<S>, Error>
+    {
+        unreachable!();
+    }
+
+    #[test]
+    fn stop() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(),
+                                         &handle).unwrap();
+        let h1 = handle.clone();
+        let mut server = spawn_listener(listener, Config::new().done(),
+            move |addr| BufferedDispatcher::new(addr, &h1, || service),
+            &handle);
+        server.stop();
+        core.run(server).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Process-wide policy of disabling keep-alive under load
+///
+/// The policy is shared between all connections which have it in their
+/// `Config` (see `Config::load_shedding`) and is consulted each time
+/// a response is started. When overloaded, the response is sent with
+/// `Connection: close` and connection is closed after the response is
+/// written, requests pipelined after it are dropped. Responses in progress
+/// are never interrupted, so this only sheds idle keep-alive connections
+/// and lets clients reconnect (probably to a less loaded server).
+///
+/// Overload is detected either by the number of open connections (all
+/// connections which have the policy in config are counted) or by
+/// a custom function (e.g. checking CPU usage or a request queue).
+pub struct LoadShedding {
+    connections: AtomicUsize,
+    check: Check,
+}
+
+enum Check {
+    Connections(usize),
+    Custom(Box<Fn(usize) -> bool + Send + Sync>),
+}
+
+impl LoadShedding {
+    /// Shed load when more than `limit` connections are open
+    pub fn connection_limit(limit: usize) -> Arc<LoadShedding> {
+        Arc::new(LoadShedding {
+            connections: AtomicUsize::new(0),
+            check: Check::Connections(limit),
+        })
+    }
+    /// Shed load when `func` returns true
+    ///
+    /// Function receives the number of currently open connections.
+    /// It's called in the event loop, so it must be fast.
+    pub fn custom<F>(func: F) -> Arc<LoadShedding>
+        where F: Fn(usize) -> bool + Send + Sync + 'static
+    {
+        Arc::new(LoadShedding {
+            connections: AtomicUsize::new(0),
+            check: Check::Custom(Box::new(func)),
+        })
+    }
+    /// Returns the number of currently open connections
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+    /// Returns true if keep-alive should be disabled now
+    pub fn is_overloaded(&self) -> bool {
+        match self.check {
+            Check::Connections(limit) => self.connections() > limit,
+            Check::Custom(ref func) => func(self.connections()),
+        }
+    }
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for LoadShedding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("LoadShedding");
+        s.field("connections", &self.connections());
+        match self.check {
+            Check::Connections(limit) => s.field("limit", &limit),
+            Check::Custom(..) => s.field("custom", &true),
+        };
+        s.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LoadShedding;
+
+    #[test]
+    fn connection_limit() {
+        let shed = LoadShedding::connection_limit(1);
+        shed.connection_opened();
+        assert!(!shed.is_overloaded());
+        shed.connection_opened();
+        assert!(shed.is_overloaded());
+        shed.connection_closed();
+        assert!(!shed.is_overloaded());
+        assert_eq!(format!("{:?}", shed),
+                   "LoadShedding { connections: 1, limit: 1 }");
+    }
+
+    #[test]
+    fn custom() {
+        let shed = LoadShedding::custom(|n| n >= 3);
+        shed.connection_opened();
+        shed.connection_opened();
+        assert!(!shed.is_overloaded());
+        shed.connection_opened();
+        assert!(shed.is_overloaded());
+    }
+}
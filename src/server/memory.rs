@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Process-wide limit of memory used for buffering requests and responses
+///
+/// The budget is shared between all connections which have it in their
+/// `Config` (see `Config::memory_budget`). Every connection accounts the
+/// bytes held in its input buffer, i.e. request headers and request bodies
+/// buffered for `RecvMode::buffered_upfront` or waiting to be consumed by
+/// a codec, and the bytes of responses which are not flushed to the peer
+/// yet. When the budget is exhausted:
+///
+/// * connections which are not processing a request stop reading until
+///   memory is freed by others, so requests already in progress can still
+///   finish
+/// * requests with a body in `RecvMode::buffered_upfront` mode are
+///   responded with `503 Service Unavailable` right after headers and
+///   connection is closed (error is `MemoryExhausted`)
+///
+/// You may also check `is_exhausted()` in `Dispatcher::headers_received`
+/// to reply with `503 Service Unavailable` to other requests.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// Create a new budget of `limit` bytes
+    pub fn new(limit: usize) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget {
+            limit: limit,
+            used: AtomicUsize::new(0),
+        })
+    }
+    /// Returns the limit this budget was created with
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    /// Returns the number of bytes currently accounted by all connections
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+    /// Returns true if no more memory is available
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+    pub(crate) fn update(&self, old: usize, new: usize) {
+        if new > old {
+            self.used.fetch_add(new - old, Ordering::Relaxed);
+        } else if old > new {
+            self.used.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryBudget;
+
+    #[test]
+    fn accounting() {
+        let budget = MemoryBudget::new(100);
+        budget.update(0, 60);
+        assert!(!budget.is_exhausted());
+        budget.update(0, 40);
+        assert!(budget.is_exhausted());
+        budget.update(60, 10);
+        assert_eq!(budget.used(), 50);
+        assert!(!budget.is_exhausted());
+    }
+}
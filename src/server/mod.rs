@@ -7,21 +7,67 @@ mod proto;
 mod encoder;
 mod request_target;
 mod headers;
-mod websocket;
-mod recv_mode;
+#[cfg(feature="websocket")] mod websocket;
+mod content_coding;
+mod memory;
+mod load_shedding;
+mod forwarded;
+mod connection_info;
+mod lazy_body;
+mod body_stream;
+mod hijacked;
+mod dispatcher_fn;
+mod blocking;
+mod head_builder;
+mod shutdown;
+mod half_close;
+mod config_handle;
+mod validated_headers;
+#[cfg(feature="listener")] mod listener;
+#[cfg(feature="compression")] mod compression;
 pub mod buffered;
+#[cfg(feature="websocket")] pub mod polling;
+pub mod cors;
+pub mod tee;
+pub mod auto;
+#[cfg(feature="date_header")] pub mod conditional;
 
 pub use self::error::Error;
 pub use self::encoder::{Encoder, EncoderDone};
 pub use self::encoder::{WaitFlush, FutureRawBody, RawBody};
-pub use self::codec::{Codec, Dispatcher};
+pub use self::codec::{Codec, Dispatcher, BodyKind, BodyInfo};
 pub use self::proto::Proto;
-pub use self::headers::{Head, HeaderIter};
+pub use self::headers::{Head, HeaderIter, HeaderValues, Cookies};
 pub use self::request_target::RequestTarget;
+#[cfg(feature="websocket")]
 pub use self::websocket::{WebsocketHandshake};
+pub use self::content_coding::ContentCoding;
+pub use self::memory::MemoryBudget;
+pub use self::load_shedding::LoadShedding;
+pub use self::forwarded::ForwardedClient;
+pub use self::connection_info::ConnectionInfo;
+pub use self::hijacked::HijackedStream;
+pub use self::dispatcher_fn::{DispatcherFn, DispatcherFactory};
+pub use self::blocking::{BlockingBody, BlockingWriter};
+pub use self::head_builder::HeadBuilder;
+pub use self::shutdown::ShutdownHandle;
+pub use self::half_close::HalfClose;
+pub use self::config_handle::ConfigHandle;
+pub use self::validated_headers::ValidatedHeaders;
+#[cfg(feature="listener")]
+pub use self::listener::{spawn_listener, ListenerConfig, ListenerHandle};
+pub use recv_mode::RecvMode;
+pub use headers::ConnectionTokens;
+#[cfg(any(feature="bench", feature="fuzz"))]
+pub(crate) use self::headers::parse_headers;
 
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
+use base_serializer::HeaderLimits;
+use self::encoder::AutoHeaders;
+
 
 /// Fine-grained configuration of the HTTP server
 #[derive(Debug, Clone)]
@@ -35,22 +81,18 @@ pub struct Config {
     input_body_whole_timeout: Duration,
     output_body_byte_timeout: Duration,
     output_body_whole_timeout: Duration,
-}
-
-/// This type is returned from `headers_received` handler of either
-/// client client or server protocol handler
-///
-/// The marker is used to denote whether you want to have the whole response
-/// buffered for you or read chunk by chunk.
-///
-/// The `Progressive` (chunk by chunk) mode is mostly useful for proxy servers.
-/// Or it may be useful if your handler is able to parse data without holding
-/// everything in the memory.
-///
-/// Otherwise, it's best to use `Buffered` mode (for example, comparing with
-/// using your own buffering). We do our best to optimize it for you.
-#[derive(Debug, Clone)]
-pub struct RecvMode {
-    mode: recv_mode::Mode,
-    timeout: Option<Duration>,
+    hijack_write_timeout: Duration,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    load_shedding: Option<Arc<LoadShedding>>,
+    allow_get_body: bool,
+    trusted_proxies: Vec<IpAddr>,
+    max_chunk_size: Option<u64>,
+    origin_server: Option<(String, Vec<String>)>,
+    linger_timeout: Duration,
+    drain_timeout: Option<Duration>,
+    require_host: bool,
+    reject_conflicting_host: bool,
+    max_connection_age: Option<Duration>,
+    header_limits: HeaderLimits,
+    auto_headers: AutoHeaders,
 }
@@ -0,0 +1,454 @@
+//! Long-polling fallback for websocket services
+//!
+//! Some clients can't upgrade connection to a websocket (old proxies and
+//! browsers). This module allows to serve the same `websocket::Dispatcher`
+//! over a sequence of plain (buffered) HTTP requests:
+//!
+//! * `POST` request sends a single message, the body of the request is
+//!   passed to dispatcher as a text frame, or as a binary frame if
+//!   `Content-Type` is `application/octet-stream`
+//! * `GET` request waits for a single outgoing message, up to the
+//!   `poll_timeout`, and returns `204 No Content` if there is nothing to
+//!   send. When session is closed `410 Gone` is returned.
+//!
+//! Outgoing messages are taken from a stream, same as for `websocket::Loop`.
+//!
+//! Session ids are generated by `Sessions::create` and should be passed
+//! to the client by the application (usually in the response to the first
+//! request). Routing of requests is also up to the application: extract
+//! session id from the path (or a header) and call `Sessions::serve`.
+//!
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream, Async, Poll};
+use futures::future::ok;
+use rand::{Rng, thread_rng};
+use tokio_core::reactor::{Handle, Timeout};
+
+use enums::Status;
+use websocket::{Dispatcher, Frame, Packet};
+use super::{Error, Encoder, EncoderDone};
+use super::buffered::Request;
+
+
+/// A boxed future returned by `Sessions::serve`
+pub type ResponseFuture<S> = Box<Future<Item=EncoderDone<S>, Error=Error>>;
+
+struct Session<D, T> {
+    dispatcher: D,
+    output: T,
+    last_activity: Instant,
+}
+
+struct Inner<D, T> {
+    sessions: HashMap<String, Session<D, T>>,
+    last_cleanup: Instant,
+}
+
+/// A registry of long-polling sessions
+///
+/// `D` is a websocket dispatcher receiving messages, `T` is a stream of
+/// outgoing packets (usually a receiving side of a channel). Registry is
+/// cheap to clone, all clones share the same sessions.
+pub struct Sessions<D, T> {
+    inner: Arc<Mutex<Inner<D, T>>>,
+    poll_timeout: Duration,
+    session_timeout: Duration,
+    handle: Handle,
+}
+
+/// A future which waits for the outgoing message, returned for `GET`
+struct Receive<S, D, T> {
+    id: String,
+    inner: Arc<Mutex<Inner<D, T>>>,
+    encoder: Option<Encoder<S>>,
+    timeout: Timeout,
+}
+
+impl<D, T> Clone for Sessions<D, T> {
+    fn clone(&self) -> Sessions<D, T> {
+        Sessions {
+            inner: self.inner.clone(),
+            poll_timeout: self.poll_timeout,
+            session_timeout: self.session_timeout,
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+fn new_id() -> String {
+    let mut key = [0u8; 16];
+    thread_rng().fill_bytes(&mut key);
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn respond<S>(mut e: Encoder<S>, status: Status,
+    content_type: Option<&str>, body: &[u8])
+    -> EncoderDone<S>
+{
+    e.status(status);
+    if status != Status::NoContent {
+        e.add_length(body.len() as u64).unwrap();
+    }
+    if let Some(ctype) = content_type {
+        e.add_header("Content-Type", ctype).unwrap();
+    }
+    e.add_header("Cache-Control", "no-cache").unwrap();
+    if e.done_headers().unwrap() {
+        e.write_body(body);
+    }
+    e.done()
+}
+
+fn is_binary(req: &Request) -> bool {
+    req.headers().iter()
+        .find(|&&(ref name, _)| name.eq_ignore_ascii_case("Content-Type"))
+        .map(|&(_, ref value)| value.starts_with(b"application/octet-stream"))
+        .unwrap_or(false)
+}
+
+impl<D, T> Sessions<D, T>
+    where D: Dispatcher + 'static,
+          T: Stream<Item=Packet> + 'static,
+{
+    /// Create a new session registry
+    ///
+    /// Default poll timeout is 25 seconds and sessions are forgotten
+    /// after 60 seconds of inactivity.
+    pub fn new(handle: &Handle) -> Sessions<D, T> {
+        Sessions {
+            inner: Arc::new(Mutex::new(Inner {
+                sessions: HashMap::new(),
+                last_cleanup: Instant::now(),
+            })),
+            poll_timeout: Duration::new(25, 0),
+            session_timeout: Duration::new(60, 0),
+            handle: handle.clone(),
+        }
+    }
+    /// Maximum time `GET` request waits for a message
+    ///
+    /// Should be less than timeouts of proxies in front of the server
+    pub fn poll_timeout(&mut self, value: Duration) -> &mut Self {
+        self.poll_timeout = value;
+        self
+    }
+    /// Time after which inactive session is removed
+    ///
+    /// Should be larger than `poll_timeout`
+    pub fn session_timeout(&mut self, value: Duration) -> &mut Self {
+        self.session_timeout = value;
+        self
+    }
+    /// Register a new session and return its id
+    pub fn create(&self, dispatcher: D, output: T) -> String {
+        let mut inner = self.inner.lock().expect("sessions lock");
+        let mut id = new_id();
+        while inner.sessions.contains_key(&id) {
+            id = new_id();
+        }
+        inner.sessions.insert(id.clone(), Session {
+            dispatcher: dispatcher,
+            output: output,
+            last_activity: Instant::now(),
+        });
+        return id;
+    }
+    /// Remove a session, returns `false` if there was no such session
+    pub fn close(&self, id: &str) -> bool {
+        self.inner.lock().expect("sessions lock")
+            .sessions.remove(id).is_some()
+    }
+    /// Returns number of active sessions
+    pub fn count(&self) -> usize {
+        self.inner.lock().expect("sessions lock").sessions.len()
+    }
+    /// Serve a single long-polling request for session `id`
+    ///
+    /// Returns `404 Not Found` for unknown (or expired) sessions and
+    /// `405 Method Not Allowed` for methods other than `GET` and `POST`.
+    /// Errors returned by dispatcher are propagated and close the
+    /// connection, the session is removed in this case too.
+    pub fn serve<S: 'static>(&self, id: &str, request: &Request,
+        e: Encoder<S>)
+        -> ResponseFuture<S>
+    {
+        let mut inner = self.inner.lock().expect("sessions lock");
+        let now = Instant::now();
+        if inner.last_cleanup + self.session_timeout < now {
+            let timeo = self.session_timeout;
+            inner.sessions.retain(|_, s| s.last_activity + timeo >= now);
+            inner.last_cleanup = now;
+        }
+        if !inner.sessions.contains_key(id) {
+            return Box::new(ok(respond(e, Status::NotFound, None, b"")));
+        }
+        match request.method() {
+            "GET" => {
+                inner.sessions.get_mut(id).unwrap().last_activity = now;
+                Box::new(Receive {
+                    id: id.to_string(),
+                    inner: self.inner.clone(),
+                    encoder: Some(e),
+                    timeout: Timeout::new(self.poll_timeout, &self.handle)
+                        .expect("can always add a timeout"),
+                })
+            }
+            "POST" => {
+                let fut = {
+                    let session = inner.sessions.get_mut(id).unwrap();
+                    session.last_activity = now;
+                    let frame = if is_binary(request) {
+                        Frame::Binary(request.body())
+                    } else {
+                        match ::std::str::from_utf8(request.body()) {
+                            Ok(text) => Frame::Text(text),
+                            Err(_) => {
+                                return Box::new(ok(respond(e,
+                                    Status::BadRequest, None, b"")));
+                            }
+                        }
+                    };
+                    session.dispatcher.frame(&frame)
+                };
+                let sessions = self.inner.clone();
+                let id = id.to_string();
+                Box::new(fut.then(move |res| match res {
+                    Ok(()) => Ok(respond(e, Status::NoContent, None, b"")),
+                    Err(err) => {
+                        sessions.lock().expect("sessions lock")
+                            .sessions.remove(&id);
+                        Err(Error::custom(err))
+                    }
+                }))
+            }
+            _ => {
+                Box::new(ok(respond(e, Status::MethodNotAllowed, None, b"")))
+            }
+        }
+    }
+}
+
+impl<S, D, T> Future for Receive<S, D, T>
+    where T: Stream<Item=Packet>,
+{
+    type Item = EncoderDone<S>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<EncoderDone<S>, Error> {
+        let mut inner = self.inner.lock().expect("sessions lock");
+        let packet = match inner.sessions.get_mut(&self.id) {
+            Some(session) => {
+                session.last_activity = Instant::now();
+                loop {
+                    match session.output.poll() {
+                        Ok(Async::Ready(Some(Packet::Ping(_))))
+                        | Ok(Async::Ready(Some(Packet::Pong(_))))
+                        => continue,
+                        Ok(Async::Ready(pkt)) => break pkt,
+                        Ok(Async::NotReady) => {
+                            let timeo = self.timeout.poll()
+                                .expect("timeout can't fail on poll");
+                            if timeo.is_ready() {
+                                let e = self.encoder.take()
+                                    .expect("future polled twice");
+                                return Ok(Async::Ready(respond(e,
+                                    Status::NoContent, None, b"")));
+                            }
+                            return Ok(Async::NotReady);
+                        }
+                        Err(_) => break None,
+                    }
+                }
+            }
+            None => None,
+        };
+        let e = self.encoder.take().expect("future polled twice");
+        let done = match packet {
+            Some(Packet::Text(text)) => {
+                respond(e, Status::Ok,
+                    Some("text/plain; charset=utf-8"), text.as_bytes())
+            }
+            Some(Packet::Binary(data)) => {
+                respond(e, Status::Ok,
+                    Some("application/octet-stream"), &data)
+            }
+            Some(Packet::Close(_, _)) | None => {
+                inner.sessions.remove(&self.id);
+                respond(e, Status::Gone, None, b"")
+            }
+            Some(Packet::Ping(_)) | Some(Packet::Pong(_)) => unreachable!(),
+        };
+        Ok(Async::Ready(done))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+
+    use futures::Future;
+    use futures::future::{FutureResult, ok, lazy};
+    use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+    use tk_bufstream::{MockData, ReadFramed, WriteFramed};
+    use tokio_core::reactor::Core;
+
+    use server::{Config, Encoder, Proto};
+    use server::buffered::{BufferedDispatcher, NewService, Service, Request};
+    use websocket::{self, Dispatcher, Frame, Packet, ServerCodec};
+    use super::{Sessions, ResponseFuture};
+
+    type Registry = Sessions<Log, UnboundedReceiver<Packet>>;
+    type Conn = Proto<MockData, BufferedDispatcher<MockData, Serve>>;
+
+    struct Log(Arc<Mutex<Vec<String>>>);
+
+    /// Serves session which id is the path of the request
+    struct Serve(Registry);
+
+    impl Dispatcher for Log {
+        type Future = FutureResult<(), websocket::Error>;
+        fn frame(&mut self, frame: &Frame) -> Self::Future {
+            self.0.lock().unwrap().push(format!("{:?}", frame));
+            ok(())
+        }
+    }
+
+    impl NewService<MockData> for Serve {
+        type Future = ResponseFuture<MockData>;
+        type Instance = Serve;
+        fn new(&self) -> Serve {
+            Serve(self.0.clone())
+        }
+    }
+
+    impl Service<MockData> for Serve {
+        type Future = ResponseFuture<MockData>;
+        type WebsocketFuture = FutureResult<(), ()>;
+        fn call(&mut self, request: Request, e: Encoder<MockData>)
+            -> ResponseFuture<MockData>
+        {
+            let id = request.path()[1..].to_string();
+            self.0.serve(&id, &request, e)
+        }
+        fn start_websocket(&mut self,
+            _output: WriteFramed<MockData, ServerCodec>,
+            _input: ReadFramed<MockData, ServerCodec>)
+            -> FutureResult<(), ()>
+        {
+            unreachable!();
+        }
+    }
+
+    fn connect(core: &Core, sessions: &Registry) -> (MockData, Conn) {
+        let mock = MockData::new();
+        let disp = BufferedDispatcher::new("127.0.0.1:80".parse().unwrap(),
+            &core.handle(), Serve(sessions.clone()));
+        let proto = Proto::new(mock.clone(), &Config::new().done(), disp,
+                               &core.handle());
+        (mock, proto)
+    }
+
+    fn run(core: &mut Core, proto: &mut Conn) {
+        core.run(lazy(|| proto.poll())).unwrap();
+    }
+
+    fn request(core: &mut Core, sessions: &Registry, req: &str) -> String {
+        let (mock, mut proto) = connect(core, sessions);
+        mock.add_input(req);
+        run(core, &mut proto);
+        String::from_utf8(mock.output(..)).unwrap()
+    }
+
+    fn status(response: &str) -> &str {
+        response.lines().next().unwrap()
+    }
+
+    #[test]
+    fn send_receive() {
+        let mut core = Core::new().unwrap();
+        let mut sessions = Sessions::new(&core.handle());
+        sessions.poll_timeout(Duration::from_millis(10));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = unbounded();
+        let id = sessions.create(Log(log.clone()), rx);
+        assert_eq!(sessions.count(), 1);
+
+        let resp = request(&mut core, &sessions, &format!(
+            "POST /{} HTTP/1.1\r\nHost: example.com\r\n\
+             Content-Length: 5\r\n\r\nhello", id));
+        assert_eq!(status(&resp), "HTTP/1.1 204 No Content");
+        let resp = request(&mut core, &sessions, &format!(
+            "POST /{} HTTP/1.1\r\nHost: example.com\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Content-Length: 2\r\n\r\n\x01\x02", id));
+        assert_eq!(status(&resp), "HTTP/1.1 204 No Content");
+        assert_eq!(*log.lock().unwrap(),
+            vec![r#"Text("hello")"#, "Binary([1, 2])"]);
+
+        // GET waits for the message
+        let (mock, mut proto) = connect(&core, &sessions);
+        mock.add_input(format!("GET /{} HTTP/1.1\r\n\
+                                Host: example.com\r\n\r\n", id));
+        run(&mut core, &mut proto);
+        assert_eq!(mock.output(..), b"");
+        tx.unbounded_send(Packet::Ping(vec![1])).unwrap();
+        tx.unbounded_send(Packet::Text("world".into())).unwrap();
+        run(&mut core, &mut proto);
+        let resp = String::from_utf8(mock.output(..)).unwrap();
+        assert_eq!(status(&resp), "HTTP/1.1 200 OK");
+        assert!(resp.ends_with("\r\n\r\nworld"), "{}", resp);
+
+        // ... up to the poll timeout
+        let (mock, mut proto) = connect(&core, &sessions);
+        mock.add_input(format!("GET /{} HTTP/1.1\r\n\
+                                Host: example.com\r\n\r\n", id));
+        run(&mut core, &mut proto);
+        assert_eq!(mock.output(..), b"");
+        sleep(Duration::from_millis(20));
+        core.turn(Some(Duration::new(0, 0)));
+        run(&mut core, &mut proto);
+        let resp = String::from_utf8(mock.output(..)).unwrap();
+        assert_eq!(status(&resp), "HTTP/1.1 204 No Content");
+        assert_eq!(sessions.count(), 1);
+    }
+
+    #[test]
+    fn gone() {
+        let mut core = Core::new().unwrap();
+        let sessions = Sessions::new(&core.handle());
+        let (tx, rx) = unbounded();
+        let id = sessions.create(Log(Arc::new(Mutex::new(Vec::new()))), rx);
+        drop(tx);
+        let resp = request(&mut core, &sessions, &format!(
+            "GET /{} HTTP/1.1\r\nHost: example.com\r\n\r\n", id));
+        assert_eq!(status(&resp), "HTTP/1.1 410 Gone");
+        assert_eq!(sessions.count(), 0);
+        let resp = request(&mut core, &sessions, &format!(
+            "GET /{} HTTP/1.1\r\nHost: example.com\r\n\r\n", id));
+        assert_eq!(status(&resp), "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn session_timeout() {
+        let mut core = Core::new().unwrap();
+        let mut sessions = Sessions::new(&core.handle());
+        sessions.session_timeout(Duration::from_millis(10));
+        let (_tx, rx) = unbounded();
+        let id = sessions.create(Log(Arc::new(Mutex::new(Vec::new()))), rx);
+        let resp = request(&mut core, &sessions, &format!(
+            "PUT /{} HTTP/1.1\r\nHost: example.com\r\n\r\n", id));
+        assert_eq!(status(&resp), "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(sessions.count(), 1);
+        sleep(Duration::from_millis(20));
+        let resp = request(&mut core, &sessions, &format!(
+            "PUT /{} HTTP/1.1\r\nHost: example.com\r\n\r\n", id));
+        assert_eq!(status(&resp), "HTTP/1.1 404 Not Found");
+        assert_eq!(sessions.count(), 0);
+    }
+}
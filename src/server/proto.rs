@@ -1,35 +1,71 @@
+use std::io;
 use std::mem;
+use std::cmp::min;
 use std::sync::Arc;
 use std::collections::VecDeque;
-use std::time::Instant;
+use std::net::SocketAddr;
+use std::time::{Instant, Duration};
 
 use futures::{Future, Poll, Async};
+use futures::task;
 use tk_bufstream::{IoBuf, WriteBuf, ReadBuf};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_core::reactor::{Handle, Timeout};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
 
-use super::encoder::{self, get_inner, ResponseConfig};
-use super::{Dispatcher, Codec, Config};
+use super::encoder::{self, get_inner, wants_close, bytes_written};
+use super::encoder::ResponseConfig;
+use super::{Dispatcher, Codec, Config, ConnectionInfo, ShutdownHandle};
+use super::HalfClose;
+use super::half_close;
+use super::ConfigHandle;
 use super::headers::parse_headers;
-use super::codec::BodyKind;
+use super::codec::{BodyKind, body_info};
 use server::error::{ErrorEnum, Error};
-use server::recv_mode::{Mode, get_mode};
+use recv_mode::{Mode, get_mode, get_timeout, is_full_duplex};
 use chunked;
 use body_parser::BodyProgress;
+use spans::Span;
+use timer::{Timer, TokioTimer};
+use ConnectionId;
+
+
+/// Interval of retrying reads when throttled by the memory budget
+const BUDGET_RETRY_MS: u64 = 100;
+/// Response for requests rejected by the protocol itself
+const BAD_REQUEST: &'static [u8] = b"HTTP/1.1 400 Bad Request\r\n\
+    Content-Length: 0\r\nConnection: close\r\n\r\n";
+/// Response for requests rejected because memory budget is exhausted
+const SERVICE_UNAVAILABLE: &'static [u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\n\
+    Content-Length: 0\r\nConnection: close\r\n\r\n";
+/// Interim response for requests with `Expect: 100-continue`
+const CONTINUE: &'static [u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+/// Number of bytes of a malformed request put into the error
+const MALFORMED_PREFIX: usize = 64;
 
 
 enum OutState<S, F, C> {
     Idle(WriteBuf<S>),
-    Write(F),
-    Switch(F, C),
+    Write(F, Span),
+    Switch(F, C, Span),
     Void,
 }
 
 struct BodyState<C> {
     mode: Mode,
+    kind: BodyKind,
+    consumed: u64,
     progress: BodyProgress,
     response_config: ResponseConfig,
     codec: C,
+    span: Span,
+    /// Bytes of the fixed-size body passed to `Codec::raw_body_received`
+    raw_received: u64,
+    /// Response is started before request body is received
+    full_duplex: bool,
+    /// `start_response` has already been called (full-duplex mode)
+    responded: bool,
 }
 
 enum InState<C> {
@@ -41,11 +77,13 @@ enum InState<C> {
     Closed,
 }
 
-pub struct PureProto<S, D: Dispatcher<S>> {
+pub struct PureProto<S, D: Dispatcher<S>, T> {
+    id: ConnectionId,
+    info: ConnectionInfo,
     dispatcher: D,
     inbuf: Option<ReadBuf<S>>, // it's optional only for hijacking
     reading: InState<D::Codec>,
-    waiting: VecDeque<(ResponseConfig, D::Codec)>,
+    waiting: VecDeque<(ResponseConfig, D::Codec, Span)>,
     writing: OutState<S, <D::Codec as Codec<S>>::ResponseFuture, D::Codec>,
     config: Arc<Config>,
 
@@ -54,20 +92,50 @@ pub struct PureProto<S, D: Dispatcher<S>> {
     /// Long-term deadline for reading (headers- or input body_whole- timeout)
     read_deadline: Instant,
     response_deadline: Instant,
+
+    /// Bytes accounted in the memory budget by this connection
+    memory: usize,
+    /// Reading has been suspended because memory budget is exhausted
+    throttled: bool,
+    /// Connection is closed as soon as output is flushed
+    closing: bool,
+    /// Deadline of flushing output when closing or input is closed
+    linger: Option<Instant>,
+    /// Deadline of discarding input after output is shut down
+    drain: Option<Instant>,
+    /// Write half of the connection is shut down (when draining)
+    write_shut: bool,
+    /// Shuts down write half of the connection
+    shutdown_write: fn(&mut S) -> Poll<(), io::Error>,
+    /// Error returned when output is flushed after a rejected request
+    rejected: Option<Error>,
+    /// Response of the protocol (i.e. `400 Bad Request`) which should be
+    /// written after pending responses
+    canned_response: Option<&'static [u8]>,
+    /// Number of requests which headers have been parsed
+    requests_received: u64,
+    /// Created when somebody asks for a handle to shut down connection
+    shutdown: Option<ShutdownHandle>,
+    /// Handle to reload config from and generation of the config loaded
+    config_handle: Option<(ConfigHandle, usize)>,
+    timer: T,
+    span: Span,
 }
 
 /// A low-level HTTP/1.x server protocol handler
-pub struct Proto<S, D: Dispatcher<S>> {
-    proto: PureProto<S, D>,
-    handle: Handle,
-    timeout: Timeout,
+///
+/// The future resolves successfully only when all the output has been
+/// flushed to the socket (see `Config::linger_timeout`).
+pub struct Proto<S, D: Dispatcher<S>, T: Timer = TokioTimer> {
+    proto: PureProto<S, D, T>,
+    timeout: T::Timeout,
 }
 
-fn new_body(mode: BodyKind, recv_mode: Mode)
+fn new_body(mode: BodyKind, recv_mode: Mode, config: &Config)
     -> Result<BodyProgress, ErrorEnum>
 {
     use super::codec::BodyKind as B;
-    use super::recv_mode::Mode as M;
+    use recv_mode::Mode as M;
     use body_parser::BodyProgress as P;
     match (mode, recv_mode) {
         // TODO(tailhook) check size < usize
@@ -76,7 +144,9 @@ fn new_body(mode: BodyKind, recv_mode: Mode)
             Err(ErrorEnum::RequestTooLong)
         }
         (B::Fixed(x), _)  => Ok(P::Fixed(x as usize)),
-        (B::Chunked, _) => Ok(P::Chunked(chunked::State::new())),
+        (B::Chunked, _) => {
+            Ok(P::Chunked(chunked::State::with_limit(config.max_chunk_size)))
+        }
     }
 }
 
@@ -88,22 +158,109 @@ impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>> Proto<S, D> {
         handle: &Handle)
         -> Proto<S, D>
     {
+        Proto::with_timer(conn, cfg, dispatcher, TokioTimer::new(handle))
+    }
+}
+
+impl<D: Dispatcher<TcpStream>> Proto<TcpStream, D> {
+    /// Same as `new` but also fills in addresses of the connection
+    ///
+    /// See `ConnectionInfo::peer_addr` and `ConnectionInfo::local_addr`.
+    pub fn from_tcp(conn: TcpStream, cfg: &Arc<Config>, dispatcher: D,
+        handle: &Handle)
+        -> Proto<TcpStream, D>
+    {
+        let peer = conn.peer_addr().ok();
+        let local = conn.local_addr().ok();
+        let mut proto = Proto::new(conn, cfg, dispatcher, handle);
+        proto.enable_half_close();
+        if let Some(addr) = peer {
+            proto.set_peer_addr(addr);
+        }
+        if let Some(addr) = local {
+            proto.set_local_addr(addr);
+        }
+        proto
+    }
+}
+
+impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>, T: Timer> Proto<S, D, T> {
+    /// Same as `new` but with a custom timer (see `timer` module)
+    pub fn with_timer(conn: S, cfg: &Arc<Config>, dispatcher: D, timer: T)
+        -> Proto<S, D, T>
+    {
+        let timeout = timer.timeout_at(timer.now() + cfg.first_byte_timeout);
         return Proto {
-            proto: PureProto::new(conn, cfg, dispatcher),
-            handle: handle.clone(),
-            timeout: Timeout::new(cfg.first_byte_timeout, handle)
-                .expect("can always add a timeout"),
+            proto: PureProto::new(conn, cfg, dispatcher, timer),
+            timeout: timeout,
         }
     }
+    /// Returns identifier of the connection (also passed in `Head`)
+    pub fn connection_id(&self) -> ConnectionId {
+        self.proto.id
+    }
+    /// Returns current statistics of the connection
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.proto.info
+    }
+    /// Returns a handle which can be used to shut down connection
+    /// gracefully (see `ShutdownHandle` for details)
+    ///
+    /// Every call returns a clone of the same handle.
+    pub fn shutdown_handle(&mut self) -> ShutdownHandle {
+        self.proto.shutdown_handle()
+    }
+    /// Set address of the peer
+    ///
+    /// It's available as `ConnectionInfo::peer_addr` (e.g. in
+    /// `Head::connection_info`) and recorded in the connection span when
+    /// `tracing` feature is enabled. `spawn_listener` sets it for every
+    /// connection.
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.proto.info.set_peer_addr(addr);
+        self.proto.span.record_peer(addr);
+    }
+    /// Set local address of the connection
+    ///
+    /// It's available as `ConnectionInfo::local_addr`.
+    pub fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.proto.info.set_local_addr(addr);
+    }
+    /// Follow config changes made through the handle
+    ///
+    /// Current config of the handle replaces the one passed to the
+    /// constructor right away, later changes are applied between requests
+    /// (see `ConfigHandle` for details). `spawn_listener` calls this when
+    /// `ListenerConfig::config_handle` returns a handle.
+    pub fn set_config_handle(&mut self, handle: &ConfigHandle) {
+        self.proto.set_config_handle(handle);
+    }
+    /// Shut down output with `HalfClose` when draining input
+    ///
+    /// See `Config::drain_timeout` for details. `from_tcp` and
+    /// `spawn_listener` call this for every connection.
+    pub fn enable_half_close(&mut self)
+        where S: HalfClose
+    {
+        self.proto.shutdown_write = half_close::shutdown_write::<S>;
+    }
 }
 
-impl<S, D: Dispatcher<S>> PureProto<S, D> {
-    pub fn new(conn: S, cfg: &Arc<Config>, dispatcher: D)
-        -> PureProto<S, D>
+impl<S, D: Dispatcher<S>, T: Timer> PureProto<S, D, T> {
+    pub fn new(conn: S, cfg: &Arc<Config>, dispatcher: D, timer: T)
+        -> PureProto<S, D, T>
         where S: AsyncRead + AsyncWrite
     {
         let (cout, cin) = IoBuf::new(conn).split();
+        let id = ConnectionId::next();
+        debug!("{}: new connection", id);
+        if let Some(ref policy) = cfg.load_shedding {
+            policy.connection_opened();
+        }
+        let now = timer.now();
         PureProto {
+            id: id,
+            info: ConnectionInfo::new(now),
             dispatcher: dispatcher,
             inbuf: Some(cin),
             reading: InState::Connected,
@@ -112,18 +269,45 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
             writing: OutState::Idle(cout),
             config: cfg.clone(),
 
-            last_byte_read: Instant::now(),
-            last_byte_written: Instant::now(),
-            read_deadline: Instant::now() + cfg.first_byte_timeout,
-            response_deadline: Instant::now(),  // irrelevant at start
+            last_byte_read: now,
+            last_byte_written: now,
+            read_deadline: now + cfg.first_byte_timeout,
+            response_deadline: now,  // irrelevant at start
+
+            memory: 0,
+            throttled: false,
+            closing: false,
+            linger: None,
+            drain: None,
+            write_shut: false,
+            shutdown_write: half_close::shutdown::<S>,
+            rejected: None,
+            canned_response: None,
+            requests_received: 0,
+            shutdown: None,
+            config_handle: None,
+            timer: timer,
+            span: Span::connection("server", id),
         }
     }
+    pub fn shutdown_handle(&mut self) -> ShutdownHandle {
+        self.shutdown.get_or_insert_with(ShutdownHandle::new).clone()
+    }
+    pub fn set_config_handle(&mut self, handle: &ConfigHandle) {
+        let mut generation = 0;
+        handle.reload(&mut generation, &mut self.config);
+        self.config_handle = Some((handle.clone(), generation));
+    }
+    fn shutting_down(&self) -> bool {
+        self.shutdown.as_ref().map(|h| h.is_requested()).unwrap_or(false)
+    }
     /// Resturns Ok(true) if new data has been read
     fn do_reads(&mut self) -> Result<bool, Error>
         where S: AsyncRead
     {
         use self::InState::*;
         let mut changed = false;
+        let shutdown = self.shutting_down();
         let mut inbuf = self.inbuf.as_mut();
         let inbuf = if let Some(ref mut inbuf) = inbuf {
             inbuf
@@ -133,44 +317,142 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
         };
         loop {
             let limit = match self.reading {
+                // Body of the request which is being received now is read
+                // to the end, but no new requests are accepted
+                Headers | Connected | KeepAlive if shutdown => {
+                    debug!("{}: shutting down", self.id);
+                    self.reading = Closed;
+                    self.closing = true;
+                    return Ok(changed);
+                }
                 Headers| Connected | KeepAlive
                 => self.config.inflight_request_limit,
                 Body(..) => self.config.inflight_request_limit-1,
                 Closed | Hijack => return Ok(changed),
             };
-            if self.waiting.len() <= limit {
+            // Only idle connections are throttled, so that requests in
+            // progress can finish and free memory
+            let throttle = matches!(self.reading, Connected | KeepAlive) &&
+                self.config.memory_budget.as_ref()
+                    .map(|b| b.is_exhausted()).unwrap_or(false);
+            if throttle {
+                self.throttled = true;
+            } else if self.waiting.len() <= limit {
                 // TODO(tailhook) Do reads after parse_headers() [optimization]
-                if inbuf.read().map_err(ErrorEnum::Io)? > 0 {
-                    self.last_byte_read = Instant::now();
+                let bytes = inbuf.read().map_err(ErrorEnum::Io)?;
+                if bytes > 0 {
+                    self.last_byte_read = self.timer.now();
+                    self.info.add_received(bytes);
                 }
             }
             let (next, cont) = match mem::replace(&mut self.reading, Closed) {
                 KeepAlive | Connected if inbuf.in_buf.len() > 0 => {
-                    self.read_deadline = Instant::now()
+                    // config is only replaced between requests
+                    let idle = self.waiting.len() == 0 &&
+                        matches!(self.writing, OutState::Idle(..));
+                    if let Some((ref handle, ref mut generation)) =
+                        self.config_handle
+                    {
+                        if idle &&
+                            handle.reload(generation, &mut self.config)
+                        {
+                            debug!("{}: config reloaded", self.id);
+                        }
+                    }
+                    self.read_deadline = self.timer.now()
                         + self.config.headers_timeout;
                     (Headers, true)
                 }
                 Connected => (Connected, false),
                 KeepAlive => (KeepAlive, false),
                 Headers => {
-                    match parse_headers(&mut inbuf.in_buf,
-                                        &mut self.dispatcher)?
+                    let parsed = match parse_headers(&mut inbuf.in_buf,
+                        &mut self.dispatcher, &self.config, self.id,
+                        &self.info, self.timer.now(), &self.span)
                     {
-                        Some((body, mut codec, cfg)) => {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            if !e.is_bad_request() {
+                                return Err(e);
+                            }
+                            let prefix = &inbuf.in_buf[..min(
+                                inbuf.in_buf.len(), MALFORMED_PREFIX)];
+                            let e: Error = ErrorEnum::MalformedRequest(
+                                self.requests_received,
+                                String::from_utf8_lossy(prefix).into_owned(),
+                                Box::new(e)).into();
+                            debug!("{}: rejecting request: {}", self.id, e);
+                            // Responses to the requests received before
+                            // are sent before `400 Bad Request`
+                            self.canned_response = Some(BAD_REQUEST);
+                            self.closing = true;
+                            self.reading = Closed;
+                            self.rejected = Some(e);
+                            return Ok(true);
+                        }
+                    };
+                    match parsed {
+                        Some((body, mut codec, mut cfg, span)) => {
+                            self.requests_received += 1;
+                            trace!("{}: request headers received, body {:?}",
+                                self.id, body);
                             changed = true;
-                            let mode = codec.recv_mode();
+                            let mode = span.in_scope(|| codec.recv_mode());
+                            let buffered = matches!(get_mode(&mode),
+                                Mode::BufferedUpfront(..));
+                            let exhausted = self.config.memory_budget
+                                .as_ref().map(|b| b.is_exhausted())
+                                .unwrap_or(false);
+                            if buffered && exhausted &&
+                                body != BodyKind::Fixed(0)
+                            {
+                                // Body is never read, so connection is
+                                // closed after `503 Service Unavailable`
+                                let e: Error =
+                                    ErrorEnum::MemoryExhausted.into();
+                                debug!("{}: rejecting request: {}",
+                                    self.id, e);
+                                self.canned_response =
+                                    Some(SERVICE_UNAVAILABLE);
+                                self.closing = true;
+                                self.rejected = Some(e);
+                                return Ok(true);
+                            }
                             if get_mode(&mode) == Mode::Hijack {
-                                self.waiting.push_back((cfg, codec));
+                                self.waiting.push_back((cfg, codec, span));
                                 (Hijack, true)
+                            } else if get_mode(&mode) == Mode::Reject {
+                                trace!("{}: responding without reading \
+                                    request body", self.id);
+                                if body == BodyKind::Fixed(0) {
+                                    self.waiting.push_back((cfg, codec, span));
+                                    self.read_deadline = self.timer.now()
+                                        + self.config.keep_alive_timeout;
+                                    (KeepAlive, true)
+                                } else {
+                                    // Body is never read, so we can't find
+                                    // where the next request starts
+                                    cfg.do_close = true;
+                                    self.waiting.push_back((cfg, codec, span));
+                                    self.closing = true;
+                                    (Closed, false)
+                                }
                             } else {
-                                let timeo = mode.timeout.unwrap_or(
+                                let timeo = get_timeout(&mode).unwrap_or(
                                     self.config.input_body_whole_timeout);
-                                self.read_deadline = Instant::now() + timeo;
+                                self.read_deadline = self.timer.now() + timeo;
                                 (Body(BodyState {
                                     mode: get_mode(&mode),
+                                    kind: body,
+                                    consumed: 0,
                                     response_config: cfg,
-                                    progress: new_body(body, get_mode(&mode))?,
-                                    codec: codec }),
+                                    progress: new_body(body, get_mode(&mode),
+                                                       &self.config)?,
+                                    codec: codec,
+                                    span: span,
+                                    raw_received: 0,
+                                    full_duplex: is_full_duplex(&mode),
+                                    responded: false }),
                                  true)
                             }
                         }
@@ -178,41 +460,87 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
                     }
                 }
                 Body(mut body) => {
-                    body.progress.parse(inbuf)
-                        .map_err(ErrorEnum::ChunkParseError)?;
+                    {
+                        let BodyState { ref mut progress, ref mut codec,
+                                        ref span, .. } = body;
+                        span.in_scope(|| progress.parse_with(inbuf,
+                            |raw| codec.raw_body_received(raw)))
+                        .map_err(ErrorEnum::from)?;
+                    }
                     let (bytes, done) = body.progress.check_buf(inbuf);
-                    let operation = if done {
-                        Some(body.codec.data_received(
-                            &inbuf.in_buf[..bytes], true)?)
-                    } else if inbuf.done() {
+                    // Fixed-size body isn't changed by the parser, so the
+                    // new part of the buffer is passed as raw bytes
+                    let received = body.consumed + bytes as u64;
+                    let fixed = matches!(body.progress,
+                                         BodyProgress::Fixed(..));
+                    if fixed && received > body.raw_received {
+                        let start = (body.raw_received - body.consumed)
+                                    as usize;
+                        {
+                            let BodyState { ref mut codec, ref span, .. }
+                                = body;
+                            let raw = &inbuf.in_buf[start..bytes];
+                            span.in_scope(|| codec.raw_body_received(raw));
+                        }
+                        body.raw_received = received;
+                    }
+                    let operation = if !done && inbuf.done() {
                         return Err(ErrorEnum::ConnectionReset.into());
-                    } else if matches!(body.mode, Mode::Progressive(x) if x <= bytes) {
-                        Some(body.codec.data_received(
-                            &inbuf.in_buf[..bytes], false)?)
+                    } else if done ||
+                        matches!(body.mode, Mode::Progressive(x) if x <= bytes)
+                    {
+                        let BodyState { ref mut codec, ref span, kind,
+                                        consumed, .. } = body;
+                        let data = &inbuf.in_buf[..bytes];
+                        Some(span.in_scope(|| {
+                            codec.body_progress(
+                                &body_info(kind, consumed, bytes));
+                            codec.data_received(data, done)
+                        })?)
                     } else {
                         None
                     };
                     match operation {
                         Some(Async::Ready(consumed)) => {
+                            trace!("{}: codec consumed {} of {} body bytes",
+                                self.id, consumed, bytes);
+                            if consumed > bytes {
+                                return Err(ErrorEnum::OverConsume(
+                                    consumed, bytes).into());
+                            }
+                            if matches!(body.mode, Mode::BufferedUpfront(_))
+                                && consumed < bytes
+                            {
+                                return Err(ErrorEnum::PartialConsume(
+                                    consumed, bytes).into());
+                            }
                             body.progress.consume(inbuf, consumed);
+                            body.consumed += consumed as u64;
                             if done && consumed == bytes {
+                                trace!("{}: request body done", self.id);
                                 changed = true;
-                                self.waiting.push_back(
-                                    (body.response_config, body.codec));
-                                self.read_deadline = Instant::now()
+                                // In full-duplex mode response is already
+                                // in progress, so codec isn't needed anymore
+                                if !body.responded {
+                                    self.waiting.push_back((
+                                        body.response_config,
+                                        body.codec,
+                                        body.span));
+                                }
+                                self.read_deadline = self.timer.now()
                                     + self.config.keep_alive_timeout;
                                 (KeepAlive, true)
                             } else {
-                                (Body(body), true) // TODO(tailhook) check
-                            }
-                        }
-                        Some(Async::NotReady) => {
-                            if matches!(body.mode, Mode::Progressive(x) if x > bytes) {
-                                (Body(body), false)
-                            } else {
-                                (Body(body), true) // TODO(tailhook) check
+                                // Progressive mode: the rest of the data is
+                                // passed again right away if enough bytes
+                                // are buffered. If nothing is consumed we
+                                // wait for more data (or codec's wakeup)
+                                // to avoid spinning.
+                                (Body(body), consumed > 0)
                             }
                         }
+                        // Codec is responsible for waking up the task
+                        Some(Async::NotReady) => (Body(body), false),
                         None => (Body(body), false),
                     }
                 }
@@ -226,12 +554,57 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
         }
         Ok(changed)
     }
+    fn expired(&self) -> bool {
+        self.config.max_connection_age
+            .map(|age| self.info.age() >= age)
+            .unwrap_or(false)
+    }
+    fn overloaded(&self) -> bool {
+        self.config.load_shedding.as_ref()
+            .map(|policy| policy.is_overloaded())
+            .unwrap_or(false)
+    }
+    /// Returns `true` if connection should be closed after next response
+    fn should_close(&self) -> bool {
+        if self.expired() {
+            debug!("{}: connection is too old, closing", self.id);
+            true
+        } else if self.overloaded() {
+            debug!("{}: server is overloaded, closing", self.id);
+            true
+        } else {
+            false
+        }
+    }
+    /// Writes `100 Continue` if request which body is being read now is
+    /// the next one to respond to
+    ///
+    /// Should only be called when there is no response in progress, so
+    /// all previous responses are already in the buffer and interim
+    /// response can't get in the middle of one of them.
+    fn send_continue(&mut self, io: &mut WriteBuf<S>) -> bool {
+        if self.waiting.len() > 0 {
+            return false;
+        }
+        match self.reading {
+            InState::Body(BodyState { ref mut response_config, .. })
+            if response_config.expect_continue &&
+                !response_config.continue_sent
+            => {
+                io.out_buf.extend(CONTINUE);
+                self.info.add_sent(CONTINUE.len() as u64);
+                response_config.continue_sent = true;
+                true
+            }
+            _ => false,
+        }
+    }
     fn do_writes(&mut self) -> Result<(), Error>
         where S: AsyncWrite
     {
         use self::OutState::*;
         use self::InState::*;
-        use server::recv_mode::Mode::{BufferedUpfront, Progressive};
+        use recv_mode::Mode::{BufferedUpfront, Progressive};
         loop {
             let (next, cont) = match mem::replace(&mut self.writing, Void) {
                 Idle(mut io) => {
@@ -239,18 +612,68 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
                     if old_len > 0 {
                         io.flush().map_err(ErrorEnum::Io)?;
                         if io.out_buf.len() < old_len {
-                            self.last_byte_written = Instant::now();
+                            self.last_byte_written = self.timer.now();
                         }
                     }
 
-                    if let Some((rc, mut codec)) = self.waiting.pop_front() {
-                        self.response_deadline = Instant::now()
+                    if self.send_continue(&mut io) {
+                        (Idle(io), true)
+                    } else if self.canned_response.is_some() &&
+                        self.waiting.len() == 0
+                    {
+                        let data = self.canned_response.take().unwrap();
+                        io.out_buf.extend(data);
+                        self.info.add_sent(data.len() as u64);
+                        (Idle(io), true)
+                    } else if let Some((rc, mut codec, span)) =
+                        self.waiting.pop_front()
+                    {
+                        self.response_deadline = self.timer.now()
                             + self.config.output_body_whole_timeout;
-                        let e = encoder::new(io, rc);
+                        let mut e = encoder::new(io, rc);
+                        encoder::set_span(&mut e, span.clone());
+                        // On shutdown no more requests are read after
+                        // the current body, so this is the last response
+                        let last = self.shutting_down() &&
+                            self.waiting.len() == 0 &&
+                            !matches!(self.reading, Body(..));
+                        if self.should_close() || last {
+                            e.force_close();
+                        }
                         if matches!(self.reading, Hijack) {
-                            (Switch(codec.start_response(e), codec), true)
+                            self.response_deadline = self.timer.now()
+                                + self.config.hijack_write_timeout;
+                            let f = span.in_scope(|| codec.start_response(e));
+                            (Switch(f, codec, span), true)
                         } else {
-                            (Write(codec.start_response(e)), true)
+                            let f = span.in_scope(|| codec.start_response(e));
+                            (Write(f, span), true)
+                        }
+                    } else if matches!(self.reading, Body(BodyState {
+                        full_duplex: true, responded: false, ..}))
+                    {
+                        let close = self.should_close() ||
+                            self.shutting_down();
+                        let timeout = self.config.output_body_whole_timeout;
+                        self.response_deadline = self.timer.now() + timeout;
+                        match self.reading {
+                            Body(ref mut body) => {
+                                trace!("{}: starting response before \
+                                    request body is received", self.id);
+                                body.responded = true;
+                                let mut e = encoder::new(io,
+                                    body.response_config.clone());
+                                encoder::set_span(&mut e, body.span.clone());
+                                if close {
+                                    e.force_close();
+                                }
+                                let BodyState {
+                                    ref mut codec, ref span, .. } = *body;
+                                let f = span.in_scope(
+                                    || codec.start_response(e));
+                                (Write(f, span.clone()), true)
+                            }
+                            _ => unreachable!(),
                         }
                     } else {
                         match self.reading {
@@ -259,45 +682,62 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
                             => {
                                 (Idle(io), false)
                             }
-                            Body(BodyState { mode: Mode::Hijack, ..}) => {
+                            Body(BodyState { mode: Mode::Hijack, ..}) |
+                            Body(BodyState { mode: Mode::Reject, ..}) => {
                                 unreachable!();
                             }
-                            Body(BodyState {
-                                mode: Progressive(_),
-                                codec: ref mut _codec, ..})
-                            => {
-                                self.response_deadline = Instant::now()
-                                    + self.config.output_body_whole_timeout;
-                                // TODO(tailhook) start writing now
-                                unimplemented!();
+                            // Unless in full-duplex mode, response is started
+                            // when body is done like in buffered mode
+                            Body(BodyState { mode: Progressive(_), ..}) => {
+                                (Idle(io), false)
                             }
                             Hijack => unreachable!(),
                         }
                     }
                 }
-                Write(mut f) => {
-                    match f.poll()? {
+                Write(mut f, span) => {
+                    match span.in_scope(|| f.poll())? {
                         Async::Ready(x) => {
-                            self.read_deadline = Instant::now()
-                                + self.config.keep_alive_timeout;
+                            trace!("{}: response done", self.id);
+                            span.record_bytes(bytes_written(&x));
+                            self.info.request_served();
+                            self.info.add_sent(bytes_written(&x));
+                            // Body of the request is still being read
+                            // if response was started early
+                            if !matches!(self.reading, Body(..)) {
+                                self.read_deadline = self.timer.now()
+                                    + self.config.keep_alive_timeout;
+                            }
+                            if wants_close(&x) {
+                                debug!("{}: closing connection after response",
+                                    self.id);
+                                self.canned_response = None;
+                                self.closing = true;
+                                self.reading = Closed;
+                                self.waiting.clear();
+                            }
                             (Idle(get_inner(x)), true)
                         }
                         Async::NotReady => {
-                            (Write(f), false)
+                            (Write(f, span), false)
                         }
                     }
                 }
-                Switch(mut f, mut codec) => {
-                    match f.poll()? {
+                Switch(mut f, mut codec, span) => {
+                    let res = span.in_scope(|| f.poll()).map_err(|e| {
+                        ErrorEnum::HijackWrite(Box::new(e))
+                    })?;
+                    match res {
                         Async::Ready(x) => {
                             let wr = get_inner(x);
                             let rd = self.inbuf.take()
                                 .expect("can hijack only once");
-                            codec.hijack(wr, rd);
+                            debug!("{}: connection hijacked", self.id);
+                            span.in_scope(|| codec.hijack(wr, rd));
                             return Ok(());
                         }
                         Async::NotReady => {
-                            (Switch(f, codec), false)
+                            (Switch(f, codec, span), false)
                         }
                     }
                 }
@@ -311,27 +751,127 @@ impl<S, D: Dispatcher<S>> PureProto<S, D> {
     }
 }
 
-impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>> PureProto<S, D> {
+impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>, T: Timer>
+    PureProto<S, D, T>
+{
     /// Does all needed processing and returns Ok(true) if connection is fine
     /// and Ok(false) if it needs to be closed
     fn process(&mut self) -> Result<bool, Error> {
+        self.throttled = false;
         self.do_writes()?;
         while self.do_reads()? {
             self.do_writes()?;
         }
-        if self.inbuf.as_ref().map(|x| x.done()).unwrap_or(true) {
-            Ok(false)
+        self.account_memory();
+        let eof = match self.inbuf {
+            Some(ref inbuf) => inbuf.done(),
+            None => return Ok(false),  // hijacked
+        };
+        if !self.closing && !eof {
+            return Ok(true);
+        }
+        // Respond to already received requests and flush the output
+        let flushed = matches!(self.writing,
+            OutState::Idle(ref io) if io.out_buf.len() == 0);
+        if flushed && self.waiting.len() == 0 &&
+            self.canned_response.is_none()
+        {
+            if let (false, Some(timeout)) = (eof, self.config.drain_timeout) {
+                return self.drain(timeout);
+            }
+            return self.finish();
+        }
+        if self.linger.is_none() {
+            debug!("{}: input closed, flushing output", self.id);
+            self.linger = Some(self.timer.now() + self.config.linger_timeout);
+        }
+        Ok(true)
+    }
+    /// Returns `Ok(false)` or the error of the rejected request when
+    /// connection is done
+    fn finish(&mut self) -> Result<bool, Error> {
+        match self.rejected.take() {
+            Some(err) => Err(err),
+            None => Ok(false),
+        }
+    }
+    /// Shuts down output and discards input until client closes connection
+    ///
+    /// Should only be called when output is flushed. Returns `Ok(true)`
+    /// until input is closed (the deadline is checked in `deadline_reached`)
+    fn drain(&mut self, timeout: Duration) -> Result<bool, Error> {
+        if self.drain.is_none() {
+            debug!("{}: output flushed, draining input", self.id);
+            self.drain = Some(self.timer.now() + timeout);
+        }
+        if !self.write_shut {
+            let io = match mem::replace(&mut self.writing, OutState::Void) {
+                OutState::Idle(io) => io,
+                _ => unreachable!("output is flushed"),
+            };
+            let mut raw = match io.borrow_raw().poll()
+                .map_err(ErrorEnum::Io)?
+            {
+                Async::Ready(raw) => raw,
+                Async::NotReady => unreachable!("output is flushed"),
+            };
+            // Shutdown may need more writes (e.g. for TLS), in this case
+            // it's retried when connection is polled next time
+            match (self.shutdown_write)(raw.get_mut()) {
+                Ok(Async::Ready(())) => self.write_shut = true,
+                Ok(Async::NotReady) => {}
+                Err(e) => {
+                    debug!("{}: error shutting down output: {}", self.id, e);
+                    self.write_shut = true;
+                }
+            }
+            self.writing = OutState::Idle(raw.into_buf());
+        }
+        let eof = {
+            let inbuf = self.inbuf.as_mut().expect("not hijacked");
+            loop {
+                match inbuf.read() {
+                    Ok(0) => break inbuf.done(),
+                    Ok(_) => {
+                        let len = inbuf.in_buf.len();
+                        inbuf.in_buf.consume(len);
+                    }
+                    Err(e) => {
+                        debug!("{}: error draining input: {}", self.id, e);
+                        break true;
+                    }
+                }
+            }
+        };
+        if eof {
+            self.finish()
         } else {
             Ok(true)
         }
     }
+    /// Called when deadline returned by `timeout()` is reached
+    fn deadline_reached(&mut self) -> Result<(), Error> {
+        if self.drain.is_some() {
+            debug!("{}: input is not closed in time, closing", self.id);
+            return self.finish().map(|_| ());
+        }
+        Err(self.timeout_error())
+    }
     fn timeout(&mut self) -> Option<Instant> {
         use self::OutState::*;
 
+        if let Some(drain) = self.drain {
+            return Some(drain);
+        }
+
+        if let Some(linger) = self.linger {
+            return Some(linger);
+        }
+
         match self.writing {
             Idle(..) => {}
             Write(..) => return Some(self.response_deadline),
-            Switch(..) => return None,  // TODO(tailhook) is it right?
+            Switch(..) => return Some(self.response_deadline),
             Void => return None,  // TODO(tailhook) is it reachable?
         }
         if self.waiting.len() > 0 { // if there are requests processing now
@@ -340,34 +880,83 @@ impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>> PureProto<S, D> {
         }
         return Some(self.read_deadline);
     }
+    fn account_memory(&mut self) {
+        if let Some(ref budget) = self.config.memory_budget {
+            let input = self.inbuf.as_ref()
+                .map(|x| x.in_buf.len()).unwrap_or(0);
+            // Output buffer is owned by the encoder while response is
+            // being written, so only buffer of the idle connection is known
+            let output = match self.writing {
+                OutState::Idle(ref io) => io.out_buf.len(),
+                _ => 0,
+            };
+            let new = input + output;
+            budget.update(self.memory, new);
+            self.memory = new;
+        }
+    }
+    fn timeout_error(&self) -> Error {
+        let err: Error = match self.writing {
+            _ if self.linger.is_some() => {
+                let (bytes, writing) = match self.writing {
+                    OutState::Idle(ref io) => (io.out_buf.len(), 0),
+                    _ => (0, 1),
+                };
+                ErrorEnum::OutputDropped(bytes, self.waiting.len() + writing)
+                    .into()
+            }
+            OutState::Switch(..) => ErrorEnum::HijackTimeout.into(),
+            _ => ErrorEnum::Timeout.into(),
+        };
+        debug!("{}: {}", self.id, err);
+        err
+    }
 }
 
-impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>> Future for Proto<S, D> {
+impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>, T: Timer> Future
+    for Proto<S, D, T>
+{
     type Item = ();
     type Error = Error;
 
     fn poll(&mut self) -> Poll<(), Error> {
+        if let Some(ref handle) = self.proto.shutdown {
+            handle.register();
+        }
         match self.proto.process() {
-            Ok(false) => Ok(Async::Ready(())),
+            Ok(false) => {
+                debug!("{}: connection closed", self.proto.id);
+                Ok(Async::Ready(()))
+            }
             Ok(true) => {
-                // TODO(tailhook) schedule notification with timeout
-                match self.proto.timeout() {
-                    Some(new_timeout) => {
-                        let now = Instant::now();
-                        if now > new_timeout {
-                            Err(ErrorEnum::Timeout.into())
-                        } else {
-                            self.timeout = Timeout::new(new_timeout - now,
-                                &self.handle)
-                                .expect("can always add a timeout");
-                            let timeo = self.timeout.poll()
-                                .expect("timeout can't fail on poll");
-                            match timeo {
-                                Async::Ready(()) => {
-                                    Err(ErrorEnum::Timeout.into())
-                                }
-                                Async::NotReady => Ok(Async::NotReady),
+                let now = self.proto.timer.now();
+                let deadline = self.proto.timeout();
+                if deadline.map(|x| now > x).unwrap_or(false) {
+                    return self.proto.deadline_reached().map(Async::Ready);
+                }
+                // When throttled by memory budget nobody will wake us up
+                // when memory is freed, so we retry reading periodically
+                let retry = now + Duration::from_millis(BUDGET_RETRY_MS);
+                let wakeup = match (deadline, self.proto.throttled) {
+                    (Some(x), true) => Some(min(x, retry)),
+                    (None, true) => Some(retry),
+                    (x, false) => x,
+                };
+                match wakeup {
+                    Some(wakeup) => {
+                        self.timeout = self.proto.timer.timeout_at(wakeup);
+                        let timeo = self.timeout.poll()
+                            .expect("timeout can't fail on poll");
+                        match timeo {
+                            Async::Ready(()) if Some(wakeup) == deadline => {
+                                self.proto.deadline_reached()
+                                    .map(Async::Ready)
+                            }
+                            Async::Ready(()) => {
+                                task::current().notify();
+                                Ok(Async::NotReady)
                             }
+                            Async::NotReady => Ok(Async::NotReady),
                         }
                     }
                     None => {
@@ -378,33 +967,63 @@ impl<S: AsyncRead+AsyncWrite, D: Dispatcher<S>> Future for Proto<S, D> {
                     }
                 }
             }
-            Err(e) => Err(e),
+            Err(e) => {
+                debug!("{}: connection error: {}", self.proto.id, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S, D: Dispatcher<S>, T> Drop for PureProto<S, D, T> {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.config.memory_budget {
+            budget.update(self.memory, 0);
+        }
+        if let Some(ref policy) = self.config.load_shedding {
+            policy.connection_closed();
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::cmp;
+    use std::error::Error as StdError;
+    use std::io::{self, Read, Write};
+    use std::net::SocketAddr;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
 
-    use futures::{Empty, Async, empty};
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicBool;
+
+    use futures::{Future, Empty, Async, Poll, empty};
+    use futures::future::{FutureResult, ok, poll_fn, lazy};
     use tk_bufstream::{MockData, ReadBuf, WriteBuf};
+    use tokio_io::{AsyncRead, AsyncWrite};
 
-    use super::PureProto;
-    use server::{Config, Dispatcher, Codec};
+    use super::{PureProto, Proto, OutState};
+    use timer::{Timer, ManualTimer};
+    use server::{Config, Dispatcher, Codec, BodyInfo, BodyKind, MemoryBudget};
+    use server::{ConfigHandle, ConnectionInfo};
     use server::{Head, RecvMode, Error, Encoder, EncoderDone};
+    use Status;
 
     struct MockDisp<'a> {
         counter: &'a AtomicUsize,
     }
 
+    #[cfg(feature="websocket")]
     struct MockWs<'a> {
         websockets: &'a AtomicUsize,
+        hijack: bool,
     }
 
     struct MockCodec<'a> {
         counter: &'a AtomicUsize,
+        hijack: bool,
     }
 
     impl<'a> Dispatcher<MockData> for MockDisp<'a> {
@@ -413,10 +1032,11 @@ mod test {
         fn headers_received(&mut self, _headers: &Head)
             -> Result<Self::Codec, Error>
         {
-            Ok(MockCodec { counter: self.counter })
+            Ok(MockCodec { counter: self.counter, hijack: false })
         }
     }
 
+    #[cfg(feature="websocket")]
     impl<'a> Dispatcher<MockData> for MockWs<'a> {
         type Codec = MockCodec<'a>;
 
@@ -425,9 +1045,9 @@ mod test {
         {
             if headers.get_websocket_upgrade().unwrap().is_some() {
                 self.websockets.fetch_add(1, Ordering::SeqCst);
-                Ok(MockCodec { counter: self.websockets })
+                Ok(MockCodec { counter: self.websockets, hijack: self.hijack })
             } else {
-                Ok(MockCodec { counter: self.websockets })
+                Ok(MockCodec { counter: self.websockets, hijack: false })
             }
         }
     }
@@ -435,7 +1055,11 @@ mod test {
     impl<'a> Codec<MockData> for MockCodec<'a> {
         type ResponseFuture = Empty<EncoderDone<MockData>, Error>;
         fn recv_mode(&mut self) -> RecvMode {
-            RecvMode::buffered_upfront(1024)
+            if self.hijack {
+                RecvMode::hijack()
+            } else {
+                RecvMode::buffered_upfront(1024)
+            }
         }
         fn data_received(&mut self, data: &[u8], end: bool)
             -> Result<Async<usize>, Error>
@@ -456,84 +1080,1409 @@ mod test {
         }
     }
 
-    #[test]
-    fn simple_get_request() {
-        let counter = AtomicUsize::new(0);
-        let mock = MockData::new();
-        let mut proto = PureProto::new(mock.clone(),
-            &Arc::new(Config::new()), MockDisp { counter: &counter });
-        proto.process().unwrap();
-        mock.add_input("GET / HTTP/1.0\r\n\r\n");
-        proto.process().unwrap();
-        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    struct LengthDisp<'a> {
+        counter: &'a AtomicUsize,
+        log: &'a Mutex<Vec<(Option<u64>, bool)>>,
     }
 
-    #[test]
-    #[should_panic(expected="Version")]
-    fn failing_get_request() {
-        let counter = AtomicUsize::new(0);
-        let mock = MockData::new();
-        let mut proto = PureProto::new(mock.clone(),
-            &Arc::new(Config::new()),
-            MockDisp { counter: &counter });
-        proto.process().unwrap();
-        mock.add_input("GET / TTMP/2.0\r\n\r\n");
-        proto.process().unwrap();
+    impl<'a> Dispatcher<MockData> for LengthDisp<'a> {
+        type Codec = MockCodec<'a>;
+
+        fn headers_received(&mut self, headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            self.log.lock().unwrap()
+                .push((headers.body_length(), headers.has_content_length()));
+            Ok(MockCodec { counter: self.counter, hijack: false })
+        }
     }
 
-    #[test]
-    fn simple_get_request_with_limit_one() {
-        let counter = AtomicUsize::new(0);
-        let mock = MockData::new();
-        let mut proto = PureProto::new(mock.clone(),
-            &Config::new().inflight_request_limit(1).done(),
-            MockDisp { counter: &counter });
-        proto.process().unwrap();
-        mock.add_input("GET / HTTP/1.0\r\n\r\n");
-        proto.process().unwrap();
-        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    struct ProgressDisp<'a> {
+        log: &'a Mutex<Vec<(BodyKind, u64, Option<u64>)>>,
     }
 
-    #[test]
-    fn websocket() {
-        let counter = AtomicUsize::new(0);
-        let mock = MockData::new();
-        let mut proto = PureProto::new(mock.clone(),
-            &Config::new().inflight_request_limit(1).done(),
-            MockWs { websockets: &counter });
-        proto.process().unwrap();
-        mock.add_input("GET /chat HTTP/1.1\r\n\
-            Host: server.example.com\r\n\
-            Upgrade: websocket\r\n\
-            Connection: Upgrade\r\n\
-            Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\
-            Sec-WebSocket-Protocol: chat, superchat\r\n\
-            Sec-WebSocket-Version: 13\r\n\
-            Origin: http://example.com\r\n\r\n");
-        proto.process().unwrap();
-        // counts as a request and as a websocket
-        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    struct ProgressCodec<'a> {
+        log: &'a Mutex<Vec<(BodyKind, u64, Option<u64>)>>,
     }
 
-    #[test]
-    fn websocket_with_keepalive() {
-        let counter = AtomicUsize::new(0);
-        let mock = MockData::new();
-        let mut proto = PureProto::new(mock.clone(),
-            &Config::new().inflight_request_limit(1).done(),
-            MockWs { websockets: &counter });
-        proto.process().unwrap();
-        mock.add_input("GET /chat HTTP/1.1\r\n\
-            Host: server.example.com\r\n\
-            Upgrade: websocket\r\n\
-            Connection: keep-alive, Upgrade\r\n\
-            Keep-Alive: some thing\r\n\
-            Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\
-            Sec-WebSocket-Protocol: chat, superchat\r\n\
-            Sec-WebSocket-Version: 13\r\n\
-            Origin: http://example.com\r\n\r\n");
-        proto.process().unwrap();
-        // counts as a request and as a websocket
-        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    impl<'a> Dispatcher<MockData> for ProgressDisp<'a> {
+        type Codec = ProgressCodec<'a>;
+
+        fn headers_received(&mut self, _headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(ProgressCodec { log: self.log })
+        }
+    }
+
+    impl<'a> Codec<MockData> for ProgressCodec<'a> {
+        type ResponseFuture = Empty<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::progressive(1)
+        }
+        fn body_progress(&mut self, info: &BodyInfo) {
+            self.log.lock().unwrap()
+                .push((info.kind(), info.received(), info.remaining()));
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, _e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            empty()
+        }
+    }
+
+    struct RawDisp<'a> {
+        log: &'a Mutex<(Vec<u8>, Vec<u8>)>,
+    }
+
+    struct RawCodec<'a> {
+        log: &'a Mutex<(Vec<u8>, Vec<u8>)>,
+    }
+
+    impl<'a> Dispatcher<MockData> for RawDisp<'a> {
+        type Codec = RawCodec<'a>;
+
+        fn headers_received(&mut self, _headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(RawCodec { log: self.log })
+        }
+    }
+
+    impl<'a> Codec<MockData> for RawCodec<'a> {
+        type ResponseFuture = Empty<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::progressive(1)
+        }
+        fn raw_body_received(&mut self, data: &[u8]) {
+            self.log.lock().unwrap().0.extend_from_slice(data);
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            // consume partially to check that raw bytes aren't repeated
+            let n = cmp::min(data.len(), 3);
+            self.log.lock().unwrap().1.extend_from_slice(&data[..n]);
+            Ok(Async::Ready(n))
+        }
+        fn start_response(&mut self, _e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            empty()
+        }
+    }
+
+    struct PartialDisp<'a> {
+        mode: RecvMode,
+        step: usize,
+        /// Bytes reported as consumed in excess of received ones
+        extra: usize,
+        log: &'a Mutex<Vec<(usize, bool)>>,
+    }
+
+    struct PartialCodec<'a> {
+        mode: RecvMode,
+        step: usize,
+        /// Bytes reported as consumed in excess of received ones
+        extra: usize,
+        log: &'a Mutex<Vec<(usize, bool)>>,
+    }
+
+    impl<'a> Dispatcher<MockData> for PartialDisp<'a> {
+        type Codec = PartialCodec<'a>;
+
+        fn headers_received(&mut self, _headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(PartialCodec {
+                mode: self.mode.clone(),
+                step: self.step,
+                extra: self.extra,
+                log: self.log,
+            })
+        }
+    }
+
+    impl<'a> Codec<MockData> for PartialCodec<'a> {
+        type ResponseFuture = Empty<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            self.mode.clone()
+        }
+        fn data_received(&mut self, data: &[u8], end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            self.log.lock().unwrap().push((data.len(), end));
+            Ok(Async::Ready(cmp::min(data.len(), self.step) + self.extra))
+        }
+        fn start_response(&mut self, _e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            empty()
+        }
+    }
+
+    struct CloseDisp<'a> {
+        counter: &'a AtomicUsize,
+    }
+
+    struct CloseCodec<'a> {
+        counter: &'a AtomicUsize,
+    }
+
+    impl<'a> Dispatcher<MockData> for CloseDisp<'a> {
+        type Codec = CloseCodec<'a>;
+
+        fn headers_received(&mut self, _headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(CloseCodec { counter: self.counter })
+        }
+    }
+
+    impl<'a> Codec<MockData> for CloseCodec<'a> {
+        type ResponseFuture = FutureResult<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::buffered_upfront(1024)
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, mut e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            self.counter.fetch_add(1, Ordering::SeqCst);
+            e.status(Status::Forbidden);
+            assert!(e.force_close());
+            e.add_length(0).unwrap();
+            e.done_headers().unwrap();
+            ok(e.done())
+        }
+    }
+
+    struct DuplexDisp<'a> {
+        counter: &'a AtomicUsize,
+        log: &'a Mutex<Vec<(usize, bool)>>,
+    }
+
+    struct DuplexCodec<'a> {
+        counter: &'a AtomicUsize,
+        log: &'a Mutex<Vec<(usize, bool)>>,
+    }
+
+    impl<'a> Dispatcher<MockData> for DuplexDisp<'a> {
+        type Codec = DuplexCodec<'a>;
+
+        fn headers_received(&mut self, _headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(DuplexCodec { counter: self.counter, log: self.log })
+        }
+    }
+
+    impl<'a> Codec<MockData> for DuplexCodec<'a> {
+        type ResponseFuture = FutureResult<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::progressive(1).full_duplex()
+        }
+        fn data_received(&mut self, data: &[u8], end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            self.log.lock().unwrap().push((data.len(), end));
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, mut e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            self.counter.fetch_add(1, Ordering::SeqCst);
+            e.status(Status::Accepted);
+            e.add_length(0).unwrap();
+            e.done_headers().unwrap();
+            ok(e.done())
+        }
+    }
+
+    struct EchoDisp {
+        gate: Arc<AtomicBool>,
+    }
+
+    struct EchoCodec {
+        gate: Option<Arc<AtomicBool>>,
+        expect_continue: bool,
+        reject: bool,
+        body: Vec<u8>,
+    }
+
+    impl Dispatcher<MockData> for EchoDisp {
+        type Codec = EchoCodec;
+
+        fn headers_received(&mut self, headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(EchoCodec {
+                gate: if headers.path() == Some("/slow") {
+                    Some(self.gate.clone())
+                } else {
+                    None
+                },
+                expect_continue: headers.expect_continue(),
+                reject: headers.path() == Some("/reject"),
+                body: Vec::new(),
+            })
+        }
+    }
+
+    impl Codec<MockData> for EchoCodec {
+        type ResponseFuture = Box<Future<Item=EncoderDone<MockData>,
+                                         Error=Error>>;
+        fn recv_mode(&mut self) -> RecvMode {
+            if self.reject {
+                RecvMode::reject()
+            } else {
+                RecvMode::buffered_upfront(1024)
+            }
+        }
+        fn data_received(&mut self, data: &[u8], end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            assert!(end);
+            assert!(!self.reject);
+            self.body.extend(data);
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, mut e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            if self.reject {
+                e.status(Status::ExpectationFailed);
+                e.add_length(0).unwrap();
+                e.done_headers().unwrap();
+                return Box::new(ok(e.done()));
+            }
+            if self.expect_continue {
+                // no-op if protocol has already sent one
+                e.response_continue();
+            }
+            e.status(Status::Ok);
+            e.add_length(self.body.len() as u64).unwrap();
+            e.done_headers().unwrap();
+            let body = self.body.clone();
+            let gate = self.gate.clone();
+            let mut e = Some(e);
+            Box::new(poll_fn(move || {
+                if gate.as_ref().map(|g| !g.load(Ordering::SeqCst))
+                    .unwrap_or(false)
+                {
+                    return Ok(Async::NotReady);
+                }
+                let mut e = e.take().unwrap();
+                e.write_body(&body);
+                Ok(Async::Ready(e.done()))
+            }))
+        }
+    }
+
+    fn echo_proto(mock: &MockData, gate: &Arc<AtomicBool>)
+        -> PureProto<MockData, EchoDisp, ManualTimer>
+    {
+        PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(4).done(),
+            EchoDisp { gate: gate.clone() }, ManualTimer::new())
+    }
+
+    /// Same as `echo_proto` but accepts HTTP/1.1 requests without `Host`
+    fn hostless_proto(mock: &MockData, gate: &Arc<AtomicBool>)
+        -> PureProto<MockData, EchoDisp, ManualTimer>
+    {
+        PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(4)
+                .require_host(false).done(),
+            EchoDisp { gate: gate.clone() }, ManualTimer::new())
+    }
+
+    #[test]
+    fn simple_get_request() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Arc::new(Config::new()), MockDisp { counter: &counter },
+            ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input("GET / HTTP/1.0\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[should_panic(expected="Version")]
+    fn failing_get_request() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Arc::new(Config::new()),
+            MockDisp { counter: &counter }, ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input("GET / TTMP/2.0\r\n\r\n");
+        proto.process().unwrap();
+    }
+
+    #[test]
+    fn simple_get_request_with_limit_one() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(1).done(),
+            MockDisp { counter: &counter }, ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input("GET / HTTP/1.0\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[cfg(feature="websocket")]
+    fn websocket() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(1).done(),
+            MockWs { websockets: &counter, hijack: false },
+            ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input("GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\
+            Sec-WebSocket-Protocol: chat, superchat\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Origin: http://example.com\r\n\r\n");
+        proto.process().unwrap();
+        // counts as a request and as a websocket
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    #[cfg(feature="websocket")]
+    fn websocket_with_keepalive() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(1).done(),
+            MockWs { websockets: &counter, hijack: false },
+            ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input("GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Keep-Alive: some thing\r\n\
+            Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\
+            Sec-WebSocket-Protocol: chat, superchat\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Origin: http://example.com\r\n\r\n");
+        proto.process().unwrap();
+        // counts as a request and as a websocket
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn body_progress() {
+        let log = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(1).done(),
+            ProgressDisp { log: &log }, ManualTimer::new());
+        proto.do_reads().unwrap();
+        mock.add_input("POST / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 10\r\n\r\n\
+            hello");
+        proto.do_reads().unwrap();
+        assert_eq!(*log.lock().unwrap(),
+            vec![(BodyKind::Fixed(10), 5, Some(5))]);
+        mock.add_input("world");
+        proto.do_reads().unwrap();
+        assert_eq!(log.lock().unwrap()[1..],
+            [(BodyKind::Fixed(10), 10, Some(0))]);
+    }
+
+    #[test]
+    fn raw_body() {
+        let log = Mutex::new((Vec::new(), Vec::new()));
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            RawDisp { log: &log }, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\nHost: a\r\n\
+                        Content-Length: 10\r\n\r\nhello");
+        proto.do_reads().unwrap();
+        mock.add_input("world\
+                        POST / HTTP/1.1\r\nHost: a\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n\
+                        4\r\nhell\r\n1;x=y\r\no\r\n0\r\n");
+        proto.do_reads().unwrap();
+        mock.add_input("\r\n");
+        proto.do_reads().unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(String::from_utf8_lossy(&log.0),
+            "helloworld4\r\nhell\r\n1;x=y\r\no\r\n0\r\n\r\n");
+        assert_eq!(String::from_utf8_lossy(&log.1), "helloworldhello");
+    }
+
+    #[test]
+    fn progressive_partial_consume() {
+        let log = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            PartialDisp {
+                mode: RecvMode::progressive(4),
+                step: 3,
+                extra: 0,
+                log: &log,
+            }, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 12\r\n\r\n\
+            hello");
+        proto.do_reads().unwrap();
+        // two bytes left is less than the chunk size, so wait for more
+        assert_eq!(*log.lock().unwrap(), vec![(5, false)]);
+        mock.add_input("world!!");
+        proto.do_reads().unwrap();
+        assert_eq!(log.lock().unwrap()[1..],
+            [(9, true), (6, true), (3, true)]);
+    }
+
+    #[test]
+    fn full_duplex() {
+        let counter = AtomicUsize::new(0);
+        let log = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            DuplexDisp { counter: &counter, log: &log }, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\nHost: a\r\n\
+                        Expect: 100-continue\r\n\
+                        Content-Length: 10\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        mock.add_input("hello");
+        proto.process().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![(5, false)]);
+        mock.add_input("worldGET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(log.lock().unwrap()[1..], [(5, true), (0, true)]);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn buffered_partial_consume() {
+        let log = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            PartialDisp {
+                mode: RecvMode::buffered_upfront(1024),
+                step: 3,
+                extra: 0,
+                log: &log,
+            }, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 10\r\n\r\n\
+            helloworld");
+        let err = proto.do_reads().unwrap_err();
+        assert_eq!(err.to_string(),
+            "codec consumed 3 bytes out of 10 of the buffered request body");
+        assert_eq!(*log.lock().unwrap(), vec![(10, true)]);
+    }
+
+    #[test]
+    fn over_consume() {
+        let log = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            PartialDisp {
+                mode: RecvMode::progressive(1),
+                step: 5,
+                extra: 1,
+                log: &log,
+            }, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 10\r\n\r\n\
+            hello");
+        let err = proto.do_reads().unwrap_err();
+        assert_eq!(err.to_string(), "codec consumed 6 bytes out of 5 bytes \
+            of the request body passed to it");
+    }
+
+    #[test]
+    #[cfg(feature="websocket")]
+    fn websocket_hijack_timeout() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().inflight_request_limit(1).done(),
+            MockWs { websockets: &counter, hijack: true }, ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input("GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n");
+        proto.process().unwrap();
+        // response future never finishes, so we are stuck in upgrade
+        assert!(proto.timeout().is_some());
+        assert_eq!(format!("{:?}", proto.timeout_error()),
+            "Error(HijackTimeout)");
+    }
+
+    #[test]
+    fn memory_budget() {
+        let counter = AtomicUsize::new(0);
+        let budget = MemoryBudget::new(8);
+        let cfg = Config::new().memory_budget(&budget).done();
+        let mock1 = MockData::new();
+        let mut proto1 = PureProto::new(mock1.clone(), &cfg,
+            MockDisp { counter: &counter }, ManualTimer::new());
+        let mock2 = MockData::new();
+        let mut proto2 = PureProto::new(mock2.clone(), &cfg,
+            MockDisp { counter: &counter }, ManualTimer::new());
+        mock1.add_input("GET / HT");
+        proto1.process().unwrap();
+        assert_eq!(budget.used(), 8);
+        mock2.add_input("GET / HTTP/1.0\r\n\r\n");
+        proto2.process().unwrap();
+        assert!(proto2.throttled);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+        drop(proto1);
+        assert_eq!(budget.used(), 0);
+        proto2.process().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn memory_budget_rejects_buffered_body() {
+        let counter = AtomicUsize::new(0);
+        let budget = MemoryBudget::new(32);
+        let cfg = Config::new().memory_budget(&budget).done();
+        let mock1 = MockData::new();
+        let mut proto1 = PureProto::new(mock1.clone(), &cfg,
+            MockDisp { counter: &counter }, ManualTimer::new());
+        let mock2 = MockData::new();
+        let mut proto2 = PureProto::new(mock2.clone(), &cfg,
+            MockDisp { counter: &counter }, ManualTimer::new());
+        // connection which is reading headers is not throttled
+        mock2.add_input("POST / HTTP/1.1\r\n");
+        proto2.process().unwrap();
+        mock1.add_input("GET / HTTP/1.1\r\nHost: example.com");
+        proto1.process().unwrap();
+        assert!(budget.is_exhausted());
+        mock2.add_input("Host: example.com\r\n\
+                         Content-Length: 5\r\n\r\nhello");
+        let err = proto2.process().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(MemoryExhausted)");
+        assert_eq!(&mock2.output(..)[..],
+            &b"HTTP/1.1 503 Service Unavailable\r\n\
+               Content-Length: 0\r\nConnection: close\r\n\r\n"[..]);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn memory_budget_output() {
+        let counter = AtomicUsize::new(0);
+        let budget = MemoryBudget::new(1024);
+        let cfg = Config::new().memory_budget(&budget).done();
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &cfg,
+            MockDisp { counter: &counter }, ManualTimer::new());
+        if let OutState::Idle(ref mut io) = proto.writing {
+            io.out_buf.extend(b"HTTP/1.1 200 OK\r\n");
+        }
+        proto.account_memory();
+        assert_eq!(budget.used(), 17);
+        mock.add_input("GET / HT");
+        proto.process().unwrap();
+        assert_eq!(budget.used(), 8);
+        drop(proto);
+        assert_eq!(budget.used(), 0);
+    }
+
+    /// Returns debug representation of the error inside `MalformedRequest`
+    #[allow(deprecated)]  // `source()` is not available in older rust
+    fn malformed(err: &Error) -> String {
+        assert!(err.malformed_request().is_some(), "{:?}", err);
+        format!("{:?}", StdError::cause(err).unwrap())
+    }
+
+    fn request_error(cfg: &Arc<Config>, request: &str) -> String {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), cfg,
+            MockDisp { counter: &counter }, ManualTimer::new());
+        proto.process().unwrap();
+        mock.add_input(request);
+        let err = proto.process().unwrap_err();
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+        malformed(&err)
+    }
+
+    #[test]
+    fn trace_with_body() {
+        assert_eq!(request_error(&Config::new().done(),
+            "TRACE / HTTP/1.1\r\nContent-Length: 2\r\n\r\nok"),
+            "Error(BodyNotAllowed)");
+    }
+
+    #[test]
+    fn get_body_policy() {
+        let log = Mutex::new(Vec::new());
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().require_host(false).done(),
+            ProgressDisp { log: &log }, ManualTimer::new());
+        proto.do_reads().unwrap();
+        mock.add_input("GET / HTTP/1.1\r\nContent-Length: 2\r\n\r\nok");
+        proto.do_reads().unwrap();
+        assert_eq!(*log.lock().unwrap(),
+            vec![(BodyKind::Fixed(2), 2, Some(0))]);
+
+        assert_eq!(request_error(&Config::new().allow_get_body(false).done(),
+            "HEAD / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            "Error(BodyNotAllowed)");
+    }
+
+    #[test]
+    fn config_reload() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        let handle = ConfigHandle::new(&Config::new().done());
+        proto.set_config_handle(&handle);
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\
+                        Content-Length: 2\r\n\r\nok");
+        assert!(proto.process().unwrap());
+        handle.set(&Config::new().allow_get_body(false).done());
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\
+                        Content-Length: 2\r\n\r\nok");
+        let err = proto.process().unwrap_err();
+        assert_eq!(malformed(&err), "Error(BodyNotAllowed)");
+        assert_eq!(err.malformed_request().unwrap().0, 1);
+    }
+
+    #[test]
+    fn force_close() {
+        let counter = AtomicUsize::new(0);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            CloseDisp { counter: &counter }, ManualTimer::new());
+        assert!(proto.process().unwrap());
+        mock.add_input("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                        GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert!(!proto.process().unwrap());
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn missing_host() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n\
+                        POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nok");
+        let err = proto.process().unwrap_err();
+        assert_eq!(malformed(&err), "Error(MissingHost)");
+        assert_eq!(err.malformed_request(),
+            Some((1, "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nok")));
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 400 Bad Request\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n");
+
+        // host is optional in HTTP/1.0
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input("GET / HTTP/1.0\r\n\r\n");
+        assert!(proto.process().unwrap());
+        assert!(mock.output(..).starts_with(b"HTTP/1.0 200 OK\r\n"));
+    }
+
+    #[test]
+    fn drain_input() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().drain_timeout(Duration::new(1, 0)).done(),
+            EchoDisp { gate: gate.clone() }, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nok");
+        assert!(proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 400 Bad Request\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n");
+        assert_eq!(proto.timeout(),
+            Some(proto.timer.now() + Duration::new(1, 0)));
+        mock.add_input("ok");
+        assert!(proto.process().unwrap());
+        assert_eq!(proto.inbuf.as_ref().unwrap().in_buf.len(), 0);
+        let err = proto.deadline_reached().unwrap_err();
+        assert_eq!(malformed(&err), "Error(MissingHost)");
+    }
+
+    #[test]
+    fn drain_sends_fin() {
+        use std::net;
+        use std::thread;
+        use futures::Stream;
+        use futures::future::FutureResult;
+        use tokio_core::net::TcpListener;
+        use tokio_core::reactor::Core;
+        use server::buffered::{BufferedDispatcher, Request};
+
+        fn service<S>(_: Request, _: Encoder<S>)
+            -> FutureResult<EncoderDone<S>, Error>
+        {
+            unreachable!();
+        }
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(),
+                                         &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut sock = net::TcpStream::connect(addr).unwrap();
+            sock.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            sock.write_all(b"POST / HTTP/1.1\r\n\
+                             Content-Length: 4\r\n\r\nok").unwrap();
+            // Write half of the client is still open, so this returns
+            // only if server shuts down its write half
+            let mut response = Vec::new();
+            sock.read_to_end(&mut response).unwrap();
+            response
+        });
+        let (sock, _) = core.run(listener.incoming().into_future())
+            .map_err(|(e, _)| e).unwrap();
+        let (sock, _) = sock.unwrap();
+        let proto = Proto::from_tcp(sock,
+            &Config::new().drain_timeout(Duration::new(10, 0))
+                .require_host(true).done(),
+            BufferedDispatcher::new(addr, &handle, || service), &handle);
+        let err = core.run(proto).unwrap_err();
+        assert_eq!(malformed(&err), "Error(MissingHost)");
+        assert!(client.join().unwrap()
+            .starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn conflicting_host() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let request = "GET http://a/ HTTP/1.1\r\nHost: b\r\n\r\n";
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input(request);
+        assert!(proto.process().unwrap());
+        assert!(mock.output(..).starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().reject_conflicting_host(true).done(),
+            EchoDisp { gate: gate.clone() }, ManualTimer::new());
+        mock.add_input(request);
+        let err = proto.process().unwrap_err();
+        assert_eq!(malformed(&err), "Error(ConflictingHost)");
+        assert!(mock.output(..).starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn pipelined_posts() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        proto.process().unwrap();
+        mock.add_input("POST / HTTP/1.1\r\nHost: example.com\r\n\
+                        Content-Length: 5\r\n\r\nhello\
+                        POST / HTTP/1.1\r\nHost: example.com\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n\
+                        5\r\nworld\r\n0\r\n\r\n\
+                        GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello\
+             HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nworld\
+             HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn expect_continue() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = hostless_proto(&mock, &gate);
+        proto.process().unwrap();
+        mock.add_input("POST / HTTP/1.1\r\nExpect: 100-continue\r\n\
+                        Content-Length: 5\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 100 Continue\r\n\r\n");
+        mock.add_input("hello");
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    #[test]
+    fn expect_continue_body_received() {
+        // client didn't wait for `100 Continue`, so it's up to the codec
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = hostless_proto(&mock, &gate);
+        proto.process().unwrap();
+        mock.add_input("POST / HTTP/1.1\r\nExpect: 100-continue\r\n\
+                        Content-Length: 5\r\n\r\nhello");
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    #[test]
+    fn expect_continue_pipelined() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = hostless_proto(&mock, &gate);
+        proto.process().unwrap();
+        mock.add_input("POST /slow HTTP/1.1\r\nContent-Length: 3\r\n\r\none\
+                        POST / HTTP/1.1\r\nExpect: 100-continue\r\n\
+                        Content-Length: 3\r\n\r\n");
+        proto.process().unwrap();
+        // interim response must not get in the middle of the previous one
+        assert_eq!(mock.output(..).len(), 0);
+        gate.store(true, Ordering::SeqCst);
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none\
+             HTTP/1.1 100 Continue\r\n\r\n");
+        mock.add_input("two");
+        proto.process().unwrap();
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none\
+             HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\ntwo");
+    }
+
+    #[test]
+    fn expect_continue_http10() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        proto.process().unwrap();
+        mock.add_input("POST / HTTP/1.0\r\nExpect: 100-continue\r\n\
+                        Content-Length: 2\r\n\r\n");
+        proto.process().unwrap();
+        assert_eq!(mock.output(..).len(), 0);
+    }
+
+    #[test]
+    fn reject_body() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        proto.process().unwrap();
+        mock.add_input("GET /reject HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n");
+        mock.add_input("POST /reject HTTP/1.1\r\nHost: a\r\n\
+                        Expect: 100-continue\r\n\
+                        Content-Length: 5\r\n\r\n");
+        // no `100 Continue`, and connection is closed as the body
+        // is never read
+        assert!(!proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n");
+    }
+
+    /// A peer which sent the input, closed its side of the connection
+    /// and doesn't read the output (unless `writable` is set)
+    struct HalfClosed {
+        input: io::Cursor<Vec<u8>>,
+        writable: Arc<AtomicBool>,
+        output: Vec<u8>,
+    }
+
+    impl Read for HalfClosed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for HalfClosed {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writable.load(Ordering::SeqCst) {
+                self.output.write(buf)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for HalfClosed {}
+    impl AsyncWrite for HalfClosed {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    struct OkDisp;
+    struct OkCodec;
+
+    impl Dispatcher<HalfClosed> for OkDisp {
+        type Codec = OkCodec;
+
+        fn headers_received(&mut self, _headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            Ok(OkCodec)
+        }
+    }
+
+    impl Codec<HalfClosed> for OkCodec {
+        type ResponseFuture = FutureResult<EncoderDone<HalfClosed>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::buffered_upfront(0)
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, mut e: Encoder<HalfClosed>)
+            -> Self::ResponseFuture
+        {
+            e.status(Status::Ok);
+            e.add_length(2).unwrap();
+            e.done_headers().unwrap();
+            e.write_body(b"ok");
+            ok(e.done())
+        }
+    }
+
+    fn half_closed(writable: &Arc<AtomicBool>)
+        -> PureProto<HalfClosed, OkDisp, ManualTimer>
+    {
+        let conn = HalfClosed {
+            input: io::Cursor::new(b"GET / HTTP/1.1\r\n\r\n\
+                                     GET / HTTP/1.1\r\n\r\n".to_vec()),
+            writable: writable.clone(),
+            output: Vec::new(),
+        };
+        PureProto::new(conn, &Config::new().require_host(false).done(),
+                       OkDisp, ManualTimer::new())
+    }
+
+    #[test]
+    fn linger() {
+        let writable = Arc::new(AtomicBool::new(false));
+        let mut proto = half_closed(&writable);
+        // input is closed, but responses are not sent yet
+        assert!(proto.process().unwrap());
+        assert!(proto.linger.is_some());
+        assert_eq!(proto.timeout(), proto.linger);
+        assert_eq!(proto.timeout_error().dropped_bytes(), Some(80));
+        writable.store(true, Ordering::SeqCst);
+        assert!(!proto.process().unwrap());
+    }
+
+    #[test]
+    fn buffered_lazy_body() {
+        use tokio_core::reactor::Core;
+        use server::buffered::{BufferedDispatcher, Request};
+
+        let core = Core::new().unwrap();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let log = bodies.clone();
+        let mut disp = BufferedDispatcher::new(
+            "127.0.0.1:80".parse().unwrap(), &core.handle(),
+            move || {
+                let log = log.clone();
+                move |req: Request, mut e: Encoder<MockData>| {
+                    let lazy = req.lazy_body().map(|b| {
+                        assert!(b.path().exists());
+                        b.read_to_vec().unwrap()
+                    });
+                    log.lock().unwrap().push((req.body().to_vec(), lazy));
+                    e.status(Status::Ok);
+                    e.add_length(0).unwrap();
+                    e.done_headers().unwrap();
+                    ok(e.done())
+                }
+            });
+        disp.lazy_body(4, 10);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            disp, ManualTimer::new());
+        mock.add_input("POST / HTTP/1.1\r\nHost: a\r\n\
+                        Content-Length: 3\r\n\r\nabc\
+                        POST / HTTP/1.1\r\nHost: a\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n\
+                        2\r\nab\r\n5\r\ncdefg\r\n0\r\n\r\n");
+        assert!(proto.process().unwrap());
+        assert_eq!(*bodies.lock().unwrap(), vec![
+            (b"abc".to_vec(), None),
+            (Vec::new(), Some(b"abcdefg".to_vec())),
+        ]);
+
+        mock.add_input("POST / HTTP/1.1\r\nHost: a\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n\
+                        6\r\nabcdef\r\n6\r\nghijkl\r\n0\r\n\r\n");
+        let err = proto.process().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(RequestTooLong)");
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn buffered_body_stream() {
+        use futures::Stream;
+        use tokio_core::reactor::Core;
+        use server::buffered::{BufferedDispatcher, Request};
+
+        let core = Core::new().unwrap();
+        let mut disp = BufferedDispatcher::new(
+            "127.0.0.1:80".parse().unwrap(), &core.handle(),
+            || |mut req: Request, mut e: Encoder<MockData>| {
+                let stream = req.take_body_stream().unwrap();
+                stream.collect().map_err(Error::from).map(move |chunks| {
+                    let body = chunks.concat();
+                    e.status(Status::Ok);
+                    e.add_length(body.len() as u64).unwrap();
+                    e.done_headers().unwrap();
+                    e.write_body(&body);
+                    e.done()
+                })
+            });
+        disp.streaming_body(10);
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            disp, ManualTimer::new());
+        lazy(|| {
+            mock.add_input("POST / HTTP/1.1\r\nHost: a\r\n\
+                            Transfer-Encoding: chunked\r\n\r\n\
+                            2\r\nab\r\n");
+            proto.process().unwrap();
+            assert_eq!(mock.output(..), b"");
+            mock.add_input("3\r\ncde\r\n0\r\n\r\n");
+            proto.process().unwrap();
+            assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabcde");
+            mock.add_input("POST / HTTP/1.1\r\nHost: a\r\n\
+                            Content-Length: 11\r\n\r\n");
+            let err = proto.process().unwrap_err();
+            assert_eq!(format!("{:?}", err), "Error(RequestTooLong)");
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn buffered_asterisk() {
+        use tokio_core::reactor::Core;
+        use server::buffered::{BufferedDispatcher, Request};
+
+        let core = Core::new().unwrap();
+        let disp = BufferedDispatcher::new(
+            "127.0.0.1:80".parse().unwrap(), &core.handle(),
+            || |req: Request, mut e: Encoder<MockData>| {
+                let body = format!("{} {}", req.method(), req.path());
+                e.status(Status::Ok);
+                e.add_length(body.len() as u64).unwrap();
+                e.done_headers().unwrap();
+                e.write_body(body.as_bytes());
+                ok(e.done())
+            });
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            disp, ManualTimer::new());
+        mock.add_input("OPTIONS * HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nOPTIONS *");
+    }
+
+    #[test]
+    fn connection_info() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let input = "GET / HTTP/1.1\r\nHost: a\r\n\r\n\
+                     POST / HTTP/1.1\r\nHost: a\r\n\
+                     Content-Length: 2\r\n\r\nok";
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input(input);
+        assert!(proto.process().unwrap());
+        assert_eq!(proto.info.requests_served(), 2);
+        assert_eq!(proto.info.bytes_received(), input.len() as u64);
+        assert_eq!(proto.info.bytes_sent(), mock.output(..).len() as u64);
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input("GET /slow HTTP/1.1\r\nHost: a\r\n\r\n\
+                        POST / HTTP/1.1\r\nHost: a\r\n\
+                        Content-Length: 5\r\n\r\nhel");
+        assert!(proto.process().unwrap());
+        assert_eq!(mock.output(..).len(), 0);
+        let handle = proto.shutdown_handle();
+        handle.shutdown();
+        assert!(proto.shutdown_handle().is_requested());
+        // the body which is in progress is received, but next requests
+        // are not read anymore
+        mock.add_input("lo\
+                        GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(proto.process().unwrap());
+        gate.store(true, Ordering::SeqCst);
+        assert!(!proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\
+             Connection: close\r\n\r\nhello");
+        assert_eq!(proto.requests_received, 2);
+    }
+
+    #[test]
+    fn graceful_shutdown_idle() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(proto.process().unwrap());
+        proto.shutdown_handle().shutdown();
+        assert!(!proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    /// Records connection info and receive time of every request
+    struct InfoDisp {
+        heads: Vec<(ConnectionInfo, Instant)>,
+    }
+
+    impl Dispatcher<MockData> for InfoDisp {
+        type Codec = EchoCodec;
+
+        fn headers_received(&mut self, headers: &Head)
+            -> Result<Self::Codec, Error>
+        {
+            self.heads.push((*headers.connection_info(),
+                             headers.received_at()));
+            Ok(EchoCodec {
+                gate: None,
+                expect_continue: false,
+                reject: false,
+                body: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn received_at() {
+        let timer = ManualTimer::new();
+        let start = timer.now();
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+            InfoDisp { heads: Vec::new() }, timer.clone());
+        timer.advance(Duration::new(5, 0));
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        proto.process().unwrap();
+        timer.advance(Duration::new(1, 0));
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        proto.process().unwrap();
+        let times = proto.dispatcher.heads.iter()
+            .map(|&(ref info, at)| (info.established_at(), at))
+            .collect::<Vec<_>>();
+        assert_eq!(times, vec![
+            (start, start + Duration::new(5, 0)),
+            (start, start + Duration::new(6, 0)),
+        ]);
+    }
+
+    #[test]
+    fn peer_addr() {
+        let peer: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let mock = MockData::new();
+        let mut proto = Proto::with_timer(mock.clone(),
+            &Config::new().done(), InfoDisp { heads: Vec::new() },
+            ManualTimer::new());
+        assert_eq!(proto.connection_info().peer_addr(), None);
+        proto.set_peer_addr(peer);
+        proto.set_local_addr(local);
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        lazy(|| {
+            assert!(proto.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        let addrs = proto.proto.dispatcher.heads.iter()
+            .map(|&(ref info, _)| (info.peer_addr(), info.local_addr()))
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, vec![(Some(peer), Some(local))]);
+        assert_eq!(proto.connection_info().local_addr(), Some(local));
+    }
+
+    #[test]
+    fn max_connection_age() {
+        use std::time::Duration;
+
+        let gate = Arc::new(AtomicBool::new(true));
+        let mock = MockData::new();
+        let mut proto = PureProto::new(mock.clone(),
+            &Config::new().max_connection_age(Duration::new(0, 0)).done(),
+            EchoDisp { gate: gate.clone() }, ManualTimer::new());
+        mock.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n\
+                        GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(!proto.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn load_shedding() {
+        use server::LoadShedding;
+
+        let gate = Arc::new(AtomicBool::new(true));
+        let policy = LoadShedding::connection_limit(1);
+        let cfg = Config::new().load_shedding(&policy).done();
+        let mock1 = MockData::new();
+        let mut proto1 = PureProto::new(mock1.clone(), &cfg,
+            EchoDisp { gate: gate.clone() }, ManualTimer::new());
+        mock1.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(proto1.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock1.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+
+        let mock2 = MockData::new();
+        let mut proto2 = PureProto::new(mock2.clone(), &cfg,
+            EchoDisp { gate: gate.clone() }, ManualTimer::new());
+        assert_eq!(policy.connections(), 2);
+        mock2.add_input("GET / HTTP/1.1\r\nHost: a\r\n\r\n\
+                         GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(!proto2.process().unwrap());
+        assert_eq!(String::from_utf8_lossy(&mock2.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n");
+        drop(proto2);
+        assert_eq!(policy.connections(), 1);
+    }
+
+    #[test]
+    fn explicit_zero_length() {
+        fn check(input: &str) -> (Option<u64>, bool) {
+            let counter = AtomicUsize::new(0);
+            let log = Mutex::new(Vec::new());
+            let mock = MockData::new();
+            let mut proto = PureProto::new(mock.clone(), &Config::new().done(),
+                LengthDisp { counter: &counter, log: &log },
+                ManualTimer::new());
+            mock.add_input(input);
+            proto.process().unwrap();
+            let result = log.lock().unwrap()[0];
+            result
+        }
+        assert_eq!(check("OPTIONS * HTTP/1.1\r\nHost: a\r\n\r\n"),
+                   (Some(0), false));
+        assert_eq!(check("OPTIONS * HTTP/1.1\r\nHost: a\r\n\
+                          Content-Length: 0\r\n\r\n"),
+                   (Some(0), true));
+        assert_eq!(check("POST / HTTP/1.1\r\nHost: a\r\n\
+                          Content-Length: 3\r\n\
+                          Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n"),
+                   (None, false));
+    }
+
+    #[test]
+    fn malformed_pipelined_request() {
+        let gate = Arc::new(AtomicBool::new(true));
+        let mock = MockData::new();
+        let mut proto = echo_proto(&mock, &gate);
+        mock.add_input("GET /1 HTTP/1.1\r\nHost: a\r\n\r\n\
+                        GET /2 HTTP/1.1\r\nHost: a\r\n\r\n\
+                        GET /3 HTTP/1.1\r\nHost: a\r\nBad Header\r\n\r\n");
+        let err = proto.process().unwrap_err();
+        assert_eq!(malformed(&err), "Error(ParseError(HeaderName))");
+        assert_eq!(err.malformed_request().unwrap().0, 2);
+        assert!(err.malformed_request().unwrap().1.starts_with("GET /3 "));
+        assert_eq!(String::from_utf8_lossy(&mock.output(..)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 400 Bad Request\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn headers_timeout() {
+        let counter = AtomicUsize::new(0);
+        let timer = ManualTimer::new();
+        let mock = MockData::new();
+        let cfg = Config::new()
+            .first_byte_timeout(Duration::new(5, 0))
+            .headers_timeout(Duration::new(10, 0))
+            .done();
+        let mut proto = Proto::with_timer(mock.clone(), &cfg,
+            MockDisp { counter: &counter }, timer.clone());
+        let err = lazy(|| {
+            assert!(proto.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(4, 0));
+            mock.add_input("GET / HTTP/1.1\r\n");
+            assert!(proto.poll().unwrap().is_not_ready());
+            // first byte timeout doesn't apply any more
+            timer.advance(Duration::new(9, 0));
+            assert!(proto.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(2, 0));
+            proto.poll()
+        }).wait().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(Timeout)");
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn first_byte_timeout() {
+        let counter = AtomicUsize::new(0);
+        let timer = ManualTimer::new();
+        let cfg = Config::new()
+            .first_byte_timeout(Duration::new(5, 0))
+            .done();
+        let mut proto = Proto::with_timer(MockData::new(), &cfg,
+            MockDisp { counter: &counter }, timer.clone());
+        let err = lazy(|| {
+            assert!(proto.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(4, 0));
+            assert!(proto.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(2, 0));
+            proto.poll()
+        }).wait().unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(Timeout)");
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
     }
 }
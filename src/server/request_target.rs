@@ -1,3 +1,7 @@
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+
+
 /// A middle part of the request line
 ///
 /// Most people get used to having path there or maybe asterisk. But in the
@@ -27,6 +31,42 @@ pub enum RequestTarget<'a> {
     Asterisk,
 }
 
+impl<'a> RequestTarget<'a> {
+    /// Returns path for `Origin` and `Absolute` forms
+    ///
+    /// Same as `Head::path()`, returns `None` for `Authority` and `Asterisk`
+    pub fn path(&self) -> Option<&'a str> {
+        use self::RequestTarget::*;
+        match *self {
+            Origin(x) => Some(x),
+            Absolute { path, .. } => Some(path),
+            Authority(..) => None,
+            Asterisk => None,
+        }
+    }
+    /// Returns path, or `default` if there is no path in request-target
+    ///
+    /// Unlike `path()` this returns `/` for absolute form without a path
+    /// (`http://example.com`). A common default is `"*"` (or
+    /// `Head::raw_request_target()`), so that `OPTIONS *` can be routed
+    /// like any other request.
+    pub fn effective_path_or<'b>(&self, default: &'b str) -> &'b str
+        where 'a: 'b
+    {
+        match *self {
+            RequestTarget::Absolute { path: "", .. } => "/",
+            _ => self.path().unwrap_or(default),
+        }
+    }
+    /// Returns `true` for asterisk form, i.e. `OPTIONS * HTTP/1.1`
+    ///
+    /// Such request is about the server in general rather than about any
+    /// resource. Reply to it with `Allow` header or `404 Not Found`.
+    pub fn is_asterisk(&self) -> bool {
+        matches!(*self, RequestTarget::Asterisk)
+    }
+}
+
 
 // Authority can't contain `/` or `?` or `#`, user and password
 // is not supported in HTTP either (so no `@` but otherwise we accept
@@ -74,10 +114,53 @@ pub fn parse(s: &str) -> Option<RequestTarget> {
     return None;
 }
 
+fn strip_default_port<'a>(scheme: &str, authority: &'a str) -> &'a str {
+    let port = if scheme.eq_ignore_ascii_case("https") { ":443" }
+               else { ":80" };
+    if authority.ends_with(port) {
+        &authority[..authority.len() - port.len()]
+    } else {
+        authority
+    }
+}
+
+/// Converts absolute-form into origin-form if it's the server's identity
+///
+/// Returns `None` if the scheme or authority doesn't match.
+pub fn canonicalize<'a>(target: RequestTarget<'a>,
+    server_scheme: &str, server_authorities: &[String])
+    -> Option<RequestTarget<'a>>
+{
+    use self::RequestTarget::*;
+    match target {
+        Absolute { scheme, authority, path } => {
+            if !scheme.eq_ignore_ascii_case(server_scheme) {
+                return None;
+            }
+            let authority = strip_default_port(scheme, authority);
+            let known = server_authorities.iter().any(|x| {
+                strip_default_port(scheme, x).eq_ignore_ascii_case(authority)
+            });
+            if !known {
+                return None;
+            }
+            if path == "" {
+                Some(Origin("/"))
+            } else if path.starts_with("/") {
+                Some(Origin(path))
+            } else {
+                // query without a path, can't represent it without copying
+                None
+            }
+        }
+        other => Some(other),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::RequestTarget::*;
-    use super::parse;
+    use super::{parse, canonicalize};
 
     #[test]
     fn test_empty() {
@@ -128,4 +211,31 @@ mod test {
                                         path: "/hello?world" }));
     }
 
+    #[test]
+    fn effective_path() {
+        let path = |x| parse(x).unwrap().effective_path_or("*");
+        assert_eq!(path("/hello?x"), "/hello?x");
+        assert_eq!(path("http://x:932/hello"), "/hello");
+        assert_eq!(path("http://x"), "/");
+        assert_eq!(path("*"), "*");
+        assert_eq!(path("example.com:443"), "*");
+        assert!(parse("*").unwrap().is_asterisk());
+        assert!(!parse("/").unwrap().is_asterisk());
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let names = vec!["example.com".to_string(),
+                         "example.org:8080".to_string()];
+        let canon = |x| canonicalize(parse(x).unwrap(), "http", &names);
+        assert_matches!(canon("http://example.com/hello?world"),
+                        Some(Origin("/hello?world")));
+        assert_matches!(canon("http://EXAMPLE.com:80"), Some(Origin("/")));
+        assert_matches!(canon("http://example.org:8080/x"),
+                        Some(Origin("/x")));
+        assert_matches!(canon("/plain"), Some(Origin("/plain")));
+        assert_matches!(canon("http://example.org/x"), None);
+        assert_matches!(canon("https://example.com/x"), None);
+        assert_matches!(canon("http://example.com?x"), None);
+    }
 }
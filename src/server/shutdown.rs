@@ -0,0 +1,53 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::task::AtomicTask;
+
+
+/// A handle to gracefully shut down a server connection
+///
+/// Created by `Proto::shutdown_handle`. When `shutdown` is called, the
+/// connection stops accepting new requests (including the ones which are
+/// pipelined but not parsed yet), finishes the request which body is being
+/// received and responses to all requests received so far. The last
+/// response is sent with `Connection: close` (unless it's already started),
+/// then the `Proto` future resolves as usual when output is flushed.
+///
+/// The handle may be cloned and sent to other threads. Dropping the handle
+/// doesn't shut down the connection.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    requested: AtomicBool,
+    task: AtomicTask,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            inner: Arc::new(Inner {
+                requested: AtomicBool::new(false),
+                task: AtomicTask::new(),
+            }),
+        }
+    }
+    /// Start graceful shutdown of the connection
+    ///
+    /// Calling it more than once is a no-op.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        self.inner.task.notify();
+    }
+    /// Returns `true` if `shutdown` has been called
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+    /// Register current task to be woken up on `shutdown`
+    pub(crate) fn register(&self) {
+        self.inner.task.register();
+    }
+}
@@ -0,0 +1,196 @@
+//! Request body tee: copy request bodies to a secondary consumer
+//!
+//! This is useful for audit logging or for forwarding shadow traffic. The
+//! copy is sent to a bounded channel, so a slow consumer never slows
+//! down the application: when the channel is full, the copy is abandoned
+//! (the stream just ends without `TeeEvent::Done`).
+//!
+//! Only bytes actually consumed by the application codec are copied, so
+//! the copy is exactly the body as the application sees it (i.e. after
+//! chunked decoding).
+//!
+//! ```rust,ignore
+//! let dispatcher = Tee::new(dispatcher, |head: &Head| {
+//!     if head.method() == Method::Post {
+//!         let (tx, rx) = channel(16);
+//!         handle.spawn(audit_log(head.path(), rx));
+//!         Some(tx)
+//!     } else {
+//!         None
+//!     }
+//! });
+//! ```
+use futures::{Async, AsyncSink, Sink};
+use futures::sync::mpsc::Sender;
+use tk_bufstream::{ReadBuf, WriteBuf};
+
+use super::{Dispatcher, Codec, Head, Encoder, Error, RecvMode, BodyInfo};
+
+
+/// An item sent to the tee channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TeeEvent {
+    /// A chunk of the request body
+    Data(Vec<u8>),
+    /// Request body is complete
+    ///
+    /// If channel is closed without this event, the copy is incomplete:
+    /// either channel overflowed or request failed.
+    Done,
+}
+
+/// A dispatcher wrapper that copies request bodies
+///
+/// The function is called for every request and returns a channel where
+/// the body is copied to, or `None` if the request should not be copied.
+pub struct Tee<D, F> {
+    dispatcher: D,
+    select: F,
+}
+
+/// A codec returned by `Tee` dispatcher
+pub struct TeeCodec<C> {
+    codec: C,
+    sender: Option<Sender<TeeEvent>>,
+}
+
+impl<D, F> Tee<D, F> {
+    /// Wrap a dispatcher
+    pub fn new(dispatcher: D, select: F) -> Tee<D, F> {
+        Tee {
+            dispatcher: dispatcher,
+            select: select,
+        }
+    }
+}
+
+impl<S, D, F> Dispatcher<S> for Tee<D, F>
+    where D: Dispatcher<S>,
+          F: FnMut(&Head) -> Option<Sender<TeeEvent>>,
+{
+    type Codec = TeeCodec<D::Codec>;
+    fn headers_received(&mut self, headers: &Head)
+        -> Result<Self::Codec, Error>
+    {
+        let codec = self.dispatcher.headers_received(headers)?;
+        Ok(TeeCodec {
+            codec: codec,
+            sender: (self.select)(headers),
+        })
+    }
+}
+
+impl<C> TeeCodec<C> {
+    fn send(&mut self, event: TeeEvent) {
+        let ok = match self.sender {
+            Some(ref mut sender) => {
+                matches!(sender.start_send(event), Ok(AsyncSink::Ready))
+            }
+            None => return,
+        };
+        if !ok {
+            debug!("Request body tee is full or closed, abandoning copy");
+            self.sender = None;
+        }
+    }
+}
+
+impl<S, C: Codec<S>> Codec<S> for TeeCodec<C> {
+    type ResponseFuture = C::ResponseFuture;
+    fn recv_mode(&mut self) -> RecvMode {
+        self.codec.recv_mode()
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        let result = self.codec.data_received(data, end)?;
+        if let Async::Ready(consumed) = result {
+            if consumed > 0 {
+                self.send(TeeEvent::Data(data[..consumed].to_vec()));
+            }
+            if end && consumed == data.len() {
+                self.send(TeeEvent::Done);
+                self.sender = None;
+            }
+        }
+        Ok(result)
+    }
+    fn body_progress(&mut self, info: &BodyInfo) {
+        self.codec.body_progress(info)
+    }
+    fn raw_body_received(&mut self, data: &[u8]) {
+        self.codec.raw_body_received(data)
+    }
+    fn start_response(&mut self, e: Encoder<S>) -> Self::ResponseFuture {
+        self.codec.start_response(e)
+    }
+    fn hijack(&mut self, output: WriteBuf<S>, input: ReadBuf<S>) {
+        self.sender = None;
+        self.codec.hijack(output, input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Async, Future, Stream, Empty};
+    use futures::future::lazy;
+    use futures::sync::mpsc::channel;
+    use tk_bufstream::MockData;
+
+    use server::{Codec, Encoder, EncoderDone, Error, RecvMode};
+    use super::{TeeCodec, TeeEvent};
+
+    /// Consumes at most 3 bytes at a time
+    struct Slow;
+
+    impl Codec<MockData> for Slow {
+        type ResponseFuture = Empty<EncoderDone<MockData>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::progressive(1)
+        }
+        fn data_received(&mut self, data: &[u8], _end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            Ok(Async::Ready(::std::cmp::min(data.len(), 3)))
+        }
+        fn start_response(&mut self, _e: Encoder<MockData>)
+            -> Self::ResponseFuture
+        {
+            unimplemented!();
+        }
+    }
+
+    #[test]
+    fn copy_consumed() {
+        let (tx, rx) = channel(10);
+        lazy(move || {
+            let mut codec = TeeCodec { codec: Slow, sender: Some(tx) };
+            assert_eq!(codec.data_received(b"hello", false).unwrap(),
+                       Async::Ready(3));
+            assert_eq!(codec.data_received(b"lo", true).unwrap(),
+                       Async::Ready(2));
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        assert_eq!(rx.collect().wait().unwrap(), vec![
+            TeeEvent::Data(b"hel".to_vec()),
+            TeeEvent::Data(b"lo".to_vec()),
+            TeeEvent::Done,
+        ]);
+    }
+
+    #[test]
+    fn overflow() {
+        let (tx, rx) = channel(0);
+        lazy(move || {
+            let mut codec = TeeCodec { codec: Slow, sender: Some(tx) };
+            for _ in 0..5 {
+                codec.data_received(b"abc", false).unwrap();
+            }
+            assert!(codec.sender.is_none());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        let items = rx.collect().wait().unwrap();
+        assert!(items.len() < 5);
+        assert!(!items.contains(&TeeEvent::Done));
+    }
+}
@@ -0,0 +1,115 @@
+use base_serializer::{HeaderError, HeaderLimits, check_header};
+
+
+/// A block of response headers validated and serialized in advance
+///
+/// Build it once at startup and add to responses with
+/// `Encoder::add_static_headers`, which just copies the bytes:
+///
+/// ```rust,ignore
+/// let mut security = ValidatedHeaders::new();
+/// security.add_header("X-Frame-Options", "DENY")?;
+/// security.add_header("X-Content-Type-Options", "nosniff")?;
+/// let security = Arc::new(security);
+/// // in the codec
+/// e.status(Status::Ok);
+/// e.add_static_headers(&security)?;
+/// ```
+///
+/// Validation is as strict as any `Config` can require: names must be
+/// tokens and values must not contain line breaks. Only the value size
+/// limit (`Config::max_header_value_size`) is checked when headers are
+/// added to the response.
+#[derive(Debug, Clone)]
+pub struct ValidatedHeaders {
+    data: Vec<u8>,
+    /// Size and name of the largest value
+    largest: Option<(usize, String)>,
+    date: bool,
+    server: bool,
+}
+
+impl ValidatedHeaders {
+    /// Create an empty block of headers
+    pub fn new() -> ValidatedHeaders {
+        ValidatedHeaders {
+            data: Vec::new(),
+            largest: None,
+            date: false,
+            server: false,
+        }
+    }
+    /// Validate and append a header to the block
+    ///
+    /// Same restrictions as for `Encoder::add_header` apply (i.e.
+    /// `Content-Length` and `Transfer-Encoding` can't be added). Nothing
+    /// is added if an error is returned.
+    pub fn add_header<V: AsRef<[u8]>>(&mut self, name: &str, value: V)
+        -> Result<(), HeaderError>
+    {
+        let value = value.as_ref();
+        check_header(name, value, &HeaderLimits {
+            strict_names: true,
+            max_value_size: None,
+        })?;
+        if self.largest.as_ref().map(|&(n, _)| value.len() > n)
+            .unwrap_or(true)
+        {
+            self.largest = Some((value.len(), name.to_string()));
+        }
+        self.date |= name.eq_ignore_ascii_case("Date");
+        self.server |= name.eq_ignore_ascii_case("Server");
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.extend_from_slice(b": ");
+        self.data.extend_from_slice(value);
+        self.data.extend_from_slice(b"\r\n");
+        Ok(())
+    }
+    /// Returns `true` if no headers are added
+    pub fn is_empty(&self) -> bool {
+        self.data.len() == 0
+    }
+    /// Serialized headers, each one followed by CRLF
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+    /// Checks the value size limit of the response
+    pub(crate) fn check(&self, limits: &HeaderLimits)
+        -> Result<(), HeaderError>
+    {
+        match (&self.largest, limits.max_value_size) {
+            (&Some((size, ref name)), Some(max)) if size > max => {
+                Err(HeaderError::InvalidHeaderValue(name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Returns whether `Date` and `Server` headers are in the block
+    pub(crate) fn has_date_server(&self) -> (bool, bool) {
+        (self.date, self.server)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use base_serializer::HeaderLimits;
+    use super::ValidatedHeaders;
+
+    #[test]
+    fn validate() {
+        let mut headers = ValidatedHeaders::new();
+        assert!(headers.is_empty());
+        headers.add_header("X-A", "1").unwrap();
+        headers.add_header("Server", "tk-http").unwrap();
+        assert!(headers.add_header("X A", "1").is_err());
+        assert!(headers.add_header("X-B", "1\r\nX-C: 2").is_err());
+        assert!(headers.add_header("content-length", "1").is_err());
+        assert_eq!(headers.bytes(), b"X-A: 1\r\nServer: tk-http\r\n");
+        assert_eq!(headers.has_date_server(), (false, true));
+        assert!(headers.check(&HeaderLimits::default()).is_ok());
+        assert_eq!(format!("{:?}", headers.check(&HeaderLimits {
+                strict_names: false,
+                max_value_size: Some(5),
+            })), r#"Err(InvalidHeaderValue("Server"))"#);
+    }
+}
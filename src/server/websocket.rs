@@ -30,17 +30,23 @@ fn bytes_trim(mut x: &[u8]) -> &[u8] {
 }
 
 pub fn get_handshake(req: &Head) -> Result<Option<WebsocketHandshake>, ()> {
-    let conn_upgrade = req.connection_header().map(|x| {
-        x.split(',').any(|tok| tok.trim().eq_ignore_ascii_case("upgrade"))
-    });
-    if !conn_upgrade.unwrap_or(false) {
+    if !req.connection_tokens().any(|tok| tok.eq_ignore_ascii_case("upgrade"))
+    {
+        return Ok(None);
+    }
+    let upgrade = req.all_headers().iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("Upgrade"))
+        .any(|h| h.value.split(|&x| x == b',')
+                 .any(|x| bytes_trim(x).eq_ignore_ascii_case(b"websocket")));
+    if !upgrade {
+        // Upgrade to some other protocol (or no `Upgrade` header at all),
+        // consider this not a websocket
         return Ok(None);
     }
     if req.path().is_none() {
         debug!("Invalid request-target for websocket request");
         return Err(());
     }
-    let mut upgrade = false;
     let mut version = false;
     let mut accept = None;
     let mut protocols = Vec::new();
@@ -75,22 +81,12 @@ pub fn get_handshake(req: &Head) -> Result<Option<WebsocketHandshake>, ()> {
                 .map(|x| x.trim())
                 .filter(|x| x.len() > 0)
                 .map(|x| x.to_string()));
-        } else if h.name.eq_ignore_ascii_case("Upgrade") {
-            if !h.value.eq_ignore_ascii_case(b"websocket") {
-                return Ok(None); // Consider this not a websocket
-            } else {
-                upgrade = true;
-            }
         }
     }
     if req.has_body() {
         debug!("Websocket handshake has payload");
         return Err(());
     }
-    if !upgrade {
-        debug!("No upgrade header for a websocket");
-        return Err(());
-    }
     if !version || accept.is_none() {
         debug!("No required headers for a websocket");
         return Err(());
@@ -0,0 +1,88 @@
+//! Connection and request spans for the `tracing` ecosystem
+//!
+//! Protocol handlers keep a `Span` for each connection and request and
+//! enter it around codec callbacks. Without the `tracing` feature the span
+//! is an empty structure and every method is a no-op.
+//!
+//! Request spans have `method`, `path`, `status` and `bytes` fields, the
+//! latter is the size of the response for servers and the size of the
+//! response body for clients.
+pub use self::imp::Span;
+
+
+#[cfg(feature="tracing")]
+mod imp {
+    use std::net::SocketAddr;
+
+    use tracing::{self, field};
+
+    use ConnectionId;
+
+    #[derive(Debug, Clone)]
+    pub struct Span(tracing::Span);
+
+    impl Span {
+        pub fn none() -> Span {
+            Span(tracing::Span::none())
+        }
+        pub fn connection(side: &'static str, id: ConnectionId) -> Span {
+            Span(::tracing::info_span!("connection",
+                side = side,
+                id = id.as_usize() as u64,
+                peer = field::Empty))
+        }
+        pub fn request(&self) -> Span {
+            Span(::tracing::info_span!(parent: &self.0, "request",
+                method = field::Empty,
+                path = field::Empty,
+                status = field::Empty,
+                bytes = field::Empty))
+        }
+        pub fn record_peer(&self, addr: SocketAddr) {
+            self.0.record("peer", &field::display(addr));
+        }
+        pub fn record_request(&self, method: &str, path: &str) {
+            self.0.record("method", &method);
+            self.0.record("path", &path);
+        }
+        pub fn record_status(&self, status: u16) {
+            self.0.record("status", &(status as u64));
+        }
+        pub fn record_bytes(&self, bytes: u64) {
+            self.0.record("bytes", &bytes);
+        }
+        pub fn in_scope<F: FnOnce() -> R, R>(&self, f: F) -> R {
+            self.0.in_scope(f)
+        }
+    }
+}
+
+#[cfg(not(feature="tracing"))]
+mod imp {
+    use std::net::SocketAddr;
+
+    use ConnectionId;
+
+    #[derive(Debug, Clone)]
+    pub struct Span;
+
+    impl Span {
+        pub fn none() -> Span {
+            Span
+        }
+        pub fn connection(_side: &'static str, _id: ConnectionId) -> Span {
+            Span
+        }
+        pub fn request(&self) -> Span {
+            Span
+        }
+        pub fn record_peer(&self, _addr: SocketAddr) {}
+        pub fn record_request(&self, _method: &str, _path: &str) {}
+        pub fn record_status(&self, _status: u16) {}
+        pub fn record_bytes(&self, _bytes: u64) {}
+        #[inline(always)]
+        pub fn in_scope<F: FnOnce() -> R, R>(&self, f: F) -> R {
+            f()
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::mem::replace;
+use std::rc::Rc;
+
+use futures::{Async, Future, Poll};
+use tk_bufstream::{ReadBuf, WriteBuf};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use server::{BodyInfo, Codec, Config, Dispatcher, Encoder, Error, Head};
+use server::{Proto, RecvMode};
+use timer::ManualTimer;
+
+
+/// An in-memory connection which `run_codec` passes to the protocol
+///
+/// Reading returns the request and then end of stream, everything written
+/// is collected as the response. Codecs that are generic over the stream
+/// type work with it as is.
+pub struct MemoryStream {
+    input: io::Cursor<Vec<u8>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+/// Calls the protocol made to the codec in `run_codec`
+#[derive(Debug, Default)]
+pub struct CodecEvents {
+    recv_mode: Option<RecvMode>,
+    body: Vec<u8>,
+    body_done: bool,
+    response_started: bool,
+    error: Option<Error>,
+}
+
+struct Recorder<C> {
+    codec: C,
+    events: Rc<RefCell<CodecEvents>>,
+}
+
+struct Once<C> {
+    codec: Option<Recorder<C>>,
+}
+
+/// Serve a request from memory using the codec
+///
+/// The whole server pipeline is run: request headers are parsed and
+/// validated, then `recv_mode`, `data_received` and `start_response` of
+/// the codec are called just like on a real connection with the default
+/// `Config`. Returns everything written to the connection (including
+/// `100 Continue` and `400 Bad Request` if protocol sends them) and the
+/// calls the codec has received:
+///
+/// ```rust,ignore
+/// let (response, events) = run_codec(MyCodec::new(),
+///     b"POST /x HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhi");
+/// assert_eq!(events.body(), b"hi");
+/// assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+/// ```
+///
+/// `request` must contain a single request, the connection is closed by
+/// the client right after it. A connection error (e.g. for an incomplete
+/// request) is returned in `CodecEvents::error`.
+///
+/// There is no event loop, so the response future must complete by
+/// itself (or be woken up by another thread), otherwise this function
+/// blocks forever.
+pub fn run_codec<C>(codec: C, request: &[u8]) -> (Vec<u8>, CodecEvents)
+    where C: Codec<MemoryStream>,
+{
+    let events = Rc::new(RefCell::new(CodecEvents::default()));
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let stream = MemoryStream {
+        input: io::Cursor::new(request.to_vec()),
+        output: output.clone(),
+    };
+    let dispatcher = Once {
+        codec: Some(Recorder { codec: codec, events: events.clone() }),
+    };
+    let result = Proto::with_timer(stream, &Config::new().done(), dispatcher,
+                                   ManualTimer::new()).wait();
+    let mut events = replace(&mut *events.borrow_mut(),
+                             CodecEvents::default());
+    events.error = result.err();
+    let output = output.borrow().clone();
+    (output, events)
+}
+
+impl CodecEvents {
+    /// Returns the value `Codec::recv_mode` returned, if it was called
+    pub fn recv_mode(&self) -> Option<RecvMode> {
+        self.recv_mode
+    }
+    /// Returns request body consumed by the codec in `data_received`
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+    /// Returns `true` if the whole request body was passed to the codec
+    pub fn body_done(&self) -> bool {
+        self.body_done
+    }
+    /// Returns `true` if `Codec::start_response` was called
+    pub fn response_started(&self) -> bool {
+        self.response_started
+    }
+    /// Returns the error connection is closed with, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<C: Codec<MemoryStream>> Dispatcher<MemoryStream> for Once<C> {
+    type Codec = Recorder<C>;
+    fn headers_received(&mut self, _headers: &Head)
+        -> Result<Recorder<C>, Error>
+    {
+        self.codec.take().ok_or_else(|| {
+            Error::custom("run_codec only supports a single request")
+        })
+    }
+}
+
+impl<C: Codec<MemoryStream>> Codec<MemoryStream> for Recorder<C> {
+    type ResponseFuture = C::ResponseFuture;
+    fn recv_mode(&mut self) -> RecvMode {
+        let mode = self.codec.recv_mode();
+        self.events.borrow_mut().recv_mode = Some(mode);
+        mode
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        let result = self.codec.data_received(data, end)?;
+        if let Async::Ready(consumed) = result {
+            let mut events = self.events.borrow_mut();
+            events.body.extend_from_slice(&data[..consumed]);
+            events.body_done = end && consumed == data.len();
+        }
+        Ok(result)
+    }
+    fn body_progress(&mut self, info: &BodyInfo) {
+        self.codec.body_progress(info)
+    }
+    fn raw_body_received(&mut self, data: &[u8]) {
+        self.codec.raw_body_received(data)
+    }
+    fn start_response(&mut self, e: Encoder<MemoryStream>)
+        -> Self::ResponseFuture
+    {
+        self.events.borrow_mut().response_started = true;
+        self.codec.start_response(e)
+    }
+    fn hijack(&mut self, output: WriteBuf<MemoryStream>,
+                         input: ReadBuf<MemoryStream>)
+    {
+        self.codec.hijack(output, input)
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl AsyncWrite for MemoryStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Async;
+    use futures::future::{FutureResult, ok};
+
+    use server::{Codec, Encoder, EncoderDone, Error, RecvMode};
+    use Status;
+    use super::{run_codec, MemoryStream};
+
+    struct Echo(Vec<u8>);
+
+    impl Codec<MemoryStream> for Echo {
+        type ResponseFuture = FutureResult<EncoderDone<MemoryStream>, Error>;
+        fn recv_mode(&mut self) -> RecvMode {
+            RecvMode::buffered_upfront(100)
+        }
+        fn data_received(&mut self, data: &[u8], end: bool)
+            -> Result<Async<usize>, Error>
+        {
+            assert!(end);
+            self.0.extend_from_slice(data);
+            Ok(Async::Ready(data.len()))
+        }
+        fn start_response(&mut self, mut e: Encoder<MemoryStream>)
+            -> Self::ResponseFuture
+        {
+            e.status(Status::Ok);
+            e.add_length(self.0.len() as u64).unwrap();
+            e.done_headers().unwrap();
+            e.write_body(&self.0);
+            ok(e.done())
+        }
+    }
+
+    #[test]
+    fn echo() {
+        let (response, events) = run_codec(Echo(Vec::new()),
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello");
+        assert_eq!(String::from_utf8_lossy(&response),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        assert_eq!(events.recv_mode(), Some(RecvMode::buffered_upfront(100)));
+        assert_eq!(events.body(), b"hello");
+        assert!(events.body_done());
+        assert!(events.response_started());
+        assert!(events.error().is_none());
+    }
+
+    #[test]
+    fn incomplete() {
+        let (response, events) = run_codec(Echo(Vec::new()),
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhel");
+        assert_eq!(response.len(), 0);
+        assert!(!events.body_done());
+        assert!(!events.response_started());
+        assert_eq!(format!("{:?}", events.error().unwrap()),
+                   "Error(ConnectionReset)");
+    }
+}
@@ -0,0 +1,359 @@
+//! Fault injection for testing applications against bad networks
+//!
+//! This module is enabled by the `testing` feature and is not meant to be
+//! used in production. It lets you check how your code (retries, connection
+//! pools, timeouts) behaves when the network is slow or connections break
+//! in the middle of the message, without any external tools.
+//!
+//! Faults are described by the `FaultInjector` trait. There are two
+//! places to plug it into, the same for server and client:
+//!
+//! 1. Wrap the socket into a `FaultyStream` before passing it to `Proto`,
+//!    this adds delays, short writes and premature connection close.
+//! 2. Pass the injector to `Config::inject_faults`, this accelerates
+//!    timeouts so that timeout handling can be tested quickly.
+//!
+//! ```rust,ignore
+//! let faults = RandomFaults::new()
+//!     .delays(0.1, Duration::from_millis(200))
+//!     .short_writes(0.5)
+//!     .close_after(100, 10000)
+//!     .accelerate_timeouts(10)
+//!     .done();
+//! let cfg = client::Config::new().inject_faults(&*faults).done();
+//! let sock = FaultyStream::new(sock, faults.clone(), &handle);
+//! let proto = client::Proto::new(sock, &handle, &cfg);
+//! ```
+//!
+//! With the `server` feature there is also `run_codec`, which serves
+//! a request from memory, so server codecs can be unit-tested with the
+//! real protocol implementation and without an event loop.
+#[cfg(feature="server")] mod codec;
+#[cfg(feature="server")]
+pub use self::codec::{run_codec, CodecEvents, MemoryStream};
+
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Async, Poll};
+use rand::{Rng, thread_rng};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+
+/// A source of faults injected into the connection
+///
+/// All methods have defaults which don't inject anything, so you only
+/// need to implement the ones you're interested in. Methods are called
+/// from the event loop, so they should be fast.
+pub trait FaultInjector: Send + Sync {
+    /// Delay before the next chunk of data is written to the socket
+    ///
+    /// Called each time `FaultyStream` is about to write data (after the
+    /// previous delay has expired).
+    fn write_delay(&self) -> Option<Duration> {
+        None
+    }
+    /// Number of bytes accepted by a single write of `len` bytes
+    ///
+    /// Returning less than `len` emulates a short write (a full kernel
+    /// buffer). Zero is treated as one, so progress is always made.
+    fn write_limit(&self, len: usize) -> usize {
+        len
+    }
+    /// Close connection after this number of bytes is written
+    ///
+    /// Called once when `FaultyStream` is created. When the limit is
+    /// reached, the write side of the socket is shut down (peer sees
+    /// an early FIN) and further writes fail with `BrokenPipe`.
+    fn close_after(&self) -> Option<u64> {
+        None
+    }
+    /// Timeout that should be used instead of `value`
+    ///
+    /// Called by `Config::inject_faults` for each timeout in the config.
+    fn timeout(&self, value: Duration) -> Duration {
+        value
+    }
+}
+
+/// A fault injector which makes random decisions
+///
+/// Created with no faults enabled, use builder methods to add them.
+#[derive(Debug, Clone)]
+pub struct RandomFaults {
+    delay: Option<(f64, Duration)>,
+    short_writes: f64,
+    close_after: Option<(u64, u64)>,
+    timeout_divisor: u32,
+}
+
+/// A stream wrapper which injects faults into the writes
+///
+/// Reads are passed through, except that nothing is read after the
+/// connection was closed by injector.
+pub struct FaultyStream<S> {
+    stream: S,
+    injector: Arc<FaultInjector>,
+    handle: Handle,
+    delay: Option<Timeout>,
+    delay_passed: bool,
+    close_after: Option<u64>,
+    closed: bool,
+}
+
+impl RandomFaults {
+    /// Create an injector with no faults
+    pub fn new() -> RandomFaults {
+        RandomFaults {
+            delay: None,
+            short_writes: 0.,
+            close_after: None,
+            timeout_divisor: 1,
+        }
+    }
+    /// Delay writes with `probability` for a random time up to `max`
+    pub fn delays(&mut self, probability: f64, max: Duration) -> &mut Self {
+        self.delay = Some((probability, max));
+        self
+    }
+    /// Make a write shorter (random length) with `probability`
+    pub fn short_writes(&mut self, probability: f64) -> &mut Self {
+        self.short_writes = probability;
+        self
+    }
+    /// Close every connection after random number of bytes in the range
+    pub fn close_after(&mut self, min: u64, max: u64) -> &mut Self {
+        assert!(min <= max);
+        self.close_after = Some((min, max));
+        self
+    }
+    /// Divide all timeouts by `divisor`
+    pub fn accelerate_timeouts(&mut self, divisor: u32) -> &mut Self {
+        assert!(divisor > 0);
+        self.timeout_divisor = divisor;
+        self
+    }
+    /// Create a Arc'd clone to pass to the stream and config
+    pub fn done(&mut self) -> Arc<RandomFaults> {
+        Arc::new(self.clone())
+    }
+}
+
+impl FaultInjector for RandomFaults {
+    fn write_delay(&self) -> Option<Duration> {
+        let (probability, max) = match self.delay {
+            Some(pair) => pair,
+            None => return None,
+        };
+        let mut rng = thread_rng();
+        if rng.gen::<f64>() >= probability {
+            return None;
+        }
+        let max_ms = max.as_secs()*1000 + (max.subsec_nanos()/1000000) as u64;
+        Some(Duration::from_millis(rng.gen_range(0, max_ms + 1)))
+    }
+    fn write_limit(&self, len: usize) -> usize {
+        let mut rng = thread_rng();
+        if len > 1 && rng.gen::<f64>() < self.short_writes {
+            rng.gen_range(1, len)
+        } else {
+            len
+        }
+    }
+    fn close_after(&self) -> Option<u64> {
+        self.close_after.map(|(min, max)| {
+            thread_rng().gen_range(min, max + 1)
+        })
+    }
+    fn timeout(&self, value: Duration) -> Duration {
+        value / self.timeout_divisor
+    }
+}
+
+impl<S> FaultyStream<S> {
+    /// Wrap a stream, handle is used for delays
+    pub fn new(stream: S, injector: Arc<FaultInjector>, handle: &Handle)
+        -> FaultyStream<S>
+    {
+        FaultyStream {
+            stream: stream,
+            close_after: injector.close_after(),
+            injector: injector,
+            handle: handle.clone(),
+            delay: None,
+            delay_passed: false,
+            closed: false,
+        }
+    }
+    /// Returns true if connection was closed by the injector
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+    /// Returns the original stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+    fn poll_delay(&mut self) -> io::Result<bool> {
+        if self.delay_passed {
+            return Ok(true);
+        }
+        if self.delay.is_none() {
+            match self.injector.write_delay() {
+                Some(dur) => {
+                    self.delay = Some(Timeout::new(dur, &self.handle)?);
+                }
+                None => {
+                    self.delay_passed = true;
+                    return Ok(true);
+                }
+            }
+        }
+        match self.delay.as_mut().unwrap().poll()? {
+            Async::Ready(()) => {
+                self.delay = None;
+                self.delay_passed = true;
+                Ok(true)
+            }
+            Async::NotReady => Ok(false),
+        }
+    }
+}
+
+impl<S: AsyncWrite> FaultyStream<S> {
+    fn close(&mut self) -> io::Error {
+        if !self.closed {
+            debug!("Fault injected: closing connection");
+            self.closed = true;
+            // best effort, the socket is broken for us anyway
+            self.stream.shutdown().ok();
+        }
+        io::ErrorKind::BrokenPipe.into()
+    }
+}
+
+impl<S: Read> Read for FaultyStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.closed {
+            return Ok(0);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl<S: AsyncWrite> Write for FaultyStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed || self.close_after == Some(0) {
+            return Err(self.close());
+        }
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+        if !self.poll_delay()? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let mut limit = min(self.injector.write_limit(buf.len()), buf.len());
+        if limit == 0 {
+            limit = 1;
+        }
+        if let Some(left) = self.close_after {
+            limit = min(limit as u64, left) as usize;
+        }
+        let bytes = self.stream.write(&buf[..limit])?;
+        self.delay_passed = false;
+        if let Some(ref mut left) = self.close_after {
+            *left -= bytes as u64;
+        }
+        Ok(bytes)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for FaultyStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for FaultyStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if self.closed {
+            return Ok(Async::Ready(()));
+        }
+        self.stream.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use futures::future::poll_fn;
+    use futures::Async;
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+    use super::{FaultInjector, FaultyStream, RandomFaults};
+
+    struct Faults;
+
+    impl FaultInjector for Faults {
+        fn write_delay(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+        fn write_limit(&self, _len: usize) -> usize {
+            3
+        }
+        fn close_after(&self) -> Option<u64> {
+            Some(5)
+        }
+    }
+
+    #[test]
+    fn short_writes_and_close() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let mut stream = FaultyStream::new(mock.clone(), Arc::new(Faults),
+                                           &core.handle());
+        let start = Instant::now();
+        let mut written = Vec::new();
+        let err = core.run(poll_fn(|| {
+            loop {
+                let offset = written.iter().sum::<usize>();
+                match stream.write(&b"hello world"[offset..]) {
+                    Ok(n) => written.push(n),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err::<Async<()>, _>(e),
+                }
+            }
+        })).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(written, vec![3, 2]);
+        assert_eq!(mock.output(..), b"hello");
+        assert!(stream.is_closed());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn random_faults() {
+        let faults = RandomFaults::new()
+            .short_writes(1.)
+            .close_after(10, 10)
+            .accelerate_timeouts(10)
+            .done();
+        assert_eq!(faults.write_delay(), None);
+        let limit = faults.write_limit(100);
+        assert!(limit >= 1 && limit < 100);
+        assert_eq!(faults.write_limit(1), 1);
+        assert_eq!(faults.close_after(), Some(10));
+        assert_eq!(faults.timeout(Duration::new(5, 0)),
+                   Duration::from_millis(500));
+        assert_eq!(RandomFaults::new().close_after(), None);
+    }
+}
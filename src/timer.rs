@@ -0,0 +1,154 @@
+//! Timers used by protocol implementations
+//!
+//! All timeouts of server and client `Proto` and websocket `Loop` are
+//! created by a `Timer`. By default it's `TokioTimer` which uses real time
+//! and the event loop. `ManualTimer` is a clock which only moves when you
+//! advance it, so timeouts can be tested without waiting:
+//!
+//! ```rust,ignore
+//! let timer = ManualTimer::new();
+//! let mut proto = Proto::with_timer(sock, &cfg, disp, timer.clone());
+//! assert!(proto.poll().unwrap().is_not_ready());
+//! timer.advance(Duration::new(90, 0));
+//! assert!(proto.poll().is_err());  // keep-alive timeout
+//! ```
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Async, Poll};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+
+
+/// A source of current time and timeouts
+pub trait Timer: Clone {
+    /// A future which resolves when deadline is reached
+    type Timeout: Future<Item=(), Error=io::Error>;
+    /// Current time
+    fn now(&self) -> Instant;
+    /// Create a timeout which resolves at `deadline`
+    fn timeout_at(&self, deadline: Instant) -> Self::Timeout;
+}
+
+/// Timer which uses real time and `tokio_core` timeouts
+#[derive(Clone)]
+pub struct TokioTimer {
+    handle: Handle,
+}
+
+/// Timer which only moves forward by calling `advance()`
+///
+/// Clones share the same clock. Timeouts resolve when they are polled
+/// after the clock has reached their deadline, tasks waiting for
+/// timeouts are woken up on every `advance()`.
+#[derive(Clone)]
+pub struct ManualTimer {
+    clock: Arc<Mutex<Clock>>,
+}
+
+/// Timeout of the `ManualTimer`
+pub struct ManualTimeout {
+    clock: Arc<Mutex<Clock>>,
+    deadline: Instant,
+}
+
+struct Clock {
+    now: Instant,
+    waiting: Vec<Task>,
+}
+
+impl TokioTimer {
+    /// Create a timer running on the specified event loop
+    pub fn new(handle: &Handle) -> TokioTimer {
+        TokioTimer {
+            handle: handle.clone(),
+        }
+    }
+}
+
+impl Timer for TokioTimer {
+    type Timeout = Timeout;
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn timeout_at(&self, deadline: Instant) -> Timeout {
+        Timeout::new_at(deadline, &self.handle)
+            .expect("can always add a timeout")
+    }
+}
+
+impl ManualTimer {
+    /// Create a clock starting at the current time
+    pub fn new() -> ManualTimer {
+        ManualTimer {
+            clock: Arc::new(Mutex::new(Clock {
+                now: Instant::now(),
+                waiting: Vec::new(),
+            })),
+        }
+    }
+    /// Move clock forward and wake up tasks waiting for timeouts
+    pub fn advance(&self, duration: Duration) {
+        let waiting = {
+            let mut clock = self.clock.lock().expect("clock is not poisoned");
+            clock.now += duration;
+            clock.waiting.drain(..).collect::<Vec<_>>()
+        };
+        for task in waiting {
+            task.notify();
+        }
+    }
+}
+
+impl Timer for ManualTimer {
+    type Timeout = ManualTimeout;
+    fn now(&self) -> Instant {
+        self.clock.lock().expect("clock is not poisoned").now
+    }
+    fn timeout_at(&self, deadline: Instant) -> ManualTimeout {
+        ManualTimeout {
+            clock: self.clock.clone(),
+            deadline: deadline,
+        }
+    }
+}
+
+impl Future for ManualTimeout {
+    type Item = ();
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut clock = self.clock.lock().expect("clock is not poisoned");
+        if clock.now >= self.deadline {
+            Ok(Async::Ready(()))
+        } else {
+            clock.waiting.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::Async;
+    use futures::future::{Future, lazy};
+    use super::{Timer, ManualTimer};
+
+    #[test]
+    fn manual() {
+        let timer = ManualTimer::new();
+        let start = timer.now();
+        let mut timeout = timer.timeout_at(start + Duration::new(10, 0));
+        lazy(|| {
+            assert_eq!(timeout.poll().unwrap(), Async::NotReady);
+            timer.advance(Duration::new(9, 0));
+            assert_eq!(timeout.poll().unwrap(), Async::NotReady);
+            timer.clone().advance(Duration::new(1, 0));
+            assert_eq!(timeout.poll().unwrap(), Async::Ready(()));
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        assert_eq!(timer.now() - start, Duration::new(10, 0));
+    }
+}
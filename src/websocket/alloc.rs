@@ -1,4 +1,6 @@
-use websocket::zero_copy::Frame;
+use tk_bufstream::Buf;
+
+use websocket::zero_copy::{Frame, write_fragment};
 
 /// A websocket packet
 ///
@@ -18,6 +20,19 @@ pub enum Packet {
     Close(u16, String),
 }
 
+/// A part of a binary message sent in multiple frames
+///
+/// Allows sending a large message without building it in memory. Parts
+/// are sent to the sink returned by `Loop::chunked_sink`, the last part
+/// of the message has `fin` set, and the next part starts a new message.
+#[derive(Debug, Clone)]
+pub struct BinaryChunk {
+    /// Payload of the part
+    pub data: Vec<u8>,
+    /// This is the last part of the message
+    pub fin: bool,
+}
+
 impl<'a> From<&'a Packet> for Frame<'a> {
     fn from(pkt: &'a Packet) -> Frame<'a> {
         use websocket::zero_copy::Frame as F;
@@ -31,3 +46,31 @@ impl<'a> From<&'a Packet> for Frame<'a> {
         }
     }
 }
+
+impl BinaryChunk {
+    /// Write a part into specified buffer as a frame
+    ///
+    /// The `first` part is a binary frame, others are continuation frames.
+    pub(crate) fn write(&self, buf: &mut Buf, first: bool, masked: bool) {
+        let opcode = if first { 0x2 } else { 0x0 /* continuation */ };
+        write_fragment(buf, opcode, &self.data, self.fin, masked)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tk_bufstream::Buf;
+    use super::BinaryChunk;
+
+    #[test]
+    fn write_chunks() {
+        let mut buf = Buf::new();
+        BinaryChunk { data: b"ab".to_vec(), fin: false }
+            .write(&mut buf, true, false);
+        BinaryChunk { data: b"c".to_vec(), fin: false }
+            .write(&mut buf, false, false);
+        BinaryChunk { data: Vec::new(), fin: true }
+            .write(&mut buf, false, false);
+        assert_eq!(&buf[..], b"\x02\x02ab\x00\x01c\x80\x00");
+    }
+}
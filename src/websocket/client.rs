@@ -3,13 +3,15 @@
 #[allow(unused_imports)]
 use std::ascii::AsciiExt;
 use std::fmt::Display;
+use std::time::Duration;
 
 use futures::{Future, Async};
 use httparse::{self, Header};
 use tk_bufstream::{IoBuf, ReadBuf, WriteBuf, WriteFramed, ReadFramed};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use base_serializer::{MessageState, HeaderError};
+use base_serializer::{MessageState, HeaderError, HeaderLimits};
 // TODO(tailhook) change the error
 use websocket::{Error};
 use websocket::error::ErrorEnum;
@@ -22,6 +24,8 @@ use websocket::{ClientCodec, Key};
 const MIN_HEADERS: usize = 16;
 /// A hard limit on the number of headers
 const MAX_HEADERS: usize = 1024;
+/// Default limit on the size of response headers
+const MAX_HEAD_SIZE: usize = 65536;
 
 /// This a request writer that you receive in `Codec`
 ///
@@ -83,6 +87,8 @@ pub struct HandshakeProto<S, A> {
     input: Option<ReadBuf<S>>,
     output: Option<WriteBuf<S>>,
     authorizer: A,
+    timeout: Option<Timeout>,
+    max_head_size: usize,
 }
 
 /// Default handshake handler, if you just want to get websocket connected
@@ -172,7 +178,8 @@ impl<S> Encoder<S> {
         -> Result<(), HeaderError>
     {
         check_header(name);
-        self.message.add_header(&mut self.buf.out_buf, name, value.as_ref())
+        self.message.add_header(&mut self.buf.out_buf, name, value.as_ref(),
+                                &HeaderLimits::default())
     }
 
     /// Same as `add_header` but allows value to be formatted directly into
@@ -184,7 +191,8 @@ impl<S> Encoder<S> {
         -> Result<(), HeaderError>
     {
         check_header(name);
-        self.message.format_header(&mut self.buf.out_buf, name, value)
+        self.message.format_header(&mut self.buf.out_buf, name, value,
+                                   &HeaderLimits::default())
     }
     /// Finish writing headers and return `EncoderDone` which can be moved to
     ///
@@ -192,15 +200,16 @@ impl<S> Encoder<S> {
     ///
     /// Panics when the request is in a wrong state.
     pub fn done(mut self) -> EncoderDone<S> {
+        let limits = HeaderLimits::default();
         self.message.add_header(&mut self.buf.out_buf,
-            "Connection", b"upgrade").unwrap();
+            "Connection", b"upgrade", &limits).unwrap();
         self.message.add_header(&mut self.buf.out_buf,
-            "Upgrade", b"websocket").unwrap();
+            "Upgrade", b"websocket", &limits).unwrap();
         // TODO(tailhook) generate real random key
         self.message.format_header(&mut self.buf.out_buf,
-            "Sec-WebSocket-Key", Key::new()).unwrap();
+            "Sec-WebSocket-Key", Key::new(), &limits).unwrap();
         self.message.add_header(&mut self.buf.out_buf,
-            "Sec-WebSocket-Version", b"13").unwrap();
+            "Sec-WebSocket-Version", b"13", &limits).unwrap();
         self.message.done_headers(&mut self.buf.out_buf)
             .map(|ignore_body| assert!(ignore_body)).unwrap();
         self.message.done(&mut self.buf.out_buf);
@@ -226,8 +235,28 @@ impl<S, A: Authorizer<S>> HandshakeProto<S, A> {
             authorizer: authorizer,
             input: Some(rx),
             output: Some(out),
+            timeout: None,
+            max_head_size: MAX_HEAD_SIZE,
         }
     }
+    /// Fail with `HandshakeTimeout` if handshake isn't complete in `dur`
+    ///
+    /// By default there is no timeout, so if server accepts connection
+    /// but never responds, future waits forever. The timer starts when
+    /// this method is called.
+    pub fn timeout(mut self, dur: Duration, handle: &Handle) -> Self {
+        self.timeout = Some(Timeout::new(dur, handle)
+            .expect("can always set timeout"));
+        self
+    }
+    /// Maximum size of response headers, default is 64KiB
+    ///
+    /// If there are more bytes received without the end of headers,
+    /// future fails with `HeadersTooLong`.
+    pub fn max_head_size(mut self, bytes: usize) -> Self {
+        self.max_head_size = bytes;
+        self
+    }
     fn parse_headers(&mut self) -> Result<Option<A::Result>, Error> {
         let ref mut buf = self.input.as_mut()
             .expect("buffer still exists")
@@ -245,13 +274,16 @@ impl<S, A: Authorizer<S>> HandshakeProto<S, A> {
                 }
                 match result.map_err(ErrorEnum::HeaderError)? {
                     httparse::Status::Complete(bytes) => {
-                        let ver = raw.version.unwrap();
-                        if ver != 1 {
-                            //return Error::VersionTooOld;
-                            unimplemented!();
-                        }
-                        let code = raw.code.unwrap();
-                        (code, raw.reason.unwrap(), raw.headers, bytes)
+                        let code = match (raw.version, raw.code) {
+                            (Some(1), Some(code)) => code,
+                            // websockets require HTTP/1.1
+                            _ => return Err(ErrorEnum::HeaderError(
+                                httparse::Error::Version).into()),
+                        };
+                        (code, raw.reason.unwrap_or(""), raw.headers, bytes)
+                    }
+                    _ if buf.len() > self.max_head_size => {
+                        return Err(ErrorEnum::HeadersTooLong.into());
                     }
                     _ => return Ok(None),
                 }
@@ -295,7 +327,16 @@ impl<S, A> Future for HandshakeProto<S, A>
                     .framed(ClientCodec);
                 Ok(Async::Ready((out, inp, x)))
             }
-            None => Ok(Async::NotReady),
+            None => {
+                if let Some(ref mut timeout) = self.timeout {
+                    if timeout.poll().map_err(|_| ErrorEnum::Timeout)?
+                        .is_ready()
+                    {
+                        return Err(ErrorEnum::HandshakeTimeout.into());
+                    }
+                }
+                Ok(Async::NotReady)
+            }
         }
     }
 }
@@ -325,3 +366,50 @@ impl<'a> Head<'a> {
         self.headers
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::Future;
+    use tk_bufstream::MockData;
+    use tokio_core::reactor::Core;
+
+    use super::{HandshakeProto, SimpleAuthorizer};
+
+    #[test]
+    fn timeout() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let proto = HandshakeProto::new(mock.clone(),
+                SimpleAuthorizer::new("example.com", "/"))
+            .timeout(Duration::from_millis(10), &core.handle());
+        let err = core.run(proto.map(|_| ())).unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(HandshakeTimeout)");
+        assert!(mock.output(..).starts_with(b"GET / HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn head_too_long() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        mock.add_input("HTTP/1.1 101 Switching Protocols\r\nX-Junk: ");
+        mock.add_input(vec![b'x'; 200]);
+        let proto = HandshakeProto::new(mock.clone(),
+                SimpleAuthorizer::new("example.com", "/"))
+            .max_head_size(100);
+        let err = core.run(proto.map(|_| ())).unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(HeadersTooLong)");
+    }
+
+    #[test]
+    fn http10_response() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        mock.add_input("HTTP/1.0 101 Switching Protocols\r\n\r\n");
+        let proto = HandshakeProto::new(mock.clone(),
+                SimpleAuthorizer::new("example.com", "/"));
+        let err = core.run(proto.map(|_| ())).unwrap_err();
+        assert_eq!(format!("{:?}", err), "Error(HeaderError(Version))");
+    }
+}
@@ -1,7 +1,7 @@
 use std::time::Duration;
 use std::sync::Arc;
 
-use websocket::{Config};
+use websocket::{Config, BacklogPolicy, Utf8Policy};
 
 impl Config {
     /// Create a config with defaults
@@ -10,7 +10,15 @@ impl Config {
             ping_interval: Duration::new(10, 0),
             message_timeout: Duration::new(30, 0),
             byte_timeout: Duration::new(30, 0),
-            max_packet_size: 10 << 20,
+            max_text_size: 10 << 20,
+            max_binary_size: 10 << 20,
+            max_output_backlog: None,
+            backlog_policy: BacklogPolicy::Close(1013),
+            max_pending_pings: 32,
+            tick_interval: None,
+            accept_unmasked_frames: false,
+            reject_masked_frames: true,
+            invalid_utf8: Utf8Policy::Fail,
         }
     }
     /// Set ping interval
@@ -91,8 +99,113 @@ impl Config {
     ///
     /// If some frame declares size larger than this, we immediately abort
     /// the connection
+    ///
+    /// This sets both `max_text_size` and `max_binary_size`. Control frames
+    /// (ping, pong, close) are limited to 125 bytes by the protocol
+    /// regardless of this setting.
     pub fn max_packet_size(&mut self, size: usize) -> &mut Self {
-        self.max_packet_size = size;
+        self.max_text_size = size;
+        self.max_binary_size = size;
+        self
+    }
+
+    /// Maximum size of a text message
+    ///
+    /// Default is 10 MiB. If a text frame declares size larger than this,
+    /// we immediately abort the connection
+    pub fn max_text_size(&mut self, size: usize) -> &mut Self {
+        self.max_text_size = size;
+        self
+    }
+
+    /// Maximum size of a binary message
+    ///
+    /// Default is 10 MiB. If a binary frame declares size larger than this,
+    /// we immediately abort the connection
+    pub fn max_binary_size(&mut self, size: usize) -> &mut Self {
+        self.max_binary_size = size;
+        self
+    }
+
+    /// Maximum number of bytes buffered for sending to the peer
+    ///
+    /// By default output is unlimited: everything read from the stream
+    /// of packets is buffered until peer reads it. When a peer is slower
+    /// than the stream (and there is no backpressure on the stream) buffer
+    /// grows indefinitely. With this limit set, the `backlog_policy` is
+    /// applied when the buffer exceeds the limit.
+    ///
+    /// Note: a single message larger than the limit is still sent as is.
+    pub fn max_output_backlog(&mut self, bytes: usize) -> &mut Self {
+        self.max_output_backlog = Some(bytes);
+        self
+    }
+
+    /// What to do when `max_output_backlog` is exceeded
+    ///
+    /// Default is `BacklogPolicy::Close(1013)` (Try Again Later).
+    /// Use `DropOldest` for lossy feeds where only recent messages matter.
+    pub fn backlog_policy(&mut self, policy: BacklogPolicy) -> &mut Self {
+        self.backlog_policy = policy;
+        self
+    }
+
+    /// Maximum number of pings received while our output is not flushed
+    ///
+    /// Default is 32. Replies to pings are coalesced: only a single pong
+    /// carrying the payload of the latest ping is queued, and it isn't
+    /// queued while output buffer exceeds `max_output_backlog`. Still
+    /// a peer which floods us with pings and doesn't read replies is
+    /// abusive, so when more than this number of pings is received since
+    /// the last pong was written to the output buffer (or since output
+    /// was flushed), the connection is closed with `1008` (Policy
+    /// Violation).
+    pub fn max_pending_pings(&mut self, num: usize) -> &mut Self {
+        self.max_pending_pings = num;
+        self
+    }
+
+    /// Call `Dispatcher::tick` with this interval
+    ///
+    /// By default tick is never called. The interval is counted from the
+    /// previous tick regardless of the traffic, and ticks stop when
+    /// closing handshake is started.
+    pub fn tick_interval(&mut self, dur: Duration) -> &mut Self {
+        self.tick_interval = Some(dur);
+        self
+    }
+
+    /// Accept unmasked frames from clients (server-side only)
+    ///
+    /// The protocol requires clients to mask every frame, so by default
+    /// an unmasked frame closes the connection with `Unmasked` error. Some
+    /// non-browser clients don't mask frames, enable this only for trusted
+    /// peers (e.g. internal services), as masking protects intermediate
+    /// proxies from cache poisoning.
+    pub fn accept_unmasked_frames(&mut self, value: bool) -> &mut Self {
+        self.accept_unmasked_frames = value;
+        self
+    }
+
+    /// Reject masked frames from servers (client-side only)
+    ///
+    /// Servers must not mask frames, so by default a masked frame closes
+    /// the connection with `Masked` error as the protocol requires.
+    /// Disable to tolerate misbehaving servers.
+    pub fn reject_masked_frames(&mut self, value: bool) -> &mut Self {
+        self.reject_masked_frames = value;
+        self
+    }
+
+    /// What to do with text frames which aren't valid utf-8
+    ///
+    /// Default is `Utf8Policy::Fail` which closes the connection with
+    /// `InvalidUtf8` error as the protocol requires. Other policies are
+    /// useful for peers known to send mangled text (e.g. truncated in the
+    /// middle of a multibyte character) which you'd rather not disconnect.
+    /// Close reason is validated regardless of this setting.
+    pub fn invalid_utf8(&mut self, policy: Utf8Policy) -> &mut Self {
+        self.invalid_utf8 = policy;
         self
     }
 
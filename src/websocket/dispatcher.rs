@@ -1,19 +1,28 @@
 use std::cmp::min;
+use std::collections::VecDeque;
 use std::fmt;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, Duration};
 
-use futures::{Future, Async, Stream};
+use futures::{Future, Async, Stream, Poll};
 use futures::future::{FutureResult, ok};
 use futures::stream;
+use futures::sync::mpsc;
+use bytes::BytesMut;
 use tk_bufstream::{ReadFramed, WriteFramed, ReadBuf, WriteBuf};
 use tk_bufstream::{Encode};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_core::reactor::{Handle, Timeout};
+use tokio_core::reactor::Handle;
+use netbuf::Buf;
 
 use websocket::{Frame, Config, Packet, Error, ServerCodec, ClientCodec};
+use websocket::{BinaryChunk, Membership};
+use websocket::{Message};
 use websocket::error::ErrorEnum;
-use websocket::zero_copy::{write_packet, write_close};
+use websocket::message;
+use websocket::zero_copy::{write_packet, write_close, parse_header};
+use websocket::zero_copy::{apply_mask};
+use timer::{Timer, TokioTimer};
 
 
 /// Dispatches messages received from websocket
@@ -25,6 +34,68 @@ pub trait Dispatcher {
     /// If backpressure is desired, method may return a future other than
     /// `futures::FutureResult`.
     fn frame(&mut self, frame: &Frame) -> Self::Future;
+    /// Returns a buffer to receive payload of a text or binary message
+    ///
+    /// By default (`None`) messages are passed to `frame()` borrowed from
+    /// the input buffer of the connection. If a buffer is returned, the
+    /// loop clears it, reserves `len` bytes, copies the payload there and
+    /// passes it to `message()` instead. Payload is not copied after that,
+    /// so this is useful to forward or keep large messages.
+    ///
+    /// The payload can't be split off without copying because the input
+    /// buffer of the connection is a `netbuf::Buf` which is reused for the
+    /// next frames, so exactly one copy is made here.
+    ///
+    /// Memory of the buffer is reused if it has enough capacity and isn't
+    /// shared with other `Bytes` or `BytesMut` objects. For example a pool
+    /// of buffers can be refilled with `Message::into_bytes().try_mut()`
+    /// when the message is processed. Note that parts of the buffer split
+    /// off earlier keep the allocation alive too.
+    fn payload_buffer(&mut self, _len: usize) -> Option<BytesMut> {
+        None
+    }
+    /// A text or binary message received into a `payload_buffer()`
+    ///
+    /// Default implementation passes message to `frame()`.
+    fn message(&mut self, message: Message) -> Self::Future {
+        self.frame(&message.as_frame())
+    }
+    /// Returns `true` to receive a binary message in chunks
+    ///
+    /// Called when a header of a binary frame is received, `len` is the
+    /// size of the frame (of the first one if the message is fragmented).
+    /// If `true` is returned, the payload is passed to `frame_chunk()` as
+    /// soon as it's read from the network, so messages of any size can be
+    /// received without buffering. `Config::max_binary_size` isn't
+    /// checked for such messages, and they may be fragmented.
+    ///
+    /// Default implementation returns `false`.
+    fn stream_binary(&mut self, _len: u64) -> bool {
+        false
+    }
+    /// A part of a binary message for which `stream_binary()` returned
+    /// `true`
+    ///
+    /// `fin` is `true` for the last part. Parts may be of any size
+    /// including zero, and control frames (e.g. close) may be received in
+    /// between.
+    fn frame_chunk(&mut self, _data: &[u8], _fin: bool) -> Self::Future {
+        panic!("`Dispatcher::stream_binary` returned `true` but \
+            no frame_chunk() method implemented");
+    }
+    /// Called every `Config::tick_interval` while connection is open
+    ///
+    /// This is useful for application-level keepalives (like `ka`
+    /// messages of GraphQL over websockets) and other periodic tasks
+    /// without running a separate timer alongside the loop. Returned
+    /// packet (if any) is sent to the peer like packets of the stream,
+    /// i.e. it's subject to `Config::max_output_backlog`. The tick is
+    /// skipped while a message from `Loop::chunked_sink` is being sent.
+    ///
+    /// Default implementation does nothing.
+    fn tick(&mut self) -> Option<Packet> {
+        None
+    }
 }
 
 
@@ -35,7 +106,7 @@ pub trait Dispatcher {
 /// output messages to from external futures.
 ///
 /// Also Loop object answers pings by itself and pings idle connections.
-pub struct Loop<S, T, D: Dispatcher> {
+pub struct Loop<S, T, D: Dispatcher, M: Timer = TokioTimer> {
     config: Arc<Config>,
     input: ReadBuf<S>,
     output: WriteBuf<S>,
@@ -44,11 +115,73 @@ pub struct Loop<S, T, D: Dispatcher> {
     backpressure: Option<D::Future>,
     state: LoopState,
     server: bool,
-    handle: Handle,
+    timer: M,
     last_message_received: Instant,
     last_ping: Instant,
     last_byte: Instant,
-    timeout: Timeout,
+    last_tick: Instant,
+    timeout: M::Timeout,
+    backlog: VecDeque<Queued>,
+    backlog_bytes: usize,
+    pending_pong: Option<Vec<u8>>,
+    pending_pings: usize,
+    chunked: Option<Chunked>,
+    /// Dispatcher declined to stream the frame at the start of the buffer
+    buffer_frame: bool,
+    /// Receiver of the sink returned by `chunked_sink`
+    chunks: Option<mpsc::Receiver<BinaryChunk>>,
+    /// Last part of the chunked message is not sent yet
+    sending_chunked: bool,
+}
+
+/// An item of the output backlog
+enum Queued {
+    Packet(Packet),
+    /// A part of chunked message and whether it's the first one
+    Chunk(BinaryChunk, bool),
+}
+
+/// A binary message passed to `Dispatcher::frame_chunk`
+struct Chunked {
+    /// Payload of the current frame not received yet
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    /// Payload of the current frame received so far
+    offset: u64,
+    /// Current frame is the last one of the message
+    fin: bool,
+    /// `false` when waiting for a header of the continuation frame
+    in_frame: bool,
+}
+
+/// Policy applied when websocket output exceeds `max_output_backlog`
+///
+/// See `Config::max_output_backlog` and `Config::backlog_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    /// Drop oldest text and binary messages which are not yet buffered
+    ///
+    /// Control frames (pings, pongs, close) and parts of chunked messages
+    /// are never dropped.
+    DropOldest,
+    /// Send close frame with the specified code and stop sending messages
+    ///
+    /// Usual codes are `1008` (Policy Violation) and `1013`
+    /// (Try Again Later).
+    Close(u16),
+}
+
+/// Policy applied to received text frames which aren't valid utf-8
+///
+/// See `Config::invalid_utf8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Close the connection with `InvalidUtf8` error (as the spec requires)
+    Fail,
+    /// Deliver the payload as is in a `Frame::Binary`
+    Binary,
+    /// Deliver a `Frame::Text` with invalid sequences replaced by `U+FFFD`
+    Lossy,
 }
 
 
@@ -62,6 +195,14 @@ pub struct BlackHole;
 /// This is used with `Loop::closing()`.
 pub struct VoidError;
 
+/// A stream of outgoing packets of a loop created with `*_with_sink`
+///
+/// Packets are put there by the `mpsc::Sender` returned along with the loop.
+pub struct Outbox {
+    receiver: mpsc::Receiver<Packet>,
+    memberships: Vec<Membership>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum LoopState {
     Open,
@@ -85,27 +226,8 @@ impl<S, T, D, E> Loop<S, T, D>
         handle: &Handle)
         -> Loop<S, T, D>
     {
-        Loop {
-            config: config.clone(),
-            input: inp.into_inner(),
-            output: outp.into_inner(),
-            stream: Some(stream),
-            dispatcher: dispatcher,
-            backpressure: None,
-            state: LoopState::Open,
-            server: true,
-            handle: handle.clone(),
-            last_message_received: Instant::now(),
-            last_ping: Instant::now(),
-            last_byte: Instant::now(),
-            // Note: we expect that loop is polled immediately, so timeout
-            // is polled too
-            timeout: Timeout::new(
-                min(config.byte_timeout,
-                    min(config.ping_interval, config.message_timeout)),
-                handle)
-                .expect("Can always set timeout"),
-        }
+        Loop::server_with_timer(outp, inp, stream, dispatcher, config,
+                                TokioTimer::new(handle))
     }
     /// Create a new websocket Loop (client-side)
     ///
@@ -116,30 +238,168 @@ impl<S, T, D, E> Loop<S, T, D>
         stream: T, dispatcher: D, config: &Arc<Config>, handle: &Handle)
         -> Loop<S, T, D>
     {
+        Loop::client_with_timer(outp, inp, stream, dispatcher, config,
+                                TokioTimer::new(handle))
+    }
+}
+
+impl<S, T, D, E, M> Loop<S, T, D, M>
+    where T: Stream<Item=Packet, Error=E>,
+          D: Dispatcher,
+          M: Timer,
+{
+    /// Same as `server` but with a custom timer (see `timer` module)
+    pub fn server_with_timer(
+        outp: WriteFramed<S, ServerCodec>,
+        inp: ReadFramed<S, ServerCodec>,
+        stream: T, dispatcher: D, config: &Arc<Config>, timer: M)
+        -> Loop<S, T, D, M>
+    {
+        Loop::new(inp.into_inner(), outp.into_inner(), Some(stream),
+                  dispatcher, true, config, timer)
+    }
+    /// Same as `client` but with a custom timer (see `timer` module)
+    pub fn client_with_timer(
+        outp: WriteFramed<S, ClientCodec>,
+        inp: ReadFramed<S, ClientCodec>,
+        stream: T, dispatcher: D, config: &Arc<Config>, timer: M)
+        -> Loop<S, T, D, M>
+    {
+        Loop::new(inp.into_inner(), outp.into_inner(), Some(stream),
+                  dispatcher, false, config, timer)
+    }
+}
+
+impl<S, T, D: Dispatcher, M: Timer> Loop<S, T, D, M> {
+    fn new(input: ReadBuf<S>, output: WriteBuf<S>, stream: Option<T>,
+        dispatcher: D, server: bool,
+        config: &Arc<Config>, timer: M)
+        -> Loop<S, T, D, M>
+    {
+        let now = timer.now();
         Loop {
             config: config.clone(),
-            input: inp.into_inner(),
-            output: outp.into_inner(),
-            stream: Some(stream),
+            input: input,
+            output: output,
+            stream: stream,
             dispatcher: dispatcher,
             backpressure: None,
             state: LoopState::Open,
-            server: false,
-            handle: handle.clone(),
-            last_message_received: Instant::now(),
-            last_ping: Instant::now(),
-            last_byte: Instant::now(),
+            server: server,
+            last_message_received: now,
+            last_ping: now,
+            last_byte: now,
+            last_tick: now,
             // Note: we expect that loop is polled immediately, so timeout
             // is polled too
-            timeout: Timeout::new(
-                min(config.byte_timeout,
-                    min(config.ping_interval, config.message_timeout)),
-                handle)
-                .expect("Can always set timeout"),
+            timeout: timer.timeout_at(now + first_timeout(config)),
+            timer: timer,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            pending_pong: None,
+            pending_pings: 0,
+            chunked: None,
+            buffer_frame: false,
+            chunks: None,
+            sending_chunked: false,
+        }
+    }
+    /// Returns a sink to send large binary messages in parts
+    ///
+    /// Each message is a sequence of `BinaryChunk`s, the last one has
+    /// `fin` set. Up to `buffer` parts (plus one per sender) may be queued
+    /// in the channel. Parts are counted against `max_output_backlog`
+    /// like other messages, but are never dropped by `DropOldest` policy.
+    ///
+    /// Packets from the stream (including pings and pongs) wait until the
+    /// message being sent is finished, otherwise there is no order
+    /// between the stream and the sink. When the stream finishes, the sink
+    /// is closed too. If the sink is dropped (or replaced by calling this
+    /// method again) in the middle of a message, the connection is closed
+    /// with code `1011`.
+    pub fn chunked_sink(&mut self, buffer: usize)
+        -> mpsc::Sender<BinaryChunk>
+    {
+        if self.sending_chunked {
+            self.abort_chunked();
+        }
+        let (tx, rx) = mpsc::channel(buffer);
+        if self.stream.is_some() {
+            self.chunks = Some(rx);
+        }
+        tx
+    }
+    /// Closes connection because the chunked message can't be finished
+    fn abort_chunked(&mut self) {
+        debug!("Chunked message is not finished, closing");
+        self.sending_chunked = false;
+        self.chunks = None;
+        self.backlog.clear();
+        self.backlog_bytes = 0;
+        self.stream = None;
+        if self.state == LoopState::Open {
+            write_close(&mut self.output.out_buf,
+                        1011, "Chunked message is not finished",
+                        !self.server);
+            self.state = LoopState::CloseSent;
+        } else if self.state == LoopState::CloseReceived {
+            self.state = LoopState::Done;
         }
     }
 }
 
+impl<S, D: Dispatcher> Loop<S, Outbox, D> {
+    /// Create a new websocket Loop (server-side) and a sink to send
+    /// messages to
+    ///
+    /// This is an alternative to `Loop::server` for applications which
+    /// push messages imperatively instead of providing a stream of them.
+    /// Up to `buffer` messages (plus one per sender) may be queued in the
+    /// channel, the loop moves them into the output buffer (or backlog, see
+    /// `Config::max_output_backlog`) each time it's woken up.
+    ///
+    /// When all the senders are dropped, the loop sends close frame.
+    pub fn server_with_sink(
+        outp: WriteFramed<S, ServerCodec>,
+        inp: ReadFramed<S, ServerCodec>,
+        dispatcher: D, buffer: usize, config: &Arc<Config>,
+        handle: &Handle)
+        -> (Loop<S, Outbox, D>, mpsc::Sender<Packet>)
+    {
+        let (tx, rx) = mpsc::channel(buffer);
+        let outbox = Outbox::new(rx);
+        (Loop::server(outp, inp, outbox, dispatcher, config, handle), tx)
+    }
+    /// Create a new websocket Loop (client-side) and a sink to send
+    /// messages to
+    ///
+    /// See `server_with_sink` for details.
+    pub fn client_with_sink(
+        outp: WriteFramed<S, ClientCodec>,
+        inp: ReadFramed<S, ClientCodec>,
+        dispatcher: D, buffer: usize, config: &Arc<Config>,
+        handle: &Handle)
+        -> (Loop<S, Outbox, D>, mpsc::Sender<Packet>)
+    {
+        let (tx, rx) = mpsc::channel(buffer);
+        let outbox = Outbox::new(rx);
+        (Loop::client(outp, inp, outbox, dispatcher, config, handle), tx)
+    }
+}
+
+impl<S, D: Dispatcher, M: Timer> Loop<S, Outbox, D, M> {
+    /// Keep group membership until the loop is dropped
+    ///
+    /// So connection leaves the group (see `Groups::join`) immediately
+    /// when the loop is dropped, rather than on the next broadcast.
+    pub fn keep_membership(&mut self, membership: Membership) {
+        if let Some(ref mut outbox) = self.stream {
+            outbox.memberships.push(membership);
+        }
+        // otherwise all senders are gone and loop is closing anyway
+    }
+}
+
 impl<S> Loop<S, stream::Empty<Packet, VoidError>, BlackHole>
 {
     /// A websocket loop that sends failure and waits for closing handshake
@@ -166,35 +426,19 @@ impl<S> Loop<S, stream::Empty<Packet, VoidError>, BlackHole>
     {
         let mut out = outp.into_inner();
         write_close(&mut out.out_buf, reason, text, false);
-        Loop {
-            config: config.clone(),
-            input: inp.into_inner(),
-            output: out,
-            stream: None,
-            dispatcher: BlackHole,
-            backpressure: None,
-            state: LoopState::CloseSent,
-            // TODO(tailhook) should we provide client-size thing?
-            server: true,
-            handle: handle.clone(),
-            last_message_received: Instant::now(),
-            last_ping: Instant::now(),
-            last_byte: Instant::now(),
-            // Note: we expect that loop is polled immediately, so timeout
-            // is polled too
-            timeout: Timeout::new(
-                min(config.byte_timeout,
-                    min(config.ping_interval, config.message_timeout)),
-                handle)
-                .expect("Can always set timeout"),
-        }
+        // TODO(tailhook) should we provide client-size thing?
+        let mut lp = Loop::new(inp.into_inner(), out, None, BlackHole,
+                               true, config, TokioTimer::new(handle));
+        lp.state = LoopState::CloseSent;
+        lp
     }
 }
 
-impl<S, T, D, E> Loop<S, T, D>
+impl<S, T, D, E, M> Loop<S, T, D, M>
     where T: Stream<Item=Packet, Error=E>,
           D: Dispatcher,
           S: AsyncRead + AsyncWrite,
+          M: Timer,
 {
     fn read_stream(&mut self) -> Result<(), E> {
         if self.state == LoopState::CloseSent {
@@ -202,45 +446,323 @@ impl<S, T, D, E> Loop<S, T, D>
         }
         // For now we assume that there is no useful backpressure can
         // be applied to a stream, so we read everything from the stream
-        // and put it into a buffer
-        if let Some(ref mut stream) = self.stream {
-            loop {
-                match stream.poll()? {
-                    Async::Ready(value) => match value {
-                        Some(pkt) => {
-                            if self.server {
-                                ServerCodec.encode(pkt,
-                                    &mut self.output.out_buf);
-                            } else {
-                                ClientCodec.encode(pkt,
-                                    &mut self.output.out_buf);
-                            }
+        // and put it into a buffer (or a backlog if it's limited)
+        self.read_chunks();
+        if self.sending_chunked {
+            // the rest of the stream waits for the last part
+            self.flush_backlog();
+            return Ok(());
+        }
+        let mut pending = false;
+        loop {
+            let value = match self.stream {
+                Some(ref mut stream) => stream.poll()?,
+                None => break,
+            };
+            match value {
+                Async::Ready(Some(pkt)) => self.send_packet(pkt),
+                Async::Ready(None) => {
+                    self.chunks = None;
+                    // stream is finished, so all messages must
+                    // be delivered regardless of the limit
+                    for item in self.backlog.drain(..) {
+                        encode(self.server, item, &mut self.output.out_buf);
+                    }
+                    self.backlog_bytes = 0;
+                    match self.state {
+                        LoopState::Open => {
+                            // send close
+                            write_close(&mut self.output.out_buf,
+                                        1000, "", !self.server);
+                            self.state = LoopState::CloseSent;
                         }
-                        None => {
-                            match self.state {
-                                LoopState::Open => {
-                                    // send close
-                                    write_close(&mut self.output.out_buf,
-                                                1000, "", !self.server);
-                                    self.state = LoopState::CloseSent;
-                                }
-                                LoopState::CloseReceived => {
-                                    self.state = LoopState::Done;
-                                }
-                                _ => {}
-                            }
-                            break;
+                        LoopState::CloseReceived => {
+                            self.state = LoopState::Done;
                         }
-                    },
-                    Async::NotReady => {
-                        return Ok(());
+                        _ => {}
                     }
+                    break;
+                }
+                Async::NotReady => {
+                    pending = true;
+                    break;
+                }
+            }
+        }
+        if pending {
+            self.flush_backlog();
+        } else {
+            self.stream = None;
+        }
+        Ok(())
+    }
+    /// Puts parts of chunked messages from the sink into the output
+    /// buffer or backlog
+    fn read_chunks(&mut self) {
+        if self.stream.is_none() {
+            // no messages are sent any more
+            self.chunks = None;
+        }
+        loop {
+            let value = match self.chunks {
+                Some(ref mut chunks) => chunks.poll(),
+                None => return,
+            };
+            match value {
+                Ok(Async::Ready(Some(chunk))) => {
+                    let first = !self.sending_chunked;
+                    self.sending_chunked = !chunk.fin;
+                    self.write_or_backlog(Queued::Chunk(chunk, first));
+                }
+                Ok(Async::Ready(None)) | Err(()) => {
+                    self.chunks = None;
+                    if self.sending_chunked {
+                        self.abort_chunked();
+                    }
+                    return;
+                }
+                Ok(Async::NotReady) => return,
+            }
+        }
+    }
+    /// Puts a packet into the output buffer or backlog
+    fn send_packet(&mut self, pkt: Packet) {
+        self.write_or_backlog(Queued::Packet(pkt));
+    }
+    fn write_or_backlog(&mut self, item: Queued) {
+        if self.config.max_output_backlog.is_some() {
+            self.backlog_bytes += queued_size(&item);
+            self.backlog.push_back(item);
+        } else {
+            encode(self.server, item, &mut self.output.out_buf);
+        }
+    }
+    /// Moves messages from the backlog to the output buffer and applies
+    /// `backlog_policy` if there are more than `max_output_backlog` bytes
+    fn flush_backlog(&mut self) {
+        let limit = match self.config.max_output_backlog {
+            Some(limit) => limit,
+            None => return,
+        };
+        while self.output.out_buf.len() < limit ||
+              self.output.out_buf.len() == 0
+        {
+            match self.backlog.pop_front() {
+                Some(item) => {
+                    self.backlog_bytes -= queued_size(&item);
+                    encode(self.server, item, &mut self.output.out_buf);
+                }
+                None => break,
+            }
+        }
+        if self.output.out_buf.len() + self.backlog_bytes <= limit {
+            return;
+        }
+        match self.config.backlog_policy {
+            BacklogPolicy::DropOldest => {
+                let mut dropped = 0;
+                while self.output.out_buf.len() + self.backlog_bytes > limit {
+                    let idx = match self.backlog.iter().position(is_message) {
+                        Some(idx) => idx,
+                        None => break,
+                    };
+                    let item = self.backlog.remove(idx)
+                        .expect("index is valid");
+                    self.backlog_bytes -= queued_size(&item);
+                    dropped += 1;
+                }
+                debug!("Websocket output backlog exceeded, \
+                    dropped {} messages", dropped);
+            }
+            BacklogPolicy::Close(code) => {
+                debug!("Websocket output backlog exceeded, closing [{}]",
+                    code);
+                self.backlog.clear();
+                self.backlog_bytes = 0;
+                self.stream = None;
+                self.chunks = None;
+                self.sending_chunked = false;
+                if self.state == LoopState::Open {
+                    write_close(&mut self.output.out_buf,
+                                code, "Output backlog exceeded",
+                                !self.server);
+                    self.state = LoopState::CloseSent;
+                } else if self.state == LoopState::CloseReceived {
+                    self.state = LoopState::Done;
                 }
             }
         }
+    }
+    /// Puts a reply to the latest ping into the output buffer unless
+    /// the buffer is over `max_output_backlog`
+    fn write_pong(&mut self) {
+        if self.state == LoopState::CloseSent ||
+           self.state == LoopState::Done
+        {
+            self.pending_pong = None;
+            return;
+        }
+        if let Some(limit) = self.config.max_output_backlog {
+            let buffered = self.output.out_buf.len();
+            if buffered > 0 && buffered >= limit {
+                return;
+            }
+        }
+        if let Some(data) = self.pending_pong.take() {
+            write_packet(&mut self.output.out_buf,
+                         0xA, &data, !self.server);
+            self.pending_pings = 0;
+        }
+    }
+    /// Registers a ping received, closes connection if there are too many
+    fn ping_received(&mut self, data: Vec<u8>) {
+        if self.state != LoopState::Open &&
+           self.state != LoopState::CloseReceived
+        {
+            // no frames are allowed after close frame is sent
+            return;
+        }
+        self.pending_pings += 1;
+        if self.pending_pings <= self.config.max_pending_pings {
+            self.pending_pong = Some(data);
+            return;
+        }
+        debug!("Too many pings received while pong can't be sent \
+            ({}), closing", self.pending_pings);
+        self.pending_pong = None;
+        self.backlog.clear();
+        self.backlog_bytes = 0;
         self.stream = None;
+        self.chunks = None;
+        self.sending_chunked = false;
+        if self.state == LoopState::Open {
+            write_close(&mut self.output.out_buf,
+                        1008, "Too many pings", !self.server);
+            self.state = LoopState::CloseSent;
+        } else {
+            self.state = LoopState::Done;
+        }
+    }
+    /// Flushes output buffer refilling it from the backlog
+    fn flush_output(&mut self) -> Result<(), Error> {
+        self.write_pong();
+        loop {
+            let old_val = self.output.out_buf.len();
+            self.output.flush().map_err(ErrorEnum::Io)?;
+            if self.output.out_buf.len() >= old_val {
+                return Ok(());
+            }
+            self.last_byte = self.timer.now();
+            self.pending_pings = 0;
+            self.write_pong();
+            if self.backlog.is_empty() {
+                return Ok(());
+            }
+            self.flush_backlog();
+        }
+    }
+    fn next_deadline(&self) -> Instant {
+        let deadline = min(
+            self.last_message_received + self.config.message_timeout,
+            min(self.last_ping + self.config.ping_interval,
+                self.last_byte + self.config.byte_timeout));
+        match self.config.tick_interval {
+            Some(interval) if self.state == LoopState::Open => {
+                min(deadline, self.last_tick + interval)
+            }
+            _ => deadline,
+        }
+    }
+    fn tick(&mut self) -> Result<(), Error> {
+        let interval = match self.config.tick_interval {
+            Some(interval) if self.state == LoopState::Open => interval,
+            _ => return Ok(()),
+        };
+        let now = self.timer.now();
+        if now < self.last_tick + interval {
+            return Ok(());
+        }
+        self.last_tick = now;
+        if self.sending_chunked {
+            // packet can't be sent in the middle of the message anyway
+            return Ok(());
+        }
+        if let Some(pkt) = self.dispatcher.tick() {
+            self.send_packet(pkt);
+            self.flush_backlog();
+            self.flush_output()?;
+        }
         Ok(())
     }
+    /// Starts passing a binary frame to `Dispatcher::frame_chunk`
+    ///
+    /// Returns `true` if the payload at the start of the input buffer
+    /// belongs to such a frame.
+    fn start_chunk(&mut self, masked: Option<bool>) -> Result<bool, Error> {
+        if let Some(Chunked { in_frame: true, .. }) = self.chunked {
+            return Ok(true);
+        }
+        if self.chunked.is_none() && self.input.in_buf[0] & 0x0F != 0x2 {
+            return Ok(false);
+        }
+        let header = match parse_header(&self.input.in_buf, masked)? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        match (header.opcode, self.chunked.is_some()) {
+            (0x0, true) => {}
+            (0x1, true) | (0x2, true) => {
+                return Err(ErrorEnum::IncompleteMessage.into());
+            }
+            (0x2, false) if !self.buffer_frame => {
+                if !self.dispatcher.stream_binary(header.size) {
+                    self.buffer_frame = true;
+                    return Ok(false);
+                }
+            }
+            _ => return Ok(false),
+        }
+        self.input.in_buf.consume(header.len);
+        self.chunked = Some(Chunked {
+            remaining: header.size,
+            mask: header.mask,
+            offset: 0,
+            fin: header.fin,
+            in_frame: true,
+        });
+        Ok(true)
+    }
+    /// Passes the received part of a chunked frame to the dispatcher
+    ///
+    /// Returns `None` if there is no data to pass.
+    fn read_chunk(&mut self) -> Option<D::Future> {
+        let (fut, last) = {
+            let chunk = self.chunked.as_mut().expect("chunk is started");
+            let buffered = self.input.in_buf.len() as u64;
+            let len = min(chunk.remaining, buffered) as usize;
+            if len == 0 && chunk.remaining > 0 {
+                return None;
+            }
+            if let Some(mask) = chunk.mask {
+                let shift = (chunk.offset % 4) as usize;
+                apply_mask(&mut self.input.in_buf[..len],
+                    [mask[shift], mask[(shift + 1) % 4],
+                     mask[(shift + 2) % 4], mask[(shift + 3) % 4]]);
+            }
+            chunk.remaining -= len as u64;
+            chunk.offset += len as u64;
+            chunk.in_frame = chunk.remaining > 0;
+            let last = !chunk.in_frame && chunk.fin;
+            let fut = self.dispatcher.frame_chunk(
+                &self.input.in_buf[..len], last);
+            self.input.in_buf.consume(len);
+            (fut, last)
+        };
+        if last {
+            self.chunked = None;
+        }
+        Some(fut)
+    }
     /// Returns number of messages read
     fn read_messages(&mut self) -> Result<usize, Error> {
         if let Some(mut back) = self.backpressure.take() {
@@ -256,17 +778,47 @@ impl<S, T, D, E> Loop<S, T, D>
         let mut nmessages = 0;
         loop {
             while self.input.in_buf.len() > 0 {
-                let (fut, nbytes) = match
-                    Frame::parse(&mut self.input.in_buf,
-                                self.config.max_packet_size, self.server)?
+                let masked = if self.server {
+                    if self.config.accept_unmasked_frames {
+                        None
+                    } else {
+                        Some(true)
+                    }
+                } else if self.config.reject_masked_frames {
+                    Some(false)
+                } else {
+                    None
+                };
+                if self.start_chunk(masked)? {
+                    let mut fut = match self.read_chunk() {
+                        Some(fut) => fut,
+                        None => break,
+                    };
+                    nmessages += 1;
+                    match fut.poll()? {
+                        Async::Ready(()) => continue,
+                        Async::NotReady => {
+                            self.backpressure = Some(fut);
+                            return Ok(nmessages);
+                        }
+                    }
+                }
+                let lossy = self.config.invalid_utf8 == Utf8Policy::Lossy &&
+                    self.input.in_buf[0] & 0x0F == 0x1;
+                let (fut, ping, nbytes) = match
+                    Frame::parse_frame(&mut self.input.in_buf,
+                        self.config.max_text_size,
+                        self.config.max_binary_size,
+                        masked, self.config.invalid_utf8)?
                 {
                     Some((frame, nbytes)) => {
                         nmessages += 1;
+                        self.buffer_frame = false;
+                        let mut ping = None;
                         let fut = match frame {
                             Frame::Ping(data) => {
                                 trace!("Received ping {:?}", data);
-                                write_packet(&mut self.output.out_buf,
-                                             0xA, data, !self.server);
+                                ping = Some(data.to_vec());
                                 None
                             }
                             Frame::Pong(data) => {
@@ -280,15 +832,24 @@ impl<S, T, D, E> Loop<S, T, D>
                                 Some(self.dispatcher.frame(
                                     &Frame::Close(code, reply)))
                             }
+                            // text frame with invalid utf-8
+                            Frame::Binary(data) if lossy => {
+                                let text = String::from_utf8_lossy(data);
+                                Some(dispatch(&mut self.dispatcher,
+                                    &Frame::Text(&text)))
+                            }
                             pkt @ Frame::Text(_) | pkt @ Frame::Binary(_) => {
-                                Some(self.dispatcher.frame(&pkt))
+                                Some(dispatch(&mut self.dispatcher, &pkt))
                             }
                         };
-                        (fut, nbytes)
+                        (fut, ping, nbytes)
                     }
                     None => break,
                 };
                 self.input.in_buf.consume(nbytes);
+                if let Some(data) = ping {
+                    self.ping_received(data);
+                }
                 if self.state == LoopState::Done {
                     return Ok(nmessages);
                 }
@@ -310,7 +871,7 @@ impl<S, T, D, E> Loop<S, T, D>
                     return Ok(nmessages);
                 }
                 _ => {
-                    self.last_byte = Instant::now();
+                    self.last_byte = self.timer.now();
                     continue;
                 }
             }
@@ -318,11 +879,12 @@ impl<S, T, D, E> Loop<S, T, D>
     }
 }
 
-impl<S, T, D, E> Future for Loop<S, T, D>
+impl<S, T, D, E, M> Future for Loop<S, T, D, M>
     where T: Stream<Item=Packet, Error=E>,
           D: Dispatcher,
           E: fmt::Display,
           S: AsyncRead + AsyncWrite,
+          M: Timer,
 {
     type Item = ();  // TODO(tailhook) void?
     type Error = Error;
@@ -330,23 +892,14 @@ impl<S, T, D, E> Future for Loop<S, T, D>
     fn poll(&mut self) -> Result<Async<()>, Error> {
         self.read_stream()
             .map_err(|e| error!("Can't read from stream: {}", e)).ok();
-        let old_val = self.output.out_buf.len();
-        self.output.flush().map_err(ErrorEnum::Io)?;
-        if self.output.out_buf.len() < old_val {
-            self.last_byte = Instant::now();
-        }
+        self.flush_output()?;
         if self.state == LoopState::Done {
             return Ok(Async::Ready(()));
         }
         if self.read_messages()? > 0 {
-            self.last_message_received = Instant::now();
-            self.timeout = Timeout::new_at(
-                min(self.last_message_received +
-                        self.config.message_timeout,
-                min(self.last_ping + self.config.ping_interval,
-                    self.last_byte + self.config.byte_timeout)),
-                &self.handle,
-            ).expect("can always set timeout");
+            self.last_message_received = self.timer.now();
+            self.timeout = self.timer.timeout_at(self.next_deadline());
+            self.flush_output()?;
         }
         loop {
             match self.timeout.poll().map_err(|_| ErrorEnum::Timeout)? {
@@ -355,10 +908,11 @@ impl<S, T, D, E> Future for Loop<S, T, D>
                         self.last_message_received +
                             self.config.message_timeout,
                         self.last_byte + self.config.byte_timeout);
-                    if Instant::now() > deadline {
+                    let now = self.timer.now();
+                    if now > deadline {
                         self.state = LoopState::Done;
                         return Ok(Async::Ready(()));
-                    } else if Instant::now() >
+                    } else if now >
                         self.last_ping + self.config.ping_interval
                     {
                         debug!("Sending ping");
@@ -368,18 +922,14 @@ impl<S, T, D, E> Future for Loop<S, T, D>
                         self.output.flush().map_err(ErrorEnum::Io)?;
                         // only update time if more than ping has been flushed
                         if old_val > 0 && self.output.out_buf.len() < old_val {
-                            self.last_byte = Instant::now();
+                            self.last_byte = self.timer.now();
                         }
-                        self.last_ping = Instant::now();
+                        self.last_ping = self.timer.now();
                     }
+                    self.tick()?;
 
-                    self.timeout = Timeout::new_at(
-                        min(self.last_message_received +
-                                self.config.message_timeout,
-                        min(self.last_ping + self.config.ping_interval,
-                            self.last_byte + self.config.byte_timeout)),
-                        &self.handle)
-                        .expect("can always set timeout");
+                    self.timeout = self.timer.timeout_at(
+                        self.next_deadline());
                     match self.timeout.poll()
                           .map_err(|_| ErrorEnum::Timeout)?
                     {
@@ -397,6 +947,63 @@ impl<S, T, D, E> Future for Loop<S, T, D>
     }
 }
 
+fn first_timeout(config: &Config) -> Duration {
+    let timeout = min(config.byte_timeout,
+        min(config.ping_interval, config.message_timeout));
+    match config.tick_interval {
+        Some(interval) => min(timeout, interval),
+        None => timeout,
+    }
+}
+
+fn encode(server: bool, item: Queued, buf: &mut Buf) {
+    match item {
+        Queued::Packet(pkt) => if server {
+            ServerCodec.encode(pkt, buf);
+        } else {
+            ClientCodec.encode(pkt, buf);
+        },
+        Queued::Chunk(chunk, first) => chunk.write(buf, first, !server),
+    }
+}
+
+/// Passes a text or binary frame to the dispatcher
+fn dispatch<D: Dispatcher>(dispatcher: &mut D, frame: &Frame) -> D::Future {
+    let len = match *frame {
+        Frame::Text(text) => text.len(),
+        Frame::Binary(data) => data.len(),
+        _ => return dispatcher.frame(frame),
+    };
+    match dispatcher.payload_buffer(len) {
+        Some(buf) => {
+            let msg = message::new(frame, buf).expect("frame is a message");
+            dispatcher.message(msg)
+        }
+        None => dispatcher.frame(frame),
+    }
+}
+
+fn queued_size(item: &Queued) -> usize {
+    match *item {
+        Queued::Packet(Packet::Ping(ref data)) |
+        Queued::Packet(Packet::Pong(ref data)) => data.len(),
+        Queued::Packet(Packet::Binary(ref data)) => data.len(),
+        Queued::Packet(Packet::Text(ref text)) => text.len(),
+        Queued::Packet(Packet::Close(_, ref text)) => text.len() + 2,
+        Queued::Chunk(ref chunk, _) => chunk.data.len(),
+    }
+}
+
+fn is_message(item: &Queued) -> bool {
+    match *item {
+        Queued::Packet(Packet::Text(_)) => true,
+        Queued::Packet(Packet::Binary(_)) => true,
+        Queued::Packet(_) => false,
+        // dropping a part would corrupt the whole message
+        Queued::Chunk(..) => false,
+    }
+}
+
 impl Dispatcher for BlackHole {
     type Future = FutureResult<(), Error>;
     fn frame(&mut self, _frame: &Frame) -> Self::Future {
@@ -404,8 +1011,607 @@ impl Dispatcher for BlackHole {
     }
 }
 
+impl Outbox {
+    fn new(receiver: mpsc::Receiver<Packet>) -> Outbox {
+        Outbox {
+            receiver: receiver,
+            memberships: Vec::new(),
+        }
+    }
+}
+
+impl Stream for Outbox {
+    type Item = Packet;
+    type Error = VoidError;
+    fn poll(&mut self) -> Poll<Option<Packet>, VoidError> {
+        // receiver never fails
+        self.receiver.poll().map_err(|()| VoidError)
+    }
+}
+
 impl fmt::Display for VoidError {
     fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
         unreachable!();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cmp::min;
+    use std::io::{self, Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+
+    use futures::{Future, Async, Sink};
+    use futures::future::{lazy, Either, FutureResult, ok};
+    use futures::stream::{self, Stream};
+    use futures::sync::mpsc;
+    use tk_bufstream::{IoBuf, MockData};
+    use tokio_core::reactor::{Core, Timeout};
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    use websocket::{Config, Packet, ServerCodec, BacklogPolicy, Utf8Policy};
+    use websocket::{Frame, Error, Message, BinaryChunk};
+    use websocket::{Groups, MemberPolicy};
+    use timer::ManualTimer;
+    use super::{Loop, LoopState, BlackHole, VoidError, Dispatcher, Outbox};
+
+    /// A peer that never reads anything
+    struct Stalled;
+
+    impl Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Stalled {}
+    impl AsyncWrite for Stalled {
+        fn shutdown(&mut self) -> io::Result<Async<()>> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// A peer that sends data and never reads anything
+    struct Flood(io::Cursor<Vec<u8>>);
+
+    impl Read for Flood {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for Flood {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Flood {}
+    impl AsyncWrite for Flood {
+        fn shutdown(&mut self) -> io::Result<Async<()>> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Same as `Flood` but a single ping is read after the flag is set
+    struct Trickle(io::Cursor<Vec<u8>>, Arc<AtomicBool>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.1.swap(false, Ordering::SeqCst) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = min(buf.len(), 7);
+            match self.0.read(&mut buf[..len])? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Trickle {}
+    impl AsyncWrite for Trickle {
+        fn shutdown(&mut self) -> io::Result<Async<()>> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn pings(num: usize) -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..num {
+            // masked ping with zero mask and a single byte payload
+            input.extend_from_slice(b"\x89\x81\x00\x00\x00\x00");
+            input.push(b'a' + i as u8);
+        }
+        input
+    }
+
+    fn poll_pings(num: usize) -> (LoopState, Vec<u8>) {
+        let cfg = Config::new().max_pending_pings(4).done();
+        let (w, r) = IoBuf::new(Flood(io::Cursor::new(pings(num)))).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, BlackHole, &cfg, ManualTimer::new());
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        (lp.state, lp.output.out_buf[..].to_vec())
+    }
+
+    #[test]
+    fn pongs_coalesced() {
+        let (state, data) = poll_pings(4);
+        assert_eq!(state, LoopState::Open);
+        assert_eq!(data, b"\x8a\x01d");
+    }
+
+    #[test]
+    fn pings_answered_without_flush() {
+        let cfg = Config::new().max_pending_pings(4).done();
+        let next = Arc::new(AtomicBool::new(false));
+        let io = Trickle(io::Cursor::new(pings(6)), next.clone());
+        let (w, r) = IoBuf::new(io).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, BlackHole, &cfg, ManualTimer::new());
+        lazy(|| {
+            for _ in 0..6 {
+                next.store(true, Ordering::SeqCst);
+                assert!(lp.poll().unwrap().is_not_ready());
+            }
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        // every ping is answered as soon as it's received, so output
+        // not being flushed doesn't count as too many pending pings
+        assert_eq!(lp.state, LoopState::Open);
+        assert_eq!(&lp.output.out_buf[..],
+                   &b"\x8a\x01a\x8a\x01b\x8a\x01c\
+                      \x8a\x01d\x8a\x01e\x8a\x01f"[..]);
+    }
+
+    #[test]
+    fn too_many_pings() {
+        let (state, data) = poll_pings(5);
+        assert_eq!(state, LoopState::CloseSent);
+        assert_eq!(&data[..4], b"\x88\x10\x03\xF0");
+        assert_eq!(&data[4..], b"Too many pings");
+    }
+
+    fn poll_backlog(policy: BacklogPolicy)
+        -> (usize, usize, LoopState, Vec<u8>)
+    {
+        let mut core = Core::new().unwrap();
+        let cfg = Config::new()
+            .max_output_backlog(100)
+            .backlog_policy(policy)
+            .done();
+        let packets = (0..10).map(|_| Packet::Text("x".repeat(40)))
+            .collect::<Vec<_>>();
+        let stream = stream::iter_ok::<_, VoidError>(packets)
+            .chain(stream::poll_fn(|| Ok(Async::NotReady)));
+        let (w, r) = IoBuf::new(Stalled).split();
+        let mut lp = Loop::server(w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, BlackHole, &cfg, &core.handle());
+        core.run(lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        })).unwrap();
+        (lp.output.out_buf.len(), lp.backlog.len(), lp.state,
+         lp.output.out_buf[..].to_vec())
+    }
+
+    #[test]
+    fn backlog_drop_oldest() {
+        let (buffered, backlog, state, _) =
+            poll_backlog(BacklogPolicy::DropOldest);
+        // three frames (2 bytes header + 40 bytes) fit under the limit
+        assert_eq!(buffered, 126);
+        assert_eq!(backlog, 0);
+        assert_eq!(state, LoopState::Open);
+    }
+
+    #[test]
+    fn backlog_close() {
+        let (buffered, backlog, state, data) =
+            poll_backlog(BacklogPolicy::Close(1008));
+        assert_eq!(backlog, 0);
+        assert_eq!(state, LoopState::CloseSent);
+        assert_eq!(&data[126..130], &[0x88, 25, 0x03, 0xF0]);
+        assert_eq!(buffered, 126 + 4 + 23);
+    }
+
+    struct Keepalive;
+
+    impl Dispatcher for Keepalive {
+        type Future = FutureResult<(), Error>;
+        fn frame(&mut self, _frame: &Frame) -> Self::Future {
+            ok(())
+        }
+        fn tick(&mut self) -> Option<Packet> {
+            Some(Packet::Text("ka".into()))
+        }
+    }
+
+    struct Owned {
+        frames: Vec<Packet>,
+        messages: Vec<Message>,
+    }
+
+    impl Dispatcher for Owned {
+        type Future = FutureResult<(), Error>;
+        fn frame(&mut self, frame: &Frame) -> Self::Future {
+            self.frames.push(frame.into());
+            ok(())
+        }
+        fn payload_buffer(&mut self, len: usize) -> Option<BytesMut> {
+            if len > 3 {
+                Some(BytesMut::with_capacity(len))
+            } else {
+                None
+            }
+        }
+        fn message(&mut self, message: Message) -> Self::Future {
+            self.messages.push(message);
+            ok(())
+        }
+    }
+
+    struct Chunks {
+        chunks: Vec<(Vec<u8>, bool)>,
+        frames: Vec<Packet>,
+    }
+
+    impl Dispatcher for Chunks {
+        type Future = FutureResult<(), Error>;
+        fn frame(&mut self, frame: &Frame) -> Self::Future {
+            self.frames.push(frame.into());
+            ok(())
+        }
+        fn stream_binary(&mut self, len: u64) -> bool {
+            len > 3
+        }
+        fn frame_chunk(&mut self, data: &[u8], fin: bool) -> Self::Future {
+            self.chunks.push((data.to_vec(), fin));
+            ok(())
+        }
+    }
+
+    #[test]
+    fn chunked_message() {
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, Chunks { chunks: Vec::new(), frames: Vec::new() },
+            &Config::new().max_binary_size(4).done(), ManualTimer::new());
+        // "hello" masked with 01020304, split in the middle of the payload
+        mock.add_input(b"\x02\x85\x01\x02\x03\x04\x69\x67");
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            mock.add_input(b"\x6f\x68\x6e");
+            // a ping between the frames of the message
+            mock.add_input(b"\x89\x80\x00\x00\x00\x00");
+            mock.add_input(b"\x80\x85\x01\x02\x03\x04\
+                             \x76\x6d\x71\x68\x65");
+            mock.add_input(b"\x82\x82\x00\x00\x00\x00ab");
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        assert_eq!(lp.dispatcher.chunks, vec![
+            (b"he".to_vec(), false),
+            (b"llo".to_vec(), false),
+            (b"world".to_vec(), true),
+        ]);
+        assert_eq!(lp.dispatcher.frames.len(), 1);
+        assert!(matches!(lp.dispatcher.frames[0],
+            Packet::Binary(ref x) if x == b"ab"));
+        assert_eq!(mock.output(..), b"\x8a\x00");
+    }
+
+    #[test]
+    fn chunked_message_interrupted() {
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, Chunks { chunks: Vec::new(), frames: Vec::new() },
+            &Config::new().done(), ManualTimer::new());
+        mock.add_input(b"\x02\x84\x00\x00\x00\x00abcd");
+        mock.add_input(b"\x81\x82\x00\x00\x00\x00ab");
+        lazy(|| {
+            assert_eq!(format!("{:?}", lp.poll()),
+                       "Err(Error(IncompleteMessage))");
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        assert_eq!(lp.dispatcher.chunks, vec![(b"abcd".to_vec(), false)]);
+    }
+
+    fn chunk(data: &[u8], fin: bool) -> BinaryChunk {
+        BinaryChunk { data: data.to_vec(), fin: fin }
+    }
+
+    #[test]
+    fn chunked_output() {
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let (mut packets, rx) = mpsc::channel(10);
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            Outbox::new(rx), BlackHole, &Config::new().done(),
+            ManualTimer::new());
+        let mut chunks = lp.chunked_sink(10);
+        chunks.try_send(chunk(b"ab", false)).unwrap();
+        packets.try_send(Packet::Text("x".into())).unwrap();
+        packets.try_send(Packet::Ping(Vec::new())).unwrap();
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            chunks.try_send(chunk(b"c", true)).unwrap();
+            chunks.try_send(chunk(b"d", true)).unwrap();
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        // packets from the stream wait for the last part
+        assert_eq!(&mock.output(..)[..],
+            &b"\x02\x02ab\x80\x01c\x82\x01d\x81\x01x\x89\x00"[..]);
+    }
+
+    #[test]
+    fn chunked_sink_dropped() {
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, BlackHole, &Config::new().done(), ManualTimer::new());
+        let mut chunks = lp.chunked_sink(10);
+        chunks.try_send(chunk(b"ab", false)).unwrap();
+        drop(chunks);
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        assert_eq!(lp.state, LoopState::CloseSent);
+        let output = mock.output(..);
+        assert_eq!(&output[..8], b"\x02\x02ab\x88\x21\x03\xF3");
+    }
+
+    #[test]
+    fn membership_dropped_with_loop() {
+        let groups = Groups::new();
+        let (tx, rx) = mpsc::channel(10);
+        let (w, r) = IoBuf::new(MockData::new()).split();
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            Outbox::new(rx), BlackHole, &Config::new().done(),
+            ManualTimer::new());
+        lp.keep_membership(groups.join("a", tx, MemberPolicy::Skip));
+        assert_eq!(groups.members(&"a"), 1);
+        drop(lp);
+        // removed without calling `members` or `broadcast`
+        assert!(groups.lock().is_empty());
+    }
+
+    #[test]
+    fn chunked_backlog() {
+        let cfg = Config::new()
+            .max_output_backlog(100)
+            .backlog_policy(BacklogPolicy::DropOldest)
+            .done();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let (w, r) = IoBuf::new(Stalled).split();
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, BlackHole, &cfg, ManualTimer::new());
+        let mut chunks = lp.chunked_sink(10);
+        for _ in 0..5 {
+            chunks.try_send(chunk(&[0; 40], false)).unwrap();
+        }
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        // parts over the limit are kept in the backlog, not dropped
+        assert_eq!(lp.output.out_buf.len(), 126);
+        assert_eq!(lp.backlog.len(), 2);
+        assert_eq!(lp.backlog_bytes, 80);
+    }
+
+    #[test]
+    fn owned_messages() {
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, Owned { frames: Vec::new(), messages: Vec::new() },
+            &Config::new().done(), ManualTimer::new());
+        mock.add_input(b"\x81\x85\x01\x02\x03\x04\x69\x67\x6f\x68\x6e");
+        mock.add_input(b"\x82\x82\x00\x00\x00\x00\x01\x02");
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        let disp = &lp.dispatcher;
+        assert_eq!(disp.messages.len(), 1);
+        assert_eq!(disp.messages[0].as_text(), Some("hello"));
+        assert_eq!(disp.frames.len(), 1);
+        assert!(matches!(disp.frames[0],
+            Packet::Binary(ref x) if x == b"\x01\x02"));
+    }
+
+    fn invalid_utf8(policy: Utf8Policy) -> Owned {
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, Owned { frames: Vec::new(), messages: Vec::new() },
+            &Config::new().invalid_utf8(policy).done(), ManualTimer::new());
+        mock.add_input(b"\x81\x81\x00\x00\x00\x00\xff");
+        mock.add_input(b"\x81\x84\x00\x00\x00\x00ab\xffc");
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        lp.dispatcher
+    }
+
+    #[test]
+    fn invalid_utf8_binary() {
+        let disp = invalid_utf8(Utf8Policy::Binary);
+        assert_eq!(disp.frames.len(), 1);
+        assert!(matches!(disp.frames[0],
+            Packet::Binary(ref x) if x == b"\xff"));
+        assert_eq!(disp.messages.len(), 1);
+        assert!(!disp.messages[0].is_text());
+        assert_eq!(&disp.messages[0].data()[..], b"ab\xffc");
+    }
+
+    #[test]
+    fn invalid_utf8_lossy() {
+        let disp = invalid_utf8(Utf8Policy::Lossy);
+        assert_eq!(disp.frames.len(), 1);
+        assert!(matches!(disp.frames[0],
+            Packet::Text(ref x) if x == "\u{fffd}"));
+        assert_eq!(disp.messages.len(), 1);
+        assert_eq!(disp.messages[0].as_text(), Some("ab\u{fffd}c"));
+    }
+
+    #[test]
+    fn sink() {
+        let mut core = Core::new().unwrap();
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let (mut lp, tx) = Loop::server_with_sink(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            BlackHole, 1, &Config::new().done(), &core.handle());
+        let tx = core.run(tx.send(Packet::Text("hi".into()))).unwrap();
+        core.run(lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        })).unwrap();
+        assert_eq!(mock.output(..), b"\x81\x02hi");
+        drop(tx);
+        core.run(lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        })).unwrap();
+        assert_eq!(lp.state, LoopState::CloseSent);
+        assert_eq!(&mock.output(..)[4..], b"\x88\x02\x03\xE8");
+    }
+
+    #[test]
+    fn tick() {
+        let mut core = Core::new().unwrap();
+        let cfg = Config::new()
+            .tick_interval(Duration::from_millis(10))
+            .done();
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let lp = Loop::server(w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, Keepalive, &cfg, &core.handle());
+        let timeout = Timeout::new(Duration::from_millis(55), &core.handle())
+            .unwrap();
+        let result = core.run(lp.select2(timeout));
+        assert!(matches!(result, Ok(Either::B(..))));
+        let out = mock.output(..);
+        assert!(out.len() >= 8);
+        assert_eq!(out.len() % 4, 0);
+        assert_eq!(&out[..4], b"\x81\x02ka");
+    }
+
+    #[test]
+    fn tick_backlog() {
+        let timer = ManualTimer::new();
+        let cfg = Config::new()
+            .max_output_backlog(100)
+            .backlog_policy(BacklogPolicy::DropOldest)
+            .tick_interval(Duration::new(1, 0))
+            .done();
+        let packets = (0..3).map(|_| Packet::Text("x".repeat(40)))
+            .collect::<Vec<_>>();
+        let stream = stream::iter_ok::<_, VoidError>(packets)
+            .chain(stream::poll_fn(|| Ok(Async::NotReady)));
+        let (w, r) = IoBuf::new(Stalled).split();
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, Keepalive, &cfg, timer.clone());
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            assert_eq!(lp.output.out_buf.len(), 126);
+            timer.advance(Duration::new(1, 0));
+            assert!(lp.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        // keepalive is dropped as the buffer is over the limit
+        assert_eq!(lp.output.out_buf.len(), 126);
+        assert_eq!(lp.backlog.len(), 0);
+    }
+
+    #[test]
+    fn ping_and_message_timeout() {
+        let timer = ManualTimer::new();
+        let cfg = Config::new()
+            .ping_interval(Duration::new(10, 0))
+            .message_timeout(Duration::new(30, 0))
+            .byte_timeout(Duration::new(60, 0))
+            .done();
+        let mock = MockData::new();
+        let (w, r) = IoBuf::new(mock.clone()).split();
+        let stream = stream::poll_fn(|| Ok::<_, VoidError>(Async::NotReady));
+        let mut lp = Loop::server_with_timer(
+            w.framed(ServerCodec), r.framed(ServerCodec),
+            stream, BlackHole, &cfg, timer.clone());
+        lazy(|| {
+            assert!(lp.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(9, 0));
+            assert!(lp.poll().unwrap().is_not_ready());
+            assert_eq!(mock.output(..), b"");
+            timer.advance(Duration::new(2, 0));
+            assert!(lp.poll().unwrap().is_not_ready());
+            assert_eq!(mock.output(..), b"\x89\x0ctk-http-ping");
+            // pong postpones message timeout
+            mock.add_input(b"\x8a\x80\x00\x00\x00\x00");
+            assert!(lp.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(20, 0));
+            assert!(lp.poll().unwrap().is_not_ready());
+            timer.advance(Duration::new(11, 0));
+            assert!(lp.poll().unwrap().is_ready());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+}
@@ -30,18 +30,38 @@ quick_error! {
             display("Opcode of the frame is invalid: {}", code)
             from()
         }
-        /// Got unmasked frame
+        /// Got unmasked frame from the client
+        ///
+        /// See `Config::accept_unmasked_frames`
         Unmasked {
             description("Received unmasked frame")
         }
-        /// Got fragmented frame (fragmented frames are not supported yet)
+        /// Got masked frame from the server
+        ///
+        /// See `Config::reject_masked_frames`
+        Masked {
+            description("Received masked frame")
+        }
+        /// Got fragmented frame (only supported when receiving chunks)
         Fragmented {
             description("Received fragmented frame")
         }
+        /// A new message started before the fragmented one is complete
+        ///
+        /// Fragmented messages are only accepted when receiving chunks
+        /// (see `Dispatcher::stream_binary`).
+        IncompleteMessage {
+            description("Received new message before fragmented one is \
+                complete")
+        }
         /// Received frame that is longer than configured limit
         TooLong {
             description("Received frame that is too long")
         }
+        /// Received control frame (ping, pong, close) longer than 125 bytes
+        ControlTooLong {
+            description("Received control frame that is too long")
+        }
         /// Currently this error means that channel to/from websocket closed
         ///
         /// In future we expect this condition (processor dropping channel) to
@@ -62,6 +82,14 @@ quick_error! {
         PrematureResponseHeaders {
             description("response headers before request are sent")
         }
+        /// Handshake response is not received within the timeout
+        HandshakeTimeout {
+            description("timeout while waiting for handshake response")
+        }
+        /// Handshake response headers are larger than the limit
+        HeadersTooLong {
+            description("handshake response headers are too long")
+        }
         Custom(err: Box<::std::error::Error + Send + Sync>) {
             description("custom error")
             display("custom error: {}", err)
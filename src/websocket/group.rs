@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::sync::mpsc::Sender;
+
+use websocket::Packet;
+
+
+/// What to do with a member of a group which can't accept a message
+///
+/// Member can't accept a message when its channel is full, i.e. the peer
+/// reads slower than messages are broadcasted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberPolicy {
+    /// Skip the message for this member (lossy feeds)
+    Skip,
+    /// Remove the member from the group
+    ///
+    /// The sender is dropped, so if the group holds the last sender of the
+    /// loop, the loop sends close frame after already queued messages.
+    Remove,
+}
+
+static NEXT_MEMBER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) struct Member {
+    id: usize,
+    sender: Sender<Packet>,
+    policy: MemberPolicy,
+}
+
+trait Leave: Send {
+    fn leave(&mut self);
+}
+
+struct Entry<K: Hash + Eq> {
+    groups: Groups<K>,
+    key: K,
+    id: usize,
+}
+
+/// A guard which removes a connection from the group when dropped
+///
+/// Returned by `Groups::join`. Usually it's passed to
+/// `Loop::keep_membership` so the connection leaves the group as soon as
+/// its loop is dropped.
+#[must_use = "connection leaves the group when membership is dropped"]
+pub struct Membership(Box<Leave>);
+
+/// A registry of broadcast groups of websocket connections
+///
+/// Connections join a group by the sender returned from
+/// `Loop::server_with_sink` (or a clone of it). This is a cheap handle,
+/// clones share the same registry:
+///
+/// ```rust,ignore
+/// let (mut lp, tx) = Loop::server_with_sink(outp, inp, disp, 16, &cfg, &h);
+/// lp.keep_membership(groups.join(room.clone(), tx, MemberPolicy::Remove));
+/// // ... somewhere else
+/// groups.broadcast(&room, &Packet::Text(message));
+/// ```
+///
+/// Members are removed when their `Membership` is dropped, i.e. together
+/// with the loop. Loops which are finished but not dropped yet are removed
+/// on the next `broadcast`, `join` or `members` call for the group. Group
+/// is removed when it has no members left.
+pub struct Groups<K: Hash + Eq> {
+    groups: Arc<Mutex<HashMap<K, Vec<Member>>>>,
+}
+
+impl<K: Hash + Eq> Groups<K> {
+    /// Create an empty registry
+    pub fn new() -> Groups<K> {
+        Groups {
+            groups: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    pub(crate) fn lock<'a>(&'a self)
+        -> MutexGuard<'a, HashMap<K, Vec<Member>>>
+    {
+        self.groups.lock().expect("groups are not poisoned")
+    }
+    /// Add a connection to the group
+    ///
+    /// Connection stays in the group until returned guard is dropped (or
+    /// its loop is finished).
+    pub fn join(&self, key: K, sender: Sender<Packet>, policy: MemberPolicy)
+        -> Membership
+        where K: Clone + Send + 'static
+    {
+        let id = NEXT_MEMBER.fetch_add(1, Ordering::Relaxed);
+        let mut groups = self.lock();
+        let members = groups.entry(key.clone()).or_insert_with(Vec::new);
+        prune(members);
+        members.push(Member {
+            id: id,
+            sender: sender,
+            policy: policy,
+        });
+        Membership(Box::new(Entry {
+            groups: self.clone(),
+            key: key,
+            id: id,
+        }))
+    }
+    fn leave(&self, key: &K, id: usize) {
+        let mut groups = self.lock();
+        let empty = match groups.get_mut(key) {
+            Some(members) => {
+                members.retain(|m| m.id != id);
+                members.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            groups.remove(key);
+        }
+    }
+    /// Send a packet to all members of the group
+    ///
+    /// Returns number of members the packet is queued for. This never
+    /// blocks: members that can't accept the packet right now are handled
+    /// according to their `MemberPolicy`.
+    pub fn broadcast(&self, key: &K, packet: &Packet) -> usize {
+        let mut groups = self.lock();
+        let mut delivered = 0;
+        let empty = match groups.get_mut(key) {
+            Some(members) => {
+                let mut idx = 0;
+                while idx < members.len() {
+                    let keep = match members[idx].sender
+                        .try_send(packet.clone())
+                    {
+                        Ok(()) => {
+                            delivered += 1;
+                            true
+                        }
+                        Err(ref e) if e.is_full() => {
+                            members[idx].policy == MemberPolicy::Skip
+                        }
+                        // loop is finished
+                        Err(_) => false,
+                    };
+                    if keep {
+                        idx += 1;
+                    } else {
+                        members.swap_remove(idx);
+                    }
+                }
+                members.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            groups.remove(key);
+        }
+        delivered
+    }
+    /// Returns number of members of the group
+    ///
+    /// Finished loops are removed from the group before counting.
+    pub fn members(&self, key: &K) -> usize {
+        let mut groups = self.lock();
+        let num = match groups.get_mut(key) {
+            Some(members) => {
+                prune(members);
+                members.len()
+            }
+            None => return 0,
+        };
+        if num == 0 {
+            groups.remove(key);
+        }
+        num
+    }
+}
+
+/// Removes members whose loop is finished (receiver is dropped)
+fn prune(members: &mut Vec<Member>) {
+    members.retain(|m| !m.sender.is_closed());
+}
+
+impl<K: Hash + Eq> Clone for Groups<K> {
+    fn clone(&self) -> Groups<K> {
+        Groups {
+            groups: self.groups.clone(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Send> Leave for Entry<K> {
+    fn leave(&mut self) {
+        self.groups.leave(&self.key, self.id);
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.0.leave();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Stream, Async};
+    use futures::future::{Future, lazy};
+    use futures::sync::mpsc::channel;
+
+    use websocket::Packet;
+    use super::{Groups, MemberPolicy};
+
+    fn text(text: &str) -> Packet {
+        Packet::Text(text.to_string())
+    }
+
+    #[test]
+    fn broadcast() {
+        let groups = Groups::new();
+        let (tx1, mut rx1) = channel(1);
+        let (tx2, rx2) = channel(1);
+        let (tx3, mut rx3) = channel(0);
+        let _m1 = groups.join("a", tx1, MemberPolicy::Skip);
+        let _m2 = groups.join("a", tx2, MemberPolicy::Skip);
+        let _m3 = groups.join("b", tx3, MemberPolicy::Remove);
+        assert_eq!(groups.members(&"a"), 2);
+        drop(rx2);
+        assert_eq!(groups.broadcast(&"a", &text("x")), 1);
+        assert_eq!(groups.members(&"a"), 1);
+        assert_eq!(groups.broadcast(&"b", &text("y")), 1);
+        assert_eq!(groups.broadcast(&"c", &text("z")), 0);
+        lazy(|| {
+            assert!(matches!(rx1.poll(),
+                Ok(Async::Ready(Some(Packet::Text(ref x)))) if x == "x"));
+            assert!(matches!(rx1.poll(), Ok(Async::NotReady)));
+            assert!(matches!(rx3.poll(),
+                Ok(Async::Ready(Some(Packet::Text(ref x)))) if x == "y"));
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn slow_members() {
+        let groups = Groups::new();
+        let (tx1, _rx1) = channel(0);
+        let (tx2, _rx2) = channel(0);
+        let _m1 = groups.join(1, tx1, MemberPolicy::Skip);
+        let _m2 = groups.join(1, tx2, MemberPolicy::Remove);
+        assert_eq!(groups.broadcast(&1, &text("a")), 2);
+        // channels are full now
+        assert_eq!(groups.broadcast(&1, &text("b")), 0);
+        assert_eq!(groups.members(&1), 1);
+        assert_eq!(groups.clone().broadcast(&1, &text("c")), 0);
+        assert_eq!(groups.members(&1), 1);
+    }
+
+    #[test]
+    fn finished_members() {
+        let groups = Groups::new();
+        let (tx1, rx1) = channel(1);
+        let (tx2, rx2) = channel(1);
+        let (tx3, rx3) = channel(1);
+        let _m1 = groups.join("a", tx1, MemberPolicy::Skip);
+        let _m2 = groups.join("a", tx2, MemberPolicy::Remove);
+        drop(rx1);
+        assert_eq!(groups.members(&"a"), 1);
+        drop(rx2);
+        let _m3 = groups.join("a", tx3, MemberPolicy::Skip);
+        assert_eq!(groups.members(&"a"), 1);
+        assert_eq!(groups.lock()[&"a"].len(), 1);
+        drop(rx3);
+        assert_eq!(groups.members(&"a"), 0);
+        assert!(groups.lock().is_empty());
+    }
+
+    #[test]
+    fn dropped_membership() {
+        let groups = Groups::new();
+        let (tx1, _rx1) = channel(1);
+        let (tx2, _rx2) = channel(1);
+        let m1 = groups.join("a", tx1, MemberPolicy::Skip);
+        let m2 = groups.join("a", tx2.clone(), MemberPolicy::Skip);
+        drop(m1);
+        assert_eq!(groups.lock()[&"a"].len(), 1);
+        drop(m2);
+        assert!(groups.lock().is_empty());
+        // the other sender is still alive, but not in the group anymore
+        assert!(!tx2.is_closed());
+        assert_eq!(groups.broadcast(&"a", &text("x")), 0);
+    }
+}
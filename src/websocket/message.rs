@@ -0,0 +1,102 @@
+use std::str::from_utf8_unchecked;
+
+use bytes::{Bytes, BytesMut};
+
+use websocket::{Frame, Packet};
+
+
+/// An owned text or binary message
+///
+/// Message is created when `Dispatcher::payload_buffer` returns a buffer.
+/// The payload is copied there once from the input buffer of the
+/// connection (which is a `netbuf::Buf` and can't give away its memory),
+/// and after that can be sliced, cloned and sent to other threads without
+/// copying.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    text: bool,
+    data: Bytes,
+}
+
+/// Fills the buffer with payload of the frame
+///
+/// Returns `None` for control frames.
+pub fn new(frame: &Frame, mut buf: BytesMut) -> Option<Message> {
+    let (text, data) = match *frame {
+        Frame::Text(data) => (true, data.as_bytes()),
+        Frame::Binary(data) => (false, data),
+        _ => return None,
+    };
+    buf.clear();
+    buf.reserve(data.len());
+    buf.extend_from_slice(data);
+    Some(Message {
+        text: text,
+        data: buf.freeze(),
+    })
+}
+
+impl Message {
+    /// Returns `true` for text messages
+    pub fn is_text(&self) -> bool {
+        self.text
+    }
+    /// Returns text of the message or `None` for binary messages
+    pub fn as_text(&self) -> Option<&str> {
+        if self.text {
+            // utf-8 is validated when frame is parsed
+            Some(unsafe { from_utf8_unchecked(&self.data) })
+        } else {
+            None
+        }
+    }
+    /// Returns payload of the message (text is encoded as utf-8)
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+    /// Returns payload of the message
+    ///
+    /// Use `Bytes::try_mut` to get the buffer back for reuse when all the
+    /// clones of the payload are dropped.
+    pub fn into_bytes(self) -> Bytes {
+        self.data
+    }
+    /// Borrow message as a frame
+    pub fn as_frame<'a>(&'a self) -> Frame<'a> {
+        match self.as_text() {
+            Some(text) => Frame::Text(text),
+            None => Frame::Binary(&self.data),
+        }
+    }
+}
+
+impl Into<Packet> for Message {
+    fn into(self) -> Packet {
+        self.as_frame().into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use websocket::{Frame, Packet};
+    use super::new;
+
+    #[test]
+    fn reuse() {
+        let mut buf = BytesMut::with_capacity(64);
+        buf.extend_from_slice(b"garbage");
+        let msg = new(&Frame::Text("hello"), buf).unwrap();
+        assert_eq!(msg.as_text(), Some("hello"));
+        assert_eq!(msg.as_frame(), Frame::Text("hello"));
+        let buf = msg.into_bytes().try_mut().unwrap();
+        assert!(buf.capacity() >= 64);
+        let msg = new(&Frame::Binary(b"\x00\x01"), buf).unwrap();
+        assert!(!msg.is_text());
+        assert_eq!(&msg.data()[..], b"\x00\x01");
+        let pkt: Packet = msg.into();
+        assert!(matches!(pkt, Packet::Binary(ref x) if x == b"\x00\x01"));
+        assert!(new(&Frame::Ping(b""), BytesMut::new()).is_none());
+    }
+}
@@ -9,16 +9,23 @@ mod codec;
 mod config;
 mod dispatcher;
 mod error;
+mod group;
 mod keys;
+mod message;
 mod zero_copy;
 pub mod client;
 
-pub use self::alloc::Packet;
+pub use self::alloc::{Packet, BinaryChunk};
 pub use self::codec::{ServerCodec, ClientCodec};
-pub use self::dispatcher::{Loop, Dispatcher};
+pub use self::dispatcher::{Loop, Dispatcher, BacklogPolicy, Outbox};
+pub use self::dispatcher::Utf8Policy;
 pub use self::error::Error;
+pub use self::group::{Groups, MemberPolicy, Membership};
 pub use self::keys::{GUID, Accept, Key};
+pub use self::message::Message;
 pub use self::zero_copy::Frame;
+#[cfg(feature="bench")]
+pub(crate) use self::zero_copy::apply_mask;
 
 
 /// Configuration of a `websocket::Loop` object (a server-side websocket
@@ -28,5 +35,13 @@ pub struct Config {
     ping_interval: Duration,
     message_timeout: Duration,
     byte_timeout: Duration,
-    max_packet_size: usize,
+    max_text_size: usize,
+    max_binary_size: usize,
+    max_output_backlog: Option<usize>,
+    backlog_policy: BacklogPolicy,
+    max_pending_pings: usize,
+    tick_interval: Option<Duration>,
+    accept_unmasked_frames: bool,
+    reject_masked_frames: bool,
+    invalid_utf8: Utf8Policy,
 }
@@ -1,10 +1,11 @@
+use std::cmp::min;
 use std::str::from_utf8;
 
 use rand::{thread_rng, Rng};
 use tk_bufstream::Buf;
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, NativeEndian, ByteOrder};
 
-use super::{Packet};
+use super::{Packet, Utf8Policy};
 use websocket::error::ErrorEnum;
 
 
@@ -23,6 +24,18 @@ pub enum Frame<'a> {
     Close(u16, &'a str),
 }
 
+/// Header of a frame which payload is passed to the dispatcher in chunks
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameHeader {
+    pub opcode: u8,
+    pub fin: bool,
+    pub mask: Option<[u8; 4]>,
+    /// Size of the payload
+    pub size: u64,
+    /// Size of the header itself
+    pub len: usize,
+}
+
 impl<'a> Into<Packet> for Frame<'a> {
     fn into(self) -> Packet {
         use self::Frame as F;
@@ -57,61 +70,85 @@ impl<'a> Frame<'a> {
     /// Returns a frame and a number of bytes or None if no full frame was
     /// in the buffer. After frame is processes you should use
     /// `buf.consume(nbytes)`.
+    ///
+    /// The `limit` applies to text and binary frames, control frames are
+    /// always limited to 125 bytes.
     pub fn parse<'x>(buf: &'x mut Buf, limit: usize, masked: bool)
         -> Result<Option<(Frame<'x>, usize)>, ErrorEnum>
+    {
+        Frame::parse_with_limits(buf, limit, limit, masked)
+    }
+
+    /// Same as `parse` but with separate limits for text and binary frames
+    pub fn parse_with_limits<'x>(buf: &'x mut Buf,
+        text_limit: usize, binary_limit: usize, masked: bool)
+        -> Result<Option<(Frame<'x>, usize)>, ErrorEnum>
+    {
+        Frame::parse_frame(buf, text_limit, binary_limit, Some(masked),
+                           Utf8Policy::Fail)
+    }
+
+    /// Parse a frame with optional mask check
+    ///
+    /// `None` accepts both masked and unmasked frames. Unless `utf8` is
+    /// `Fail`, a text frame with invalid utf-8 is returned as `Binary`.
+    pub(crate) fn parse_frame<'x>(buf: &'x mut Buf,
+        text_limit: usize, binary_limit: usize, masked: Option<bool>,
+        utf8: Utf8Policy)
+        -> Result<Option<(Frame<'x>, usize)>, ErrorEnum>
     {
         use self::Frame::*;
 
-        if buf.len() < 2 {
-            return Ok(None);
-        }
-        let (size, fsize) = {
-            match buf[1] & 0x7F {
-                126 => {
-                    if buf.len() < 4 {
-                        return Ok(None);
-                    }
-                    (BigEndian::read_u16(&buf[2..4]) as u64, 4)
-                }
-                127 => {
-                    if buf.len() < 10 {
-                        return Ok(None);
-                    }
-                    (BigEndian::read_u64(&buf[2..10]), 10)
+        let (size, fsize) = match payload_size(buf) {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        let opcode = buf[0] & 0x0F;
+        let limit = match opcode {
+            0x1 => text_limit,
+            0x2 => binary_limit,
+            _ if opcode & 0x8 != 0 => {
+                if size > 125 {
+                    return Err(ErrorEnum::ControlTooLong);
                 }
-                size => (size as u64, 2),
+                125
             }
+            x => return Err(ErrorEnum::InvalidOpcode(x)),
         };
         if size > limit as u64 {
             return Err(ErrorEnum::TooLong);
         }
         let size = size as usize;
-        let start = fsize + if masked { 4 } else { 0 } /* mask size */;
-        if buf.len() < start + size {
+        let mask = buf[1] & 0x80 != 0;
+        match masked {
+            Some(true) if !mask => return Err(ErrorEnum::Unmasked),
+            Some(false) if mask => return Err(ErrorEnum::Masked),
+            _ => {}
+        }
+        let start = fsize + if mask { 4 } else { 0 } /* mask size */;
+        // size may be up to `limit`, so `start + size` can overflow
+        if buf.len() < start || buf.len() - start < size {
             return Ok(None);
         }
 
         let fin = buf[0] & 0x80 != 0;
-        let opcode = buf[0] & 0x0F;
         // TODO(tailhook) should we assert that reserved bits are zero?
-        let mask = buf[1] & 0x80 != 0;
         if !fin {
             return Err(ErrorEnum::Fragmented);
         }
-        if mask != masked {
-            return Err(ErrorEnum::Unmasked);
-        }
         if mask {
             let mask = [buf[start-4], buf[start-3], buf[start-2], buf[start-1]];
-            for idx in 0..size { // hopefully llvm is smart enough to optimize it
-                buf[start + idx] ^= mask[idx % 4];
-            }
+            apply_mask(&mut buf[start..start+size], mask);
         }
         let data = &buf[start..(start + size)];
         let frame = match opcode {
             0x9 => Ping(data),
             0xA => Pong(data),
-            0x1 => Text(from_utf8(data)?),
+            0x1 => match from_utf8(data) {
+                Ok(text) => Text(text),
+                Err(_) if utf8 != Utf8Policy::Fail => Binary(data),
+                Err(e) => return Err(e.into()),
+            },
             0x2 => Binary(data),
             // TODO(tailhook) implement shutdown packets
             0x8 => {
@@ -142,10 +179,73 @@ impl<'a> Frame<'a> {
     }
 }
 
+/// Returns size of the payload and number of bytes it's encoded with
+fn payload_size(buf: &Buf) -> Option<(u64, usize)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return None;
+            }
+            Some((BigEndian::read_u16(&buf[2..4]) as u64, 4))
+        }
+        127 => {
+            if buf.len() < 10 {
+                return None;
+            }
+            Some((BigEndian::read_u64(&buf[2..10]), 10))
+        }
+        size => Some((size as u64, 2)),
+    }
+}
+
+/// Parse only a header of the frame
+///
+/// Unlike `Frame::parse_frame` this doesn't check the opcode and size
+/// limits, so it works for frames which don't fit the buffer.
+pub(crate) fn parse_header(buf: &Buf, masked: Option<bool>)
+    -> Result<Option<FrameHeader>, ErrorEnum>
+{
+    let (size, fsize) = match payload_size(buf) {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    let mask = buf[1] & 0x80 != 0;
+    match masked {
+        Some(true) if !mask => return Err(ErrorEnum::Unmasked),
+        Some(false) if mask => return Err(ErrorEnum::Masked),
+        _ => {}
+    }
+    let len = fsize + if mask { 4 } else { 0 };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(FrameHeader {
+        opcode: buf[0] & 0x0F,
+        fin: buf[0] & 0x80 != 0,
+        mask: if mask {
+            Some([buf[len-4], buf[len-3], buf[len-2], buf[len-1]])
+        } else {
+            None
+        },
+        size: size,
+        len: len,
+    }))
+}
+
 pub(crate) fn write_packet(buf: &mut Buf, opcode: u8, data: &[u8], mask: bool)
+{
+    write_fragment(buf, opcode, data, true, mask)
+}
+
+/// Write a frame which may be not the last one of the message
+pub(crate) fn write_fragment(buf: &mut Buf, opcode: u8, data: &[u8],
+    fin: bool, mask: bool)
 {
     debug_assert!(opcode & 0xF0 == 0);
-    let first_byte = opcode | 0x80;  // always fin
+    let first_byte = if fin { opcode | 0x80 } else { opcode };
     let mask_bit = if mask { 0x80 } else { 0 };
     match data.len() {
         len @ 0...125 => {
@@ -177,9 +277,8 @@ pub(crate) fn write_packet(buf: &mut Buf, opcode: u8, data: &[u8], mask: bool)
     };
     buf.extend(data);
     if let Some((start, bytes)) = mask_data {
-        for idx in 0..(buf.len() - start) { // hopefully llvm will optimize it
-            buf[start + idx] ^= bytes[idx % 4];
-        }
+        let end = buf.len();
+        apply_mask(&mut buf[start..end], bytes);
     };
 }
 
@@ -200,17 +299,45 @@ pub(crate) fn write_close(buf: &mut Buf, code: u16, reason: &str, mask: bool) {
     buf.extend(&[(code >> 8) as u8, (code & 0xFF) as u8]);
     buf.extend(data);
     if let Some((start, bytes)) = mask_data {
-        for idx in 0..(buf.len() - start) { // hopefully llvm will optimize it
-            buf[start + idx] ^= bytes[idx % 4];
-        }
+        let end = buf.len();
+        apply_mask(&mut buf[start..end], bytes);
     };
 }
 
+/// XOR data with a mask (masking and unmasking are the same operation)
+///
+/// Works on 64-bit words for the aligned part of the data, which is also
+/// easily vectorized by the compiler.
+pub(crate) fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    let offset = (8 - data.as_ptr() as usize % 8) % 8;
+    let (head, tail) = data.split_at_mut(min(offset, data.len()));
+    for (idx, byte) in head.iter_mut().enumerate() {
+        *byte ^= mask[idx % 4];
+    }
+    let shift = head.len();
+    let mask = [mask[shift % 4], mask[(shift + 1) % 4],
+                mask[(shift + 2) % 4], mask[(shift + 3) % 4]];
+    let mask32 = NativeEndian::read_u32(&mask);
+    let mask64 = (mask32 as u64) << 32 | mask32 as u64;
+    let words = tail.len() / 8;
+    {
+        let ptr = tail.as_mut_ptr() as *mut u64;
+        for idx in 0..words {
+            // pointer is aligned and in bounds of the slice
+            unsafe { *ptr.offset(idx as isize) ^= mask64; }
+        }
+    }
+    for (idx, byte) in tail[words*8..].iter_mut().enumerate() {
+        *byte ^= mask[idx % 4];
+    }
+}
+
 #[cfg(test)]
 mod test {
     use netbuf::Buf;
     use std::iter::repeat;
-    use super::Frame;
+    use websocket::Utf8Policy;
+    use super::{Frame, apply_mask, parse_header};
     use super::Frame::*;
 
     #[test]
@@ -282,6 +409,25 @@ mod test {
             Some((Text(&repeat('x').take(4096).collect::<String>()), 4104)));
     }
 
+    #[test]
+    fn mask() {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let data = (0..100u8).collect::<Vec<_>>();
+        for start in 0..8 {
+            for len in 0..(100 - start) {
+                let mut buf = data.clone();
+                apply_mask(&mut buf[start..start+len], mask);
+                for (idx, &byte) in buf.iter().enumerate() {
+                    if idx >= start && idx < start + len {
+                        assert_eq!(byte, data[idx] ^ mask[(idx-start) % 4]);
+                    } else {
+                        assert_eq!(byte, data[idx]);
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn parse_small() {
         let data = b"\x81\x05hello";
@@ -334,4 +480,85 @@ mod test {
         assert_eq!(Frame::parse(&mut buf, 4096, false).unwrap(),
             Some((Text(&repeat('x').take(4096).collect::<String>()), 4100)));
     }
+
+    #[test]
+    fn control_frame_too_long() {
+        let mut buf = Buf::new();
+        // only header of the ping, the error is reported before payload
+        buf.extend(b"\x89\x7E\x00\x7E");
+        assert_eq!(format!("{:?}", Frame::parse(&mut buf, 1000, false)),
+                   "Err(ControlTooLong)");
+        let mut buf = Buf::new();
+        buf.extend(b"\x89\x7D");
+        buf.extend(&[b'x'; 125][..]);
+        assert_eq!(Frame::parse(&mut buf, 1000, false).unwrap(),
+                   Some((Ping(&[b'x'; 125][..]), 127)));
+    }
+
+    #[test]
+    fn text_and_binary_limits() {
+        let mut buf = Buf::new();
+        buf.extend(b"\x81\x05hello");
+        assert_eq!(format!("{:?}",
+                   Frame::parse_with_limits(&mut buf, 4, 10, false)),
+                   "Err(TooLong)");
+        assert_eq!(Frame::parse_with_limits(&mut buf, 10, 4, false).unwrap(),
+                   Some((Text("hello"), 7)));
+        let mut buf = Buf::new();
+        buf.extend(b"\x82\x05hello");
+        assert_eq!(format!("{:?}",
+                   Frame::parse_with_limits(&mut buf, 10, 4, false)),
+                   "Err(TooLong)");
+        assert_eq!(Frame::parse_with_limits(&mut buf, 4, 10, false).unwrap(),
+                   Some((Binary(b"hello"), 7)));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut buf = Buf::new();
+        buf.extend(b"\x81\x02\xff\xfe");
+        assert_eq!(format!("{:?}", Frame::parse(&mut buf, 1000, false)),
+                   "Err(InvalidUtf8(Utf8Error { valid_up_to: 0, \
+                    error_len: Some(1) }))");
+        assert_eq!(Frame::parse_frame(&mut buf, 1000, 1000, None,
+                                      Utf8Policy::Binary).unwrap(),
+                   Some((Binary(b"\xff\xfe"), 4)));
+        let mut buf = Buf::new();
+        // close reason is always validated
+        buf.extend(b"\x88\x04\x03\xe8\xff\xfe");
+        assert!(Frame::parse_frame(&mut buf, 1000, 1000, None,
+                                   Utf8Policy::Lossy).is_err());
+    }
+
+    #[test]
+    fn mask_check() {
+        let mut buf = Buf::new();
+        buf.extend(b"\x81\x05hello");
+        assert_eq!(format!("{:?}", Frame::parse(&mut buf, 1000, true)),
+                   "Err(Unmasked)");
+        assert_eq!(Frame::parse_frame(&mut buf, 1000, 1000, None,
+                                      Utf8Policy::Fail).unwrap(),
+                   Some((Text("hello"), 7)));
+        let mut buf = Buf::new();
+        // only header, the error is reported before payload
+        buf.extend(b"\x81\x85");
+        assert_eq!(format!("{:?}", Frame::parse(&mut buf, 1000, false)),
+                   "Err(Masked)");
+        buf.extend(b"\x00\x00\x00\x00hello");
+        assert_eq!(Frame::parse_frame(&mut buf, 1000, 1000, None,
+                                      Utf8Policy::Fail).unwrap(),
+                   Some((Text("hello"), 11)));
+    }
+
+    #[test]
+    fn huge_length() {
+        let mut buf = Buf::new();
+        buf.extend(b"\x82\x7F\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFFdata");
+        assert_eq!(Frame::parse(&mut buf, ::std::usize::MAX, false).unwrap(),
+                   None);
+        let header = parse_header(&buf, Some(false)).unwrap().unwrap();
+        assert_eq!(header.opcode, 2);
+        assert_eq!(header.size, ::std::u64::MAX);
+        assert_eq!(header.len, 10);
+    }
 }
@@ -0,0 +1,90 @@
+//! Counts allocations made in hot paths, run with `--features=bench`
+#![cfg(feature="bench")]
+extern crate futures;
+extern crate netbuf;
+extern crate tk_bufstream;
+extern crate tk_http;
+
+use std::alloc::{GlobalAlloc, System, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::Async;
+use futures::future::{FutureResult, ok};
+use netbuf::Buf;
+use tk_bufstream::MockData;
+
+use tk_http::bench::{parse_request_headers, decode_chunked};
+use tk_http::server::{Dispatcher, Codec, Head, RecvMode, Error};
+use tk_http::server::{Encoder, EncoderDone};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+struct Disp;
+struct Nop;
+
+impl Dispatcher<MockData> for Disp {
+    type Codec = Nop;
+    fn headers_received(&mut self, headers: &Head)
+        -> Result<Nop, Error>
+    {
+        assert_eq!(headers.path(), Some("/index.html"));
+        Ok(Nop)
+    }
+}
+
+impl Codec<MockData> for Nop {
+    type ResponseFuture = FutureResult<EncoderDone<MockData>, Error>;
+    fn recv_mode(&mut self) -> RecvMode {
+        RecvMode::buffered_upfront(0)
+    }
+    fn data_received(&mut self, data: &[u8], _end: bool)
+        -> Result<Async<usize>, Error>
+    {
+        Ok(Async::Ready(data.len()))
+    }
+    fn start_response(&mut self, e: Encoder<MockData>)
+        -> Self::ResponseFuture
+    {
+        ok(e.done())
+    }
+}
+
+fn count<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    f();
+    ALLOCATIONS.load(Ordering::SeqCst) - before
+}
+
+// Both checks are in a single test, because tests run in parallel and
+// share the counter
+#[test]
+fn allocations() {
+    let mut buf = Buf::new();
+    buf.extend(b"GET /index.html HTTP/1.1\r\n\
+                 Host: example.com\r\n\
+                 Connection: keep-alive\r\n\r\n");
+    assert_eq!(count(|| {
+        parse_request_headers(&mut buf, &mut Disp).unwrap().unwrap();
+    }), 0);
+
+    let mut buf = Buf::new();
+    buf.extend(b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n");
+    assert_eq!(count(|| {
+        assert_eq!(decode_chunked(&mut buf).unwrap(), Some(11));
+    }), 0);
+}